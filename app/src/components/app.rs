@@ -68,6 +68,7 @@ pub struct App {
 
 pub enum Msg {
     Login((String, bool)),
+    PasswordChangeRequired((String, bool)),
     Logout,
     PasswordResetProbeFinished(anyhow::Result<bool>),
 }
@@ -116,6 +117,10 @@ impl Component for App {
                     }
                 }));
             }
+            Msg::PasswordChangeRequired((user_name, is_admin)) => {
+                self.user_info = Some((user_name.clone(), is_admin));
+                history.push(AppRoute::ChangePassword { user_id: user_name });
+            }
             Msg::Logout => {
                 self.user_info = None;
                 self.redirect_to = None;
@@ -212,7 +217,7 @@ impl App {
     ) -> Html {
         match switch {
             AppRoute::Login => html! {
-                <LoginForm on_logged_in={link.callback(Msg::Login)} password_reset_enabled={password_reset_enabled.unwrap_or(false)}/>
+                <LoginForm on_logged_in={link.callback(Msg::Login)} on_password_change_required={link.callback(Msg::PasswordChangeRequired)} password_reset_enabled={password_reset_enabled.unwrap_or(false)}/>
             },
             AppRoute::CreateUser => html! {
                 <CreateUserForm/>
@@ -67,6 +67,7 @@ pub enum Msg {
     Submit,
     AuthenticationStartResponse(Result<Box<login::ServerLoginStartResponse>>),
     SubmitNewPassword,
+    PasswordPolicyResponse(Result<()>),
     RegistrationStartResponse(Result<Box<registration::ServerRegistrationStartResponse>>),
     RegistrationFinishResponse(Result<()>),
 }
@@ -99,6 +100,8 @@ impl CommonComponent<ChangePasswordForm> for ChangePasswordForm {
                     let req = login::ClientLoginStartRequest {
                         username: ctx.props().username.clone().into(),
                         login_start_request: login_start_request.message,
+                        challenge: None,
+                        context: None,
                     };
                     self.common.call_backend(
                         ctx,
@@ -126,6 +129,18 @@ impl CommonComponent<ChangePasswordForm> for ChangePasswordForm {
                 self.handle_msg(ctx, Msg::SubmitNewPassword)
             }
             Msg::SubmitNewPassword => {
+                self.common.call_backend(
+                    ctx,
+                    HostService::check_password_policy(
+                        ctx.props().username.clone(),
+                        self.form.model().password,
+                    ),
+                    Msg::PasswordPolicyResponse,
+                );
+                Ok(true)
+            }
+            Msg::PasswordPolicyResponse(res) => {
+                res?;
                 let mut rng = rand::rngs::OsRng;
                 let new_password = self.form.model().password;
                 let registration_start_request = opaque::client::registration::start_registration(
@@ -136,6 +151,7 @@ impl CommonComponent<ChangePasswordForm> for ChangePasswordForm {
                 let req = registration::ClientRegistrationStartRequest {
                     username: ctx.props().username.clone().into(),
                     registration_start_request: registration_start_request.message,
+                    context: None,
                 };
                 self.opaque_data = OpaqueData::Registration(registration_start_request.state);
                 self.common.call_backend(
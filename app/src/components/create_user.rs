@@ -57,6 +57,8 @@ fn empty_or_long(value: &str) -> Result<(), validator::ValidationError> {
 pub enum Msg {
     Update,
     SubmitForm,
+    PasswordPolicyResponse(Result<()>),
+    CreateUser,
     CreateUserResponse(Result<create_user::ResponseData>),
     SuccessfulCreation,
     RegistrationStartResponse(
@@ -80,6 +82,22 @@ impl CommonComponent<CreateUserForm> for CreateUserForm {
                 if !self.form.validate() {
                     bail!("Check the form for errors");
                 }
+                let model = self.form.model();
+                if model.password.is_empty() {
+                    return self.handle_msg(ctx, Msg::CreateUser);
+                }
+                self.common.call_backend(
+                    ctx,
+                    HostService::check_password_policy(model.username, model.password),
+                    Msg::PasswordPolicyResponse,
+                );
+                Ok(true)
+            }
+            Msg::PasswordPolicyResponse(res) => {
+                res?;
+                self.handle_msg(ctx, Msg::CreateUser)
+            }
+            Msg::CreateUser => {
                 let model = self.form.model();
                 let to_option = |s: String| if s.is_empty() { None } else { Some(s) };
                 let req = create_user::Variables {
@@ -125,6 +143,7 @@ impl CommonComponent<CreateUserForm> for CreateUserForm {
                     let req = registration::ClientRegistrationStartRequest {
                         username: user_id.into(),
                         registration_start_request: message,
+                        context: None,
                     };
                     self.common
                         .call_backend(ctx, HostService::register_start(req), move |r| {
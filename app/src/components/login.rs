@@ -17,6 +17,9 @@ pub struct LoginForm {
     common: CommonComponentParts<Self>,
     form: Form<FormModel>,
     refreshing: bool,
+    /// The last login finalization, sent again with the TOTP code when the server asks for one.
+    login_finish_request: Option<login::ClientLoginFinishRequest>,
+    totp_required: bool,
 }
 
 /// The fields of the form, with the constraints.
@@ -26,11 +29,15 @@ pub struct FormModel {
     username: String,
     #[validate(length(min = 8, message = "Invalid password. Min length: 8"))]
     password: String,
+    /// Only asked for when the server requires it.
+    totp_code: String,
 }
 
 #[derive(Clone, PartialEq, Properties)]
 pub struct Props {
     pub on_logged_in: Callback<(String, bool)>,
+    /// Called instead of `on_logged_in` when the password was set by an admin.
+    pub on_password_change_required: Callback<(String, bool)>,
     pub password_reset_enabled: bool,
 }
 
@@ -44,7 +51,7 @@ pub enum Msg {
             Result<Box<login::ServerLoginStartResponse>>,
         ),
     ),
-    AuthenticationFinishResponse(Result<(String, bool)>),
+    AuthenticationFinishResponse(Result<Option<((String, bool), bool)>>),
 }
 
 impl CommonComponent<LoginForm> for LoginForm {
@@ -60,7 +67,29 @@ impl CommonComponent<LoginForm> for LoginForm {
                 if !self.form.validate() {
                     bail!("Check the form for errors");
                 }
-                let FormModel { username, password } = self.form.model();
+                let FormModel {
+                    username,
+                    password,
+                    totp_code,
+                } = self.form.model();
+                if self.totp_required {
+                    if let Some(request) = self.login_finish_request.clone() {
+                        let totp_code = totp_code.trim();
+                        if totp_code.is_empty() {
+                            bail!("Missing authenticator code");
+                        }
+                        let req = login::ClientLoginFinishRequest {
+                            totp_code: Some(totp_code.to_owned()),
+                            ..request
+                        };
+                        self.common.call_backend(
+                            ctx,
+                            HostService::login_finish(req),
+                            Msg::AuthenticationFinishResponse,
+                        );
+                        return Ok(true);
+                    }
+                }
                 let mut rng = rand::rngs::OsRng;
                 let opaque::client::login::ClientLoginStartResult { state, message } =
                     opaque::client::login::start_login(&password, &mut rng)
@@ -68,6 +97,8 @@ impl CommonComponent<LoginForm> for LoginForm {
                 let req = login::ClientLoginStartRequest {
                     username: username.into(),
                     login_start_request: message,
+                    challenge: None,
+                    context: None,
                 };
                 self.common
                     .call_backend(ctx, HostService::login_start(req), move |r| {
@@ -89,10 +120,29 @@ impl CommonComponent<LoginForm> for LoginForm {
                         }
                         Ok(l) => l,
                     };
+                let consent = match res.banner {
+                    None => None,
+                    Some(banner) => {
+                        let acknowledged = web_sys::window()
+                            .and_then(|w| w.confirm_with_message(&banner).ok())
+                            .unwrap_or(false);
+                        if !acknowledged {
+                            self.common.error =
+                                Some(anyhow!("The login banner must be acknowledged to log in"));
+                            return Ok(true);
+                        }
+                        Some(login::banner_consent(&banner))
+                    }
+                };
                 let req = login::ClientLoginFinishRequest {
                     server_data: res.server_data,
                     credential_finalization: login_finish.message,
+                    consent,
+                    device_fingerprint: None,
+                    username: None,
+                    totp_code: None,
                 };
+                self.login_finish_request = Some(req.clone());
                 self.common.call_backend(
                     ctx,
                     HostService::login_finish(req),
@@ -101,9 +151,21 @@ impl CommonComponent<LoginForm> for LoginForm {
                 Ok(false)
             }
             Msg::AuthenticationFinishResponse(user_info) => {
-                ctx.props()
-                    .on_logged_in
-                    .emit(user_info.context("Could not log in")?);
+                match user_info.context("Could not log in")? {
+                    Some((user_info, must_change_password)) => {
+                        self.login_finish_request = None;
+                        self.totp_required = false;
+                        if must_change_password {
+                            ctx.props().on_password_change_required.emit(user_info);
+                        } else {
+                            ctx.props().on_logged_in.emit(user_info);
+                        }
+                    }
+                    None if self.totp_required => {
+                        self.common.error = Some(anyhow!("Invalid authenticator code"));
+                    }
+                    None => self.totp_required = true,
+                }
                 Ok(true)
             }
             Msg::AuthenticationRefreshResponse(user_info) => {
@@ -130,6 +192,8 @@ impl Component for LoginForm {
             common: CommonComponentParts::<Self>::create(),
             form: Form::<FormModel>::new(FormModel::default()),
             refreshing: true,
+            login_finish_request: None,
+            totp_required: false,
         };
         app.common.call_backend(
             ctx,
@@ -189,6 +253,25 @@ impl Component for LoginForm {
                       placeholder="Password"
                       autocomplete="current-password" />
                   </div>
+                  { if self.totp_required {
+                    html! {
+                      <div class="input-group">
+                        <div class="input-group-prepend">
+                          <span class="input-group-text">
+                            <i class="bi-shield-lock-fill"/>
+                          </span>
+                        </div>
+                        <Field
+                          class="form-control"
+                          form={&self.form}
+                          field_name="totp_code"
+                          placeholder="Authenticator code"
+                          autocomplete="one-time-code" />
+                      </div>
+                    }
+                  } else {
+                    html!{}
+                  }}
                   <div class="form-group mt-3">
                     <button
                       type="submit"
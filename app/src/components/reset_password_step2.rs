@@ -41,6 +41,7 @@ pub enum Msg {
     ValidateTokenResponse(Result<ServerPasswordResetResponse>),
     FormUpdate,
     Submit,
+    PasswordPolicyResponse(Result<()>),
     RegistrationStartResponse(Result<Box<registration::ServerRegistrationStartResponse>>),
     RegistrationFinishResponse(Result<()>),
 }
@@ -62,6 +63,18 @@ impl CommonComponent<ResetPasswordStep2Form> for ResetPasswordStep2Form {
                 if !self.form.validate() {
                     bail!("Check the form for errors");
                 }
+                self.common.call_backend(
+                    ctx,
+                    HostService::check_password_policy(
+                        self.username.clone().unwrap(),
+                        self.form.model().password,
+                    ),
+                    Msg::PasswordPolicyResponse,
+                );
+                Ok(true)
+            }
+            Msg::PasswordPolicyResponse(res) => {
+                res?;
                 let mut rng = rand::rngs::OsRng;
                 let new_password = self.form.model().password;
                 let registration_start_request =
@@ -70,6 +83,7 @@ impl CommonComponent<ResetPasswordStep2Form> for ResetPasswordStep2Form {
                 let req = registration::ClientRegistrationStartRequest {
                     username: self.username.as_ref().unwrap().into(),
                     registration_start_request: registration_start_request.message,
+                    context: None,
                 };
                 self.opaque_data = Some(registration_start_request.state);
                 self.common.call_backend(
@@ -1,8 +1,8 @@
 use super::cookies::set_cookie;
 use anyhow::{anyhow, Context, Result};
-use gloo_net::http::{Method, Request};
+use gloo_net::http::{Method, Request, Response};
 use graphql_client::GraphQLQuery;
-use lldap_auth::{login, registration, JWTClaims};
+use lldap_auth::{login, password_policy::PasswordPolicy, registration, JWTClaims};
 
 use serde::{de::DeserializeOwned, Serialize};
 use web_sys::RequestCredentials;
@@ -22,11 +22,7 @@ fn base_url() -> String {
     yew_router::utils::base_url().unwrap_or_default()
 }
 
-async fn call_server(
-    url: &str,
-    body: Option<impl Serialize>,
-    error_message: &'static str,
-) -> Result<String> {
+async fn send_request(url: &str, body: Option<impl Serialize>) -> Result<Response> {
     let mut request = Request::new(url)
         .header("Content-Type", "application/json")
         .credentials(RequestCredentials::SameOrigin);
@@ -35,16 +31,32 @@ async fn call_server(
             .body(serde_json::to_string(&b)?)
             .method(Method::POST);
     }
-    let response = request.send().await?;
+    Ok(request.send().await?)
+}
+
+fn response_error(response: &Response, error_message: &str, text: &str) -> anyhow::Error {
+    anyhow!(
+        "{}[{} {}]: {}",
+        error_message,
+        response.status(),
+        response.status_text(),
+        text
+    )
+}
+
+async fn call_server(
+    url: &str,
+    body: Option<impl Serialize>,
+    error_message: &'static str,
+) -> Result<String> {
+    let response = send_request(url, body).await?;
     if response.ok() {
         Ok(response.text().await?)
     } else {
-        Err(anyhow!(
-            "{}[{} {}]: {}",
+        Err(response_error(
+            &response,
             error_message,
-            response.status(),
-            response.status_text(),
-            response.text().await?
+            &response.text().await?,
         ))
     }
 }
@@ -110,8 +122,16 @@ impl HostService {
     }
 
     pub async fn login_start(
-        request: login::ClientLoginStartRequest,
+        mut request: login::ClientLoginStartRequest,
     ) -> Result<Box<login::ServerLoginStartResponse>> {
+        request.challenge =
+            call_server_json_with_error_message::<login::ServerLoginChallengeResponse, _>(
+                &(base_url() + "/auth/opaque/login/challenge"),
+                NO_BODY,
+                "Could not get the login challenge: ",
+            )
+            .await?
+            .challenge;
         call_server_json_with_error_message(
             &(base_url() + "/auth/opaque/login/start"),
             Some(request),
@@ -120,14 +140,32 @@ impl HostService {
         .await
     }
 
-    pub async fn login_finish(request: login::ClientLoginFinishRequest) -> Result<(String, bool)> {
-        call_server_json_with_error_message::<login::ServerLoginResponse, _>(
-            &(base_url() + "/auth/opaque/login/finish"),
-            Some(request),
-            "Could not finish authentication",
-        )
-        .await
-        .and_then(set_cookies_from_jwt)
+    /// Returns `None` if the server needs a TOTP code: the same request can be sent again with
+    /// the code.
+    pub async fn login_finish(
+        request: login::ClientLoginFinishRequest,
+    ) -> Result<Option<((String, bool), bool)>> {
+        const ERROR_MESSAGE: &str = "Could not finish authentication";
+        let response =
+            send_request(&(base_url() + "/auth/opaque/login/finish"), Some(request)).await?;
+        let text = response.text().await?;
+        if !response.ok() {
+            if response.status() == 401
+                && matches!(
+                    serde_json::from_str(&text),
+                    Ok(login::ServerLoginFinishTotpResponse {
+                        requires_totp: true
+                    })
+                )
+            {
+                return Ok(None);
+            }
+            return Err(response_error(&response, ERROR_MESSAGE, &text));
+        }
+        let response = serde_json::from_str::<login::ServerLoginResponse>(&text)
+            .context("Could not parse response")?;
+        let must_change_password = response.must_change_password;
+        set_cookies_from_jwt(response).map(|user_info| Some((user_info, must_change_password)))
     }
 
     pub async fn register_start(
@@ -172,6 +210,30 @@ impl HostService {
         .await
     }
 
+    /// Checks a new password against the policy of the server before registering it: with
+    /// OPAQUE, the server never sees the password.
+    pub async fn check_password_policy(username: String, password: String) -> Result<()> {
+        let policy: PasswordPolicy = call_server_json_with_error_message(
+            &(base_url() + "/auth/password_policy"),
+            NO_BODY,
+            "Could not get the password policy",
+        )
+        .await?;
+        let violations = policy.violations(&username, &password);
+        if violations.is_empty() {
+            Ok(())
+        } else {
+            Err(anyhow!(
+                "{}",
+                violations
+                    .iter()
+                    .map(ToString::to_string)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ))
+        }
+    }
+
     pub async fn reset_password_step1(username: String) -> Result<()> {
         call_server_empty_response_with_error_message(
             &format!(
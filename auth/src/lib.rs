@@ -15,12 +15,28 @@ pub mod login {
     pub struct ServerData {
         pub username: UserId,
         pub server_login: opaque::server::login::ServerLogin,
+        /// Hash of the password file the login started with, to detect concurrent changes.
+        pub password_file_hash: Option<Vec<u8>>,
+        pub context: Option<String>,
+    }
+
+    #[derive(Serialize, Deserialize, Clone)]
+    pub struct ServerLoginChallengeResponse {
+        /// Challenge to echo back in the login start request, if the server requires one.
+        pub challenge: Option<String>,
     }
 
     #[derive(Serialize, Deserialize, Clone)]
     pub struct ClientLoginStartRequest {
         pub username: UserId,
         pub login_start_request: opaque::server::login::CredentialRequest,
+        /// Challenge from the previous step, if any.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub challenge: Option<String>,
+        /// The context (e.g. a downstream service) the password was registered for, see
+        /// `password_contexts` in the server configuration. None for the plain user ID.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub context: Option<String>,
     }
 
     #[derive(Serialize, Deserialize, Clone)]
@@ -28,6 +44,9 @@ pub mod login {
         /// Base64, encrypted ServerData to be passed back to the server.
         pub server_data: String,
         pub credential_response: opaque::client::login::CredentialResponse,
+        /// Banner that the user has to acknowledge to finish the login, if any.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub banner: Option<String>,
     }
 
     #[derive(Serialize, Deserialize, Clone)]
@@ -35,6 +54,37 @@ pub mod login {
         /// Encrypted ServerData from the previous step.
         pub server_data: String,
         pub credential_finalization: opaque::client::login::CredentialFinalization,
+        /// Acknowledgment of the banner from the previous step, see `banner_consent`.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub consent: Option<String>,
+        /// Identifies the device the user logs in from, to detect logins from new devices.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub device_fingerprint: Option<String>,
+        /// The user the client started the login for. The server checks it against the one in
+        /// `server_data`, to detect a finalization replayed for another user.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub username: Option<UserId>,
+        /// Code from the user's authenticator app, if they have a TOTP secret.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub totp_code: Option<String>,
+    }
+
+    /// Sent with a 401 instead of the tokens when the password is right, but the user has a TOTP
+    /// secret and the code is missing or invalid: the client should prompt for the code and log
+    /// in again with it.
+    #[derive(Serialize, Deserialize, Clone)]
+    pub struct ServerLoginFinishTotpResponse {
+        pub requires_totp: bool,
+    }
+
+    /// Computes the value to send back to acknowledge the login banner: the hex-encoded SHA-256
+    /// of the banner text.
+    pub fn banner_consent(banner: &str) -> String {
+        use sha2::{Digest, Sha256};
+        Sha256::digest(banner.as_bytes())
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
     }
 
     #[derive(Serialize, Deserialize, Clone)]
@@ -57,6 +107,13 @@ pub mod login {
         pub token: String,
         #[serde(rename = "refreshToken", skip_serializing_if = "Option::is_none")]
         pub refresh_token: Option<String>,
+        /// The password was set by an admin: the user has to choose a new one.
+        #[serde(
+            rename = "mustChangePassword",
+            default,
+            skip_serializing_if = "std::ops::Not::not"
+        )]
+        pub must_change_password: bool,
     }
 }
 
@@ -68,12 +125,16 @@ pub mod registration {
     #[derive(Serialize, Deserialize, Clone)]
     pub struct ServerData {
         pub username: UserId,
+        pub context: Option<String>,
     }
 
     #[derive(Serialize, Deserialize, Clone)]
     pub struct ClientRegistrationStartRequest {
         pub username: UserId,
         pub registration_start_request: opaque::server::registration::RegistrationRequest,
+        /// The context the password is for: it can only be verified in that context.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub context: Option<String>,
     }
 
     #[derive(Serialize, Deserialize, Clone)]
@@ -104,6 +165,208 @@ pub mod password_reset {
     }
 }
 
+/// The public part of the server's OPAQUE setup, for third-party clients.
+pub mod server_setup {
+    use super::*;
+
+    /// Identifies the OPAQUE primitives used by the server, see [`opaque::DefaultSuite`].
+    pub const CIPHER_SUITE: &str = "ristretto255-tripledh-sha512-argon2id";
+
+    #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+    pub struct PublicServerSetup {
+        pub cipher_suite: String,
+        /// Base64 static public key of the server. Clients can check that the login was done
+        /// with the same key.
+        pub public_key: String,
+    }
+}
+
+/// Checks that need the cleartext password, for the code paths that have it.
+pub mod password_policy {
+    use serde::{Deserialize, Serialize};
+
+    #[derive(thiserror::Error, Clone, Copy, Debug, PartialEq, Eq)]
+    pub enum PolicyViolation {
+        #[error("The password contains the username")]
+        ContainsUsername,
+        #[error("The password is shorter than {min_length} characters")]
+        TooShort { min_length: usize },
+        #[error("The password needs both lowercase and uppercase letters")]
+        MissingMixedCase,
+        #[error("The password needs a digit")]
+        MissingDigit,
+        #[error("The password is too easy to guess")]
+        TooPredictable,
+    }
+
+    /// The rules for new passwords. The default accepts any password.
+    ///
+    /// This is shared with the clients, so that they can check a password before registering
+    /// it: with OPAQUE, the server only sees the cleartext when it sets the password itself.
+    #[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+    pub struct PasswordPolicy {
+        /// Reject passwords that contain the username, ignoring case.
+        pub forbid_username_in_password: bool,
+        /// In characters, not bytes.
+        pub min_length: usize,
+        pub require_mixed_case: bool,
+        pub require_digit: bool,
+        /// See [`estimate_entropy_bits`].
+        pub min_entropy_bits: Option<u32>,
+    }
+
+    impl PasswordPolicy {
+        /// Returns the first rule that the password breaks, if any.
+        pub fn check(&self, username: &str, password: &str) -> Result<(), PolicyViolation> {
+            match self.violations(username, password).into_iter().next() {
+                Some(violation) => Err(violation),
+                None => Ok(()),
+            }
+        }
+
+        /// Returns all the rules that the password breaks, to fix them at once.
+        pub fn violations(&self, username: &str, password: &str) -> Vec<PolicyViolation> {
+            let mut violations = Vec::new();
+            if self.forbid_username_in_password
+                && !username.is_empty()
+                && password.to_lowercase().contains(&username.to_lowercase())
+            {
+                violations.push(PolicyViolation::ContainsUsername);
+            }
+            if password.chars().count() < self.min_length {
+                violations.push(PolicyViolation::TooShort {
+                    min_length: self.min_length,
+                });
+            }
+            if self.require_mixed_case
+                && !(password.chars().any(char::is_lowercase)
+                    && password.chars().any(char::is_uppercase))
+            {
+                violations.push(PolicyViolation::MissingMixedCase);
+            }
+            if self.require_digit && !password.chars().any(|c| c.is_ascii_digit()) {
+                violations.push(PolicyViolation::MissingDigit);
+            }
+            if let Some(min_entropy_bits) = self.min_entropy_bits {
+                if estimate_entropy_bits(password) < min_entropy_bits as f64 {
+                    violations.push(PolicyViolation::TooPredictable);
+                }
+            }
+            violations
+        }
+    }
+
+    /// A rough upper bound of the entropy of the password, in bits: the cost of a brute force
+    /// over the kinds of characters it uses. Repeated characters only count once, so that
+    /// "aaaaaaaaaaaa" isn't considered strong, but there is no dictionary of common passwords.
+    pub fn estimate_entropy_bits(password: &str) -> f64 {
+        let mut pool_size = 0;
+        if password.chars().any(|c| c.is_ascii_lowercase()) {
+            pool_size += 26;
+        }
+        if password.chars().any(|c| c.is_ascii_uppercase()) {
+            pool_size += 26;
+        }
+        if password.chars().any(|c| c.is_ascii_digit()) {
+            pool_size += 10;
+        }
+        if password.chars().any(|c| !c.is_ascii_alphanumeric()) {
+            pool_size += 33;
+        }
+        let distinct_chars = password
+            .chars()
+            .collect::<std::collections::HashSet<_>>()
+            .len();
+        if pool_size == 0 {
+            return 0.0;
+        }
+        distinct_chars as f64 * (pool_size as f64).log2()
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn test_forbid_username_in_password() {
+            let policy = PasswordPolicy {
+                forbid_username_in_password: true,
+                ..Default::default()
+            };
+            assert_eq!(
+                policy.check("bob", "my-BoB-password"),
+                Err(PolicyViolation::ContainsUsername)
+            );
+            assert_eq!(
+                policy.check("Bob", "bob"),
+                Err(PolicyViolation::ContainsUsername)
+            );
+            assert_eq!(policy.check("bob", "correct horse"), Ok(()));
+            assert_eq!(PasswordPolicy::default().check("bob", "bob"), Ok(()));
+        }
+
+        fn strict_policy() -> PasswordPolicy {
+            PasswordPolicy {
+                forbid_username_in_password: true,
+                min_length: 8,
+                require_mixed_case: true,
+                require_digit: true,
+                min_entropy_bits: Some(40),
+            }
+        }
+
+        #[test]
+        fn test_empty_password() {
+            assert_eq!(
+                strict_policy().violations("bob", ""),
+                vec![
+                    PolicyViolation::TooShort { min_length: 8 },
+                    PolicyViolation::MissingMixedCase,
+                    PolicyViolation::MissingDigit,
+                    PolicyViolation::TooPredictable,
+                ]
+            );
+            assert_eq!(PasswordPolicy::default().violations("bob", ""), vec![]);
+        }
+
+        #[test]
+        fn test_too_short() {
+            assert_eq!(
+                strict_policy().check("bob", "Ab1-x"),
+                Err(PolicyViolation::TooShort { min_length: 8 })
+            );
+            // Characters, not bytes.
+            let policy = PasswordPolicy {
+                min_length: 4,
+                ..Default::default()
+            };
+            assert_eq!(
+                policy.check("bob", "ééé"),
+                Err(PolicyViolation::TooShort { min_length: 4 })
+            );
+            assert_eq!(policy.check("bob", "éééé"), Ok(()));
+        }
+
+        #[test]
+        fn test_all_requirements_pass() {
+            assert_eq!(
+                strict_policy().violations("bob", "Correct-Horse-7-Battery"),
+                vec![]
+            );
+            assert_eq!(
+                strict_policy().violations("bob", "Bob-Password-7"),
+                vec![PolicyViolation::ContainsUsername]
+            );
+        }
+
+        #[test]
+        fn test_repeated_characters_are_predictable() {
+            assert!(estimate_entropy_bits("Aa1Aa1Aa1Aa1") < estimate_entropy_bits("Zq8Xw3Lm2Pk9"));
+            assert_eq!(estimate_entropy_bits(""), 0.0);
+        }
+    }
+}
+
 pub mod types {
     use serde::{Deserialize, Serialize};
 
@@ -157,10 +420,29 @@ pub mod types {
     #[serde(from = "CaseInsensitiveString")]
     pub struct UserId(CaseInsensitiveString);
 
+    #[derive(thiserror::Error, Clone, Debug, PartialEq, Eq)]
+    pub enum InvalidUserId {
+        #[error("The username is {length} characters long, the maximum is {max_length}")]
+        TooLong { length: usize, max_length: usize },
+    }
+
     impl UserId {
         pub fn new(s: &str) -> Self {
             s.into()
         }
+        /// Like `new`, but rejects usernames longer than `max_length` characters.
+        pub fn try_new(s: &str, max_length: usize) -> Result<Self, InvalidUserId> {
+            let user_id = Self::new(s);
+            user_id.check_length(max_length)?;
+            Ok(user_id)
+        }
+        pub fn check_length(&self, max_length: usize) -> Result<(), InvalidUserId> {
+            let length = self.as_str().chars().count();
+            if length > max_length {
+                return Err(InvalidUserId::TooLong { length, max_length });
+            }
+            Ok(())
+        }
         pub fn as_str(&self) -> &str {
             self.0.as_str()
         }
@@ -168,12 +450,18 @@ pub mod types {
             self.0.into_string()
         }
     }
+    /// User IDs are lowercased and trimmed, so that "Bob " and "bob" are the same user.
     impl<T> From<T> for UserId
     where
         T: Into<CaseInsensitiveString>,
     {
         fn from(s: T) -> Self {
-            Self(s.into())
+            let s = s.into();
+            if s.as_str().trim() == s.as_str() {
+                Self(s)
+            } else {
+                Self(CaseInsensitiveString::new(s.as_str().trim()))
+            }
         }
     }
     impl std::fmt::Display for UserId {
@@ -196,6 +484,32 @@ pub mod types {
             ))
         }
     }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn test_user_id_normalization() {
+            assert_eq!(UserId::new(" Bob\t"), UserId::new("bob"));
+            assert_eq!(UserId::new("BOB").as_str(), "bob");
+            assert_eq!(UserId::from(" BOB ".to_owned()).as_str(), "bob");
+        }
+
+        #[test]
+        fn test_user_id_max_length() {
+            assert_eq!(UserId::try_new("bob", 3), Ok(UserId::new("bob")));
+            // The length is in characters, not bytes.
+            assert_eq!(UserId::try_new("bób", 3), Ok(UserId::new("bób")));
+            assert_eq!(
+                UserId::try_new("bobby", 3),
+                Err(InvalidUserId::TooLong {
+                    length: 5,
+                    max_length: 3
+                })
+            );
+        }
+    }
 }
 
 #[derive(Clone, Serialize, Deserialize)]
@@ -204,4 +518,7 @@ pub struct JWTClaims {
     pub iat: DateTime<Utc>,
     pub user: String,
     pub groups: HashSet<String>,
+    /// When the user entered their password. Refreshed tokens don't have it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub auth_time: Option<DateTime<Utc>>,
 }
@@ -60,6 +60,11 @@ impl CipherSuite for DefaultSuite {
     type SlowHash = ArgonHasher;
 }
 
+/// Description of the primitives of [`DefaultSuite`], for the logs.
+pub const CIPHERSUITE_NAME: &str = "ristretto255-TripleDH-SHA512-Argon2id";
+/// The OPAQUE implementation: the client and the server must agree on it.
+pub const PROTOCOL_VERSION: &str = "opaque-ke 0.6";
+
 /// Client-side code for OPAQUE protocol handling, to register a new user and login.  All methods'
 /// results must be sent to the server using the serialized `.message`. Incoming messages can be
 /// deserialized using the type's `deserialize` method.
@@ -135,9 +135,19 @@ fn try_login(
     use lldap_auth::opaque::client::login::*;
     let ClientLoginStartResult { state, message } =
         start_login(password, &mut rng).context("Could not initialize login")?;
+    // Older servers don't issue challenges.
+    let challenge = client
+        .get(format!("{}/auth/opaque/login/challenge", lldap_server))
+        .send()
+        .ok()
+        .filter(|response| response.status().is_success())
+        .and_then(|response| response.json::<ServerLoginChallengeResponse>().ok())
+        .and_then(|response| response.challenge);
     let req = ClientLoginStartRequest {
         username: username.into(),
         login_start_request: message,
+        challenge,
+        context: None,
     };
     let response = client
         .post(format!("{}/auth/opaque/login/start", lldap_server))
@@ -152,9 +162,16 @@ fn try_login(
     }
     let login_start_response = response.json::<lldap_auth::login::ServerLoginStartResponse>()?;
     let login_finish = finish_login(state, login_start_response.credential_response)?;
+    if let Some(banner) = &login_start_response.banner {
+        println!("{}", banner);
+    }
     let req = ClientLoginFinishRequest {
         server_data: login_start_response.server_data,
         credential_finalization: login_finish.message,
+        consent: login_start_response.banner.as_deref().map(banner_consent),
+        device_fingerprint: None,
+        username: Some(username.into()),
+        totp_code: None,
     };
     let response = client
         .post(format!("{}/auth/opaque/login/finish", lldap_server))
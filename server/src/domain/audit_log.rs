@@ -0,0 +1,340 @@
+use crate::domain::{
+    error::{DomainError, Result},
+    handler::{AuditAction, AuditLogEntry, AuditOutcome},
+    model::{self, AuditLogColumn},
+    sql_backend_handler::SqlBackendHandler,
+    sql_tables::DbConnection,
+    types::UserId,
+};
+use async_trait::async_trait;
+use chrono::{DurationRound, NaiveDateTime};
+use sea_orm::{
+    ActiveModelTrait, ActiveValue, ColumnTrait, EntityTrait, QueryFilter, QueryOrder,
+    TransactionTrait,
+};
+use sha2::{Digest, Sha256};
+use std::str::FromStr;
+use tracing::instrument;
+
+/// An entry to append to the audit log.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AuditRecord {
+    pub actor: Option<String>,
+    pub action: AuditAction,
+    pub target: Option<String>,
+    pub source_ip: Option<String>,
+    pub outcome: AuditOutcome,
+}
+
+/// Stores the audit log. The entries are never updated or deleted.
+#[async_trait]
+pub trait AuditLogger: Send + Sync {
+    async fn append(&self, record: AuditRecord) -> Result<()>;
+    /// The entries between the two dates (inclusive), oldest first.
+    async fn query(&self, since: NaiveDateTime, until: NaiveDateTime)
+        -> Result<Vec<AuditLogEntry>>;
+}
+
+/// Keeps the audit log in the database.
+pub struct SqlAuditLogger {
+    sql_pool: DbConnection,
+    /// Chain each entry to the previous one, so that deleting or changing entries is detected by
+    /// [`find_broken_chain_link`].
+    hash_chain: bool,
+}
+
+impl SqlAuditLogger {
+    pub fn new(sql_pool: DbConnection, hash_chain: bool) -> Self {
+        Self {
+            sql_pool,
+            hash_chain,
+        }
+    }
+}
+
+fn hash_entry(previous_hash: Option<&[u8]>, date: &NaiveDateTime, record: &AuditRecord) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.update(previous_hash.unwrap_or_default());
+    hasher.update(date.timestamp_micros().to_be_bytes());
+    for field in [
+        record.actor.as_deref(),
+        Some(record.action.into()),
+        record.target.as_deref(),
+        record.source_ip.as_deref(),
+        Some(record.outcome.into()),
+    ] {
+        // Separate the fields, and tell a missing field from an empty one.
+        match field {
+            Some(value) => {
+                hasher.update([1]);
+                hasher.update((value.len() as u64).to_be_bytes());
+                hasher.update(value.as_bytes());
+            }
+            None => hasher.update([0]),
+        }
+    }
+    hasher.finalize().to_vec()
+}
+
+/// Returns the first entry that doesn't follow the previous one in the hash chain, e.g. because
+/// an entry was deleted in between. The entries must be complete and in order, from the first
+/// one of the chain. Entries without a hash are not checked.
+pub fn find_broken_chain_link(entries: &[AuditLogEntry]) -> Option<i32> {
+    let mut previous_hash: Option<&[u8]> = None;
+    for entry in entries {
+        if let Some(entry_hash) = &entry.entry_hash {
+            let record = AuditRecord {
+                actor: entry.actor.clone(),
+                action: entry.action,
+                target: entry.target.clone(),
+                source_ip: entry.source_ip.clone(),
+                outcome: entry.outcome,
+            };
+            if hash_entry(previous_hash, &entry.date, &record) != *entry_hash {
+                return Some(entry.entry_id);
+            }
+        }
+        previous_hash = entry.entry_hash.as_deref();
+    }
+    None
+}
+
+#[async_trait]
+impl AuditLogger for SqlAuditLogger {
+    async fn append(&self, record: AuditRecord) -> Result<()> {
+        // Some databases only keep microseconds, and the hash must survive the round trip.
+        let date = chrono::Utc::now()
+            .naive_utc()
+            .duration_trunc(chrono::Duration::microseconds(1))
+            .map_err(|e| DomainError::InternalError(e.to_string()))?;
+        let hash_chain = self.hash_chain;
+        self.sql_pool
+            .transaction::<_, (), DomainError>(|transaction| {
+                Box::pin(async move {
+                    let entry_hash = if hash_chain {
+                        let previous_hash = model::AuditLog::find()
+                            .order_by_desc(AuditLogColumn::EntryId)
+                            .one(transaction)
+                            .await?
+                            .and_then(|e| e.entry_hash);
+                        Some(hash_entry(previous_hash.as_deref(), &date, &record))
+                    } else {
+                        None
+                    };
+                    model::audit_log::ActiveModel {
+                        event_date: ActiveValue::Set(date),
+                        actor: ActiveValue::Set(record.actor),
+                        action: ActiveValue::Set(
+                            Into::<&'static str>::into(record.action).to_owned(),
+                        ),
+                        target: ActiveValue::Set(record.target),
+                        source_ip: ActiveValue::Set(record.source_ip),
+                        outcome: ActiveValue::Set(
+                            Into::<&'static str>::into(record.outcome).to_owned(),
+                        ),
+                        entry_hash: ActiveValue::Set(entry_hash),
+                        ..Default::default()
+                    }
+                    .insert(transaction)
+                    .await?;
+                    Ok(())
+                })
+            })
+            .await?;
+        Ok(())
+    }
+
+    async fn query(
+        &self,
+        since: NaiveDateTime,
+        until: NaiveDateTime,
+    ) -> Result<Vec<AuditLogEntry>> {
+        let invalid_entry = |e: strum::ParseError| {
+            DomainError::InternalError(format!("Invalid audit entry: {}", e))
+        };
+        model::AuditLog::find()
+            .filter(AuditLogColumn::EventDate.between(since, until))
+            .order_by_asc(AuditLogColumn::EntryId)
+            .all(&self.sql_pool)
+            .await?
+            .into_iter()
+            .map(|e| {
+                Ok(AuditLogEntry {
+                    entry_id: e.entry_id,
+                    date: e.event_date,
+                    actor: e.actor,
+                    action: AuditAction::from_str(&e.action).map_err(invalid_entry)?,
+                    target: e.target,
+                    source_ip: e.source_ip,
+                    outcome: AuditOutcome::from_str(&e.outcome).map_err(invalid_entry)?,
+                    entry_hash: e.entry_hash,
+                })
+            })
+            .collect()
+    }
+}
+
+impl SqlBackendHandler {
+    /// Appends a successful or failed action to the audit log, if enabled in the configuration.
+    pub(crate) async fn audit(
+        &self,
+        actor: Option<&UserId>,
+        action: AuditAction,
+        target: Option<String>,
+        success: bool,
+    ) -> Result<()> {
+        let audit_logger = match &self.audit_logger {
+            Some(audit_logger) => audit_logger,
+            None => return Ok(()),
+        };
+        audit_logger
+            .append(AuditRecord {
+                actor: actor.map(ToString::to_string),
+                action,
+                target,
+                source_ip: None,
+                outcome: if success {
+                    AuditOutcome::Success
+                } else {
+                    AuditOutcome::Failure
+                },
+            })
+            .await
+    }
+
+    #[instrument(skip(self), level = "debug", err)]
+    pub(crate) async fn get_audit_log(
+        &self,
+        since: NaiveDateTime,
+        until: NaiveDateTime,
+    ) -> Result<Vec<AuditLogEntry>> {
+        match &self.audit_logger {
+            Some(audit_logger) => audit_logger.query(since, until).await,
+            None => Ok(Vec::new()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::{
+        handler::{BackendHandler, BindRequest, LoginHandler, UserBackendHandler},
+        sql_backend_handler::tests::*,
+    };
+    use pretty_assertions::assert_eq;
+
+    async fn get_handler(hash_chain: bool) -> SqlBackendHandler {
+        let mut config = get_default_config();
+        config.enable_audit_log = true;
+        config.audit_log_hash_chain = hash_chain;
+        SqlBackendHandler::new(config, get_initialized_db().await)
+    }
+
+    async fn all_entries(handler: &SqlBackendHandler) -> Vec<AuditLogEntry> {
+        let now = chrono::Utc::now().naive_utc();
+        handler
+            .query_audit_log(now - chrono::Duration::hours(1), now)
+            .await
+            .unwrap()
+    }
+
+    fn actions(entries: &[AuditLogEntry]) -> Vec<(AuditAction, Option<&str>, AuditOutcome)> {
+        entries
+            .iter()
+            .map(|e| (e.action, e.target.as_deref(), e.outcome))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_create_user_is_audited() {
+        let handler = get_handler(false).await;
+        insert_user_no_password(&handler, "bob").await;
+        assert_eq!(
+            actions(&all_entries(&handler).await),
+            vec![(AuditAction::CreateUser, Some("bob"), AuditOutcome::Success)]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_logins_and_memberships_are_audited() {
+        let handler = get_handler(false).await;
+        insert_user(&handler, "bob", "bob00").await;
+        let group = insert_group(&handler, "group").await;
+        insert_membership(&handler, group, "bob").await;
+        let _ = handler
+            .bind(BindRequest {
+                name: UserId::new("bob"),
+                password: "wrong".to_owned(),
+                context: None,
+                source: None,
+            })
+            .await;
+        handler.delete_user(&UserId::new("bob")).await.unwrap();
+        let entries = all_entries(&handler).await;
+        let group_target = format!("bob in group {}", group.0);
+        assert_eq!(
+            actions(&entries),
+            vec![
+                (AuditAction::CreateUser, Some("bob"), AuditOutcome::Success),
+                (
+                    AuditAction::PasswordChange,
+                    Some("bob"),
+                    AuditOutcome::Success
+                ),
+                (
+                    AuditAction::AddUserToGroup,
+                    Some(group_target.as_str()),
+                    AuditOutcome::Success
+                ),
+                (AuditAction::Bind, Some("bob"), AuditOutcome::Failure),
+                (AuditAction::DeleteUser, Some("bob"), AuditOutcome::Success),
+            ]
+        );
+        assert_eq!(entries[3].actor.as_deref(), Some("bob"));
+    }
+
+    #[tokio::test]
+    async fn test_audit_log_time_range() {
+        let handler = get_handler(false).await;
+        insert_user_no_password(&handler, "bob").await;
+        let now = chrono::Utc::now().naive_utc();
+        assert_eq!(
+            handler
+                .query_audit_log(
+                    now - chrono::Duration::hours(2),
+                    now - chrono::Duration::hours(1)
+                )
+                .await
+                .unwrap(),
+            vec![]
+        );
+        assert_eq!(all_entries(&handler).await.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_audit_log_disabled_by_default() {
+        let handler = SqlBackendHandler::new(get_default_config(), get_initialized_db().await);
+        insert_user_no_password(&handler, "bob").await;
+        assert_eq!(all_entries(&handler).await, vec![]);
+    }
+
+    #[tokio::test]
+    async fn test_hash_chain_detects_deletions() {
+        let handler = get_handler(true).await;
+        for user in ["bob", "john", "eve"] {
+            insert_user_no_password(&handler, user).await;
+        }
+        let entries = all_entries(&handler).await;
+        assert!(entries.iter().all(|e| e.entry_hash.is_some()));
+        assert_eq!(find_broken_chain_link(&entries), None);
+        model::AuditLog::delete_by_id(entries[1].entry_id)
+            .exec(&handler.sql_pool)
+            .await
+            .unwrap();
+        assert_eq!(
+            find_broken_chain_link(&all_entries(&handler).await),
+            Some(entries[2].entry_id)
+        );
+    }
+}
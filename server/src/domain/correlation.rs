@@ -0,0 +1,65 @@
+use std::future::Future;
+
+tokio::task_local! {
+    static CORRELATION_ID: String;
+}
+
+/// The longest correlation ID accepted from a client.
+const MAX_CORRELATION_ID_LENGTH: usize = 128;
+
+pub fn new_correlation_id() -> String {
+    uuid::Uuid::new_v4().to_string()
+}
+
+/// Keeps the ID sent by a client if it's reasonable to log, otherwise generates a new one.
+pub fn correlation_id_or_new(client_id: Option<&str>) -> String {
+    match client_id {
+        Some(id)
+            if !id.is_empty()
+                && id.len() <= MAX_CORRELATION_ID_LENGTH
+                && id.bytes().all(|b| b.is_ascii_graphic()) =>
+        {
+            id.to_owned()
+        }
+        _ => new_correlation_id(),
+    }
+}
+
+/// The correlation ID of the current request. Outside of a request (e.g. at startup), a new one
+/// is generated.
+pub fn correlation_id() -> String {
+    CORRELATION_ID
+        .try_with(Clone::clone)
+        .unwrap_or_else(|_| new_correlation_id())
+}
+
+/// Runs the future with the given correlation ID, for all the spans created while it runs.
+pub async fn with_correlation_id<F: Future>(id: String, future: F) -> F::Output {
+    CORRELATION_ID.scope(id, future).await
+}
+
+/// Like [`with_correlation_id`], for the synchronous part of a request.
+pub fn sync_with_correlation_id<R>(id: String, f: impl FnOnce() -> R) -> R {
+    CORRELATION_ID.sync_scope(id, f)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_correlation_id_scope() {
+        let id = with_correlation_id("abc".to_owned(), async { correlation_id() }).await;
+        assert_eq!(id, "abc");
+        assert_ne!(correlation_id(), "abc");
+    }
+
+    #[test]
+    fn test_correlation_id_or_new() {
+        assert_eq!(correlation_id_or_new(Some("req-42")), "req-42");
+        assert_ne!(correlation_id_or_new(Some("has space")), "has space");
+        assert_ne!(correlation_id_or_new(Some("")), "");
+        assert_ne!(correlation_id_or_new(Some(&"a".repeat(200))).len(), 200);
+        assert_eq!(correlation_id_or_new(None).len(), 36);
+    }
+}
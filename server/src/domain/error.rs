@@ -6,7 +6,10 @@ pub enum DomainError {
     #[error("Authentication error: `{0}`")]
     AuthenticationError(String),
     #[error("Database error: `{0}`")]
-    DatabaseError(#[from] sea_orm::DbErr),
+    DatabaseError(#[source] sea_orm::DbErr),
+    /// No connection of the pool was free before `db_acquire_timeout_seconds`.
+    #[error("Database timeout: `{0}`")]
+    DatabaseTimeout(String),
     #[error("Database transaction error: `{0}`")]
     DatabaseTransactionError(#[from] sea_orm::TransactionError<sea_orm::DbErr>),
     #[error("Authentication protocol error for `{0}`")]
@@ -19,8 +22,82 @@ pub enum DomainError {
     Base64DecodeError(#[from] base64::DecodeError),
     #[error("Entity not found: `{0}`")]
     EntityNotFound(String),
+    /// The password changed during the login: the client should start again.
+    #[error("Credentials changed during the login: `{0}`")]
+    CredentialsChanged(String),
+    #[error("Insecure transport: `{0}`")]
+    InsecureTransport(String),
+    /// All the rules of the password policy that the password breaks.
+    #[error(
+        "Password policy violation: `{}`",
+        .0.iter().map(ToString::to_string).collect::<Vec<_>>().join(", ")
+    )]
+    PasswordPolicyViolation(Vec<lldap_auth::password_policy::PolicyViolation>),
+    /// The password is right, but the user has a TOTP secret and the code is missing or invalid.
+    #[error("TOTP code required: `{0}`")]
+    TotpRequired(String),
+    #[error("Invalid TOTP secret: `{0}`")]
+    InvalidTotpSecret(String),
+    /// The password was used before by the user, e.g. it is in their password history.
+    #[error("Password reused for user `{0}`")]
+    PasswordReused(String),
+    /// The user already has a password, and replacing it requires a reset first.
+    #[error("Password already set for user `{0}`")]
+    PasswordAlreadySet(String),
+    /// A password rejected by the policy, without the reason.
+    #[error("Weak password for user `{0}`")]
+    WeakPassword(String),
+    #[error("Username is confusable with an existing user: `{0}`")]
+    ConfusableUsername(String),
+    #[error("Invalid username: `{0}`")]
+    InvalidUserId(#[from] lldap_auth::types::InvalidUserId),
     #[error("Internal error: `{0}`")]
     InternalError(String),
+    /// Adding a group to another would make it a member of itself.
+    #[error("Group membership cycle: `{0}`")]
+    GroupCycle(String),
+    /// The value can't be parsed as the type of the attribute in the schema.
+    #[error("Attribute type mismatch: `{0}`")]
+    AttributeTypeMismatch(String),
+    /// The password file in the database doesn't match its checksum: it was modified outside of
+    /// LLDAP.
+    #[error("Tampered password file: `{0}`")]
+    TamperedPasswordFile(String),
+    /// The second field is how long until the limit is lifted: always set for the per-address
+    /// rate limit, and with `throttle_options.send_retry_after` for the others.
+    #[error("Too many attempts: `{0}`")]
+    RateLimited(String, Option<std::time::Duration>),
+    /// The password is right, but it was temporary: the user has to set a new one.
+    #[error("Password expired: `{0}`")]
+    PasswordExpired(String),
+    /// Too many wrong passwords in a row: the account is locked until the end of the window.
+    #[error("Account locked: `{0}`")]
+    AccountLocked(String),
+    /// The account was disabled by an admin. The password is not checked.
+    #[error("Account disabled: `{0}`")]
+    AccountDisabled(String),
+    #[error("Too many outstanding registrations: `{0}`")]
+    TooManyOutstandingRegistrations(String),
+    /// The client sent back a server_data that can't be decrypted: it was tampered with, or
+    /// sealed with another key. Retrying with the same data won't help.
+    #[error("Invalid server data: `{0}`")]
+    InvalidServerData(String),
+    /// New logins are refused for now, with an optional message for the users.
+    #[error("Under maintenance: `{}`", .0.as_deref().unwrap_or("try again later"))]
+    Maintenance(Option<String>),
+}
+
+impl From<sea_orm::DbErr> for DomainError {
+    fn from(value: sea_orm::DbErr) -> Self {
+        match value {
+            sea_orm::DbErr::ConnectionAcquire(sea_orm::ConnAcquireErr::Timeout) => {
+                DomainError::DatabaseTimeout(
+                    "No database connection available, the pool is exhausted".to_owned(),
+                )
+            }
+            e => DomainError::DatabaseError(e),
+        }
+    }
 }
 
 impl From<sea_orm::TransactionError<DomainError>> for DomainError {
@@ -0,0 +1,79 @@
+use crate::domain::{
+    error::Result,
+    handler::{LoginFailureReason, LoginSource},
+    types::{GroupId, UserId},
+};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::sync::Mutex;
+
+/// A login attempt, successful or not, for the services that react to them.
+#[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone)]
+pub struct LoginEvent {
+    pub user_id: UserId,
+    pub source: LoginSource,
+    pub success: bool,
+    pub failure_reason: Option<LoginFailureReason>,
+    pub date: chrono::NaiveDateTime,
+    pub correlation_id: String,
+}
+
+/// Sends the login events to a message queue or another service. The errors are logged and
+/// otherwise ignored: they never change the outcome of a login. The publishers are awaited
+/// during the login, so the ones that use the network should not wait for the delivery.
+#[async_trait]
+pub trait EventPublisher: Send + Sync {
+    async fn publish(&self, event: &LoginEvent) -> Result<()>;
+}
+
+/// Keeps the events in memory, for tests and embedding.
+#[derive(Default)]
+pub struct MemoryEventPublisher {
+    events: Mutex<Vec<LoginEvent>>,
+}
+
+impl MemoryEventPublisher {
+    pub fn events(&self) -> Vec<LoginEvent> {
+        self.events.lock().unwrap().clone()
+    }
+}
+
+#[async_trait]
+impl EventPublisher for MemoryEventPublisher {
+    async fn publish(&self, event: &LoginEvent) -> Result<()> {
+        self.events.lock().unwrap().push(event.clone());
+        Ok(())
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LifecycleEventType {
+    UserCreated,
+    UserUpdated,
+    UserDeleted,
+    UserAddedToGroup,
+    UserRemovedFromGroup,
+}
+
+/// A change to a user or their groups, for the systems that mirror LLDAP.
+#[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone)]
+pub struct LifecycleEvent {
+    pub event_type: LifecycleEventType,
+    pub user_id: UserId,
+    /// For the membership changes.
+    pub group_id: Option<GroupId>,
+    pub date: chrono::NaiveDateTime,
+}
+
+/// Delivers the lifecycle events. This is called on the request path, after the change is
+/// committed: the delivery must happen in the background, and never fail the change.
+pub trait LifecycleNotifier: Send + Sync {
+    fn notify(&self, event: LifecycleEvent);
+}
+
+/// Welcomes the users created through LLDAP, e.g. by email. Like the [`LifecycleNotifier`], it's
+/// called after the creation is committed, and must not block nor fail it.
+pub trait WelcomeNotifier: Send + Sync {
+    fn welcome(&self, user_id: &UserId, email: &str, display_name: Option<&str>);
+}
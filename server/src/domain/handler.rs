@@ -6,13 +6,25 @@ use crate::domain::{
     },
 };
 use async_trait::async_trait;
+use secstr::SecUtf8;
 use serde::{Deserialize, Serialize};
-use std::collections::HashSet;
+use std::{
+    collections::{BTreeMap, HashSet},
+    net::IpAddr,
+};
+use strum::{EnumString, IntoStaticStr};
 
 #[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone)]
 pub struct BindRequest {
     pub name: UserId,
     pub password: String,
+    /// The address the request came from, if known, for the throttling. Never taken from the
+    /// client.
+    #[serde(skip)]
+    pub source: Option<IpAddr>,
+    /// Which of the user's passwords to check, see `password_contexts`. The primary one if unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub context: Option<String>,
 }
 
 #[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone)]
@@ -106,6 +118,8 @@ pub struct CreateUserRequest {
     pub last_name: Option<String>,
     pub avatar: Option<JpegPhoto>,
     pub attributes: Vec<AttributeValue>,
+    /// The bcrypt hash of the password of a user imported from another directory.
+    pub legacy_password_hash: Option<String>,
 }
 
 #[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone, Default)]
@@ -180,11 +194,27 @@ pub struct Schema {
 #[async_trait]
 pub trait LoginHandler: Send + Sync {
     async fn bind(&self, request: BindRequest) -> Result<()>;
+    /// Checks the password like `bind`, for monitoring probes: the attempt isn't throttled,
+    /// recorded, or counted towards a lockout.
+    async fn health_bind(&self, request: BindRequest) -> Result<()>;
+    /// Checks the password like `bind`, for an LDAP Compare. The wrong passwords are throttled
+    /// and counted towards a lockout like failed binds, but a match has no effect: no login is
+    /// recorded, and the failure counters are kept.
+    async fn compare_password(&self, request: BindRequest) -> Result<bool>;
+    /// Logs in with a login link instead of a password. The link can't be used again.
+    async fn bind_with_token(&self, token: &str) -> Result<UserId>;
 }
 
 #[async_trait]
 pub trait GroupListerBackendHandler: ReadSchemaBackendHandler {
     async fn list_groups(&self, filters: Option<GroupRequestFilter>) -> Result<Vec<Group>>;
+    /// Lists at most `first` groups, sorted by ID, starting strictly after the `after` group.
+    async fn list_groups_page(
+        &self,
+        filters: Option<GroupRequestFilter>,
+        first: u64,
+        after: Option<GroupId>,
+    ) -> Result<GroupPage>;
 }
 
 #[async_trait]
@@ -197,11 +227,22 @@ pub trait GroupBackendHandler: ReadSchemaBackendHandler {
 
 #[async_trait]
 pub trait UserListerBackendHandler: ReadSchemaBackendHandler {
+    /// With `get_groups`, the groups include the ones the users are indirect members of, through
+    /// nested groups.
     async fn list_users(
         &self,
         filters: Option<UserRequestFilter>,
         get_groups: bool,
     ) -> Result<Vec<UserAndGroups>>;
+    /// Lists at most `first` users, sorted by ID, starting strictly after the `after` user. The
+    /// groups are like for `list_users`.
+    async fn list_users_page(
+        &self,
+        filters: Option<UserRequestFilter>,
+        get_groups: bool,
+        first: u64,
+        after: Option<UserId>,
+    ) -> Result<UserPage>;
 }
 
 #[async_trait]
@@ -213,6 +254,13 @@ pub trait UserBackendHandler: ReadSchemaBackendHandler {
     async fn add_user_to_group(&self, user_id: &UserId, group_id: GroupId) -> Result<()>;
     async fn remove_user_from_group(&self, user_id: &UserId, group_id: GroupId) -> Result<()>;
     async fn get_user_groups(&self, user_id: &UserId) -> Result<HashSet<GroupDetails>>;
+    /// None if the user never set a password, or set it before this was tracked.
+    async fn get_password_changed_at(
+        &self,
+        user_id: &UserId,
+    ) -> Result<Option<chrono::NaiveDateTime>>;
+    /// None if the user never logged in, or if `track_login_statistics` was off since.
+    async fn get_last_login_date(&self, user_id: &UserId) -> Result<Option<chrono::NaiveDateTime>>;
 }
 
 #[async_trait]
@@ -229,6 +277,266 @@ pub trait SchemaBackendHandler: ReadSchemaBackendHandler {
     async fn delete_group_attribute(&self, name: &AttributeName) -> Result<()>;
 }
 
+/// Where an authentication attempt came from.
+#[derive(
+    Debug,
+    Copy,
+    Clone,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    Serialize,
+    Deserialize,
+    EnumString,
+    IntoStaticStr,
+)]
+#[strum(serialize_all = "snake_case")]
+pub enum LoginSource {
+    /// LDAP bind.
+    Ldap,
+    /// OPAQUE login, from the web UI or the API.
+    Web,
+}
+
+#[derive(
+    Debug,
+    Copy,
+    Clone,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    Serialize,
+    Deserialize,
+    EnumString,
+    IntoStaticStr,
+)]
+#[strum(serialize_all = "snake_case")]
+pub enum LoginFailureReason {
+    InvalidPassword,
+    /// The user doesn't exist, or doesn't have a password.
+    UnknownUser,
+    RateLimited,
+    BannerNotAcknowledged,
+    TotpNotEnrolled,
+    InvalidTotpCode,
+    /// The password expired, and there are no grace logins left.
+    PasswordExpired,
+    /// The login was finished for another user than the one it was started for.
+    UserMismatch,
+    /// The password matches the legacy hash of an admin, which isn't allowed to use it.
+    LegacyPasswordRefused,
+    AccountLocked,
+    AccountDisabled,
+}
+
+#[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone)]
+pub struct FailedLogin {
+    pub date: chrono::NaiveDateTime,
+    pub source: LoginSource,
+    pub reason: LoginFailureReason,
+}
+
+/// The failed logins of a user over a period of time, to investigate an attack.
+#[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone)]
+pub struct FailedLoginReport {
+    pub user_id: UserId,
+    pub since: chrono::NaiveDateTime,
+    /// Oldest first.
+    pub failures: Vec<FailedLogin>,
+    pub failures_by_source: BTreeMap<LoginSource, usize>,
+    pub failures_by_reason: BTreeMap<LoginFailureReason, usize>,
+    /// The last successful login in the period, if any.
+    pub last_success: Option<chrono::NaiveDateTime>,
+}
+
+/// Users sharing the same email, ignoring case.
+#[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone)]
+pub struct DuplicateEmail {
+    /// Lowercase.
+    pub email: String,
+    pub user_ids: Vec<UserId>,
+}
+
+/// The checks on a user's password file, see [`BackendHandler::audit_password_store`].
+#[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone)]
+pub struct PasswordFileAudit {
+    pub user_id: UserId,
+    pub deserializes: bool,
+    /// None if the file has no checksum, e.g. if it was set before they were introduced.
+    pub checksum_matches: Option<bool>,
+    /// Whether the server can answer a login with this file, with the current setup.
+    pub compatible_with_setup: bool,
+}
+
+impl PasswordFileAudit {
+    pub fn is_healthy(&self) -> bool {
+        self.deserializes && self.checksum_matches != Some(false) && self.compatible_with_setup
+    }
+}
+
+/// An enabled user that hasn't logged in recently, see [`BackendHandler::list_inactive_users`].
+#[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone)]
+pub struct InactiveUser {
+    pub user_id: UserId,
+    pub creation_date: chrono::NaiveDateTime,
+    /// None if the user never logged in.
+    pub last_login_date: Option<chrono::NaiveDateTime>,
+}
+
+/// A session opened by a web login, see [`BackendHandler::list_user_sessions`].
+#[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone)]
+pub struct UserSession {
+    pub session_id: i64,
+    pub creation_date: chrono::NaiveDateTime,
+    /// When the session was last refreshed.
+    pub last_seen: chrono::NaiveDateTime,
+    pub expiry_date: chrono::NaiveDateTime,
+    /// The user agent of the browser.
+    pub device: Option<String>,
+    /// The IP address the session was opened from.
+    pub source: Option<String>,
+}
+
+/// What an audit log entry records, see [`BackendHandler::query_audit_log`].
+#[derive(
+    Debug, Copy, Clone, PartialEq, Eq, Hash, Serialize, Deserialize, EnumString, IntoStaticStr,
+)]
+#[strum(serialize_all = "snake_case")]
+pub enum AuditAction {
+    /// LDAP bind.
+    Bind,
+    /// Web login.
+    Login,
+    PasswordChange,
+    CreateUser,
+    DeleteUser,
+    RestoreUser,
+    AddUserToGroup,
+    RemoveUserFromGroup,
+}
+
+#[derive(
+    Debug, Copy, Clone, PartialEq, Eq, Hash, Serialize, Deserialize, EnumString, IntoStaticStr,
+)]
+#[strum(serialize_all = "snake_case")]
+pub enum AuditOutcome {
+    Success,
+    Failure,
+}
+
+#[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone)]
+pub struct AuditLogEntry {
+    pub entry_id: i32,
+    pub date: chrono::NaiveDateTime,
+    /// Who did it, when known: the user for the logins.
+    pub actor: Option<String>,
+    pub action: AuditAction,
+    pub target: Option<String>,
+    pub source_ip: Option<String>,
+    pub outcome: AuditOutcome,
+    /// Chains the entry to the previous one, with `audit_log_hash_chain`.
+    pub entry_hash: Option<Vec<u8>>,
+}
+
+/// Whether new logins are refused, e.g. during a maintenance window. The break-glass accounts
+/// from the configuration can still log in.
+#[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone, Default)]
+pub struct MaintenanceMode {
+    pub enabled: bool,
+    /// Shown to the users whose login is refused.
+    pub message: Option<String>,
+}
+
+/// A page of users, see [`UserListerBackendHandler::list_users_page`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UserPage {
+    pub users: Vec<UserAndGroups>,
+    pub has_next_page: bool,
+}
+
+/// A page of groups, see [`GroupListerBackendHandler::list_groups_page`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GroupPage {
+    pub groups: Vec<Group>,
+    pub has_next_page: bool,
+}
+
+/// How the passwords of the users fare against the proposed password policy, from their last
+/// bind.
+#[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone, Default)]
+pub struct PolicyComplianceReport {
+    pub compliant: u64,
+    pub non_compliant: Vec<UserId>,
+    /// The users that didn't bind since the dry run started.
+    pub unchecked: u64,
+}
+
+/// The outcome of all the recorded authentication attempts over a period of time.
+#[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone, Copy, Default)]
+pub struct AuthSuccessRatio {
+    pub successes: u64,
+    pub failures: u64,
+}
+
+impl AuthSuccessRatio {
+    pub fn attempts(&self) -> u64 {
+        self.successes + self.failures
+    }
+
+    /// None if there were no attempts.
+    pub fn failure_ratio(&self) -> Option<f64> {
+        match self.attempts() {
+            0 => None,
+            attempts => Some(self.failures as f64 / attempts as f64),
+        }
+    }
+}
+
+/// From best to worst, so that the overall status is the maximum.
+#[derive(PartialEq, Eq, PartialOrd, Ord, Debug, Serialize, Deserialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum HealthStatus {
+    Ok,
+    /// Working, but something needs attention.
+    Degraded,
+    Failing,
+}
+
+#[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone)]
+pub struct SubsystemHealth {
+    pub name: String,
+    pub status: HealthStatus,
+    /// What's wrong, if anything.
+    pub details: Option<String>,
+}
+
+/// The health of all the subsystems, see [`BackendHandler::full_health_report`].
+#[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone)]
+pub struct HealthReport {
+    /// The worst status of the subsystems.
+    pub status: HealthStatus,
+    pub subsystems: Vec<SubsystemHealth>,
+    pub generated_at: chrono::NaiveDateTime,
+}
+
+impl HealthReport {
+    pub fn new(subsystems: Vec<SubsystemHealth>) -> Self {
+        Self {
+            status: subsystems
+                .iter()
+                .map(|s| s.status)
+                .max()
+                .unwrap_or(HealthStatus::Ok),
+            subsystems,
+            generated_at: chrono::Utc::now().naive_utc(),
+        }
+    }
+}
+
 #[async_trait]
 pub trait BackendHandler:
     Send
@@ -240,6 +548,139 @@ pub trait BackendHandler:
     + ReadSchemaBackendHandler
     + SchemaBackendHandler
 {
+    /// Summarizes the recorded authentication attempts of the user within the window.
+    async fn failed_login_report(
+        &self,
+        user_id: &UserId,
+        window: chrono::Duration,
+    ) -> Result<FailedLoginReport>;
+    /// Enables or disables all the given users at once, and returns the number of users updated.
+    /// Disabled users cannot log in.
+    async fn set_users_enabled(&self, user_ids: &[UserId], enabled: bool) -> Result<u64>;
+    /// Enables or disables a single user. Disabling a user doesn't touch their password.
+    async fn set_user_enabled(&self, user_id: &UserId, enabled: bool) -> Result<()>;
+    /// Counts the recorded authentication attempts of all users within the window.
+    async fn current_auth_success_ratio(
+        &self,
+        window: chrono::Duration,
+    ) -> Result<AuthSuccessRatio>;
+    /// Sets the password of each user, e.g. temporary passwords when provisioning users. With
+    /// `must_change_password`, the users have to set a new password after their first login. The
+    /// results are in the same order as the input.
+    async fn set_passwords_batch(
+        &self,
+        passwords: Vec<(UserId, SecUtf8)>,
+        must_change_password: bool,
+    ) -> Vec<(UserId, Result<()>)>;
+    /// Lists the emails used by several users. Such emails can't identify a user, e.g. for a
+    /// password reset.
+    async fn find_duplicate_emails(&self) -> Result<Vec<DuplicateEmail>>;
+    /// Runs a serialized client `login_start_request`, e.g. recorded from a third-party client,
+    /// through the server side of the login, and returns whether the server could answer it.
+    /// There are no side effects.
+    async fn interop_self_test(&self, login_start_request: &[u8]) -> Result<bool>;
+    /// Sets a random password for the user, to give them e.g. over the phone. It can be used for
+    /// a single web login, after which the user has to set a new password; LDAP binds are
+    /// refused until then.
+    async fn issue_temporary_password(&self, user_id: &UserId) -> Result<SecUtf8>;
+    /// Replaces the legacy password hash of the user with an OPAQUE password file, if the
+    /// password matches the hash.
+    async fn migrate_legacy_to_opaque(
+        &self,
+        user_id: &UserId,
+        clear_password: &SecUtf8,
+    ) -> Result<()>;
+    /// Checks every password file in one pass, without the passwords, for maintenance. Users
+    /// without a password are skipped.
+    async fn audit_password_store(&self) -> Result<Vec<PasswordFileAudit>>;
+    /// Checks the password of each canary account, e.g. after importing the password files from
+    /// another instance, and returns the accounts that can't log in with the reason.
+    async fn validate_imported_files(
+        &self,
+        canary_credentials: Vec<(UserId, SecUtf8)>,
+    ) -> Result<Vec<(UserId, LoginFailureReason)>>;
+    /// Starts or ends a maintenance window. This takes effect immediately for all the clones of
+    /// the handler, and lasts until the next restart.
+    async fn set_maintenance_mode(&self, mode: MaintenanceMode);
+    async fn get_maintenance_mode(&self) -> MaintenanceMode;
+    /// Lists the enabled users that haven't logged in since the given date, including the users
+    /// created before then that never logged in. The logins are only recorded with
+    /// `track_login_statistics`.
+    async fn list_inactive_users(&self, since: chrono::NaiveDateTime) -> Result<Vec<InactiveUser>>;
+    /// Disables (but doesn't delete) the users listed by `list_inactive_users`, except the admin
+    /// from the configuration, and returns them.
+    async fn disable_inactive_users(&self, since: chrono::NaiveDateTime) -> Result<Vec<UserId>>;
+    /// Invalidates the refresh tokens and JWTs issued to the user. This is done automatically
+    /// when the password changes or the user is disabled.
+    async fn revoke_user_tokens(&self, user_id: &UserId) -> Result<()>;
+    /// The unexpired sessions of the user, most recently seen first. Only recorded with
+    /// `track_sessions`.
+    async fn list_user_sessions(&self, user_id: &UserId) -> Result<Vec<UserSession>>;
+    /// Ends the session: its refresh token stops working.
+    async fn revoke_session(&self, session_id: i64) -> Result<()>;
+    /// Sets the base32 TOTP secret of the user, or removes it. With a secret, the web logins
+    /// require a code from the user's authenticator.
+    async fn set_totp_secret(&self, user_id: &UserId, secret: Option<String>) -> Result<()>;
+    /// Moves the password of `from` to `to`, e.g. when merging accounts: `to` logs in with the
+    /// password of `from`, who is left without one. Requires `transferable_password_files`.
+    async fn transfer_credential(&self, from: &UserId, to: &UserId) -> Result<()>;
+    /// Removes all the password files, e.g. when the server setup they are bound to changes: the
+    /// users have to set a new password. Returns the number of users who had one.
+    async fn invalidate_all_passwords(&self) -> Result<u64>;
+    /// Lets the next password registration of the user replace their current password, with
+    /// `reject_password_overwrite`. Redeeming a reset token does the same.
+    async fn allow_password_overwrite(&self, user_id: &UserId) -> Result<()>;
+    /// Creates all the users in a single transaction, e.g. to import them from another directory:
+    /// if one of them can't be created, none are.
+    async fn create_users_bulk(&self, requests: Vec<CreateUserRequest>) -> Result<()>;
+    /// The audit log entries between the two dates (inclusive), oldest first. Only recorded with
+    /// `enable_audit_log`.
+    async fn query_audit_log(
+        &self,
+        since: chrono::NaiveDateTime,
+        until: chrono::NaiveDateTime,
+    ) -> Result<Vec<AuditLogEntry>>;
+    /// Which users' passwords would be rejected by `proposed_password_policy_options`. They are
+    /// checked at each successful LDAP bind, the only login where the server sees the password.
+    async fn proposed_policy_compliance_report(&self) -> Result<PolicyComplianceReport>;
+    /// Makes the group a member of the parent group: the members of the group become indirect
+    /// members of the parent. Fails with `GroupCycle` if the parent is already a direct or
+    /// indirect member of the group. LLDAP's own permissions only come from direct memberships.
+    async fn add_group_to_group(&self, group_id: GroupId, parent_group_id: GroupId) -> Result<()>;
+    async fn remove_group_from_group(
+        &self,
+        group_id: GroupId,
+        parent_group_id: GroupId,
+    ) -> Result<()>;
+    /// The groups of the user, including the ones it is an indirect member of through nested
+    /// groups.
+    async fn get_effective_groups(&self, user_id: &UserId) -> Result<HashSet<GroupDetails>>;
+    /// Deletes the user's own account, with `enable_self_service_deletion`. The password is
+    /// checked like a bind, and the failures count towards the user's lockout.
+    async fn delete_own_account(&self, request: BindRequest) -> Result<()>;
+    /// Brings back a user deleted less than `deleted_user_retention_days` ago, with their groups
+    /// and attributes.
+    async fn restore_user(&self, user_id: &UserId) -> Result<()>;
+    /// Sets a custom attribute of the user, checking the values against the type of the attribute
+    /// in the schema. A single value is expected for attributes that are not lists.
+    async fn set_user_attribute(
+        &self,
+        user_id: &UserId,
+        name: AttributeName,
+        values: Vec<String>,
+    ) -> Result<()>;
+    /// Checks the database, the keys, the OPAQUE server setup, the throttle store and the recent
+    /// authentication failures. The failures are reported in the subsystems, not as errors.
+    async fn full_health_report(&self) -> HealthReport;
+    /// The checks that must pass to serve requests: the database answers within a short timeout,
+    /// and the OPAQUE server setup works.
+    async fn readiness_report(&self) -> HealthReport;
+    /// Creates a single-use token to reset the password of the user, to send them out of band.
+    /// Only its hash is stored. It expires after `password_reset_token_validity_seconds`, or
+    /// when the password changes.
+    async fn generate_reset_token(&self, user_id: &UserId) -> Result<SecUtf8>;
+    /// Sets the password of the user the token was generated for, and consumes the token.
+    async fn reset_password_with_token(&self, token: &str, new_password: SecUtf8) -> Result<()>;
 }
 
 #[cfg(test)]
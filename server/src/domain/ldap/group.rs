@@ -6,10 +6,10 @@ use tracing::{debug, instrument, warn};
 
 use crate::domain::{
     deserialize::deserialize_attribute_value,
-    handler::{GroupListerBackendHandler, GroupRequestFilter},
+    handler::{GroupListerBackendHandler, GroupPage, GroupRequestFilter},
     ldap::error::LdapError,
     schema::{PublicSchema, SchemaGroupAttributeExtractor},
-    types::{AttributeName, AttributeType, Group, UserId, Uuid},
+    types::{AttributeName, AttributeType, Group, GroupId, UserId, Uuid},
 };
 
 use super::{
@@ -258,6 +258,26 @@ pub async fn get_groups_list<Backend: GroupListerBackendHandler>(
         })
 }
 
+/// Like [`get_groups_list`], for a page of at most `first` groups after the `after` group.
+pub async fn get_groups_page<Backend: GroupListerBackendHandler>(
+    ldap_info: &LdapInfo,
+    ldap_filter: &LdapFilter,
+    backend: &Backend,
+    schema: &PublicSchema,
+    first: u64,
+    after: Option<GroupId>,
+) -> LdapResult<GroupPage> {
+    let filters = convert_group_filter(ldap_info, ldap_filter, schema)?;
+    debug!(?filters);
+    backend
+        .list_groups_page(Some(filters), first, after)
+        .await
+        .map_err(|e| LdapError {
+            code: LdapResultCode::Other,
+            message: format!("Error while listing groups: {:#}", e),
+        })
+}
+
 pub fn convert_groups_to_ldap_op<'a>(
     groups: Vec<Group>,
     attributes: &'a [String],
@@ -6,7 +6,7 @@ use tracing::{debug, instrument, warn};
 
 use crate::domain::{
     deserialize::deserialize_attribute_value,
-    handler::{UserListerBackendHandler, UserRequestFilter},
+    handler::{UserListerBackendHandler, UserPage, UserRequestFilter},
     ldap::{
         error::{LdapError, LdapResult},
         utils::{
@@ -53,9 +53,25 @@ pub fn get_user_attribute(
         UserFieldType::PrimaryField(
             UserColumn::LowercaseEmail
             | UserColumn::PasswordHash
+            | UserColumn::PasswordHashChecksum
             | UserColumn::TotpSecret
-            | UserColumn::MfaType,
+            | UserColumn::MfaType
+            | UserColumn::LoginCount
+            | UserColumn::LastLoginDate
+            | UserColumn::MustChangePassword
+            | UserColumn::LegacyPasswordHash
+            | UserColumn::PasswordChangedAt
+            | UserColumn::FailedLoginCount
+            | UserColumn::LockedUntil
+            | UserColumn::PasswordIdentity
+            | UserColumn::PasswordGraceLoginsRemaining
+            | UserColumn::PasswordOverwriteAllowed
+            | UserColumn::DeletedAt,
         ) => panic!("Should not get here"),
+        // The attribute says whether the account is locked, the opposite of the column.
+        UserFieldType::PrimaryField(UserColumn::IsEnabled) => {
+            vec![ldap_boolean(!user.is_enabled).as_bytes().to_vec()]
+        }
         UserFieldType::PrimaryField(UserColumn::Uuid) => vec![user.uuid.to_string().into_bytes()],
         UserFieldType::PrimaryField(UserColumn::DisplayName) => {
             vec![user.display_name.clone()?.into_bytes()]
@@ -104,6 +120,15 @@ pub fn get_user_attribute(
     }
 }
 
+/// The LDAP syntax of booleans.
+fn ldap_boolean(value: bool) -> &'static str {
+    if value {
+        "TRUE"
+    } else {
+        "FALSE"
+    }
+}
+
 const ALL_USER_ATTRIBUTE_KEYS: &[&str] = &[
     "objectclass",
     "uid",
@@ -182,6 +207,13 @@ fn convert_user_filter(
                 UserFieldType::PrimaryField(UserColumn::UserId) => {
                     Ok(UserRequestFilter::UserId(UserId::new(&value)))
                 }
+                // nsAccountLock=TRUE matches the disabled users.
+                UserFieldType::PrimaryField(UserColumn::IsEnabled) => {
+                    Ok(UserRequestFilter::Equality(
+                        UserColumn::IsEnabled,
+                        (value != "true").to_string(),
+                    ))
+                }
                 UserFieldType::PrimaryField(field) => Ok(UserRequestFilter::Equality(field, value)),
                 UserFieldType::Attribute(field, typ, is_list) => {
                     get_user_attribute_equality_filter(&field, typ, is_list, &value)
@@ -243,7 +275,8 @@ fn convert_user_filter(
                 | UserFieldType::Dn
                 | UserFieldType::EntryDn
                 | UserFieldType::PrimaryField(UserColumn::CreationDate)
-                | UserFieldType::PrimaryField(UserColumn::Uuid) => Err(LdapError {
+                | UserFieldType::PrimaryField(UserColumn::Uuid)
+                | UserFieldType::PrimaryField(UserColumn::IsEnabled) => Err(LdapError {
                     code: LdapResultCode::UnwillingToPerform,
                     message: format!(
                         "Unsupported user attribute for substring filter: {:?}",
@@ -288,6 +321,27 @@ pub async fn get_user_list<Backend: UserListerBackendHandler>(
         })
 }
 
+/// Like [`get_user_list`], for a page of at most `first` users after the `after` user.
+pub async fn get_user_page<Backend: UserListerBackendHandler>(
+    ldap_info: &LdapInfo,
+    ldap_filter: &LdapFilter,
+    request_groups: bool,
+    backend: &Backend,
+    schema: &PublicSchema,
+    first: u64,
+    after: Option<UserId>,
+) -> LdapResult<UserPage> {
+    let filters = convert_user_filter(ldap_info, ldap_filter, schema)?;
+    debug!(?filters);
+    backend
+        .list_users_page(Some(filters), request_groups, first, after)
+        .await
+        .map_err(|e| LdapError {
+            code: LdapResultCode::Other,
+            message: format!("Error while searching users: {:#}", e),
+        })
+}
+
 pub fn convert_users_to_ldap_op<'a>(
     users: Vec<UserAndGroups>,
     attributes: &'a [String],
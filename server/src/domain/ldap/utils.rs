@@ -196,6 +196,7 @@ pub fn map_user_field(field: &AttributeName, schema: &PublicSchema) -> UserField
             UserFieldType::PrimaryField(UserColumn::CreationDate)
         }
         "entryuuid" | "uuid" => UserFieldType::PrimaryField(UserColumn::Uuid),
+        "nsaccountlock" | "accountdisabled" => UserFieldType::PrimaryField(UserColumn::IsEnabled),
         _ => schema
             .get_schema()
             .user_attributes
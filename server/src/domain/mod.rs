@@ -1,15 +1,26 @@
+pub mod audit_log;
+pub mod correlation;
 pub mod deserialize;
 pub mod error;
+pub mod events;
 pub mod handler;
 pub mod ldap;
 pub mod model;
 pub mod opaque_handler;
 pub mod schema;
+pub mod sql_auth_events;
 pub mod sql_backend_handler;
 pub mod sql_group_backend_handler;
+pub mod sql_health;
+pub mod sql_legacy_passwords;
 pub mod sql_migrations;
+pub mod sql_nested_groups;
 pub mod sql_opaque_handler;
+pub mod sql_password_policy_compliance;
+pub mod sql_password_reset;
 pub mod sql_schema_backend_handler;
 pub mod sql_tables;
 pub mod sql_user_backend_handler;
+pub mod throttle;
+pub mod totp;
 pub mod types;
@@ -0,0 +1,21 @@
+use sea_orm::entity::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::domain::types::UserId;
+
+#[derive(Clone, Debug, PartialEq, DeriveEntityModel, Eq, Serialize, Deserialize)]
+#[sea_orm(table_name = "auth_events")]
+pub struct Model {
+    #[sea_orm(primary_key)]
+    pub event_id: i32,
+    pub user_id: UserId,
+    pub event_date: chrono::NaiveDateTime,
+    pub source: String,
+    pub success: bool,
+    pub failure_reason: Option<String>,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {}
@@ -0,0 +1,37 @@
+use sea_orm::entity::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::domain::types::GroupId;
+
+/// The groups that are members of other groups. The members of the child group are indirect
+/// members of the parent group.
+#[derive(Clone, Debug, PartialEq, DeriveEntityModel, Eq, Serialize, Deserialize)]
+#[sea_orm(table_name = "group_memberships_groups")]
+pub struct Model {
+    #[sea_orm(primary_key, auto_increment = false)]
+    pub parent_group_id: GroupId,
+    #[sea_orm(primary_key, auto_increment = false)]
+    pub child_group_id: GroupId,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {
+    #[sea_orm(
+        belongs_to = "super::groups::Entity",
+        from = "Column::ParentGroupId",
+        to = "super::groups::Column::GroupId",
+        on_update = "Cascade",
+        on_delete = "Cascade"
+    )]
+    ParentGroup,
+    #[sea_orm(
+        belongs_to = "super::groups::Entity",
+        from = "Column::ChildGroupId",
+        to = "super::groups::Column::GroupId",
+        on_update = "Cascade",
+        on_delete = "Cascade"
+    )]
+    ChildGroup,
+}
+
+impl ActiveModelBehavior for ActiveModel {}
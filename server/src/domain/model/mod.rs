@@ -2,11 +2,23 @@
 
 pub mod prelude;
 
+pub mod audit_log;
+pub mod auth_events;
+pub mod group_memberships_groups;
 pub mod groups;
 pub mod jwt_refresh_storage;
 pub mod jwt_storage;
+pub mod login_links;
 pub mod memberships;
+pub mod password_history;
+pub mod password_policy_compliance;
 pub mod password_reset_tokens;
+pub mod quarantined_password_files;
+pub mod registration_states;
+pub mod throttle_counters;
+pub mod user_devices;
+pub mod user_password_contexts;
+pub mod user_sessions;
 pub mod users;
 
 pub mod user_attribute_schema;
@@ -1,22 +1,46 @@
 //! `SeaORM` Entity. Generated by sea-orm-codegen 0.10.3
 
+pub use super::audit_log::Column as AuditLogColumn;
+pub use super::audit_log::Entity as AuditLog;
+pub use super::auth_events::Column as AuthEventsColumn;
+pub use super::auth_events::Entity as AuthEvents;
 pub use super::group_attribute_schema::Column as GroupAttributeSchemaColumn;
 pub use super::group_attribute_schema::Entity as GroupAttributeSchema;
 pub use super::group_attributes::Column as GroupAttributesColumn;
 pub use super::group_attributes::Entity as GroupAttributes;
+pub use super::group_memberships_groups::Column as GroupMembershipsGroupsColumn;
+pub use super::group_memberships_groups::Entity as GroupMembershipsGroups;
 pub use super::groups::Column as GroupColumn;
 pub use super::groups::Entity as Group;
 pub use super::jwt_refresh_storage::Column as JwtRefreshStorageColumn;
 pub use super::jwt_refresh_storage::Entity as JwtRefreshStorage;
 pub use super::jwt_storage::Column as JwtStorageColumn;
 pub use super::jwt_storage::Entity as JwtStorage;
+pub use super::login_links::Column as LoginLinksColumn;
+pub use super::login_links::Entity as LoginLinks;
 pub use super::memberships::Column as MembershipColumn;
 pub use super::memberships::Entity as Membership;
+pub use super::password_history::Column as PasswordHistoryColumn;
+pub use super::password_history::Entity as PasswordHistory;
+pub use super::password_policy_compliance::Column as PasswordPolicyComplianceColumn;
+pub use super::password_policy_compliance::Entity as PasswordPolicyCompliance;
 pub use super::password_reset_tokens::Column as PasswordResetTokensColumn;
 pub use super::password_reset_tokens::Entity as PasswordResetTokens;
+pub use super::quarantined_password_files::Column as QuarantinedPasswordFilesColumn;
+pub use super::quarantined_password_files::Entity as QuarantinedPasswordFiles;
+pub use super::registration_states::Column as RegistrationStatesColumn;
+pub use super::registration_states::Entity as RegistrationStates;
+pub use super::throttle_counters::Column as ThrottleCountersColumn;
+pub use super::throttle_counters::Entity as ThrottleCounters;
 pub use super::user_attribute_schema::Column as UserAttributeSchemaColumn;
 pub use super::user_attribute_schema::Entity as UserAttributeSchema;
 pub use super::user_attributes::Column as UserAttributesColumn;
 pub use super::user_attributes::Entity as UserAttributes;
+pub use super::user_devices::Column as UserDevicesColumn;
+pub use super::user_devices::Entity as UserDevices;
+pub use super::user_password_contexts::Column as UserPasswordContextsColumn;
+pub use super::user_password_contexts::Entity as UserPasswordContexts;
+pub use super::user_sessions::Column as UserSessionsColumn;
+pub use super::user_sessions::Entity as UserSessions;
 pub use super::users::Column as UserColumn;
 pub use super::users::Entity as User;
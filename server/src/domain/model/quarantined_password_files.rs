@@ -0,0 +1,23 @@
+use sea_orm::entity::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::domain::types::UserId;
+
+/// Corrupt password files, removed from the users and kept for forensics. There is no foreign key
+/// to the users, so that they are kept when the user is deleted.
+#[derive(Clone, Debug, PartialEq, DeriveEntityModel, Eq, Serialize, Deserialize)]
+#[sea_orm(table_name = "quarantined_password_files")]
+pub struct Model {
+    #[sea_orm(primary_key)]
+    pub entry_id: i32,
+    pub user_id: UserId,
+    pub password_file: Vec<u8>,
+    pub checksum: Option<Vec<u8>>,
+    pub reason: String,
+    pub quarantine_date: chrono::NaiveDateTime,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {}
@@ -0,0 +1,19 @@
+use sea_orm::entity::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::domain::types::UserId;
+
+#[derive(Clone, Debug, PartialEq, DeriveEntityModel, Eq, Serialize, Deserialize)]
+#[sea_orm(table_name = "registration_states")]
+pub struct Model {
+    #[sea_orm(primary_key, auto_increment = false)]
+    pub handle: String,
+    pub server_data: Vec<u8>,
+    pub expiry_date: chrono::NaiveDateTime,
+    pub user_id: Option<UserId>,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {}
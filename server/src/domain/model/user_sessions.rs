@@ -0,0 +1,39 @@
+use sea_orm::entity::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::domain::types::UserId;
+
+/// Where and when each refresh token was issued, to show the users where they are logged in.
+#[derive(Clone, Debug, PartialEq, DeriveEntityModel, Eq, Serialize, Deserialize)]
+#[sea_orm(table_name = "user_sessions")]
+pub struct Model {
+    /// The hash of the refresh token.
+    #[sea_orm(primary_key, auto_increment = false)]
+    pub session_id: i64,
+    pub user_id: UserId,
+    pub creation_date: chrono::NaiveDateTime,
+    pub last_seen: chrono::NaiveDateTime,
+    pub expiry_date: chrono::NaiveDateTime,
+    pub device: Option<String>,
+    pub source: Option<String>,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {
+    #[sea_orm(
+        belongs_to = "super::users::Entity",
+        from = "Column::UserId",
+        to = "super::users::Column::UserId",
+        on_update = "Cascade",
+        on_delete = "Cascade"
+    )]
+    Users,
+}
+
+impl Related<super::users::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::Users.def()
+    }
+}
+
+impl ActiveModelBehavior for ActiveModel {}
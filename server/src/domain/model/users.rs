@@ -21,6 +21,26 @@ pub struct Model {
     pub totp_secret: Option<String>,
     pub mfa_type: Option<String>,
     pub uuid: Uuid,
+    pub display_email: Option<String>,
+    pub login_count: i32,
+    pub last_login_date: Option<chrono::NaiveDateTime>,
+    pub is_enabled: bool,
+    pub password_hash_checksum: Option<Vec<u8>>,
+    pub must_change_password: bool,
+    /// The bcrypt hash of a password from another directory, for the users without an OPAQUE
+    /// password file.
+    pub legacy_password_hash: Option<String>,
+    pub password_changed_at: Option<chrono::NaiveDateTime>,
+    pub failed_login_count: i32,
+    pub locked_until: Option<chrono::NaiveDateTime>,
+    /// The OPAQUE identity of a password file transferred from another user. None for the
+    /// password files registered by the user, bound to their user ID.
+    pub password_identity: Option<UserId>,
+    pub password_grace_logins_remaining: Option<i32>,
+    /// Set by a password reset: the next registration may replace the current password, even
+    /// with `reject_password_overwrite`.
+    pub password_overwrite_allowed: bool,
+    pub deleted_at: Option<chrono::NaiveDateTime>,
 }
 
 impl EntityName for Entity {
@@ -40,6 +60,20 @@ pub enum Column {
     TotpSecret,
     MfaType,
     Uuid,
+    DisplayEmail,
+    LoginCount,
+    LastLoginDate,
+    IsEnabled,
+    PasswordHashChecksum,
+    MustChangePassword,
+    LegacyPasswordHash,
+    PasswordChangedAt,
+    FailedLoginCount,
+    LockedUntil,
+    PasswordIdentity,
+    PasswordGraceLoginsRemaining,
+    PasswordOverwriteAllowed,
+    DeletedAt,
 }
 
 impl ColumnTrait for Column {
@@ -56,6 +90,20 @@ impl ColumnTrait for Column {
             Column::TotpSecret => ColumnType::String(Some(64)),
             Column::MfaType => ColumnType::String(Some(64)),
             Column::Uuid => ColumnType::String(Some(36)),
+            Column::DisplayEmail => ColumnType::String(Some(255)),
+            Column::LoginCount => ColumnType::Integer,
+            Column::LastLoginDate => ColumnType::DateTime,
+            Column::IsEnabled => ColumnType::Boolean,
+            Column::PasswordHashChecksum => ColumnType::Binary(BlobSize::Blob(None)),
+            Column::MustChangePassword => ColumnType::Boolean,
+            Column::LegacyPasswordHash => ColumnType::String(Some(255)),
+            Column::PasswordChangedAt => ColumnType::DateTime,
+            Column::FailedLoginCount => ColumnType::Integer,
+            Column::LockedUntil => ColumnType::DateTime,
+            Column::PasswordIdentity => ColumnType::String(Some(255)),
+            Column::PasswordGraceLoginsRemaining => ColumnType::Integer,
+            Column::PasswordOverwriteAllowed => ColumnType::Boolean,
+            Column::DeletedAt => ColumnType::DateTime,
         }
         .def()
     }
@@ -116,11 +164,13 @@ impl From<Model> for crate::domain::types::User {
     fn from(user: Model) -> Self {
         Self {
             user_id: user.user_id,
-            email: user.email,
+            // The lookups use the normalized email, the users see the one they entered.
+            email: user.display_email.map(Email::from).unwrap_or(user.email),
             display_name: user.display_name,
             creation_date: user.creation_date,
             uuid: user.uuid,
             attributes: Vec::new(),
+            is_enabled: user.is_enabled,
         }
     }
 }
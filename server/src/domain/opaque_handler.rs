@@ -1,22 +1,47 @@
 use crate::domain::{error::Result, types::UserId};
 use async_trait::async_trait;
+use std::net::IpAddr;
 
 pub use lldap_auth::{login, registration};
 
+/// The outcome of a successful login.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SuccessfulLogin {
+    pub user_id: UserId,
+    /// The password was set by someone else: the user has to choose a new one before anything
+    /// else.
+    pub must_change_password: bool,
+}
+
 #[async_trait]
 pub trait OpaqueHandler: Send + Sync {
+    async fn login_challenge(&self) -> Result<login::ServerLoginChallengeResponse>;
+    /// `source` is the address the request came from, if known, for the throttling of the failed
+    /// logins.
     async fn login_start(
         &self,
         request: login::ClientLoginStartRequest,
+        source: Option<IpAddr>,
     ) -> Result<login::ServerLoginStartResponse>;
-    async fn login_finish(&self, request: login::ClientLoginFinishRequest) -> Result<UserId>;
+    async fn login_finish(
+        &self,
+        request: login::ClientLoginFinishRequest,
+        source: Option<IpAddr>,
+    ) -> Result<SuccessfulLogin>;
+    /// `source` is the address the request came from, if known, for rate limiting.
     async fn registration_start(
         &self,
         request: registration::ClientRegistrationStartRequest,
+        source: Option<IpAddr>,
     ) -> Result<registration::ServerRegistrationStartResponse>;
+    /// `actor` is the user setting the password: when it's someone else and
+    /// `force_password_change_after_admin_reset` is set, the user has to change it.
+    /// `transport_secure` tells whether the request came over an encrypted connection.
     async fn registration_finish(
         &self,
         request: registration::ClientRegistrationFinishRequest,
+        actor: &UserId,
+        transport_secure: bool,
     ) -> Result<()>;
 }
 
@@ -28,18 +53,27 @@ mockall::mock! {
     }
     #[async_trait]
     impl OpaqueHandler for TestOpaqueHandler {
+        async fn login_challenge(&self) -> Result<login::ServerLoginChallengeResponse>;
         async fn login_start(
             &self,
-            request: login::ClientLoginStartRequest
+            request: login::ClientLoginStartRequest,
+            source: Option<IpAddr>
         ) -> Result<login::ServerLoginStartResponse>;
-        async fn login_finish(&self, request: login::ClientLoginFinishRequest ) -> Result<UserId>;
+        async fn login_finish(
+            &self,
+            request: login::ClientLoginFinishRequest,
+            source: Option<IpAddr>
+        ) -> Result<SuccessfulLogin>;
         async fn registration_start(
             &self,
-            request: registration::ClientRegistrationStartRequest
+            request: registration::ClientRegistrationStartRequest,
+            source: Option<IpAddr>
         ) -> Result<registration::ServerRegistrationStartResponse>;
         async fn registration_finish(
             &self,
-            request: registration::ClientRegistrationFinishRequest
+            request: registration::ClientRegistrationFinishRequest,
+            actor: &UserId,
+            transport_secure: bool
         ) -> Result<()>;
     }
 }
@@ -0,0 +1,389 @@
+use crate::domain::{
+    correlation::correlation_id,
+    error::{DomainError, Result},
+    events::LoginEvent,
+    handler::{
+        AuditAction, AuthSuccessRatio, FailedLogin, FailedLoginReport, LoginFailureReason,
+        LoginSource,
+    },
+    model::{self, AuthEventsColumn},
+    sql_backend_handler::SqlBackendHandler,
+    types::UserId,
+};
+use sea_orm::{
+    ActiveModelTrait, ActiveValue, ColumnTrait, EntityTrait, PaginatorTrait, QueryFilter,
+    QueryOrder,
+};
+use std::str::FromStr;
+use tracing::{instrument, warn};
+
+impl SqlBackendHandler {
+    /// Records an authentication attempt, if enabled in the configuration, and publishes it. A
+    /// `failure` of `None` is a successful login.
+    pub(crate) async fn record_auth_event(
+        &self,
+        user_id: &UserId,
+        source: LoginSource,
+        failure: Option<LoginFailureReason>,
+    ) -> Result<()> {
+        if let Some(event_publisher) = &self.event_publisher {
+            let event = LoginEvent {
+                user_id: user_id.clone(),
+                source,
+                success: failure.is_none(),
+                failure_reason: failure,
+                date: chrono::Utc::now().naive_utc(),
+                correlation_id: correlation_id(),
+            };
+            if let Err(e) = event_publisher.publish(&event).await {
+                warn!(
+                    r#"Could not publish the login event of "{}": {}"#,
+                    user_id, e
+                );
+            }
+        }
+        self.audit(
+            Some(user_id),
+            match source {
+                LoginSource::Ldap => AuditAction::Bind,
+                LoginSource::Web => AuditAction::Login,
+            },
+            Some(user_id.to_string()),
+            failure.is_none(),
+        )
+        .await?;
+        if !self.config.enable_auth_events {
+            return Ok(());
+        }
+        model::auth_events::ActiveModel {
+            user_id: ActiveValue::Set(user_id.clone()),
+            event_date: ActiveValue::Set(chrono::Utc::now().naive_utc()),
+            source: ActiveValue::Set(Into::<&'static str>::into(source).to_owned()),
+            success: ActiveValue::Set(failure.is_none()),
+            failure_reason: ActiveValue::Set(
+                failure.map(|f| Into::<&'static str>::into(f).to_owned()),
+            ),
+            ..Default::default()
+        }
+        .insert(&self.sql_pool)
+        .await?;
+        Ok(())
+    }
+
+    #[instrument(skip(self), level = "debug", err)]
+    pub(crate) async fn get_failed_login_report(
+        &self,
+        user_id: &UserId,
+        window: chrono::Duration,
+    ) -> Result<FailedLoginReport> {
+        let since = chrono::Utc::now().naive_utc() - window;
+        let events = model::AuthEvents::find()
+            .filter(AuthEventsColumn::UserId.eq(user_id))
+            .filter(AuthEventsColumn::EventDate.gte(since))
+            .order_by_asc(AuthEventsColumn::EventDate)
+            .order_by_asc(AuthEventsColumn::EventId)
+            .all(&self.sql_pool)
+            .await?;
+        let mut report = FailedLoginReport {
+            user_id: user_id.clone(),
+            since,
+            failures: Vec::new(),
+            failures_by_source: Default::default(),
+            failures_by_reason: Default::default(),
+            last_success: None,
+        };
+        let invalid_event =
+            |e: strum::ParseError| DomainError::InternalError(format!("Invalid auth event: {}", e));
+        for event in events {
+            if event.success {
+                report.last_success = Some(event.event_date);
+                continue;
+            }
+            let source = LoginSource::from_str(&event.source).map_err(invalid_event)?;
+            let reason =
+                LoginFailureReason::from_str(event.failure_reason.as_deref().unwrap_or_default())
+                    .map_err(invalid_event)?;
+            *report.failures_by_source.entry(source).or_default() += 1;
+            *report.failures_by_reason.entry(reason).or_default() += 1;
+            report.failures.push(FailedLogin {
+                date: event.event_date,
+                source,
+                reason,
+            });
+        }
+        Ok(report)
+    }
+
+    #[instrument(skip(self), level = "debug", err)]
+    pub(crate) async fn get_auth_success_ratio(
+        &self,
+        window: chrono::Duration,
+    ) -> Result<AuthSuccessRatio> {
+        let since = chrono::Utc::now().naive_utc() - window;
+        let count = |success: bool| {
+            model::AuthEvents::find()
+                .filter(AuthEventsColumn::EventDate.gte(since))
+                .filter(AuthEventsColumn::Success.eq(success))
+                .count(&self.sql_pool)
+        };
+        Ok(AuthSuccessRatio {
+            successes: count(true).await?,
+            failures: count(false).await?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::{
+        events::EventPublisher, handler::BackendHandler, sql_backend_handler::tests::*,
+    };
+    use pretty_assertions::assert_eq;
+    use std::collections::BTreeMap;
+
+    async fn get_handler() -> SqlBackendHandler {
+        let mut config = get_default_config();
+        config.enable_auth_events = true;
+        SqlBackendHandler::new(config, get_initialized_db().await)
+    }
+
+    #[tokio::test]
+    async fn test_failed_login_report_groups_failures() {
+        let handler = get_handler().await;
+        let bob = UserId::new("bob");
+        let failures = [
+            (LoginSource::Ldap, LoginFailureReason::InvalidPassword),
+            (LoginSource::Web, LoginFailureReason::InvalidPassword),
+            (LoginSource::Ldap, LoginFailureReason::RateLimited),
+            (LoginSource::Ldap, LoginFailureReason::InvalidPassword),
+        ];
+        for (source, reason) in failures {
+            handler
+                .record_auth_event(&bob, source, Some(reason))
+                .await
+                .unwrap();
+        }
+        handler
+            .record_auth_event(&bob, LoginSource::Web, None)
+            .await
+            .unwrap();
+        handler
+            .record_auth_event(
+                &UserId::new("john"),
+                LoginSource::Web,
+                Some(LoginFailureReason::UnknownUser),
+            )
+            .await
+            .unwrap();
+        let report = handler
+            .failed_login_report(&bob, chrono::Duration::hours(1))
+            .await
+            .unwrap();
+        assert_eq!(
+            report
+                .failures
+                .iter()
+                .map(|f| (f.source, f.reason))
+                .collect::<Vec<_>>(),
+            failures.to_vec()
+        );
+        assert_eq!(
+            report.failures_by_source,
+            BTreeMap::from([(LoginSource::Ldap, 3), (LoginSource::Web, 1)])
+        );
+        assert_eq!(
+            report.failures_by_reason,
+            BTreeMap::from([
+                (LoginFailureReason::InvalidPassword, 3),
+                (LoginFailureReason::RateLimited, 1)
+            ])
+        );
+        assert!(report.last_success.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_failed_login_report_window() {
+        let handler = get_handler().await;
+        let bob = UserId::new("bob");
+        handler
+            .record_auth_event(
+                &bob,
+                LoginSource::Ldap,
+                Some(LoginFailureReason::InvalidPassword),
+            )
+            .await
+            .unwrap();
+        model::AuthEvents::update_many()
+            .col_expr(
+                AuthEventsColumn::EventDate,
+                sea_orm::sea_query::Expr::value(
+                    chrono::Utc::now().naive_utc() - chrono::Duration::days(2),
+                ),
+            )
+            .exec(&handler.sql_pool)
+            .await
+            .unwrap();
+        let report = handler
+            .failed_login_report(&bob, chrono::Duration::days(1))
+            .await
+            .unwrap();
+        assert_eq!(report.failures, vec![]);
+        assert_eq!(report.last_success, None);
+    }
+
+    #[tokio::test]
+    async fn test_bind_failures_are_recorded() {
+        let handler = get_handler().await;
+        insert_user(&handler, "bob", "bob00").await;
+        use crate::domain::handler::{BindRequest, LoginHandler};
+        for (name, password) in [("bob", "wrong"), ("bob", "bob00"), ("eve", "pass")] {
+            let _ = handler
+                .bind(BindRequest {
+                    name: UserId::new(name),
+                    password: password.to_owned(),
+                    source: None,
+                    context: None,
+                })
+                .await;
+        }
+        let report = handler
+            .failed_login_report(&UserId::new("bob"), chrono::Duration::hours(1))
+            .await
+            .unwrap();
+        assert_eq!(
+            report.failures_by_reason,
+            BTreeMap::from([(LoginFailureReason::InvalidPassword, 1)])
+        );
+        assert!(report.last_success.is_some());
+        let report = handler
+            .failed_login_report(&UserId::new("eve"), chrono::Duration::hours(1))
+            .await
+            .unwrap();
+        assert_eq!(
+            report.failures_by_reason,
+            BTreeMap::from([(LoginFailureReason::UnknownUser, 1)])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_auth_success_ratio() {
+        let handler = get_handler().await;
+        let window = chrono::Duration::hours(1);
+        assert_eq!(
+            handler
+                .current_auth_success_ratio(window)
+                .await
+                .unwrap()
+                .failure_ratio(),
+            None
+        );
+        for user in ["bob", "john", "bob"] {
+            handler
+                .record_auth_event(&UserId::new(user), LoginSource::Web, None)
+                .await
+                .unwrap();
+        }
+        handler
+            .record_auth_event(
+                &UserId::new("eve"),
+                LoginSource::Ldap,
+                Some(LoginFailureReason::UnknownUser),
+            )
+            .await
+            .unwrap();
+        let ratio = handler.current_auth_success_ratio(window).await.unwrap();
+        assert_eq!(
+            ratio,
+            AuthSuccessRatio {
+                successes: 3,
+                failures: 1
+            }
+        );
+        assert_eq!(ratio.failure_ratio(), Some(0.25));
+    }
+
+    struct FailingEventPublisher;
+
+    #[async_trait::async_trait]
+    impl EventPublisher for FailingEventPublisher {
+        async fn publish(&self, _: &LoginEvent) -> Result<()> {
+            Err(DomainError::InternalError("queue unavailable".to_owned()))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_logins_are_published() {
+        use crate::domain::{
+            events::MemoryEventPublisher,
+            handler::{BindRequest, LoginHandler},
+        };
+        let publisher = std::sync::Arc::new(MemoryEventPublisher::default());
+        let handler = SqlBackendHandler::new(get_default_config(), get_initialized_db().await)
+            .with_event_publisher(publisher.clone());
+        insert_user(&handler, "bob", "bob00").await;
+        for password in ["bob00", "wrong"] {
+            let _ = handler
+                .bind(BindRequest {
+                    name: UserId::new("bob"),
+                    password: password.to_owned(),
+                    source: None,
+                    context: None,
+                })
+                .await;
+        }
+        assert_eq!(
+            publisher
+                .events()
+                .into_iter()
+                .map(|e| (e.user_id, e.source, e.success, e.failure_reason))
+                .collect::<Vec<_>>(),
+            vec![
+                (UserId::new("bob"), LoginSource::Ldap, true, None),
+                (
+                    UserId::new("bob"),
+                    LoginSource::Ldap,
+                    false,
+                    Some(LoginFailureReason::InvalidPassword)
+                ),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_publishing_failures_dont_affect_logins() {
+        use crate::domain::handler::{BindRequest, LoginHandler};
+        let handler = SqlBackendHandler::new(get_default_config(), get_initialized_db().await)
+            .with_event_publisher(std::sync::Arc::new(FailingEventPublisher));
+        insert_user(&handler, "bob", "bob00").await;
+        handler
+            .bind(BindRequest {
+                name: UserId::new("bob"),
+                password: "bob00".to_owned(),
+                source: None,
+                context: None,
+            })
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_auth_events_disabled_by_default() {
+        let handler = SqlBackendHandler::new(get_default_config(), get_initialized_db().await);
+        handler
+            .record_auth_event(
+                &UserId::new("bob"),
+                LoginSource::Ldap,
+                Some(LoginFailureReason::InvalidPassword),
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            model::AuthEvents::find()
+                .all(&handler.sql_pool)
+                .await
+                .unwrap(),
+            vec![]
+        );
+    }
+}
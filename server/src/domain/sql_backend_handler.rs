@@ -1,21 +1,604 @@
-use crate::domain::{handler::BackendHandler, sql_tables::DbConnection};
-use crate::infra::configuration::Configuration;
+use crate::domain::{
+    audit_log::{AuditLogger, SqlAuditLogger},
+    error::{DomainError, Result},
+    events::{
+        EventPublisher, LifecycleEvent, LifecycleEventType, LifecycleNotifier, WelcomeNotifier,
+    },
+    handler::{
+        AuditLogEntry, AuthSuccessRatio, BackendHandler, BindRequest, DuplicateEmail,
+        FailedLoginReport, HealthReport, InactiveUser, LoginFailureReason, MaintenanceMode,
+        PasswordFileAudit, PolicyComplianceReport, UserSession,
+    },
+    model::{
+        self, JwtRefreshStorageColumn, JwtStorageColumn, PasswordResetTokensColumn, UserColumn,
+        UserSessionsColumn,
+    },
+    sql_opaque_handler::{check_login_start_compatibility, register_password, PasswordActor},
+    sql_tables::DbConnection,
+    throttle::{MemoryThrottleStore, SqlThrottleStore, ThrottleStore},
+    totp,
+    types::{AttributeName, GroupDetails, GroupId, UserId},
+};
+use crate::infra::configuration::{Configuration, ThrottleStoreKind};
 use async_trait::async_trait;
+use futures::stream::StreamExt;
+use sea_orm::{
+    sea_query::{Expr, Func, IntoColumnRef},
+    ColumnTrait, Condition, ConnectionTrait, EntityTrait, QueryFilter, QueryOrder, QuerySelect,
+    QueryTrait, TransactionTrait,
+};
+use secstr::SecUtf8;
+use std::{
+    collections::HashSet,
+    sync::{Arc, RwLock},
+};
+use tracing::{info, instrument, warn};
+
+/// How many passwords are registered or checked at the same time in a batch.
+const PASSWORD_BATCH_CONCURRENCY: usize = 4;
+
+const TEMPORARY_PASSWORD_LENGTH: usize = 24;
 
 #[derive(Clone)]
 pub struct SqlBackendHandler {
     pub(crate) config: Configuration,
     pub(crate) sql_pool: DbConnection,
+    pub(crate) throttle_store: Arc<dyn ThrottleStore>,
+    /// Shared by the clones, so that it can be changed at runtime.
+    pub(crate) maintenance_mode: Arc<RwLock<MaintenanceMode>>,
+    /// The hashes of the revoked JWTs, shared with the HTTP server that checks them.
+    pub(crate) jwt_blacklist: Arc<RwLock<HashSet<u64>>>,
+    pub(crate) event_publisher: Option<Arc<dyn EventPublisher>>,
+    pub(crate) audit_logger: Option<Arc<dyn AuditLogger>>,
+    pub(crate) lifecycle_notifier: Option<Arc<dyn LifecycleNotifier>>,
+    pub(crate) welcome_notifier: Option<Arc<dyn WelcomeNotifier>>,
 }
 
 impl SqlBackendHandler {
     pub fn new(config: Configuration, sql_pool: DbConnection) -> Self {
-        SqlBackendHandler { config, sql_pool }
+        let throttle_store: Arc<dyn ThrottleStore> = match config.throttle_options.store {
+            ThrottleStoreKind::Memory => Arc::new(MemoryThrottleStore::default()),
+            ThrottleStoreKind::Database => Arc::new(SqlThrottleStore::new(sql_pool.clone())),
+        };
+        let maintenance_mode = Arc::new(RwLock::new(MaintenanceMode {
+            enabled: config.maintenance_mode,
+            message: config.maintenance_message.clone(),
+        }));
+        let audit_logger: Option<Arc<dyn AuditLogger>> = if config.enable_audit_log {
+            Some(Arc::new(SqlAuditLogger::new(
+                sql_pool.clone(),
+                config.audit_log_hash_chain,
+            )))
+        } else {
+            None
+        };
+        SqlBackendHandler {
+            config,
+            sql_pool,
+            throttle_store,
+            maintenance_mode,
+            jwt_blacklist: Arc::default(),
+            event_publisher: None,
+            audit_logger,
+            lifecycle_notifier: None,
+            welcome_notifier: None,
+        }
+    }
+
+    pub fn with_lifecycle_notifier(self, lifecycle_notifier: Arc<dyn LifecycleNotifier>) -> Self {
+        Self {
+            lifecycle_notifier: Some(lifecycle_notifier),
+            ..self
+        }
+    }
+
+    pub fn with_welcome_notifier(self, welcome_notifier: Arc<dyn WelcomeNotifier>) -> Self {
+        Self {
+            welcome_notifier: Some(welcome_notifier),
+            ..self
+        }
+    }
+
+    pub(crate) fn notify_lifecycle_event(
+        &self,
+        event_type: LifecycleEventType,
+        user_id: &UserId,
+        group_id: Option<GroupId>,
+    ) {
+        if let Some(lifecycle_notifier) = &self.lifecycle_notifier {
+            lifecycle_notifier.notify(LifecycleEvent {
+                event_type,
+                user_id: user_id.clone(),
+                group_id,
+                date: chrono::Utc::now().naive_utc(),
+            });
+        }
+    }
+
+    pub fn with_event_publisher(self, event_publisher: Arc<dyn EventPublisher>) -> Self {
+        Self {
+            event_publisher: Some(event_publisher),
+            ..self
+        }
+    }
+
+    pub fn with_throttle_store(self, throttle_store: Arc<dyn ThrottleStore>) -> Self {
+        Self {
+            throttle_store,
+            ..self
+        }
     }
 }
 
+/// Deletes the refresh tokens and the password reset tokens of the users, and blacklists their
+/// JWTs, both in the database and in `jwt_blacklist`. All the revocations go through here. If the
+/// surrounding transaction is rolled back, the JWTs stay revoked in memory: the users only have to
+/// log in again.
+pub(crate) async fn revoke_tokens(
+    connection: &impl ConnectionTrait,
+    jwt_blacklist: &RwLock<HashSet<u64>>,
+    user_ids: &[UserId],
+) -> Result<()> {
+    model::PasswordResetTokens::delete_many()
+        .filter(PasswordResetTokensColumn::UserId.is_in(user_ids.to_vec()))
+        .exec(connection)
+        .await?;
+    model::JwtRefreshStorage::delete_many()
+        .filter(JwtRefreshStorageColumn::UserId.is_in(user_ids.to_vec()))
+        .exec(connection)
+        .await?;
+    let jwt_hashes = model::JwtStorage::find()
+        .select_only()
+        .column(JwtStorageColumn::JwtHash)
+        .filter(JwtStorageColumn::UserId.is_in(user_ids.to_vec()))
+        .filter(JwtStorageColumn::Blacklisted.eq(false))
+        .into_tuple::<(i64,)>()
+        .all(connection)
+        .await?;
+    model::JwtStorage::update_many()
+        .col_expr(JwtStorageColumn::Blacklisted, Expr::value(true))
+        .filter(JwtStorageColumn::UserId.is_in(user_ids.to_vec()))
+        .exec(connection)
+        .await?;
+    model::UserSessions::delete_many()
+        .filter(UserSessionsColumn::UserId.is_in(user_ids.to_vec()))
+        .exec(connection)
+        .await?;
+    jwt_blacklist
+        .write()
+        .unwrap()
+        .extend(jwt_hashes.into_iter().map(|(hash,)| hash as u64));
+    Ok(())
+}
+
 #[async_trait]
-impl BackendHandler for SqlBackendHandler {}
+impl BackendHandler for SqlBackendHandler {
+    async fn failed_login_report(
+        &self,
+        user_id: &UserId,
+        window: chrono::Duration,
+    ) -> Result<FailedLoginReport> {
+        self.get_failed_login_report(user_id, window).await
+    }
+
+    async fn current_auth_success_ratio(
+        &self,
+        window: chrono::Duration,
+    ) -> Result<AuthSuccessRatio> {
+        self.get_auth_success_ratio(window).await
+    }
+
+    #[instrument(skip_all, level = "debug", err, fields(count = canary_credentials.len()))]
+    async fn validate_imported_files(
+        &self,
+        canary_credentials: Vec<(UserId, SecUtf8)>,
+    ) -> Result<Vec<(UserId, LoginFailureReason)>> {
+        let results = futures::stream::iter(canary_credentials)
+            .map(|(user_id, password)| async move {
+                let request = BindRequest {
+                    name: user_id,
+                    password: password.unsecure().to_owned(),
+                    source: None,
+                    context: None,
+                };
+                let failure = self.check_bind_password(&request).await?;
+                Ok(failure.map(|reason| (request.name, reason)))
+            })
+            .buffered(PASSWORD_BATCH_CONCURRENCY)
+            .collect::<Vec<Result<_>>>()
+            .await;
+        let mismatches = results
+            .into_iter()
+            .collect::<Result<Vec<_>>>()?
+            .into_iter()
+            .flatten()
+            .collect::<Vec<_>>();
+        for (user_id, reason) in &mismatches {
+            warn!(
+                r#"The canary account "{}" can't log in: {}"#,
+                user_id,
+                Into::<&'static str>::into(reason)
+            );
+        }
+        Ok(mismatches)
+    }
+
+    #[instrument(skip(self), level = "info")]
+    async fn set_maintenance_mode(&self, mode: MaintenanceMode) {
+        *self.maintenance_mode.write().unwrap() = mode;
+    }
+
+    async fn get_maintenance_mode(&self) -> MaintenanceMode {
+        self.maintenance_mode.read().unwrap().clone()
+    }
+
+    #[instrument(skip(self), level = "debug", err)]
+    async fn set_users_enabled(&self, user_ids: &[UserId], enabled: bool) -> Result<u64> {
+        let user_ids = user_ids.to_vec();
+        let jwt_blacklist = self.jwt_blacklist.clone();
+        Ok(self
+            .sql_pool
+            .transaction::<_, u64, DomainError>(|transaction| {
+                Box::pin(async move {
+                    if !enabled {
+                        revoke_tokens(transaction, &jwt_blacklist, &user_ids).await?;
+                    }
+                    Ok(model::User::update_many()
+                        .col_expr(UserColumn::IsEnabled, Expr::value(enabled))
+                        .filter(UserColumn::UserId.is_in(user_ids))
+                        .exec(transaction)
+                        .await?
+                        .rows_affected)
+                })
+            })
+            .await?)
+    }
+
+    #[instrument(skip(self), level = "debug", err)]
+    async fn set_user_enabled(&self, user_id: &UserId, enabled: bool) -> Result<()> {
+        if self
+            .set_users_enabled(std::slice::from_ref(user_id), enabled)
+            .await?
+            == 0
+        {
+            return Err(DomainError::EntityNotFound(format!(
+                "No such user: '{}'",
+                user_id
+            )));
+        }
+        Ok(())
+    }
+
+    #[instrument(skip_all, level = "debug", fields(count = passwords.len()))]
+    async fn set_passwords_batch(
+        &self,
+        passwords: Vec<(UserId, SecUtf8)>,
+        must_change_password: bool,
+    ) -> Vec<(UserId, Result<()>)> {
+        futures::stream::iter(passwords)
+            .map(|(user_id, password)| async move {
+                let result =
+                    register_password(self, user_id.clone(), &password, PasswordActor::Admin).await;
+                // Only the flag of the batch decides, not how the password was set.
+                let result = match result {
+                    Ok(()) => {
+                        self.set_must_change_password(&user_id, must_change_password)
+                            .await
+                    }
+                    Err(e) => Err(e),
+                };
+                (user_id, result)
+            })
+            .buffered(PASSWORD_BATCH_CONCURRENCY)
+            .collect()
+            .await
+    }
+
+    #[instrument(skip_all, level = "debug", err)]
+    async fn find_duplicate_emails(&self) -> Result<Vec<DuplicateEmail>> {
+        let duplicated_emails = model::User::find()
+            .select_only()
+            .column(UserColumn::LowercaseEmail)
+            .group_by(UserColumn::LowercaseEmail)
+            .having(Expr::expr(Func::count(Expr::col(UserColumn::UserId.as_column_ref()))).gt(1))
+            .into_query();
+        let users = model::User::find()
+            .select_only()
+            .column(UserColumn::LowercaseEmail)
+            .column(UserColumn::UserId)
+            .filter(UserColumn::LowercaseEmail.in_subquery(duplicated_emails))
+            .order_by_asc(UserColumn::LowercaseEmail)
+            .order_by_asc(UserColumn::UserId)
+            .into_tuple::<(String, UserId)>()
+            .all(&self.sql_pool)
+            .await?;
+        let mut duplicates: Vec<DuplicateEmail> = Vec::new();
+        for (email, user_id) in users {
+            match duplicates.last_mut() {
+                Some(duplicate) if duplicate.email == email => duplicate.user_ids.push(user_id),
+                _ => duplicates.push(DuplicateEmail {
+                    email,
+                    user_ids: vec![user_id],
+                }),
+            }
+        }
+        Ok(duplicates)
+    }
+
+    #[instrument(skip(self), level = "debug", err)]
+    async fn issue_temporary_password(&self, user_id: &UserId) -> Result<SecUtf8> {
+        use rand::{distributions::Alphanumeric, Rng};
+        let password: String = rand::rngs::OsRng
+            .sample_iter(&Alphanumeric)
+            .take(TEMPORARY_PASSWORD_LENGTH)
+            .map(char::from)
+            .collect();
+        let password = SecUtf8::from(password);
+        // A temporary password is meant to replace the current one.
+        self.allow_next_password_overwrite(user_id).await?;
+        register_password(self, user_id.clone(), &password, PasswordActor::Admin).await?;
+        // Setting the password cleared the flag.
+        self.set_must_change_password(user_id, true).await?;
+        Ok(password)
+    }
+
+    #[instrument(skip_all, level = "debug", err)]
+    async fn audit_password_store(&self) -> Result<Vec<PasswordFileAudit>> {
+        let mut password_files = model::User::find()
+            .filter(UserColumn::PasswordHash.is_not_null())
+            .select_only()
+            .column(UserColumn::UserId)
+            .column(UserColumn::PasswordHash)
+            .column(UserColumn::PasswordHashChecksum)
+            .order_by_asc(UserColumn::UserId)
+            .into_tuple::<(UserId, Vec<u8>, Option<Vec<u8>>)>()
+            .stream(&self.sql_pool)
+            .await?;
+        let mut report = Vec::new();
+        while let Some(row) = password_files.next().await {
+            let (user_id, password_file, checksum) = row?;
+            report.push(self.audit_password_file(user_id, &password_file, checksum.as_deref()));
+        }
+        Ok(report)
+    }
+
+    #[instrument(skip_all, level = "debug", err)]
+    async fn interop_self_test(&self, login_start_request: &[u8]) -> Result<bool> {
+        check_login_start_compatibility(self.config.get_server_setup(), login_start_request)
+    }
+
+    #[instrument(skip_all, level = "debug", err, fields(user_id = %user_id.as_str()))]
+    async fn migrate_legacy_to_opaque(
+        &self,
+        user_id: &UserId,
+        clear_password: &SecUtf8,
+    ) -> Result<()> {
+        self.migrate_legacy_password(user_id, clear_password).await
+    }
+
+    #[instrument(skip(self), level = "debug", err)]
+    async fn list_inactive_users(&self, since: chrono::NaiveDateTime) -> Result<Vec<InactiveUser>> {
+        Ok(model::User::find()
+            .filter(UserColumn::IsEnabled.eq(true))
+            .filter(
+                Condition::any()
+                    .add(UserColumn::LastLoginDate.lt(since))
+                    .add(
+                        Condition::all()
+                            .add(UserColumn::LastLoginDate.is_null())
+                            .add(UserColumn::CreationDate.lt(since)),
+                    ),
+            )
+            .select_only()
+            .column(UserColumn::UserId)
+            .column(UserColumn::CreationDate)
+            .column(UserColumn::LastLoginDate)
+            .order_by_asc(UserColumn::UserId)
+            .into_tuple::<(UserId, chrono::NaiveDateTime, Option<chrono::NaiveDateTime>)>()
+            .all(&self.sql_pool)
+            .await?
+            .into_iter()
+            .map(|(user_id, creation_date, last_login_date)| InactiveUser {
+                user_id,
+                creation_date,
+                last_login_date,
+            })
+            .collect())
+    }
+
+    #[instrument(skip(self), level = "debug", err)]
+    async fn disable_inactive_users(&self, since: chrono::NaiveDateTime) -> Result<Vec<UserId>> {
+        if !self.config.track_login_statistics {
+            // Every user would look like they never logged in.
+            return Err(DomainError::InternalError(
+                "Disabling inactive users requires track_login_statistics".to_owned(),
+            ));
+        }
+        let user_ids = self
+            .list_inactive_users(since)
+            .await?
+            .into_iter()
+            .map(|user| user.user_id)
+            .filter(|user_id| user_id != &self.config.ldap_user_dn)
+            .collect::<Vec<_>>();
+        if user_ids.is_empty() {
+            return Ok(user_ids);
+        }
+        self.set_users_enabled(&user_ids, false).await?;
+        for user_id in &user_ids {
+            info!(r#"Disabled "{}", inactive since before {}"#, user_id, since);
+        }
+        Ok(user_ids)
+    }
+
+    #[instrument(skip(self), level = "debug", err)]
+    async fn revoke_user_tokens(&self, user_id: &UserId) -> Result<()> {
+        revoke_tokens(
+            &self.sql_pool,
+            &self.jwt_blacklist,
+            std::slice::from_ref(user_id),
+        )
+        .await
+    }
+
+    #[instrument(skip(self), level = "debug", err)]
+    async fn list_user_sessions(&self, user_id: &UserId) -> Result<Vec<UserSession>> {
+        Ok(model::UserSessions::find()
+            .filter(UserSessionsColumn::UserId.eq(user_id))
+            .filter(UserSessionsColumn::ExpiryDate.gt(chrono::Utc::now().naive_utc()))
+            .order_by_desc(UserSessionsColumn::LastSeen)
+            .all(&self.sql_pool)
+            .await?
+            .into_iter()
+            .map(|s| UserSession {
+                session_id: s.session_id,
+                creation_date: s.creation_date,
+                last_seen: s.last_seen,
+                expiry_date: s.expiry_date,
+                device: s.device,
+                source: s.source,
+            })
+            .collect())
+    }
+
+    #[instrument(skip(self), level = "debug", err)]
+    async fn revoke_session(&self, session_id: i64) -> Result<()> {
+        let deleted = self
+            .sql_pool
+            .transaction::<_, u64, DomainError>(|transaction| {
+                Box::pin(async move {
+                    model::JwtRefreshStorage::delete_by_id(session_id)
+                        .exec(transaction)
+                        .await?;
+                    Ok(model::UserSessions::delete_by_id(session_id)
+                        .exec(transaction)
+                        .await?
+                        .rows_affected)
+                })
+            })
+            .await?;
+        if deleted == 0 {
+            return Err(DomainError::EntityNotFound(format!(
+                "No such session: {}",
+                session_id
+            )));
+        }
+        Ok(())
+    }
+
+    #[instrument(skip(self, secret), level = "debug", err)]
+    async fn set_totp_secret(&self, user_id: &UserId, secret: Option<String>) -> Result<()> {
+        let secret = secret
+            .map(|secret| {
+                totp::decode_secret(&secret)
+                    .map(|_| secret.trim_end_matches('=').to_ascii_uppercase())
+                    .ok_or_else(|| DomainError::InvalidTotpSecret(user_id.to_string()))
+            })
+            .transpose()?;
+        let result = model::User::update_many()
+            .col_expr(UserColumn::TotpSecret, Expr::value(secret))
+            .filter(UserColumn::UserId.eq(user_id))
+            .exec(&self.sql_pool)
+            .await?;
+        if result.rows_affected == 0 {
+            return Err(DomainError::EntityNotFound(user_id.to_string()));
+        }
+        Ok(())
+    }
+
+    #[instrument(skip(self), level = "debug", err)]
+    async fn transfer_credential(&self, from: &UserId, to: &UserId) -> Result<()> {
+        self.transfer_password_file(from, to).await
+    }
+
+    #[instrument(skip(self), level = "debug", err)]
+    async fn invalidate_all_passwords(&self) -> Result<u64> {
+        self.invalidate_password_files().await
+    }
+
+    #[instrument(skip(self), level = "debug", err)]
+    async fn allow_password_overwrite(&self, user_id: &UserId) -> Result<()> {
+        self.allow_next_password_overwrite(user_id).await
+    }
+
+    #[instrument(skip_all, level = "debug", err, fields(count = requests.len()))]
+    async fn create_users_bulk(&self, requests: Vec<CreateUserRequest>) -> Result<()> {
+        self.create_users_in_one_transaction(requests).await
+    }
+
+    async fn query_audit_log(
+        &self,
+        since: chrono::NaiveDateTime,
+        until: chrono::NaiveDateTime,
+    ) -> Result<Vec<AuditLogEntry>> {
+        self.get_audit_log(since, until).await
+    }
+
+    async fn proposed_policy_compliance_report(&self) -> Result<PolicyComplianceReport> {
+        self.get_policy_compliance_report().await
+    }
+
+    async fn add_group_to_group(&self, group_id: GroupId, parent_group_id: GroupId) -> Result<()> {
+        self.add_group_membership(group_id, parent_group_id).await
+    }
+
+    async fn remove_group_from_group(
+        &self,
+        group_id: GroupId,
+        parent_group_id: GroupId,
+    ) -> Result<()> {
+        self.remove_group_membership(group_id, parent_group_id)
+            .await
+    }
+
+    async fn get_effective_groups(&self, user_id: &UserId) -> Result<HashSet<GroupDetails>> {
+        self.get_user_effective_groups(user_id).await
+    }
+
+    #[instrument(skip_all, level = "debug", err, fields(user_id = %request.name))]
+    async fn delete_own_account(&self, request: BindRequest) -> Result<()> {
+        self.check_delete_own_account(&request).await
+    }
+
+    #[instrument(skip(self), level = "debug", err)]
+    async fn restore_user(&self, user_id: &UserId) -> Result<()> {
+        self.restore_deleted_user(user_id).await
+    }
+
+    #[instrument(skip(self), level = "debug", err)]
+    async fn set_user_attribute(
+        &self,
+        user_id: &UserId,
+        name: AttributeName,
+        values: Vec<String>,
+    ) -> Result<()> {
+        self.set_typed_user_attribute(user_id, name, values).await
+    }
+
+    #[instrument(skip_all, level = "debug")]
+    async fn full_health_report(&self) -> HealthReport {
+        self.get_health_report().await
+    }
+
+    #[instrument(skip_all, level = "debug")]
+    async fn readiness_report(&self) -> HealthReport {
+        self.get_readiness_report().await
+    }
+
+    #[instrument(skip(self), level = "debug", err)]
+    async fn generate_reset_token(&self, user_id: &UserId) -> Result<SecUtf8> {
+        Ok(SecUtf8::from(
+            self.generate_password_reset_token(user_id).await?,
+        ))
+    }
+
+    #[instrument(skip_all, level = "debug", err)]
+    async fn reset_password_with_token(&self, token: &str, new_password: SecUtf8) -> Result<()> {
+        self.reset_password_with_reset_token(token, &new_password)
+            .await
+            .map(|_| ())
+    }
+}
 
 #[cfg(test)]
 pub mod tests {
@@ -51,7 +634,26 @@ pub mod tests {
 
     pub async fn get_initialized_db() -> DbConnection {
         let sql_pool = get_in_memory_db().await;
+        init_tables(sql_pool).await
+    }
+
+    /// The database of `LLDAP_TEST_DATABASE_URL`, e.g. a Postgres container, with the tables.
+    /// It is shared with the other runs: the tests need their own users.
+    #[cfg(feature = "database_tests")]
+    pub async fn get_external_db() -> DbConnection {
+        crate::infra::logging::init_for_tests();
+        let database_url = std::env::var("LLDAP_TEST_DATABASE_URL")
+            .expect("LLDAP_TEST_DATABASE_URL is required for the database tests");
+        let sql_pool = Database::connect(database_url).await.unwrap();
+        init_tables(sql_pool).await
+    }
+
+    async fn init_tables(sql_pool: DbConnection) -> DbConnection {
         init_table(&sql_pool).await.unwrap();
+        // The tokens are revoked along with some user changes.
+        crate::infra::jwt_sql_tables::init_table(&sql_pool)
+            .await
+            .unwrap();
         sql_pool
     }
 
@@ -62,10 +664,14 @@ pub mod tests {
         let client_registration_start =
             opaque::client::registration::start_registration(pass.as_bytes(), &mut rng).unwrap();
         let response = handler
-            .registration_start(registration::ClientRegistrationStartRequest {
-                username: name.into(),
-                registration_start_request: client_registration_start.message,
-            })
+            .registration_start(
+                registration::ClientRegistrationStartRequest {
+                    username: name.into(),
+                    registration_start_request: client_registration_start.message,
+                    context: None,
+                },
+                None,
+            )
             .await
             .unwrap();
         let registration_upload = opaque::client::registration::finish_registration(
@@ -75,10 +681,14 @@ pub mod tests {
         )
         .unwrap();
         handler
-            .registration_finish(registration::ClientRegistrationFinishRequest {
-                server_data: response.server_data,
-                registration_upload: registration_upload.message,
-            })
+            .registration_finish(
+                registration::ClientRegistrationFinishRequest {
+                    server_data: response.server_data,
+                    registration_upload: registration_upload.message,
+                },
+                &UserId::new(name),
+                true,
+            )
             .await
             .unwrap();
     }
@@ -153,6 +763,253 @@ pub mod tests {
         }
     }
 
+    #[tokio::test]
+    async fn test_set_users_enabled() {
+        use crate::domain::handler::{BindRequest, LoginHandler};
+        let sql_pool = get_initialized_db().await;
+        let handler = SqlBackendHandler::new(get_default_config(), sql_pool);
+        for name in ["bob", "patrick", "john"] {
+            insert_user(&handler, name, "password").await;
+        }
+        let bind = |name: &'static str| {
+            handler.bind(BindRequest {
+                name: UserId::new(name),
+                password: "password".to_owned(),
+                source: None,
+                context: None,
+            })
+        };
+        let batch = [UserId::new("bob"), UserId::new("patrick")];
+        assert_eq!(handler.set_users_enabled(&batch, false).await.unwrap(), 2);
+        bind("bob").await.unwrap_err();
+        bind("patrick").await.unwrap_err();
+        bind("john").await.unwrap();
+        assert_eq!(handler.set_users_enabled(&batch, true).await.unwrap(), 2);
+        bind("bob").await.unwrap();
+        bind("patrick").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_inactive_users() {
+        let sql_pool = get_initialized_db().await;
+        let mut config = get_default_config();
+        config.track_login_statistics = true;
+        let handler = SqlBackendHandler::new(config, sql_pool);
+        let now = chrono::Utc::now().naive_utc();
+        let days_ago = |days| Some(now - chrono::Duration::days(days));
+        for (name, creation_date, last_login_date) in [
+            ("admin", days_ago(200), days_ago(100)),
+            ("recent", days_ago(200), days_ago(1)),
+            ("old", days_ago(200), days_ago(100)),
+            ("never_new", days_ago(1), None),
+            ("never_old", days_ago(200), None),
+            ("disabled", days_ago(200), days_ago(100)),
+        ] {
+            insert_user_no_password(&handler, name).await;
+            model::User::update_many()
+                .col_expr(UserColumn::CreationDate, Expr::value(creation_date))
+                .col_expr(UserColumn::LastLoginDate, Expr::value(last_login_date))
+                .filter(UserColumn::UserId.eq(name))
+                .exec(&handler.sql_pool)
+                .await
+                .unwrap();
+        }
+        handler
+            .set_users_enabled(&[UserId::new("disabled")], false)
+            .await
+            .unwrap();
+        let since = now - chrono::Duration::days(30);
+        let list_inactive = || async {
+            handler
+                .list_inactive_users(since)
+                .await
+                .unwrap()
+                .into_iter()
+                .map(|user| user.user_id.to_string())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(list_inactive().await, vec!["admin", "never_old", "old"]);
+        assert_eq!(
+            handler.disable_inactive_users(since).await.unwrap(),
+            vec![UserId::new("never_old"), UserId::new("old")]
+        );
+        // The admin from the configuration is never disabled.
+        assert_eq!(list_inactive().await, vec!["admin"]);
+    }
+
+    #[tokio::test]
+    async fn test_disable_inactive_users_requires_login_statistics() {
+        let sql_pool = get_initialized_db().await;
+        let handler = SqlBackendHandler::new(get_default_config(), sql_pool);
+        insert_user_no_password(&handler, "bob").await;
+        handler
+            .disable_inactive_users(chrono::Utc::now().naive_utc())
+            .await
+            .unwrap_err();
+    }
+
+    #[tokio::test]
+    async fn test_user_sessions() {
+        use crate::infra::tcp_backend_handler::TcpBackendHandler;
+        let sql_pool = get_initialized_db().await;
+        let mut config = get_default_config();
+        config.track_sessions = true;
+        let handler = SqlBackendHandler::new(config, sql_pool);
+        insert_user_no_password(&handler, "bob").await;
+        insert_user_no_password(&handler, "john").await;
+        let bob = UserId::new("bob");
+        for (device, source) in [("laptop", "10.0.0.1"), ("phone", "10.0.0.2")] {
+            handler
+                .create_refresh_token(&bob, Some(device), Some(source))
+                .await
+                .unwrap();
+        }
+        handler
+            .create_refresh_token(&UserId::new("john"), None, None)
+            .await
+            .unwrap();
+        let sessions = handler.list_user_sessions(&bob).await.unwrap();
+        let mut devices = sessions
+            .iter()
+            .map(|s| (s.device.as_deref().unwrap(), s.source.as_deref().unwrap()))
+            .collect::<Vec<_>>();
+        devices.sort();
+        assert_eq!(devices, vec![("laptop", "10.0.0.1"), ("phone", "10.0.0.2")]);
+        let revoked = sessions
+            .iter()
+            .find(|s| s.device.as_deref() == Some("phone"))
+            .unwrap()
+            .session_id;
+        handler.revoke_session(revoked).await.unwrap();
+        let sessions = handler.list_user_sessions(&bob).await.unwrap();
+        assert_eq!(sessions.len(), 1);
+        assert_eq!(sessions[0].device.as_deref(), Some("laptop"));
+        // The refresh token of the session doesn't work anymore.
+        assert!(!handler.check_token(revoked as u64, &bob).await.unwrap());
+        assert!(handler
+            .check_token(sessions[0].session_id as u64, &bob)
+            .await
+            .unwrap());
+        handler.revoke_session(revoked).await.unwrap_err();
+    }
+
+    #[tokio::test]
+    async fn test_user_sessions_not_tracked_by_default() {
+        use crate::infra::tcp_backend_handler::TcpBackendHandler;
+        let sql_pool = get_initialized_db().await;
+        let handler = SqlBackendHandler::new(get_default_config(), sql_pool);
+        insert_user_no_password(&handler, "bob").await;
+        let bob = UserId::new("bob");
+        handler
+            .create_refresh_token(&bob, Some("laptop"), None)
+            .await
+            .unwrap();
+        assert_eq!(handler.list_user_sessions(&bob).await.unwrap(), vec![]);
+    }
+
+    #[tokio::test]
+    async fn test_set_passwords_batch() {
+        use crate::domain::handler::{BindRequest, LoginHandler};
+        let sql_pool = get_initialized_db().await;
+        let handler = SqlBackendHandler::new(get_default_config(), sql_pool);
+        insert_user_no_password(&handler, "bob").await;
+        insert_user(&handler, "patrick", "pass").await;
+        let results = handler
+            .set_passwords_batch(
+                vec![
+                    (UserId::new("bob"), SecUtf8::from("bob-temp")),
+                    (UserId::new("eve"), SecUtf8::from("eve-temp")),
+                    (UserId::new("patrick"), SecUtf8::from("patrick-temp")),
+                ],
+                false,
+            )
+            .await;
+        assert_eq!(
+            results
+                .iter()
+                .map(|(user_id, result)| (user_id.as_str(), result.is_ok()))
+                .collect::<Vec<_>>(),
+            vec![("bob", true), ("eve", false), ("patrick", true)]
+        );
+        assert!(matches!(results[1].1, Err(DomainError::EntityNotFound(_))));
+        // The passwords are right, but the users have to change them first.
+        for (name, password) in [("bob", "bob-temp"), ("patrick", "patrick-temp")] {
+            handler
+                .bind(BindRequest {
+                    name: UserId::new(name),
+                    password: password.to_owned(),
+                    source: None,
+                    context: None,
+                })
+                .await
+                .unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_set_passwords_batch_must_change_password() {
+        use crate::domain::handler::{BindRequest, LoginHandler};
+        let sql_pool = get_initialized_db().await;
+        let handler = SqlBackendHandler::new(get_default_config(), sql_pool);
+        insert_user_no_password(&handler, "bob").await;
+        let results = handler
+            .set_passwords_batch(vec![(UserId::new("bob"), SecUtf8::from("bob-temp"))], true)
+            .await;
+        assert!(results[0].1.is_ok());
+        assert!(matches!(
+            handler
+                .bind(BindRequest {
+                    name: UserId::new("bob"),
+                    password: "bob-temp".to_owned(),
+                    source: None,
+                    context: None,
+                })
+                .await
+                .unwrap_err(),
+            DomainError::PasswordExpired(_)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_find_duplicate_emails() {
+        let sql_pool = get_initialized_db().await;
+        let handler = SqlBackendHandler::new(get_default_config(), sql_pool);
+        for (name, email) in [
+            ("bob", "bob@example.com"),
+            ("bobby", "Bob@Example.com"),
+            ("john", "john@example.com"),
+            ("patrick", "shared@example.com"),
+            ("pat", "Shared@example.com"),
+            ("pat2", "SHARED@example.com"),
+        ] {
+            handler
+                .create_user(CreateUserRequest {
+                    user_id: UserId::new(name),
+                    email: email.into(),
+                    ..Default::default()
+                })
+                .await
+                .unwrap();
+        }
+        assert_eq!(
+            handler.find_duplicate_emails().await.unwrap(),
+            vec![
+                DuplicateEmail {
+                    email: "bob@example.com".to_owned(),
+                    user_ids: vec![UserId::new("bob"), UserId::new("bobby")],
+                },
+                DuplicateEmail {
+                    email: "shared@example.com".to_owned(),
+                    user_ids: vec![
+                        UserId::new("pat"),
+                        UserId::new("pat2"),
+                        UserId::new("patrick")
+                    ],
+                },
+            ]
+        );
+    }
+
     #[tokio::test]
     async fn test_sql_injection() {
         let sql_pool = get_initialized_db().await;
@@ -174,4 +1031,86 @@ pub mod tests {
             assert_eq!(user.user_id, user_name);
         }
     }
+
+    #[tokio::test]
+    async fn test_revocations_update_the_jwt_blacklist() {
+        use crate::infra::tcp_backend_handler::TcpBackendHandler;
+        let handler = SqlBackendHandler::new(get_default_config(), get_initialized_db().await);
+        let expiry_date = chrono::Utc::now().naive_utc() + chrono::Duration::days(1);
+        for (jwt_hash, name) in [(1, "bob"), (2, "patrick"), (3, "john"), (4, "eve")] {
+            insert_user(&handler, name, "password").await;
+            handler
+                .register_jwt(&UserId::new(name), jwt_hash, expiry_date)
+                .await
+                .unwrap();
+        }
+        let blacklist = || handler.jwt_blacklist.read().unwrap().clone();
+        handler
+            .set_users_enabled(&[UserId::new("bob")], false)
+            .await
+            .unwrap();
+        assert_eq!(blacklist(), HashSet::from([1]));
+        handler.delete_user(&UserId::new("patrick")).await.unwrap();
+        assert_eq!(blacklist(), HashSet::from([1, 2]));
+        handler
+            .issue_temporary_password(&UserId::new("john"))
+            .await
+            .unwrap();
+        assert_eq!(blacklist(), HashSet::from([1, 2, 3]));
+        handler
+            .set_passwords_batch(
+                vec![(UserId::new("eve"), SecUtf8::from("eve-password"))],
+                false,
+            )
+            .await;
+        assert_eq!(blacklist(), HashSet::from([1, 2, 3, 4]));
+        // The database agrees, except for the JWTs deleted with patrick.
+        handler.jwt_blacklist.write().unwrap().clear();
+        let jwt_blacklist = handler.load_jwt_blacklist().await.unwrap();
+        assert_eq!(*jwt_blacklist.read().unwrap(), HashSet::from([1, 3, 4]));
+    }
+
+    async fn get_single_connection_db(acquire_timeout_seconds: u64) -> DbConnection {
+        let mut config = get_default_config();
+        config.db_max_connections = 1;
+        config.db_acquire_timeout_seconds = acquire_timeout_seconds;
+        let sql_pool = Database::connect(config.db_connect_options("sqlite::memory:".to_owned()))
+            .await
+            .unwrap();
+        init_table(&sql_pool).await.unwrap();
+        sql_pool
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_queries_share_a_single_connection() {
+        let handler =
+            SqlBackendHandler::new(get_default_config(), get_single_connection_db(30).await);
+        futures::future::join_all(
+            ["bob", "john", "patrick"].map(|name| insert_user_no_password(&handler, name)),
+        )
+        .await;
+        let results = tokio::time::timeout(
+            std::time::Duration::from_secs(10),
+            futures::future::join_all((0..10).map(|_| handler.list_users(None, false))),
+        )
+        .await
+        .expect("The queries should not deadlock");
+        for users in results {
+            assert_eq!(users.unwrap().len(), 3);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_exhausted_pool_times_out() {
+        use sea_orm::TransactionTrait;
+        let sql_pool = get_single_connection_db(1).await;
+        let handler = SqlBackendHandler::new(get_default_config(), sql_pool.clone());
+        let transaction = sql_pool.begin().await.unwrap();
+        assert!(matches!(
+            handler.list_users(None, false).await,
+            Err(DomainError::DatabaseTimeout(_))
+        ));
+        transaction.rollback().await.unwrap();
+        handler.list_users(None, false).await.unwrap();
+    }
 }
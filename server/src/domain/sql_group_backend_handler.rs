@@ -1,10 +1,10 @@
 use crate::domain::{
     error::{DomainError, Result},
     handler::{
-        CreateGroupRequest, GroupBackendHandler, GroupListerBackendHandler, GroupRequestFilter,
-        UpdateGroupRequest,
+        CreateGroupRequest, GroupBackendHandler, GroupListerBackendHandler, GroupPage,
+        GroupRequestFilter, UpdateGroupRequest,
     },
-    model::{self, GroupColumn, MembershipColumn},
+    model::{self, GroupColumn, MembershipColumn, UserColumn},
     sql_backend_handler::SqlBackendHandler,
     types::{AttributeName, AttributeValue, Group, GroupDetails, GroupId, Serialized, Uuid},
 };
@@ -75,35 +75,58 @@ fn get_group_filter_expr(filter: GroupRequestFilter) -> Cond {
     }
 }
 
+fn get_group_list_condition(filters: Option<GroupRequestFilter>) -> Cond {
+    filters
+        .map(|f| {
+            GroupColumn::GroupId
+                .in_subquery(
+                    model::Group::find()
+                        .find_also_linked(model::memberships::GroupToUser)
+                        .select_only()
+                        .column(GroupColumn::GroupId)
+                        .filter(get_group_filter_expr(f))
+                        .into_query(),
+                )
+                .into_condition()
+        })
+        .unwrap_or_else(|| SimpleExpr::Value(true.into()).into_condition())
+}
+
 #[async_trait]
 impl GroupListerBackendHandler for SqlBackendHandler {
     #[instrument(skip(self), level = "debug", ret, err)]
     async fn list_groups(&self, filters: Option<GroupRequestFilter>) -> Result<Vec<Group>> {
+        use itertools::Itertools; // For take_while_ref
         let results = model::Group::find()
             .order_by_asc(GroupColumn::GroupId)
-            .find_with_related(model::Membership)
+            .filter(get_group_list_condition(filters))
+            .all(&self.sql_pool)
+            .await?;
+        // The memberships of the deleted users are kept until they are purged, in case they are
+        // restored.
+        let memberships = model::Membership::find()
+            .filter(MembershipColumn::GroupId.is_in(results.iter().map(|g| g.group_id)))
             .filter(
-                filters
-                    .map(|f| {
-                        GroupColumn::GroupId
-                            .in_subquery(
-                                model::Group::find()
-                                    .find_also_linked(model::memberships::GroupToUser)
-                                    .select_only()
-                                    .column(GroupColumn::GroupId)
-                                    .filter(get_group_filter_expr(f))
-                                    .into_query(),
-                            )
-                            .into_condition()
-                    })
-                    .unwrap_or_else(|| SimpleExpr::Value(true.into()).into_condition()),
+                MembershipColumn::UserId.in_subquery(
+                    model::User::find()
+                        .select_only()
+                        .column(UserColumn::UserId)
+                        .filter(UserColumn::DeletedAt.is_null())
+                        .into_query(),
+                ),
             )
+            .order_by_asc(MembershipColumn::GroupId)
+            .order_by_asc(MembershipColumn::UserId)
             .all(&self.sql_pool)
             .await?;
+        let mut memberships_iter = memberships.into_iter().peekable();
         let mut groups: Vec<_> = results
             .into_iter()
-            .map(|(group, users)| {
-                let users: Vec<_> = users.into_iter().map(|u| u.user_id).collect();
+            .map(|group| {
+                let users = memberships_iter
+                    .take_while_ref(|m| m.group_id == group.group_id)
+                    .map(|m| m.user_id)
+                    .collect();
                 Group {
                     users,
                     ..group.into()
@@ -118,7 +141,6 @@ impl GroupListerBackendHandler for SqlBackendHandler {
             .all(&self.sql_pool)
             .await?;
         let mut attributes_iter = attributes.into_iter().peekable();
-        use itertools::Itertools; // For take_while_ref
         for group in groups.iter_mut() {
             assert!(attributes_iter
                 .peek()
@@ -134,6 +156,47 @@ impl GroupListerBackendHandler for SqlBackendHandler {
         groups.sort_by(|g1, g2| g1.display_name.cmp(&g2.display_name));
         Ok(groups)
     }
+
+    #[instrument(skip(self), level = "debug", err)]
+    async fn list_groups_page(
+        &self,
+        filters: Option<GroupRequestFilter>,
+        first: u64,
+        after: Option<GroupId>,
+    ) -> Result<GroupPage> {
+        // Keyset pagination, like for the users.
+        let mut query = model::Group::find()
+            .select_only()
+            .column(GroupColumn::GroupId)
+            .filter(get_group_list_condition(filters));
+        if let Some(after) = after {
+            query = query.filter(GroupColumn::GroupId.gt(after));
+        }
+        let mut group_ids: Vec<GroupId> = query
+            .order_by_asc(GroupColumn::GroupId)
+            .limit(first + 1)
+            .into_tuple()
+            .all(&self.sql_pool)
+            .await?;
+        let has_next_page = group_ids.len() as u64 > first;
+        group_ids.truncate(first as usize);
+        let mut groups = if group_ids.is_empty() {
+            Vec::new()
+        } else {
+            self.list_groups(Some(GroupRequestFilter::Or(
+                group_ids
+                    .into_iter()
+                    .map(GroupRequestFilter::GroupId)
+                    .collect(),
+            )))
+            .await?
+        };
+        groups.sort_by_key(|g| g.id);
+        Ok(GroupPage {
+            groups,
+            has_next_page,
+        })
+    }
 }
 
 #[async_trait]
@@ -419,6 +482,37 @@ mod tests {
         );
     }
 
+    #[tokio::test]
+    async fn test_list_groups_page() {
+        let fixture = TestFixture::new().await;
+        let page = fixture
+            .handler
+            .list_groups_page(None, 2, None)
+            .await
+            .unwrap();
+        assert_eq!(
+            page.groups.iter().map(|g| g.id).collect::<Vec<_>>(),
+            fixture.groups[0..2]
+        );
+        assert!(page.has_next_page);
+        let page = fixture
+            .handler
+            .list_groups_page(
+                Some(GroupRequestFilter::Not(Box::new(
+                    GroupRequestFilter::Member(UserId::new("bob")),
+                ))),
+                2,
+                Some(fixture.groups[0]),
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            page.groups.iter().map(|g| g.id).collect::<Vec<_>>(),
+            fixture.groups[1..3]
+        );
+        assert!(!page.has_next_page);
+    }
+
     #[tokio::test]
     async fn test_list_groups_other_filter() {
         let fixture = TestFixture::new().await;
@@ -0,0 +1,305 @@
+use crate::domain::{
+    handler::{HealthReport, HealthStatus, SubsystemHealth},
+    sql_backend_handler::SqlBackendHandler,
+    sql_migrations::get_schema_version,
+    sql_opaque_handler::check_server_setup,
+    sql_tables::LAST_SCHEMA_VERSION,
+};
+use sea_orm::{ConnectionTrait, DbErr, Statement};
+use std::time::Duration;
+use tracing::warn;
+
+/// The key read from the throttle store to check it. It's never written.
+const HEALTH_CHECK_THROTTLE_KEY: &str = "lldap_health_check";
+
+/// Past this, the database is considered unreachable by the readiness probe.
+const READINESS_DATABASE_TIMEOUT: Duration = Duration::from_secs(2);
+
+fn subsystem(name: &str, status: HealthStatus, details: Option<String>) -> SubsystemHealth {
+    SubsystemHealth {
+        name: name.to_owned(),
+        status,
+        details,
+    }
+}
+
+fn failing(name: &str, error: impl std::fmt::Display) -> SubsystemHealth {
+    subsystem(name, HealthStatus::Failing, Some(error.to_string()))
+}
+
+impl SqlBackendHandler {
+    pub(crate) async fn get_health_report(&self) -> HealthReport {
+        let report = HealthReport::new(vec![
+            self.check_database_health().await,
+            self.check_keys_health(),
+            self.check_server_setup_health(),
+            self.check_throttle_store_health().await,
+            self.check_auth_failures_health().await,
+        ]);
+        if report.status != HealthStatus::Ok {
+            warn!("Health report: {:?}", &report);
+        }
+        report
+    }
+
+    /// Only what's needed to serve requests, quickly: the database answers, and the OPAQUE
+    /// server setup works.
+    pub(crate) async fn get_readiness_report(&self) -> HealthReport {
+        HealthReport::new(vec![
+            self.check_database_reachable().await,
+            self.check_server_setup_health(),
+        ])
+    }
+
+    async fn ping_database(&self) -> Result<(), DbErr> {
+        self.sql_pool
+            .execute(Statement::from_string(
+                self.sql_pool.get_database_backend(),
+                "SELECT 1",
+            ))
+            .await
+            .map(|_| ())
+    }
+
+    async fn check_database_reachable(&self) -> SubsystemHealth {
+        const NAME: &str = "database";
+        match tokio::time::timeout(READINESS_DATABASE_TIMEOUT, self.ping_database()).await {
+            Ok(Ok(())) => subsystem(NAME, HealthStatus::Ok, None),
+            Ok(Err(e)) => failing(NAME, e),
+            Err(_) => failing(
+                NAME,
+                format!(
+                    "No answer after {} seconds",
+                    READINESS_DATABASE_TIMEOUT.as_secs()
+                ),
+            ),
+        }
+    }
+
+    async fn check_database_health(&self) -> SubsystemHealth {
+        const NAME: &str = "database";
+        if let Err(e) = self.ping_database().await {
+            return failing(NAME, e);
+        }
+        match get_schema_version(&self.sql_pool).await {
+            Some(version) if version == LAST_SCHEMA_VERSION => {
+                subsystem(NAME, HealthStatus::Ok, None)
+            }
+            version => subsystem(
+                NAME,
+                HealthStatus::Degraded,
+                Some(format!(
+                    "Schema version {:?}, expected {:?}",
+                    version, LAST_SCHEMA_VERSION
+                )),
+            ),
+        }
+    }
+
+    fn check_keys_health(&self) -> SubsystemHealth {
+        const NAME: &str = "keys";
+        if self.config.jwt_secret.unsecure().is_empty() {
+            return failing(NAME, "The JWT secret is empty");
+        }
+        match self.get_orion_secret_key() {
+            Ok(_) => subsystem(NAME, HealthStatus::Ok, None),
+            Err(e) => failing(NAME, e),
+        }
+    }
+
+    fn check_server_setup_health(&self) -> SubsystemHealth {
+        const NAME: &str = "server_setup";
+        match check_server_setup(self.config.get_server_setup()) {
+            Ok(()) => subsystem(NAME, HealthStatus::Ok, None),
+            Err(e) => failing(NAME, e),
+        }
+    }
+
+    async fn check_throttle_store_health(&self) -> SubsystemHealth {
+        const NAME: &str = "throttle_store";
+        match self.throttle_store.get(HEALTH_CHECK_THROTTLE_KEY).await {
+            Ok(_) => subsystem(NAME, HealthStatus::Ok, None),
+            Err(e) => failing(NAME, e),
+        }
+    }
+
+    /// Degraded when the failures go above the threshold of the authentication alert.
+    async fn check_auth_failures_health(&self) -> SubsystemHealth {
+        const NAME: &str = "auth_failures";
+        if !self.config.enable_auth_events {
+            return subsystem(
+                NAME,
+                HealthStatus::Ok,
+                Some("Authentication events are not recorded".to_owned()),
+            );
+        }
+        let options = &self.config.auth_alert_options;
+        match self
+            .get_auth_success_ratio(chrono::Duration::seconds(options.window_seconds as i64))
+            .await
+        {
+            Err(e) => failing(NAME, e),
+            Ok(ratio)
+                if options.failure_ratio_threshold > 0.0
+                    && ratio.attempts() >= options.min_attempts
+                    && ratio
+                        .failure_ratio()
+                        .map(|r| r > options.failure_ratio_threshold)
+                        .unwrap_or(false) =>
+            {
+                subsystem(
+                    NAME,
+                    HealthStatus::Degraded,
+                    Some(format!(
+                        "{} out of {} authentications failed in the last {} seconds",
+                        ratio.failures,
+                        ratio.attempts(),
+                        options.window_seconds
+                    )),
+                )
+            }
+            Ok(_) => subsystem(NAME, HealthStatus::Ok, None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::{
+        error::{DomainError, Result},
+        handler::{BackendHandler, LoginFailureReason, LoginSource},
+        sql_backend_handler::tests::*,
+        throttle::{ThrottleCounter, ThrottleStore},
+        types::UserId,
+    };
+    use async_trait::async_trait;
+    use pretty_assertions::assert_eq;
+
+    struct UnreachableThrottleStore;
+
+    #[async_trait]
+    impl ThrottleStore for UnreachableThrottleStore {
+        async fn increment(&self, _: &str, _: chrono::Duration) -> Result<ThrottleCounter> {
+            Err(DomainError::InternalError("store unreachable".to_owned()))
+        }
+        async fn get(&self, _: &str) -> Result<Option<ThrottleCounter>> {
+            Err(DomainError::InternalError("store unreachable".to_owned()))
+        }
+        async fn reset(&self, _: &str) -> Result<()> {
+            Err(DomainError::InternalError("store unreachable".to_owned()))
+        }
+    }
+
+    fn statuses(report: &HealthReport) -> Vec<(&str, HealthStatus)> {
+        report
+            .subsystems
+            .iter()
+            .map(|s| (s.name.as_str(), s.status))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_healthy_report() {
+        let handler = SqlBackendHandler::new(get_default_config(), get_initialized_db().await);
+        let report = handler.full_health_report().await;
+        assert_eq!(
+            statuses(&report),
+            vec![
+                ("database", HealthStatus::Ok),
+                ("keys", HealthStatus::Ok),
+                ("server_setup", HealthStatus::Ok),
+                ("throttle_store", HealthStatus::Ok),
+                ("auth_failures", HealthStatus::Ok),
+            ]
+        );
+        assert_eq!(report.status, HealthStatus::Ok);
+    }
+
+    #[tokio::test]
+    async fn test_unreachable_throttle_store_fails_the_report() {
+        let mut handler = SqlBackendHandler::new(get_default_config(), get_initialized_db().await);
+        handler.throttle_store = std::sync::Arc::new(UnreachableThrottleStore);
+        let report = handler.full_health_report().await;
+        let throttle_store = report
+            .subsystems
+            .iter()
+            .find(|s| s.name == "throttle_store")
+            .unwrap();
+        assert_eq!(throttle_store.status, HealthStatus::Failing);
+        assert_eq!(
+            throttle_store.details.as_deref(),
+            Some("Internal error: `store unreachable`")
+        );
+        assert_eq!(report.status, HealthStatus::Failing);
+    }
+
+    #[tokio::test]
+    async fn test_auth_failures_degrade_the_report() {
+        let mut config = get_default_config();
+        config.enable_auth_events = true;
+        config.auth_alert_options.failure_ratio_threshold = 0.5;
+        config.auth_alert_options.min_attempts = 2;
+        let handler = SqlBackendHandler::new(config, get_initialized_db().await);
+        let bob = UserId::new("bob");
+        for _ in 0..3 {
+            handler
+                .record_auth_event(
+                    &bob,
+                    LoginSource::Ldap,
+                    Some(LoginFailureReason::InvalidPassword),
+                )
+                .await
+                .unwrap();
+        }
+        let report = handler.full_health_report().await;
+        assert_eq!(
+            statuses(&report).last(),
+            Some(&("auth_failures", HealthStatus::Degraded))
+        );
+        assert_eq!(report.status, HealthStatus::Degraded);
+    }
+
+    #[tokio::test]
+    async fn test_readiness_report() {
+        let handler = SqlBackendHandler::new(get_default_config(), get_initialized_db().await);
+        let report = handler.readiness_report().await;
+        assert_eq!(
+            statuses(&report),
+            vec![
+                ("database", HealthStatus::Ok),
+                ("server_setup", HealthStatus::Ok),
+            ]
+        );
+        assert_eq!(report.status, HealthStatus::Ok);
+    }
+
+    #[test]
+    fn test_overall_status_is_the_worst() {
+        let report = HealthReport::new(vec![
+            subsystem("a", HealthStatus::Ok, None),
+            subsystem("b", HealthStatus::Degraded, None),
+            subsystem("c", HealthStatus::Ok, None),
+        ]);
+        assert_eq!(report.status, HealthStatus::Degraded);
+        let report = HealthReport::new(vec![
+            subsystem("a", HealthStatus::Failing, None),
+            subsystem("b", HealthStatus::Degraded, None),
+        ]);
+        assert_eq!(report.status, HealthStatus::Failing);
+        assert_eq!(HealthReport::new(vec![]).status, HealthStatus::Ok);
+    }
+
+    #[test]
+    fn test_report_serialization() {
+        let report = HealthReport::new(vec![subsystem(
+            "database",
+            HealthStatus::Failing,
+            Some("down".to_owned()),
+        )]);
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["status"], "failing");
+        assert_eq!(json["subsystems"][0]["name"], "database");
+        assert_eq!(json["subsystems"][0]["details"], "down");
+    }
+}
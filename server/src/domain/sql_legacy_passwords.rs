@@ -0,0 +1,436 @@
+use crate::domain::{
+    error::{DomainError, Result},
+    handler::{BindRequest, LoginFailureReason},
+    model::{self, UserColumn},
+    sql_backend_handler::SqlBackendHandler,
+    sql_opaque_handler::create_password_file,
+    types::UserId,
+};
+use sea_orm::{
+    sea_query::Expr, ColumnTrait, EntityTrait, PaginatorTrait, QueryFilter, QuerySelect,
+    TransactionTrait,
+};
+use secstr::SecUtf8;
+use tracing::{debug, info, warn};
+
+impl SqlBackendHandler {
+    /// Checks the password of a user without a password file against the bcrypt hash imported
+    /// from another directory, if any. Returns the reason why the bind fails, if it does.
+    pub(crate) async fn check_legacy_password(
+        &self,
+        request: &BindRequest,
+    ) -> Result<Option<LoginFailureReason>> {
+        let legacy_password_hash = model::User::find_by_id(request.name.clone())
+            .filter(UserColumn::IsEnabled.eq(true))
+            .filter(UserColumn::PasswordHash.is_null())
+            .select_only()
+            .column(UserColumn::LegacyPasswordHash)
+            .into_tuple::<Option<String>>()
+            .one(&self.sql_pool)
+            .await?
+            .flatten();
+        let legacy_password_hash = match legacy_password_hash {
+            Some(hash) => hash,
+            None => {
+                debug!(
+                    r#"User "{}" doesn't exist or has no password"#,
+                    &request.name
+                );
+                return Ok(Some(LoginFailureReason::UnknownUser));
+            }
+        };
+        if self.config.require_opaque_for_admins && self.is_admin(&request.name).await? {
+            debug!(
+                r#"Admin "{}" can't log in with a legacy password hash"#,
+                &request.name
+            );
+            return Ok(Some(LoginFailureReason::LegacyPasswordRefused));
+        }
+        match bcrypt::verify(&request.password, &legacy_password_hash) {
+            Ok(true) => Ok(None),
+            Ok(false) => {
+                debug!(r#"Invalid legacy password for "{}""#, &request.name);
+                Ok(Some(LoginFailureReason::InvalidPassword))
+            }
+            Err(e) => {
+                warn!(
+                    r#"The legacy password hash of "{}" is invalid: {}"#,
+                    &request.name, e
+                );
+                Ok(Some(LoginFailureReason::InvalidPassword))
+            }
+        }
+    }
+
+    /// Whether the password matches the legacy hash of the user, with `forbid_legacy_password_reuse`.
+    /// The hash is kept after the migration for this check, until the user sets a new password.
+    pub(crate) async fn is_legacy_password(
+        &self,
+        user_id: &UserId,
+        clear_password: &str,
+    ) -> Result<bool> {
+        if !self.config.forbid_legacy_password_reuse {
+            return Ok(false);
+        }
+        let legacy_password_hash = model::User::find_by_id(user_id.clone())
+            .select_only()
+            .column(UserColumn::LegacyPasswordHash)
+            .into_tuple::<Option<String>>()
+            .one(&self.sql_pool)
+            .await?
+            .flatten();
+        Ok(legacy_password_hash
+            .is_some_and(|hash| bcrypt::verify(clear_password, &hash).unwrap_or(false)))
+    }
+
+    /// Replaces the legacy password hash of the user with a password file for the same password,
+    /// e.g. a temporary password known by an admin. The hash is checked and replaced in the same
+    /// transaction. There is no password policy: the password doesn't change. With
+    /// `forbid_legacy_password_reuse`, the password file is temporary and the hash is kept, to
+    /// refuse the same password as the new one.
+    pub(crate) async fn migrate_legacy_password(
+        &self,
+        user_id: &UserId,
+        clear_password: &SecUtf8,
+    ) -> Result<()> {
+        let password_file = create_password_file(
+            self.config.get_server_setup(),
+            user_id,
+            clear_password.unsecure(),
+        )?;
+        let checksum = self.password_file_checksum(user_id, &password_file);
+        let user = user_id.clone();
+        let clear_password = clear_password.clone();
+        let forbid_reuse = self.config.forbid_legacy_password_reuse;
+        self.sql_pool
+            .transaction::<_, (), DomainError>(|transaction| {
+                Box::pin(async move {
+                    let legacy_password_hash = model::User::find_by_id(user.clone())
+                        .filter(UserColumn::PasswordHash.is_null())
+                        .select_only()
+                        .column(UserColumn::LegacyPasswordHash)
+                        .into_tuple::<Option<String>>()
+                        .one(transaction)
+                        .await?
+                        .flatten()
+                        .ok_or_else(|| {
+                            DomainError::EntityNotFound(format!(
+                                "'{}' has no legacy password hash",
+                                user
+                            ))
+                        })?;
+                    if !bcrypt::verify(clear_password.unsecure(), &legacy_password_hash)
+                        .unwrap_or(false)
+                    {
+                        return Err(DomainError::AuthenticationError(format!(
+                            "The password doesn't match the legacy hash of '{}'",
+                            user
+                        )));
+                    }
+                    let mut update = model::User::update_many()
+                        .col_expr(UserColumn::PasswordHash, Expr::value(password_file))
+                        .col_expr(UserColumn::PasswordHashChecksum, Expr::value(checksum))
+                        .col_expr(
+                            UserColumn::PasswordChangedAt,
+                            Expr::value(chrono::Utc::now().naive_utc()),
+                        );
+                    update = if forbid_reuse {
+                        update.col_expr(UserColumn::MustChangePassword, Expr::value(true))
+                    } else {
+                        update.col_expr(
+                            UserColumn::LegacyPasswordHash,
+                            Expr::value(Option::<String>::None),
+                        )
+                    };
+                    let result = update
+                        .filter(UserColumn::UserId.eq(&user))
+                        .filter(UserColumn::PasswordHash.is_null())
+                        .filter(UserColumn::LegacyPasswordHash.eq(legacy_password_hash))
+                        .exec(transaction)
+                        .await?;
+                    if result.rows_affected == 0 {
+                        return Err(DomainError::CredentialsChanged(format!(
+                            "The password of '{}' changed during the migration",
+                            user
+                        )));
+                    }
+                    Ok(())
+                })
+            })
+            .await?;
+        Ok(())
+    }
+
+    /// Replaces the legacy password hash with a password file after a successful bind, like
+    /// [`Self::migrate_legacy_password`]: the password doesn't change, so the policy doesn't
+    /// apply. A password that doesn't match the policy has to be changed. This never fails: the
+    /// user can still log in with the hash.
+    pub(crate) async fn upgrade_legacy_password(&self, user_id: &UserId, password: &str) {
+        let result = async {
+            let has_legacy_password = model::User::find_by_id(user_id.clone())
+                .filter(UserColumn::PasswordHash.is_null())
+                .filter(UserColumn::LegacyPasswordHash.is_not_null())
+                .count(&self.sql_pool)
+                .await?
+                > 0;
+            if !has_legacy_password {
+                return Ok(());
+            }
+            self.migrate_legacy_password(user_id, &SecUtf8::from(password))
+                .await?;
+            let violations = self
+                .password_policy_for(user_id)
+                .await?
+                .violations(user_id.as_str(), password);
+            if !violations.is_empty() {
+                info!(
+                    r#"The legacy password of "{}" doesn't match the policy, it has to be changed"#,
+                    user_id
+                );
+                self.set_must_change_password(user_id, true).await?;
+            }
+            info!(r#"Upgraded the legacy password of "{}""#, user_id);
+            Result::Ok(())
+        }
+        .await;
+        if let Err(e) = result {
+            warn!(
+                r#"Could not upgrade the legacy password of "{}": {}"#,
+                user_id, e
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::{
+        handler::{BackendHandler, BindRequest, LoginHandler},
+        sql_backend_handler::tests::*,
+        sql_opaque_handler::{register_password, PasswordActor},
+    };
+    use pretty_assertions::assert_eq;
+
+    async fn bind(handler: &SqlBackendHandler, name: &str, password: &str) -> Result<()> {
+        handler
+            .bind(BindRequest {
+                name: UserId::new(name),
+                password: password.to_owned(),
+                source: None,
+                context: None,
+            })
+            .await
+    }
+
+    async fn insert_legacy_user(handler: &SqlBackendHandler, name: &str, password: &str) {
+        insert_user_no_password(handler, name).await;
+        model::User::update_many()
+            .col_expr(
+                UserColumn::LegacyPasswordHash,
+                Expr::value(bcrypt::hash(password, 4).unwrap()),
+            )
+            .filter(UserColumn::UserId.eq(name))
+            .exec(&handler.sql_pool)
+            .await
+            .unwrap();
+    }
+
+    async fn get_password_columns(
+        handler: &SqlBackendHandler,
+        name: &str,
+    ) -> (Option<Vec<u8>>, Option<String>) {
+        let user = model::User::find_by_id(UserId::new(name))
+            .one(&handler.sql_pool)
+            .await
+            .unwrap()
+            .unwrap();
+        (user.password_hash, user.legacy_password_hash)
+    }
+
+    #[tokio::test]
+    async fn test_bind_with_legacy_password() {
+        let handler = SqlBackendHandler::new(get_default_config(), get_initialized_db().await);
+        insert_legacy_user(&handler, "bob", "bob00000").await;
+        bind(&handler, "bob", "bob00000").await.unwrap();
+        assert!(matches!(
+            bind(&handler, "bob", "wrong").await.unwrap_err(),
+            DomainError::AuthenticationError(_)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_legacy_password_upgraded_on_bind() {
+        let handler = SqlBackendHandler::new(get_default_config(), get_initialized_db().await);
+        insert_legacy_user(&handler, "bob", "bob00000").await;
+        bind(&handler, "bob", "bob00000").await.unwrap();
+        let (password_file, legacy_password_hash) = get_password_columns(&handler, "bob").await;
+        assert!(password_file.is_some());
+        assert_eq!(legacy_password_hash, None);
+        // The password file works for the next binds.
+        bind(&handler, "bob", "bob00000").await.unwrap();
+        assert!(matches!(
+            bind(&handler, "bob", "wrong").await.unwrap_err(),
+            DomainError::AuthenticationError(_)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_non_compliant_legacy_password_upgraded() {
+        let mut config = get_default_config();
+        config.password_policy_options.min_length = 12;
+        let handler = SqlBackendHandler::new(config, get_initialized_db().await);
+        insert_legacy_user(&handler, "bob", "bob00000").await;
+        bind(&handler, "bob", "bob00000").await.unwrap();
+        let (password_file, legacy_password_hash) = get_password_columns(&handler, "bob").await;
+        assert!(password_file.is_some());
+        assert_eq!(legacy_password_hash, None);
+        assert!(handler
+            .must_change_password(&UserId::new("bob"))
+            .await
+            .unwrap());
+        assert!(matches!(
+            bind(&handler, "bob", "bob00000").await.unwrap_err(),
+            DomainError::PasswordExpired(_)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_legacy_password_kept_after_wrong_password() {
+        let handler = SqlBackendHandler::new(get_default_config(), get_initialized_db().await);
+        insert_legacy_user(&handler, "bob", "bob00000").await;
+        bind(&handler, "bob", "wrong").await.unwrap_err();
+        let (password_file, legacy_password_hash) = get_password_columns(&handler, "bob").await;
+        assert_eq!(password_file, None);
+        assert!(legacy_password_hash.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_legacy_password_reuse_forbidden() {
+        let mut config = get_default_config();
+        config.forbid_legacy_password_reuse = true;
+        let handler = SqlBackendHandler::new(config, get_initialized_db().await);
+        insert_legacy_user(&handler, "bob", "bob00000").await;
+        handler
+            .migrate_legacy_to_opaque(&UserId::new("bob"), &SecUtf8::from("bob00000"))
+            .await
+            .unwrap();
+        assert!(matches!(
+            bind(&handler, "bob", "bob00000").await.unwrap_err(),
+            DomainError::PasswordExpired(_)
+        ));
+        let (password_file, legacy_password_hash) = get_password_columns(&handler, "bob").await;
+        assert!(password_file.is_some());
+        assert!(legacy_password_hash.is_some());
+        // The new password can't be the imported one.
+        assert!(matches!(
+            register_password(
+                &handler,
+                UserId::new("bob"),
+                &SecUtf8::from("bob00000"),
+                PasswordActor::SelfService,
+            )
+            .await
+            .unwrap_err(),
+            DomainError::PasswordReused(_)
+        ));
+        assert!(matches!(
+            register_password(
+                &handler,
+                UserId::new("bob"),
+                &SecUtf8::from("bob00000"),
+                PasswordActor::Admin,
+            )
+            .await
+            .unwrap_err(),
+            DomainError::WeakPassword(_)
+        ));
+        register_password(
+            &handler,
+            UserId::new("bob"),
+            &SecUtf8::from("bob11111"),
+            PasswordActor::SelfService,
+        )
+        .await
+        .unwrap();
+        assert_eq!(get_password_columns(&handler, "bob").await.1, None);
+        bind(&handler, "bob", "bob11111").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_legacy_password_refused_for_admins() {
+        let mut config = get_default_config();
+        config.require_opaque_for_admins = true;
+        let handler = SqlBackendHandler::new(config, get_initialized_db().await);
+        insert_legacy_user(&handler, "admin2", "admin-pass").await;
+        insert_legacy_user(&handler, "bob", "bob00000").await;
+        let admin_group = insert_group(&handler, "lldap_admin").await;
+        insert_membership(&handler, admin_group, "admin2").await;
+        assert!(matches!(
+            bind(&handler, "admin2", "admin-pass").await.unwrap_err(),
+            DomainError::AuthenticationError(_)
+        ));
+        bind(&handler, "bob", "bob00000").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_migrate_legacy_to_opaque() {
+        let handler = SqlBackendHandler::new(get_default_config(), get_initialized_db().await);
+        insert_legacy_user(&handler, "bob", "temporary").await;
+        handler
+            .migrate_legacy_to_opaque(&UserId::new("bob"), &SecUtf8::from("temporary"))
+            .await
+            .unwrap();
+        let (password_file, legacy_password_hash) = get_password_columns(&handler, "bob").await;
+        assert!(password_file.is_some());
+        assert_eq!(legacy_password_hash, None);
+        bind(&handler, "bob", "temporary").await.unwrap();
+        // There is nothing left to migrate.
+        assert!(matches!(
+            handler
+                .migrate_legacy_to_opaque(&UserId::new("bob"), &SecUtf8::from("temporary"))
+                .await
+                .unwrap_err(),
+            DomainError::EntityNotFound(_)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_migrate_legacy_to_opaque_wrong_password() {
+        let handler = SqlBackendHandler::new(get_default_config(), get_initialized_db().await);
+        insert_legacy_user(&handler, "bob", "temporary").await;
+        assert!(matches!(
+            handler
+                .migrate_legacy_to_opaque(&UserId::new("bob"), &SecUtf8::from("wrong"))
+                .await
+                .unwrap_err(),
+            DomainError::AuthenticationError(_)
+        ));
+        let (password_file, legacy_password_hash) = get_password_columns(&handler, "bob").await;
+        assert_eq!(password_file, None);
+        assert!(legacy_password_hash.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_legacy_password_hash_dropped_by_registration() {
+        let handler = SqlBackendHandler::new(get_default_config(), get_initialized_db().await);
+        insert_legacy_user(&handler, "bob", "old_password").await;
+        register_password(
+            &handler,
+            UserId::new("bob"),
+            &SecUtf8::from("new_password"),
+            PasswordActor::SelfService,
+        )
+        .await
+        .unwrap();
+        assert_eq!(get_password_columns(&handler, "bob").await.1, None);
+        assert!(matches!(
+            handler
+                .migrate_legacy_to_opaque(&UserId::new("bob"), &SecUtf8::from("old_password"))
+                .await
+                .unwrap_err(),
+            DomainError::EntityNotFound(_)
+        ));
+        bind(&handler, "bob", "new_password").await.unwrap();
+    }
+}
@@ -29,6 +29,61 @@ pub enum Users {
     TotpSecret,
     MfaType,
     Uuid,
+    DisplayEmail,
+    LoginCount,
+    LastLoginDate,
+    IsEnabled,
+    PasswordHashChecksum,
+    MustChangePassword,
+    LegacyPasswordHash,
+    PasswordChangedAt,
+    FailedLoginCount,
+    LockedUntil,
+    PasswordIdentity,
+    PasswordGraceLoginsRemaining,
+    PasswordOverwriteAllowed,
+    DeletedAt,
+}
+/// The passwords of the users for the contexts other than the primary one.
+#[derive(DeriveIden, Clone, Copy)]
+pub enum UserPasswordContexts {
+    Table,
+    UserId,
+    Context,
+    PasswordHash,
+    PasswordHashChecksum,
+}
+
+/// Nested groups: the child group is a member of the parent group.
+#[derive(DeriveIden, Clone, Copy)]
+pub enum GroupMembershipsGroups {
+    Table,
+    ParentGroupId,
+    ChildGroupId,
+}
+
+/// The results of the dry run of the proposed password policy, one row per user.
+#[derive(DeriveIden, Clone, Copy)]
+pub enum PasswordPolicyCompliance {
+    Table,
+    UserId,
+    Compliant,
+    CheckedDate,
+}
+
+/// Security-relevant events, only ever appended to. There are no foreign keys, the entries outlive
+/// the users and groups.
+#[derive(DeriveIden, Clone, Copy)]
+pub enum AuditLog {
+    Table,
+    EntryId,
+    EventDate,
+    Actor,
+    Action,
+    Target,
+    SourceIp,
+    Outcome,
+    EntryHash,
 }
 
 #[derive(DeriveIden, PartialEq, Eq, Debug, Serialize, Deserialize, Clone, Copy)]
@@ -88,6 +143,92 @@ pub enum GroupAttributes {
     GroupAttributeValue,
 }
 
+/// Counters for the failed attempts, when they are shared between instances.
+#[derive(DeriveIden, Clone, Copy)]
+pub enum ThrottleCounters {
+    Table,
+    ThrottleKey,
+    Count,
+    ExpiryDate,
+}
+
+/// OPAQUE registration states kept on the server, referenced by a handle given to the client.
+#[derive(DeriveIden, Clone, Copy)]
+pub enum RegistrationStates {
+    Table,
+    Handle,
+    ServerData,
+    ExpiryDate,
+    UserId,
+}
+
+/// The devices users logged in from.
+#[derive(DeriveIden, Clone, Copy)]
+pub enum UserDevices {
+    Table,
+    UserId,
+    FingerprintHash,
+    FirstSeen,
+    LastSeen,
+}
+
+/// Login links that haven't been used yet.
+#[derive(DeriveIden, Clone, Copy)]
+pub enum LoginLinks {
+    Table,
+    LinkId,
+    UserId,
+    ExpiryDate,
+}
+
+/// The last password files of each user.
+#[derive(DeriveIden, Clone, Copy)]
+pub enum PasswordHistory {
+    Table,
+    EntryId,
+    UserId,
+    PasswordFile,
+    CreationDate,
+}
+
+/// Corrupt password files, kept for forensics. There is no foreign key to the users.
+#[derive(DeriveIden, Clone, Copy)]
+pub enum QuarantinedPasswordFiles {
+    Table,
+    EntryId,
+    UserId,
+    PasswordFile,
+    Checksum,
+    Reason,
+    QuarantineDate,
+}
+
+/// Where and when the refresh tokens were issued.
+#[derive(DeriveIden, Clone, Copy)]
+pub enum UserSessions {
+    Table,
+    SessionId,
+    UserId,
+    CreationDate,
+    LastSeen,
+    ExpiryDate,
+    Device,
+    Source,
+}
+
+/// Authentication attempts, kept for investigations. There is no foreign key to the users, since
+/// attempts for unknown users are recorded too.
+#[derive(DeriveIden, Clone, Copy)]
+pub enum AuthEvents {
+    Table,
+    EventId,
+    UserId,
+    EventDate,
+    Source,
+    Success,
+    FailureReason,
+}
+
 // Metadata about the SQL DB.
 #[derive(DeriveIden)]
 pub enum Metadata {
@@ -96,6 +237,7 @@ pub enum Metadata {
     Version,
     PrivateKeyHash,
     PrivateKeyLocation,
+    ServerSetupFingerprint,
 }
 
 #[derive(FromQueryResult, PartialEq, Eq, Debug)]
@@ -1031,6 +1173,706 @@ async fn migrate_to_v8(transaction: DatabaseTransaction) -> Result<DatabaseTrans
     Ok(transaction)
 }
 
+async fn migrate_to_v9(transaction: DatabaseTransaction) -> Result<DatabaseTransaction, DbErr> {
+    let builder = transaction.get_database_backend();
+    // The original email, as entered, when the stored email is normalized.
+    transaction
+        .execute(
+            builder.build(
+                Table::alter()
+                    .table(Users::Table)
+                    .add_column(ColumnDef::new(Users::DisplayEmail).string_len(255)),
+            ),
+        )
+        .await?;
+    Ok(transaction)
+}
+
+async fn migrate_to_v10(transaction: DatabaseTransaction) -> Result<DatabaseTransaction, DbErr> {
+    let builder = transaction.get_database_backend();
+    transaction
+        .execute(
+            builder.build(
+                Table::create()
+                    .table(ThrottleCounters::Table)
+                    .if_not_exists()
+                    .col(
+                        ColumnDef::new(ThrottleCounters::ThrottleKey)
+                            .string_len(255)
+                            .not_null()
+                            .primary_key(),
+                    )
+                    .col(ColumnDef::new(ThrottleCounters::Count).integer().not_null())
+                    .col(
+                        ColumnDef::new(ThrottleCounters::ExpiryDate)
+                            .date_time()
+                            .not_null(),
+                    ),
+            ),
+        )
+        .await?;
+    Ok(transaction)
+}
+
+async fn migrate_to_v11(transaction: DatabaseTransaction) -> Result<DatabaseTransaction, DbErr> {
+    let builder = transaction.get_database_backend();
+    transaction
+        .execute(
+            builder.build(
+                Table::alter().table(Users::Table).add_column(
+                    ColumnDef::new(Users::LoginCount)
+                        .integer()
+                        .not_null()
+                        .default(0),
+                ),
+            ),
+        )
+        .await?;
+    transaction
+        .execute(
+            builder.build(
+                Table::alter()
+                    .table(Users::Table)
+                    .add_column(ColumnDef::new(Users::LastLoginDate).date_time()),
+            ),
+        )
+        .await?;
+    Ok(transaction)
+}
+
+async fn migrate_to_v12(transaction: DatabaseTransaction) -> Result<DatabaseTransaction, DbErr> {
+    let builder = transaction.get_database_backend();
+    transaction
+        .execute(
+            builder.build(
+                Table::create()
+                    .table(RegistrationStates::Table)
+                    .if_not_exists()
+                    .col(
+                        ColumnDef::new(RegistrationStates::Handle)
+                            .string_len(255)
+                            .not_null()
+                            .primary_key(),
+                    )
+                    .col(
+                        ColumnDef::new(RegistrationStates::ServerData)
+                            .binary()
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(RegistrationStates::ExpiryDate)
+                            .date_time()
+                            .not_null(),
+                    ),
+            ),
+        )
+        .await?;
+    Ok(transaction)
+}
+
+async fn migrate_to_v13(transaction: DatabaseTransaction) -> Result<DatabaseTransaction, DbErr> {
+    let builder = transaction.get_database_backend();
+    transaction
+        .execute(
+            builder.build(
+                Table::create()
+                    .table(AuthEvents::Table)
+                    .if_not_exists()
+                    .col(
+                        ColumnDef::new(AuthEvents::EventId)
+                            .integer()
+                            .auto_increment()
+                            .not_null()
+                            .primary_key(),
+                    )
+                    .col(
+                        ColumnDef::new(AuthEvents::UserId)
+                            .string_len(255)
+                            .not_null(),
+                    )
+                    .col(ColumnDef::new(AuthEvents::EventDate).date_time().not_null())
+                    .col(ColumnDef::new(AuthEvents::Source).string_len(64).not_null())
+                    .col(ColumnDef::new(AuthEvents::Success).boolean().not_null())
+                    .col(ColumnDef::new(AuthEvents::FailureReason).string_len(64)),
+            ),
+        )
+        .await?;
+    Ok(transaction)
+}
+
+async fn migrate_to_v14(transaction: DatabaseTransaction) -> Result<DatabaseTransaction, DbErr> {
+    let builder = transaction.get_database_backend();
+    transaction
+        .execute(
+            builder.build(
+                Table::alter().table(Users::Table).add_column(
+                    ColumnDef::new(Users::IsEnabled)
+                        .boolean()
+                        .not_null()
+                        .default(true),
+                ),
+            ),
+        )
+        .await?;
+    Ok(transaction)
+}
+
+async fn migrate_to_v15(transaction: DatabaseTransaction) -> Result<DatabaseTransaction, DbErr> {
+    let builder = transaction.get_database_backend();
+    transaction
+        .execute(
+            builder.build(
+                Table::alter()
+                    .table(Users::Table)
+                    .add_column(ColumnDef::new(Users::PasswordHashChecksum).binary()),
+            ),
+        )
+        .await?;
+    Ok(transaction)
+}
+
+async fn migrate_to_v16(transaction: DatabaseTransaction) -> Result<DatabaseTransaction, DbErr> {
+    let builder = transaction.get_database_backend();
+    transaction
+        .execute(
+            builder.build(
+                Table::alter()
+                    .table(RegistrationStates::Table)
+                    .add_column(ColumnDef::new(RegistrationStates::UserId).string_len(255)),
+            ),
+        )
+        .await?;
+    Ok(transaction)
+}
+
+async fn migrate_to_v17(transaction: DatabaseTransaction) -> Result<DatabaseTransaction, DbErr> {
+    let builder = transaction.get_database_backend();
+    transaction
+        .execute(
+            builder.build(
+                Table::create()
+                    .table(UserDevices::Table)
+                    .if_not_exists()
+                    .col(
+                        ColumnDef::new(UserDevices::UserId)
+                            .string_len(255)
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(UserDevices::FingerprintHash)
+                            .string_len(64)
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(UserDevices::FirstSeen)
+                            .date_time()
+                            .not_null(),
+                    )
+                    .col(ColumnDef::new(UserDevices::LastSeen).date_time().not_null())
+                    .foreign_key(
+                        ForeignKey::create()
+                            .name("UserDevicesUserIdForeignKey")
+                            .from(UserDevices::Table, UserDevices::UserId)
+                            .to(Users::Table, Users::UserId)
+                            .on_delete(ForeignKeyAction::Cascade)
+                            .on_update(ForeignKeyAction::Cascade),
+                    )
+                    .primary_key(
+                        Index::create()
+                            .col(UserDevices::UserId)
+                            .col(UserDevices::FingerprintHash),
+                    ),
+            ),
+        )
+        .await?;
+    Ok(transaction)
+}
+
+async fn migrate_to_v18(transaction: DatabaseTransaction) -> Result<DatabaseTransaction, DbErr> {
+    let builder = transaction.get_database_backend();
+    transaction
+        .execute(
+            builder.build(
+                Table::alter().table(Users::Table).add_column(
+                    ColumnDef::new(Users::MustChangePassword)
+                        .boolean()
+                        .not_null()
+                        .default(false),
+                ),
+            ),
+        )
+        .await?;
+    Ok(transaction)
+}
+
+async fn migrate_to_v19(transaction: DatabaseTransaction) -> Result<DatabaseTransaction, DbErr> {
+    let builder = transaction.get_database_backend();
+    transaction
+        .execute(
+            builder.build(
+                Table::alter()
+                    .table(Users::Table)
+                    .add_column(ColumnDef::new(Users::LegacyPasswordHash).string_len(255)),
+            ),
+        )
+        .await?;
+    Ok(transaction)
+}
+
+async fn migrate_to_v20(transaction: DatabaseTransaction) -> Result<DatabaseTransaction, DbErr> {
+    let builder = transaction.get_database_backend();
+    transaction
+        .execute(
+            builder.build(
+                Table::alter()
+                    .table(Users::Table)
+                    .add_column(ColumnDef::new(Users::PasswordChangedAt).date_time()),
+            ),
+        )
+        .await?;
+    Ok(transaction)
+}
+
+async fn migrate_to_v21(transaction: DatabaseTransaction) -> Result<DatabaseTransaction, DbErr> {
+    let builder = transaction.get_database_backend();
+    transaction
+        .execute(
+            builder.build(
+                Table::create()
+                    .table(LoginLinks::Table)
+                    .if_not_exists()
+                    .col(
+                        ColumnDef::new(LoginLinks::LinkId)
+                            .string_len(36)
+                            .not_null()
+                            .primary_key(),
+                    )
+                    .col(
+                        ColumnDef::new(LoginLinks::UserId)
+                            .string_len(255)
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(LoginLinks::ExpiryDate)
+                            .date_time()
+                            .not_null(),
+                    )
+                    .foreign_key(
+                        ForeignKey::create()
+                            .name("LoginLinksUserIdForeignKey")
+                            .from(LoginLinks::Table, LoginLinks::UserId)
+                            .to(Users::Table, Users::UserId)
+                            .on_delete(ForeignKeyAction::Cascade)
+                            .on_update(ForeignKeyAction::Cascade),
+                    ),
+            ),
+        )
+        .await?;
+    Ok(transaction)
+}
+
+async fn migrate_to_v22(transaction: DatabaseTransaction) -> Result<DatabaseTransaction, DbErr> {
+    let builder = transaction.get_database_backend();
+    transaction
+        .execute(
+            builder.build(
+                Table::alter().table(Users::Table).add_column(
+                    ColumnDef::new(Users::FailedLoginCount)
+                        .integer()
+                        .not_null()
+                        .default(0),
+                ),
+            ),
+        )
+        .await?;
+    transaction
+        .execute(
+            builder.build(
+                Table::alter()
+                    .table(Users::Table)
+                    .add_column(ColumnDef::new(Users::LockedUntil).date_time()),
+            ),
+        )
+        .await?;
+    Ok(transaction)
+}
+
+async fn migrate_to_v23(transaction: DatabaseTransaction) -> Result<DatabaseTransaction, DbErr> {
+    let builder = transaction.get_database_backend();
+    transaction
+        .execute(
+            builder.build(
+                Table::create()
+                    .table(PasswordHistory::Table)
+                    .if_not_exists()
+                    .col(
+                        ColumnDef::new(PasswordHistory::EntryId)
+                            .integer()
+                            .auto_increment()
+                            .not_null()
+                            .primary_key(),
+                    )
+                    .col(
+                        ColumnDef::new(PasswordHistory::UserId)
+                            .string_len(255)
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(PasswordHistory::PasswordFile)
+                            .binary()
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(PasswordHistory::CreationDate)
+                            .date_time()
+                            .not_null(),
+                    )
+                    .foreign_key(
+                        ForeignKey::create()
+                            .name("PasswordHistoryUserIdForeignKey")
+                            .from(PasswordHistory::Table, PasswordHistory::UserId)
+                            .to(Users::Table, Users::UserId)
+                            .on_delete(ForeignKeyAction::Cascade)
+                            .on_update(ForeignKeyAction::Cascade),
+                    ),
+            ),
+        )
+        .await?;
+    Ok(transaction)
+}
+
+async fn migrate_to_v24(transaction: DatabaseTransaction) -> Result<DatabaseTransaction, DbErr> {
+    let builder = transaction.get_database_backend();
+    transaction
+        .execute(
+            builder.build(
+                Table::create()
+                    .table(QuarantinedPasswordFiles::Table)
+                    .if_not_exists()
+                    .col(
+                        ColumnDef::new(QuarantinedPasswordFiles::EntryId)
+                            .integer()
+                            .auto_increment()
+                            .not_null()
+                            .primary_key(),
+                    )
+                    .col(
+                        ColumnDef::new(QuarantinedPasswordFiles::UserId)
+                            .string_len(255)
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(QuarantinedPasswordFiles::PasswordFile)
+                            .binary()
+                            .not_null(),
+                    )
+                    .col(ColumnDef::new(QuarantinedPasswordFiles::Checksum).binary())
+                    .col(
+                        ColumnDef::new(QuarantinedPasswordFiles::Reason)
+                            .string_len(64)
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(QuarantinedPasswordFiles::QuarantineDate)
+                            .date_time()
+                            .not_null(),
+                    ),
+            ),
+        )
+        .await?;
+    Ok(transaction)
+}
+
+async fn migrate_to_v25(transaction: DatabaseTransaction) -> Result<DatabaseTransaction, DbErr> {
+    let builder = transaction.get_database_backend();
+    transaction
+        .execute(
+            builder.build(
+                Table::alter()
+                    .table(Users::Table)
+                    .add_column(ColumnDef::new(Users::PasswordIdentity).string_len(255)),
+            ),
+        )
+        .await?;
+    Ok(transaction)
+}
+
+async fn migrate_to_v26(transaction: DatabaseTransaction) -> Result<DatabaseTransaction, DbErr> {
+    let builder = transaction.get_database_backend();
+    transaction
+        .execute(
+            builder.build(
+                Table::alter()
+                    .table(Users::Table)
+                    .add_column(ColumnDef::new(Users::PasswordGraceLoginsRemaining).integer()),
+            ),
+        )
+        .await?;
+    Ok(transaction)
+}
+
+async fn migrate_to_v27(transaction: DatabaseTransaction) -> Result<DatabaseTransaction, DbErr> {
+    let builder = transaction.get_database_backend();
+    transaction
+        .execute(
+            builder.build(
+                Table::create()
+                    .table(UserSessions::Table)
+                    .if_not_exists()
+                    .col(
+                        ColumnDef::new(UserSessions::SessionId)
+                            .big_integer()
+                            .not_null()
+                            .primary_key(),
+                    )
+                    .col(
+                        ColumnDef::new(UserSessions::UserId)
+                            .string_len(255)
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(UserSessions::CreationDate)
+                            .date_time()
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(UserSessions::LastSeen)
+                            .date_time()
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(UserSessions::ExpiryDate)
+                            .date_time()
+                            .not_null(),
+                    )
+                    .col(ColumnDef::new(UserSessions::Device).string_len(255))
+                    .col(ColumnDef::new(UserSessions::Source).string_len(255))
+                    .foreign_key(
+                        ForeignKey::create()
+                            .name("UserSessionsUserIdForeignKey")
+                            .from(UserSessions::Table, UserSessions::UserId)
+                            .to(Users::Table, Users::UserId)
+                            .on_delete(ForeignKeyAction::Cascade)
+                            .on_update(ForeignKeyAction::Cascade),
+                    ),
+            ),
+        )
+        .await?;
+    Ok(transaction)
+}
+
+async fn migrate_to_v28(transaction: DatabaseTransaction) -> Result<DatabaseTransaction, DbErr> {
+    let builder = transaction.get_database_backend();
+    transaction
+        .execute(
+            builder.build(
+                Table::alter().table(Users::Table).add_column(
+                    ColumnDef::new(Users::PasswordOverwriteAllowed)
+                        .boolean()
+                        .not_null()
+                        .default(false),
+                ),
+            ),
+        )
+        .await?;
+    Ok(transaction)
+}
+
+async fn migrate_to_v29(transaction: DatabaseTransaction) -> Result<DatabaseTransaction, DbErr> {
+    let builder = transaction.get_database_backend();
+    transaction
+        .execute(
+            builder.build(
+                Table::alter().table(Metadata::Table).add_column(
+                    ColumnDef::new(Metadata::ServerSetupFingerprint).blob(Blob(Some(32))),
+                ),
+            ),
+        )
+        .await?;
+    Ok(transaction)
+}
+
+async fn migrate_to_v30(transaction: DatabaseTransaction) -> Result<DatabaseTransaction, DbErr> {
+    let builder = transaction.get_database_backend();
+    transaction
+        .execute(
+            builder.build(
+                Table::create()
+                    .table(AuditLog::Table)
+                    .if_not_exists()
+                    .col(
+                        ColumnDef::new(AuditLog::EntryId)
+                            .integer()
+                            .auto_increment()
+                            .not_null()
+                            .primary_key(),
+                    )
+                    .col(ColumnDef::new(AuditLog::EventDate).date_time().not_null())
+                    .col(ColumnDef::new(AuditLog::Actor).string_len(255))
+                    .col(ColumnDef::new(AuditLog::Action).string_len(64).not_null())
+                    .col(ColumnDef::new(AuditLog::Target).string_len(255))
+                    .col(ColumnDef::new(AuditLog::SourceIp).string_len(64))
+                    .col(ColumnDef::new(AuditLog::Outcome).string_len(64).not_null())
+                    .col(ColumnDef::new(AuditLog::EntryHash).binary()),
+            ),
+        )
+        .await?;
+    Ok(transaction)
+}
+
+async fn migrate_to_v31(transaction: DatabaseTransaction) -> Result<DatabaseTransaction, DbErr> {
+    let builder = transaction.get_database_backend();
+    transaction
+        .execute(
+            builder.build(
+                Table::create()
+                    .table(PasswordPolicyCompliance::Table)
+                    .if_not_exists()
+                    .col(
+                        ColumnDef::new(PasswordPolicyCompliance::UserId)
+                            .string_len(255)
+                            .not_null()
+                            .primary_key(),
+                    )
+                    .col(
+                        ColumnDef::new(PasswordPolicyCompliance::Compliant)
+                            .boolean()
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(PasswordPolicyCompliance::CheckedDate)
+                            .date_time()
+                            .not_null(),
+                    )
+                    .foreign_key(
+                        ForeignKey::create()
+                            .name("PasswordPolicyComplianceUserIdForeignKey")
+                            .from(
+                                PasswordPolicyCompliance::Table,
+                                PasswordPolicyCompliance::UserId,
+                            )
+                            .to(Users::Table, Users::UserId)
+                            .on_delete(ForeignKeyAction::Cascade)
+                            .on_update(ForeignKeyAction::Cascade),
+                    ),
+            ),
+        )
+        .await?;
+    Ok(transaction)
+}
+
+async fn migrate_to_v32(transaction: DatabaseTransaction) -> Result<DatabaseTransaction, DbErr> {
+    let builder = transaction.get_database_backend();
+    transaction
+        .execute(
+            builder.build(
+                Table::create()
+                    .table(GroupMembershipsGroups::Table)
+                    .if_not_exists()
+                    .col(
+                        ColumnDef::new(GroupMembershipsGroups::ParentGroupId)
+                            .integer()
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(GroupMembershipsGroups::ChildGroupId)
+                            .integer()
+                            .not_null(),
+                    )
+                    .primary_key(
+                        Index::create()
+                            .col(GroupMembershipsGroups::ParentGroupId)
+                            .col(GroupMembershipsGroups::ChildGroupId),
+                    )
+                    .foreign_key(
+                        ForeignKey::create()
+                            .name("GroupMembershipsGroupsParentForeignKey")
+                            .from(
+                                GroupMembershipsGroups::Table,
+                                GroupMembershipsGroups::ParentGroupId,
+                            )
+                            .to(Groups::Table, Groups::GroupId)
+                            .on_delete(ForeignKeyAction::Cascade)
+                            .on_update(ForeignKeyAction::Cascade),
+                    )
+                    .foreign_key(
+                        ForeignKey::create()
+                            .name("GroupMembershipsGroupsChildForeignKey")
+                            .from(
+                                GroupMembershipsGroups::Table,
+                                GroupMembershipsGroups::ChildGroupId,
+                            )
+                            .to(Groups::Table, Groups::GroupId)
+                            .on_delete(ForeignKeyAction::Cascade)
+                            .on_update(ForeignKeyAction::Cascade),
+                    ),
+            ),
+        )
+        .await?;
+    Ok(transaction)
+}
+
+async fn migrate_to_v33(transaction: DatabaseTransaction) -> Result<DatabaseTransaction, DbErr> {
+    let builder = transaction.get_database_backend();
+    transaction
+        .execute(
+            builder.build(
+                Table::alter()
+                    .table(Users::Table)
+                    .add_column(ColumnDef::new(Users::DeletedAt).date_time()),
+            ),
+        )
+        .await?;
+    Ok(transaction)
+}
+
+async fn migrate_to_v34(transaction: DatabaseTransaction) -> Result<DatabaseTransaction, DbErr> {
+    let builder = transaction.get_database_backend();
+    transaction
+        .execute(
+            builder.build(
+                Table::create()
+                    .table(UserPasswordContexts::Table)
+                    .if_not_exists()
+                    .col(
+                        ColumnDef::new(UserPasswordContexts::UserId)
+                            .string_len(255)
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(UserPasswordContexts::Context)
+                            .string_len(64)
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(UserPasswordContexts::PasswordHash)
+                            .binary()
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(UserPasswordContexts::PasswordHashChecksum)
+                            .binary()
+                            .not_null(),
+                    )
+                    .primary_key(
+                        Index::create()
+                            .col(UserPasswordContexts::UserId)
+                            .col(UserPasswordContexts::Context),
+                    )
+                    .foreign_key(
+                        ForeignKey::create()
+                            .name("UserPasswordContextsUserIdForeignKey")
+                            .from(UserPasswordContexts::Table, UserPasswordContexts::UserId)
+                            .to(Users::Table, Users::UserId)
+                            .on_delete(ForeignKeyAction::Cascade)
+                            .on_update(ForeignKeyAction::Cascade),
+                    ),
+            ),
+        )
+        .await?;
+    Ok(transaction)
+}
+
 // This is needed to make an array of async functions.
 macro_rules! to_sync {
     ($l:ident) => {
@@ -1059,6 +1901,32 @@ pub async fn migrate_from_version(
         to_sync!(migrate_to_v6),
         to_sync!(migrate_to_v7),
         to_sync!(migrate_to_v8),
+        to_sync!(migrate_to_v9),
+        to_sync!(migrate_to_v10),
+        to_sync!(migrate_to_v11),
+        to_sync!(migrate_to_v12),
+        to_sync!(migrate_to_v13),
+        to_sync!(migrate_to_v14),
+        to_sync!(migrate_to_v15),
+        to_sync!(migrate_to_v16),
+        to_sync!(migrate_to_v17),
+        to_sync!(migrate_to_v18),
+        to_sync!(migrate_to_v19),
+        to_sync!(migrate_to_v20),
+        to_sync!(migrate_to_v21),
+        to_sync!(migrate_to_v22),
+        to_sync!(migrate_to_v23),
+        to_sync!(migrate_to_v24),
+        to_sync!(migrate_to_v25),
+        to_sync!(migrate_to_v26),
+        to_sync!(migrate_to_v27),
+        to_sync!(migrate_to_v28),
+        to_sync!(migrate_to_v29),
+        to_sync!(migrate_to_v30),
+        to_sync!(migrate_to_v31),
+        to_sync!(migrate_to_v32),
+        to_sync!(migrate_to_v33),
+        to_sync!(migrate_to_v34),
     ];
     assert_eq!(migrations.len(), (LAST_SCHEMA_VERSION.0 - 1) as usize);
     for migration in 2..=last_version.0 {
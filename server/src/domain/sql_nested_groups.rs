@@ -0,0 +1,233 @@
+use crate::domain::{
+    error::{DomainError, Result},
+    handler::UserBackendHandler,
+    model::{self, GroupColumn},
+    sql_backend_handler::SqlBackendHandler,
+    types::{GroupDetails, GroupId, UserAndGroups, UserId},
+};
+use sea_orm::{
+    ActiveModelTrait, ColumnTrait, ConnectionTrait, EntityTrait, QueryFilter, Set, TransactionTrait,
+};
+use std::collections::{HashMap, HashSet};
+use tracing::instrument;
+
+/// The parent groups of each nested group.
+async fn get_group_parents<C: ConnectionTrait>(
+    connection: &C,
+) -> Result<HashMap<GroupId, Vec<GroupId>>> {
+    let mut parents = HashMap::<GroupId, Vec<GroupId>>::new();
+    for membership in model::GroupMembershipsGroups::find()
+        .all(connection)
+        .await?
+    {
+        parents
+            .entry(membership.child_group_id)
+            .or_default()
+            .push(membership.parent_group_id);
+    }
+    Ok(parents)
+}
+
+/// All the groups that the given groups are direct or indirect members of.
+fn get_ancestors(
+    parents: &HashMap<GroupId, Vec<GroupId>>,
+    groups: impl IntoIterator<Item = GroupId>,
+) -> HashSet<GroupId> {
+    let mut ancestors = HashSet::new();
+    let mut to_visit: Vec<GroupId> = groups.into_iter().collect();
+    while let Some(group) = to_visit.pop() {
+        for parent in parents.get(&group).into_iter().flatten() {
+            // The cycles are refused when adding the memberships, but don't loop on them anyway.
+            if ancestors.insert(*parent) {
+                to_visit.push(*parent);
+            }
+        }
+    }
+    ancestors
+}
+
+impl SqlBackendHandler {
+    #[instrument(skip(self), level = "debug", err)]
+    pub(crate) async fn add_group_membership(
+        &self,
+        group_id: GroupId,
+        parent_group_id: GroupId,
+    ) -> Result<()> {
+        // In a transaction, so that two concurrent additions can't create a cycle.
+        self.sql_pool
+            .transaction::<_, (), DomainError>(|transaction| {
+                Box::pin(async move {
+                    let parents = get_group_parents(transaction).await?;
+                    if group_id == parent_group_id
+                        || get_ancestors(&parents, [parent_group_id]).contains(&group_id)
+                    {
+                        return Err(DomainError::GroupCycle(format!(
+                            "Group {} is already a member of group {}",
+                            parent_group_id.0, group_id.0
+                        )));
+                    }
+                    model::group_memberships_groups::ActiveModel {
+                        parent_group_id: Set(parent_group_id),
+                        child_group_id: Set(group_id),
+                    }
+                    .insert(transaction)
+                    .await?;
+                    Ok(())
+                })
+            })
+            .await?;
+        Ok(())
+    }
+
+    #[instrument(skip(self), level = "debug", err)]
+    pub(crate) async fn remove_group_membership(
+        &self,
+        group_id: GroupId,
+        parent_group_id: GroupId,
+    ) -> Result<()> {
+        let res = model::GroupMembershipsGroups::delete_by_id((parent_group_id, group_id))
+            .exec(&self.sql_pool)
+            .await?;
+        if res.rows_affected == 0 {
+            return Err(DomainError::EntityNotFound(format!(
+                "No such membership: {:?} -> {:?}",
+                group_id, parent_group_id
+            )));
+        }
+        Ok(())
+    }
+
+    #[instrument(skip(self), level = "debug", err)]
+    pub(crate) async fn get_user_effective_groups(
+        &self,
+        user_id: &UserId,
+    ) -> Result<HashSet<GroupDetails>> {
+        let mut groups = self.get_user_groups(user_id).await?;
+        let parents = get_group_parents(&self.sql_pool).await?;
+        let direct_groups: HashSet<GroupId> = groups.iter().map(|g| g.group_id).collect();
+        let inherited_groups = get_ancestors(&parents, direct_groups.iter().copied())
+            .into_iter()
+            .filter(|g| !direct_groups.contains(g));
+        groups.extend(self.get_groups_by_id(inherited_groups).await?.into_values());
+        Ok(groups)
+    }
+
+    /// Adds the groups that the users are indirect members of, through nested groups.
+    pub(crate) async fn add_inherited_groups(&self, users: &mut [UserAndGroups]) -> Result<()> {
+        let parents = get_group_parents(&self.sql_pool).await?;
+        if parents.is_empty() {
+            return Ok(());
+        }
+        let direct_groups = |user: &UserAndGroups| -> HashSet<GroupId> {
+            user.groups.iter().flatten().map(|g| g.group_id).collect()
+        };
+        let all_inherited_groups = users
+            .iter()
+            .flat_map(|u| get_ancestors(&parents, direct_groups(u)))
+            .collect::<HashSet<_>>();
+        let inherited_group_details = self.get_groups_by_id(all_inherited_groups).await?;
+        for user in users.iter_mut() {
+            let direct = direct_groups(user);
+            let groups = user.groups.get_or_insert_with(Vec::new);
+            groups.extend(
+                get_ancestors(&parents, direct.iter().copied())
+                    .into_iter()
+                    .filter(|g| !direct.contains(g))
+                    .filter_map(|g| inherited_group_details.get(&g).cloned()),
+            );
+            groups.sort_by(|g1, g2| g1.display_name.as_str().cmp(g2.display_name.as_str()));
+        }
+        Ok(())
+    }
+
+    async fn get_groups_by_id(
+        &self,
+        group_ids: impl IntoIterator<Item = GroupId>,
+    ) -> Result<HashMap<GroupId, GroupDetails>> {
+        Ok(model::Group::find()
+            .filter(GroupColumn::GroupId.is_in(group_ids))
+            .all(&self.sql_pool)
+            .await?
+            .into_iter()
+            .map(|g| (g.group_id, GroupDetails::from(g)))
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::{
+        handler::{BackendHandler, UserListerBackendHandler},
+        sql_backend_handler::tests::*,
+    };
+    use pretty_assertions::assert_eq;
+
+    async fn get_effective_group_names(handler: &SqlBackendHandler, user: &str) -> Vec<String> {
+        let mut groups = handler
+            .get_effective_groups(&UserId::new(user))
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|g| g.display_name.to_string())
+            .collect::<Vec<_>>();
+        groups.sort();
+        groups
+    }
+
+    #[tokio::test]
+    async fn test_three_level_nesting() {
+        let handler = SqlBackendHandler::new(get_default_config(), get_initialized_db().await);
+        insert_user_no_password(&handler, "bob").await;
+        let top = insert_group(&handler, "Top").await;
+        let middle = insert_group(&handler, "Middle").await;
+        let bottom = insert_group(&handler, "Bottom").await;
+        insert_membership(&handler, bottom, "bob").await;
+        handler.add_group_to_group(middle, top).await.unwrap();
+        handler.add_group_to_group(bottom, middle).await.unwrap();
+        assert_eq!(
+            get_effective_group_names(&handler, "bob").await,
+            vec!["Bottom", "Middle", "Top"]
+        );
+        // The memberOf attribute of LDAP comes from the listed groups.
+        let users = handler.list_users(None, true).await.unwrap();
+        assert_eq!(
+            users[0]
+                .groups
+                .iter()
+                .flatten()
+                .map(|g| g.display_name.to_string())
+                .collect::<Vec<_>>(),
+            vec!["Bottom", "Middle", "Top"]
+        );
+        handler.remove_group_from_group(middle, top).await.unwrap();
+        assert_eq!(
+            get_effective_group_names(&handler, "bob").await,
+            vec!["Bottom", "Middle"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_cycles_are_rejected() {
+        let handler = SqlBackendHandler::new(get_default_config(), get_initialized_db().await);
+        let a = insert_group(&handler, "A").await;
+        let b = insert_group(&handler, "B").await;
+        let c = insert_group(&handler, "C").await;
+        handler.add_group_to_group(b, a).await.unwrap();
+        assert!(matches!(
+            handler.add_group_to_group(a, b).await.unwrap_err(),
+            DomainError::GroupCycle(_)
+        ));
+        handler.add_group_to_group(c, b).await.unwrap();
+        assert!(matches!(
+            handler.add_group_to_group(a, c).await.unwrap_err(),
+            DomainError::GroupCycle(_)
+        ));
+        assert!(matches!(
+            handler.add_group_to_group(a, a).await.unwrap_err(),
+            DomainError::GroupCycle(_)
+        ));
+        // Not a cycle: A is a parent of C through two paths.
+        handler.add_group_to_group(c, a).await.unwrap();
+    }
+}
@@ -1,23 +1,71 @@
 use super::{
+    correlation::correlation_id,
     error::{DomainError, Result},
-    handler::{BindRequest, LoginHandler},
-    model::{self, UserColumn},
-    opaque_handler::{login, registration, OpaqueHandler},
+    handler::{
+        AuditAction, BackendHandler, BindRequest, LoginFailureReason, LoginHandler, LoginSource,
+        PasswordFileAudit, UserBackendHandler,
+    },
+    model::{
+        self, GroupColumn, LoginLinksColumn, PasswordHistoryColumn, QuarantinedPasswordFilesColumn,
+        UserColumn, UserPasswordContextsColumn,
+    },
+    opaque_handler::{login, registration, OpaqueHandler, SuccessfulLogin},
     sql_backend_handler::SqlBackendHandler,
+    throttle::ThrottleCounter,
+    totp,
     types::UserId,
 };
+use crate::infra::metrics;
 use async_trait::async_trait;
 use base64::Engine;
-use lldap_auth::opaque;
-use sea_orm::{ActiveModelTrait, ActiveValue, EntityTrait, QuerySelect};
+use lldap_auth::{opaque, password_policy::PasswordPolicy};
+use sea_orm::{
+    sea_query::{Expr, OnConflict},
+    ActiveModelTrait, ActiveValue, ColumnTrait, Condition, EntityTrait, ModelTrait, PaginatorTrait,
+    QueryFilter, QueryOrder, QuerySelect, TransactionTrait,
+};
 use secstr::SecUtf8;
-use tracing::{debug, instrument};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::net::IpAddr;
+use tracing::{debug, error, info, instrument, warn};
 
 type SqlOpaqueHandler = SqlBackendHandler;
 
+type HmacSha256 = hmac::Hmac<sha2::Sha256>;
+
+const LOGIN_CHALLENGE_PURPOSE: &str = "lldap_login_challenge";
+/// The target of the log events for logins from new devices, to filter or forward them.
+pub const NEW_DEVICE_EVENT_TARGET: &str = "lldap::new_device";
+/// The target of the log events for requests that were likely tampered with.
+pub const TAMPERING_EVENT_TARGET: &str = "lldap::tampering";
+const PASSWORD_FILE_CHECKSUM_PURPOSE: &str = "lldap_password_file_checksum";
+const ADMIN_GROUP: &str = "lldap_admin";
+const LOGIN_LINK_PURPOSE: &str = "lldap_login_link";
+
+/// Sealed with the server key, so that the server doesn't need to keep track of the challenges
+/// it issued. Only the used nonces are remembered, until the challenge expires.
+#[derive(Serialize, Deserialize)]
+struct LoginChallenge {
+    purpose: String,
+    nonce: String,
+    expiry: i64,
+}
+
+/// Sealed with the server key. The link ID is also kept in the database until the link is used,
+/// so that it works only once.
+#[derive(Serialize, Deserialize)]
+struct LoginLink {
+    purpose: String,
+    link_id: String,
+    user_id: UserId,
+    expiry: i64,
+}
+
+/// Without a password file, the exchange runs against a dummy one and never matches: it takes as
+/// long as a real one.
 #[instrument(skip_all, level = "debug", err, fields(username = %username.as_str()))]
 fn passwords_match(
-    password_file_bytes: &[u8],
+    password_file_bytes: Option<&[u8]>,
     clear_password: &str,
     server_setup: &opaque::server::ServerSetup,
     username: &UserId,
@@ -26,12 +74,14 @@ fn passwords_match(
     let mut rng = rand::rngs::OsRng;
     let client_login_start_result = client::login::start_login(clear_password, &mut rng)?;
 
-    let password_file = server::ServerRegistration::deserialize(password_file_bytes)
+    let password_file = password_file_bytes
+        .map(server::ServerRegistration::deserialize)
+        .transpose()
         .map_err(opaque::AuthenticationError::ProtocolError)?;
     let server_login_start_result = server::login::start_login(
         &mut rng,
         server_setup,
-        Some(password_file),
+        password_file,
         client_login_start_result.message,
         username,
     )?;
@@ -42,292 +92,5440 @@ fn passwords_match(
     Ok(())
 }
 
+fn failed_login_key(user_id: &UserId) -> String {
+    format!("failed_login:{}", user_id)
+}
+
+fn failed_login_source_key(source: &IpAddr) -> String {
+    format!("failed_login_source:{}", source)
+}
+
+// The addresses never contain a '/', so the keys can't collide.
+fn failed_login_pair_key(user_id: &UserId, source: &IpAddr) -> String {
+    format!("failed_login_pair:{}/{}", source, user_id)
+}
+
+fn login_challenge_key(nonce: &str) -> String {
+    format!("login_challenge:{}", nonce)
+}
+
+fn registration_key(source: &IpAddr) -> String {
+    format!("registration:{}", source)
+}
+
+/// Trace span around the OPAQUE computations, to debug mismatches between client and server
+/// builds.
+fn opaque_span() -> tracing::Span {
+    tracing::trace_span!(
+        "opaque",
+        ciphersuite = opaque::CIPHERSUITE_NAME,
+        version = opaque::PROTOCOL_VERSION
+    )
+}
+
+/// Why a login used a dummy password file. This is only for the logs and traces: the client
+/// gets the same answer as for a real one, to prevent user enumeration.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, strum::IntoStaticStr)]
+#[strum(serialize_all = "snake_case")]
+enum DummyPasswordFileReason {
+    UnknownUser,
+    DisabledUser,
+    NoPassword,
+    /// The password file can't be deserialized: it's treated as missing.
+    CorruptPasswordFile,
+    /// The password file was corrupt, and was just moved to the quarantine.
+    QuarantinedPasswordFile,
+}
+
+fn hash_password_file(password_file: &[u8]) -> Vec<u8> {
+    use sha2::{Digest, Sha256};
+    Sha256::digest(password_file).to_vec()
+}
+
 impl SqlBackendHandler {
-    fn get_orion_secret_key(&self) -> Result<orion::aead::SecretKey> {
+    pub(crate) fn get_orion_secret_key(&self) -> Result<orion::aead::SecretKey> {
         Ok(orion::aead::SecretKey::from_slice(
             self.config.get_server_keys().private(),
         )?)
     }
 
-    #[instrument(skip(self), level = "debug", err)]
-    async fn get_password_file_for_user(&self, user_id: UserId) -> Result<Option<Vec<u8>>> {
-        // Fetch the previously registered password file from the DB.
-        Ok(model::User::find_by_id(user_id)
+    /// Keyed with the server key, so that the password files can't be modified directly in the
+    /// database. The user ID is included so that they can't be swapped between users either.
+    fn password_file_mac(&self, user_id: &UserId, password_file: &[u8]) -> HmacSha256 {
+        use hmac::Mac;
+        let mut mac = HmacSha256::new_from_slice(self.config.get_server_keys().private())
+            .expect("HMAC accepts keys of any size");
+        mac.update(PASSWORD_FILE_CHECKSUM_PURPOSE.as_bytes());
+        mac.update(user_id.as_str().as_bytes());
+        mac.update(&[0]);
+        mac.update(password_file);
+        mac
+    }
+
+    pub(crate) fn password_file_checksum(&self, user_id: &UserId, password_file: &[u8]) -> Vec<u8> {
+        use hmac::Mac;
+        self.password_file_mac(user_id, password_file)
+            .finalize()
+            .into_bytes()
+            .to_vec()
+    }
+
+    pub(crate) fn audit_password_file(
+        &self,
+        user_id: UserId,
+        password_file: &[u8],
+        checksum: Option<&[u8]>,
+    ) -> PasswordFileAudit {
+        use hmac::Mac;
+        let checksum_matches = checksum.map(|checksum| {
+            self.password_file_mac(&user_id, password_file)
+                .verify_slice(checksum)
+                .is_ok()
+        });
+        let (deserializes, compatible_with_setup) =
+            match opaque::server::ServerRegistration::deserialize(password_file) {
+                Ok(password_file) => (
+                    true,
+                    can_start_login(self.config.get_server_setup(), &user_id, password_file),
+                ),
+                Err(_) => (false, false),
+            };
+        PasswordFileAudit {
+            user_id,
+            deserializes,
+            checksum_matches,
+            compatible_with_setup,
+        }
+    }
+
+    /// The identity a password file is bound to in the OPAQUE exchanges: the user ID, qualified by
+    /// the context if there is one. The NUL separator keeps it apart from the user IDs.
+    fn opaque_identity(&self, user_id: &UserId, context: Option<&str>) -> UserId {
+        match context {
+            Some(context) => UserId::new(&format!("{}\u{0}{}", user_id.as_str(), context)),
+            None => user_id.clone(),
+        }
+    }
+
+    fn check_password_context(&self, context: Option<&str>) -> Result<()> {
+        match context {
+            Some(context) if !self.config.password_contexts.iter().any(|c| c == context) => Err(
+                DomainError::EntityNotFound(format!("Unknown password context '{}'", context)),
+            ),
+            _ => Ok(()),
+        }
+    }
+
+    /// The identity to log in with the password file: the one it was registered under, which is
+    /// stored with the primary passwords transferred from another user.
+    async fn login_identity(&self, user_id: &UserId, context: Option<&str>) -> Result<UserId> {
+        if context.is_some() {
+            return Ok(self.opaque_identity(user_id, context));
+        }
+        Ok(model::User::find_by_id(user_id.clone())
             .select_only()
-            .column(UserColumn::PasswordHash)
-            .into_tuple::<(Option<Vec<u8>>,)>()
+            .column(UserColumn::PasswordIdentity)
+            .into_tuple::<Option<UserId>>()
             .one(&self.sql_pool)
             .await?
-            .and_then(|u| u.0))
+            .flatten()
+            .unwrap_or_else(|| user_id.clone()))
     }
-}
 
-#[async_trait]
-impl LoginHandler for SqlBackendHandler {
-    #[instrument(skip_all, level = "debug", err)]
-    async fn bind(&self, request: BindRequest) -> Result<()> {
-        if let Some(password_hash) = self
-            .get_password_file_for_user(request.name.clone())
-            .await?
-        {
-            if let Err(e) = passwords_match(
-                &password_hash,
-                &request.password,
-                self.config.get_server_setup(),
-                &request.name,
-            ) {
-                debug!(r#"Invalid password for "{}": {}"#, &request.name, e);
-            } else {
-                return Ok(());
+    /// Decrypts the server_data sent back by the client. Any failure is the client's fault.
+    /// During the grace window after `rotate_keys`, the data sealed with the previous key still
+    /// opens, so that the logins in flight during the restart get a meaningful error.
+    fn open_server_data<T: DeserializeOwned>(&self, server_data: &str) -> Result<T> {
+        let secret_key = self.get_orion_secret_key()?;
+        let sealed = base64::engine::general_purpose::STANDARD
+            .decode(server_data)
+            .map_err(|e| DomainError::InvalidServerData(format!("Invalid base64: {}", e)))?;
+        let opened = orion::aead::open(&secret_key, &sealed)
+            .or_else(|e| match &self.config.previous_server_setup {
+                Some(previous) => orion::aead::open(
+                    &orion::aead::SecretKey::from_slice(previous.keypair().private())?,
+                    &sealed,
+                ),
+                None => Err(e),
+            })
+            .map_err(|_| {
+                DomainError::InvalidServerData("Could not decrypt the server data".to_owned())
+            })?;
+        bincode::deserialize(&opened)
+            .map_err(|e| DomainError::InvalidServerData(format!("Invalid contents: {}", e)))
+    }
+
+    /// Stores the registration state in the database, and returns a handle to it.
+    async fn store_registration_state(
+        &self,
+        user_id: &UserId,
+        server_data: Vec<u8>,
+    ) -> Result<String> {
+        use model::RegistrationStatesColumn;
+        use rand::{distributions::Alphanumeric, Rng};
+        let now = chrono::Utc::now().naive_utc();
+        let max_outstanding = self.config.max_outstanding_registrations_per_user;
+        if max_outstanding > 0 {
+            // The cleaner removes the expired states eventually, don't count them in the meantime.
+            model::RegistrationStates::delete_many()
+                .filter(RegistrationStatesColumn::UserId.eq(user_id))
+                .filter(RegistrationStatesColumn::ExpiryDate.lt(now))
+                .exec(&self.sql_pool)
+                .await?;
+            let outstanding = model::RegistrationStates::find()
+                .filter(RegistrationStatesColumn::UserId.eq(user_id))
+                .count(&self.sql_pool)
+                .await?;
+            if outstanding >= max_outstanding {
+                return Err(DomainError::TooManyOutstandingRegistrations(format!(
+                    "User '{}' has too many registrations in progress",
+                    user_id
+                )));
             }
-        } else {
-            debug!(
-                r#"User "{}" doesn't exist or has no password"#,
-                &request.name
-            );
         }
-        Err(DomainError::AuthenticationError(format!(
-            " for user '{}'",
-            request.name
-        )))
+        let handle: String = rand::rngs::OsRng
+            .sample_iter(&Alphanumeric)
+            .take(32)
+            .map(char::from)
+            .collect();
+        model::registration_states::ActiveModel {
+            handle: ActiveValue::Set(handle.clone()),
+            server_data: ActiveValue::Set(server_data),
+            expiry_date: ActiveValue::Set(
+                now + chrono::Duration::seconds(
+                    self.config.registration_handle_validity_seconds as i64,
+                ),
+            ),
+            user_id: ActiveValue::Set(Some(user_id.clone())),
+        }
+        .insert(&self.sql_pool)
+        .await?;
+        Ok(handle)
     }
-}
 
-#[async_trait]
-impl OpaqueHandler for SqlOpaqueHandler {
-    #[instrument(skip_all, level = "debug", err)]
-    async fn login_start(
-        &self,
-        request: login::ClientLoginStartRequest,
-    ) -> Result<login::ServerLoginStartResponse> {
-        let user_id = request.username;
-        let maybe_password_file = self
-            .get_password_file_for_user(user_id.clone())
+    /// Fetches and removes the registration state referenced by the handle.
+    async fn take_registration_state<T: DeserializeOwned>(&self, handle: &str) -> Result<T> {
+        let unknown_handle =
+            || DomainError::InvalidServerData("Unknown or expired registration handle".to_owned());
+        let state = model::RegistrationStates::find_by_id(handle.to_owned())
+            .one(&self.sql_pool)
             .await?
-            .map(|bytes| {
-                opaque::server::ServerRegistration::deserialize(&bytes).map_err(|_| {
-                    DomainError::InternalError(format!("Corrupted password file for {}", &user_id))
-                })
-            })
-            .transpose()?;
+            .ok_or_else(unknown_handle)?;
+        // Only the caller that actually deletes the state can use it, so each handle is used once.
+        let deleted = model::RegistrationStates::delete_by_id(handle.to_owned())
+            .exec(&self.sql_pool)
+            .await?;
+        if deleted.rows_affected == 0 || state.expiry_date < chrono::Utc::now().naive_utc() {
+            return Err(unknown_handle());
+        }
+        bincode::deserialize(&state.server_data)
+            .map_err(|e| DomainError::InvalidServerData(format!("Invalid contents: {}", e)))
+    }
 
-        let mut rng = rand::rngs::OsRng;
-        // Get the CredentialResponse for the user, or a dummy one if no user/no password.
-        let start_response = opaque::server::login::start_login(
-            &mut rng,
-            self.config.get_server_setup(),
-            maybe_password_file,
-            request.login_start_request,
-            &user_id,
-        )?;
-        let secret_key = self.get_orion_secret_key()?;
-        let server_data = login::ServerData {
-            username: user_id,
-            server_login: start_response.state,
+    fn seal_login_challenge(&self, expiry: i64) -> Result<String> {
+        let challenge = LoginChallenge {
+            purpose: LOGIN_CHALLENGE_PURPOSE.to_owned(),
+            nonce: base64::engine::general_purpose::STANDARD.encode(rand::random::<[u8; 16]>()),
+            expiry,
         };
-        let encrypted_state = orion::aead::seal(&secret_key, &bincode::serialize(&server_data)?)?;
-
-        Ok(login::ServerLoginStartResponse {
-            server_data: base64::engine::general_purpose::STANDARD.encode(encrypted_state),
-            credential_response: start_response.message,
-        })
+        let sealed = orion::aead::seal(
+            &self.get_orion_secret_key()?,
+            &bincode::serialize(&challenge)?,
+        )?;
+        Ok(base64::engine::general_purpose::STANDARD.encode(sealed))
     }
 
-    #[instrument(skip_all, level = "debug", err)]
-    async fn login_finish(&self, request: login::ClientLoginFinishRequest) -> Result<UserId> {
+    /// Cheap check, done before any OPAQUE work. Each challenge works once.
+    async fn check_login_challenge(&self, challenge: Option<&str>) -> Result<()> {
+        if !self.config.enable_login_challenge {
+            return Ok(());
+        }
+        let invalid_challenge =
+            || DomainError::AuthenticationError("Missing or invalid login challenge".to_owned());
         let secret_key = self.get_orion_secret_key()?;
-        let login::ServerData {
-            username,
-            server_login,
-        } = bincode::deserialize(&orion::aead::open(
-            &secret_key,
-            &base64::engine::general_purpose::STANDARD.decode(&request.server_data)?,
-        )?)?;
-        // Finish the login: this makes sure the client data is correct, and gives a session key we
-        // don't need.
-        let _session_key =
-            opaque::server::login::finish_login(server_login, request.credential_finalization)?
-                .session_key;
+        let challenge: LoginChallenge = challenge
+            .and_then(|c| base64::engine::general_purpose::STANDARD.decode(c).ok())
+            .and_then(|sealed| orion::aead::open(&secret_key, &sealed).ok())
+            .and_then(|bytes| bincode::deserialize(&bytes).ok())
+            .ok_or_else(invalid_challenge)?;
+        let now = chrono::Utc::now().timestamp();
+        if challenge.purpose != LOGIN_CHALLENGE_PURPOSE || challenge.expiry < now {
+            return Err(invalid_challenge());
+        }
+        // The counter lives as long as the challenge: after that, the expiry check rejects it.
+        let uses = self
+            .throttle_store
+            .increment(
+                &login_challenge_key(&challenge.nonce),
+                chrono::Duration::seconds(challenge.expiry - now + 1),
+            )
+            .await?;
+        if uses.count > 1 {
+            debug!("Login challenge replayed");
+            return Err(invalid_challenge());
+        }
+        Ok(())
+    }
 
-        Ok(username)
+    /// The counters of failed logins that apply to the attempt, with their limit: per user, per
+    /// address and per user and address. The ones based on the address only apply when it's
+    /// known.
+    fn failed_login_limits(&self, user_id: &UserId, address: Option<IpAddr>) -> Vec<(String, u32)> {
+        let options = &self.config.throttle_options;
+        let mut limits = vec![(failed_login_key(user_id), options.max_failed_logins)];
+        if let Some(address) = address {
+            limits.push((
+                failed_login_source_key(&address),
+                options.max_failed_logins_per_source,
+            ));
+            limits.push((
+                failed_login_pair_key(user_id, &address),
+                options.max_failed_logins_per_user_and_source,
+            ));
+        }
+        limits.retain(|(_, limit)| *limit > 0);
+        limits
     }
 
-    #[instrument(skip_all, level = "debug", err)]
-    async fn registration_start(
+    /// Refuses the login when any of the limits is reached.
+    async fn check_login_throttle(
         &self,
-        request: registration::ClientRegistrationStartRequest,
-    ) -> Result<registration::ServerRegistrationStartResponse> {
-        // Generate the server-side key and derive the data to send back.
-        let start_response = opaque::server::registration::start_registration(
-            self.config.get_server_setup(),
-            request.registration_start_request,
-            &request.username,
-        )?;
-        let secret_key = self.get_orion_secret_key()?;
-        let server_data = registration::ServerData {
-            username: request.username,
-        };
-        let encrypted_state = orion::aead::seal(&secret_key, &bincode::serialize(&server_data)?)?;
-        Ok(registration::ServerRegistrationStartResponse {
-            server_data: base64::engine::general_purpose::STANDARD.encode(encrypted_state),
-            registration_response: start_response.message,
-        })
+        user_id: &UserId,
+        source: LoginSource,
+        address: Option<IpAddr>,
+    ) -> Result<()> {
+        for (key, limit) in self.failed_login_limits(user_id, address) {
+            match self.throttle_store.get(&key).await? {
+                Some(counter) if counter.count >= limit => {
+                    self.record_auth_event(user_id, source, Some(LoginFailureReason::RateLimited))
+                        .await?;
+                    return Err(DomainError::RateLimited(
+                        match address {
+                            Some(address) => format!(
+                                "Too many failed logins for user '{}' from {}",
+                                user_id, address
+                            ),
+                            None => format!("Too many failed logins for user '{}'", user_id),
+                        },
+                        self.retry_after(&counter),
+                    ));
+                }
+                _ => {}
+            }
+        }
+        Ok(())
     }
 
-    #[instrument(skip_all, level = "debug", err)]
-    async fn registration_finish(
+    async fn record_failed_login(
         &self,
-        request: registration::ClientRegistrationFinishRequest,
+        user_id: &UserId,
+        source: LoginSource,
+        address: Option<IpAddr>,
+        reason: LoginFailureReason,
     ) -> Result<()> {
-        let secret_key = self.get_orion_secret_key()?;
-        let registration::ServerData { username } = bincode::deserialize(&orion::aead::open(
-            &secret_key,
-            &base64::engine::general_purpose::STANDARD.decode(&request.server_data)?,
-        )?)?;
+        self.record_auth_event(user_id, source, Some(reason))
+            .await?;
+        if reason == LoginFailureReason::InvalidPassword {
+            self.record_wrong_password(user_id).await?;
+        }
+        for (key, _) in self.failed_login_limits(user_id, address) {
+            self.throttle_store
+                .increment(
+                    &key,
+                    chrono::Duration::seconds(self.config.throttle_options.window_seconds as i64),
+                )
+                .await?;
+        }
+        Ok(())
+    }
 
-        let password_file =
-            opaque::server::registration::get_password_file(request.registration_upload);
-        // Set the user password to the new password.
-        let user_update = model::users::ActiveModel {
-            user_id: ActiveValue::Set(username),
-            password_hash: ActiveValue::Set(Some(password_file.serialize())),
-            ..Default::default()
+    fn is_account_lockout_enabled(&self) -> bool {
+        self.config.account_lockout_threshold > 0
+    }
+
+    /// Whether the account is locked after too many wrong passwords. The lock lifts by itself at
+    /// the end of the window.
+    async fn is_account_locked(&self, user_id: &UserId) -> Result<bool> {
+        if !self.is_account_lockout_enabled() {
+            return Ok(false);
+        }
+        let locked_until = model::User::find_by_id(user_id.clone())
+            .select_only()
+            .column(UserColumn::LockedUntil)
+            .into_tuple::<Option<chrono::NaiveDateTime>>()
+            .one(&self.sql_pool)
+            .await?
+            .flatten();
+        Ok(locked_until.map_or(false, |locked_until| {
+            locked_until > chrono::Utc::now().naive_utc()
+        }))
+    }
+
+    /// Counts a wrong password, and locks the account when the count reaches the threshold.
+    async fn record_wrong_password(&self, user_id: &UserId) -> Result<()> {
+        if !self.is_account_lockout_enabled() {
+            return Ok(());
+        }
+        // Incremented in the database, so that concurrent attempts are all counted.
+        model::User::update_many()
+            .col_expr(
+                UserColumn::FailedLoginCount,
+                Expr::col(UserColumn::FailedLoginCount).add(1),
+            )
+            .filter(UserColumn::UserId.eq(user_id))
+            .exec(&self.sql_pool)
+            .await?;
+        let locked_until = chrono::Utc::now().naive_utc()
+            + chrono::Duration::seconds(self.config.account_lockout_window_seconds as i64);
+        let locked = model::User::update_many()
+            .col_expr(UserColumn::FailedLoginCount, Expr::value(0))
+            .col_expr(UserColumn::LockedUntil, Expr::value(locked_until))
+            .filter(UserColumn::UserId.eq(user_id))
+            .filter(UserColumn::FailedLoginCount.gte(self.config.account_lockout_threshold as i32))
+            .exec(&self.sql_pool)
+            .await?;
+        if locked.rows_affected > 0 {
+            warn!(
+                r#"Locked the account of "{}" until {} after {} wrong passwords"#,
+                user_id, locked_until, self.config.account_lockout_threshold
+            );
+        }
+        Ok(())
+    }
+
+    /// Counts every registration, successful or not.
+    async fn check_registration_throttle(&self, source: Option<IpAddr>) -> Result<()> {
+        let max_registrations = self.config.throttle_options.max_registrations;
+        let source = match source {
+            Some(source) if max_registrations > 0 => source,
+            _ => return Ok(()),
         };
-        user_update.update(&self.sql_pool).await?;
+        let counter = self
+            .throttle_store
+            .increment(
+                &registration_key(&source),
+                chrono::Duration::seconds(self.config.throttle_options.window_seconds as i64),
+            )
+            .await?;
+        if counter.count > max_registrations {
+            return Err(DomainError::RateLimited(
+                format!("Too many password registrations from {}", source),
+                self.retry_after(&counter),
+            ));
+        }
         Ok(())
     }
-}
 
-/// Convenience function to set a user's password.
-#[instrument(skip_all, level = "debug", err, fields(username = %username.as_str()))]
-pub(crate) async fn register_password(
-    opaque_handler: &SqlOpaqueHandler,
-    username: UserId,
-    password: &SecUtf8,
-) -> Result<()> {
-    let mut rng = rand::rngs::OsRng;
-    use registration::*;
-    let registration_start =
-        opaque::client::registration::start_registration(password.unsecure().as_bytes(), &mut rng)?;
-    let start_response = opaque_handler
-        .registration_start(ClientRegistrationStartRequest {
-            username,
-            registration_start_request: registration_start.message,
-        })
-        .await?;
-    let registration_finish = opaque::client::registration::finish_registration(
-        registration_start.state,
-        start_response.registration_response,
-        &mut rng,
-    )?;
-    opaque_handler
-        .registration_finish(ClientRegistrationFinishRequest {
-            server_data: start_response.server_data,
-            registration_upload: registration_finish.message,
-        })
-        .await
-}
+    /// When the counter expires, rounded up to the second, if the clients may know.
+    fn retry_after(&self, counter: &ThrottleCounter) -> Option<std::time::Duration> {
+        if !self.config.throttle_options.send_retry_after {
+            return None;
+        }
+        let remaining = (counter.expiry_date - chrono::Utc::now().naive_utc())
+            .to_std()
+            .unwrap_or_default();
+        let seconds = remaining.as_secs() + u64::from(remaining.subsec_nanos() > 0);
+        Some(std::time::Duration::from_secs(seconds.max(1)))
+    }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::domain::sql_backend_handler::tests::*;
+    pub(crate) async fn must_change_password(&self, user_id: &UserId) -> Result<bool> {
+        Ok(model::User::find_by_id(user_id.clone())
+            .select_only()
+            .column(UserColumn::MustChangePassword)
+            .into_tuple::<bool>()
+            .one(&self.sql_pool)
+            .await?
+            .unwrap_or(false))
+    }
 
-    async fn attempt_login(
-        opaque_handler: &SqlOpaqueHandler,
-        username: &str,
-        password: &str,
+    pub(crate) async fn set_must_change_password(
+        &self,
+        user_id: &UserId,
+        value: bool,
     ) -> Result<()> {
-        let mut rng = rand::rngs::OsRng;
-        use login::*;
-        let login_start = opaque::client::login::start_login(password, &mut rng)?;
-        let start_response = opaque_handler
-            .login_start(ClientLoginStartRequest {
-                username: UserId::new(username),
-                login_start_request: login_start.message,
-            })
+        model::User::update_many()
+            .col_expr(UserColumn::MustChangePassword, Expr::value(value))
+            .filter(UserColumn::UserId.eq(user_id))
+            .exec(&self.sql_pool)
             .await?;
-        let login_finish = opaque::client::login::finish_login(
-            login_start.state,
-            start_response.credential_response,
-        )?;
-        opaque_handler
-            .login_finish(ClientLoginFinishRequest {
-                server_data: start_response.server_data,
-                credential_finalization: login_finish.message,
-            })
+        Ok(())
+    }
+
+    /// Once the password has expired, the user only has a few grace logins left to change it.
+    async fn check_password_expiry(&self, user_id: &UserId) -> Result<()> {
+        if self.config.password_expiry_days == 0 {
+            return Ok(());
+        }
+        let (changed_at, grace_logins_remaining) = match model::User::find_by_id(user_id.clone())
+            .select_only()
+            .column(UserColumn::PasswordChangedAt)
+            .column(UserColumn::PasswordGraceLoginsRemaining)
+            .into_tuple::<(Option<chrono::NaiveDateTime>, Option<i32>)>()
+            .one(&self.sql_pool)
+            .await?
+        {
+            Some((Some(changed_at), grace_logins_remaining)) => {
+                (changed_at, grace_logins_remaining)
+            }
+            _ => return Ok(()),
+        };
+        if changed_at + chrono::Duration::days(self.config.password_expiry_days as i64)
+            > chrono::Utc::now().naive_utc()
+        {
+            return Ok(());
+        }
+        let grace_logins_remaining =
+            grace_logins_remaining.unwrap_or(self.config.password_expiry_grace_logins as i32);
+        if grace_logins_remaining <= 0 {
+            return Err(DomainError::PasswordExpired(format!(
+                "The password of user '{}' expired",
+                user_id
+            )));
+        }
+        model::User::update_many()
+            .col_expr(
+                UserColumn::PasswordGraceLoginsRemaining,
+                Expr::value(grace_logins_remaining - 1),
+            )
+            .filter(UserColumn::UserId.eq(user_id))
+            .exec(&self.sql_pool)
             .await?;
+        info!(
+            r#"The password of "{}" expired, {} grace logins left"#,
+            user_id,
+            grace_logins_remaining - 1
+        );
         Ok(())
     }
 
-    #[tokio::test]
-    async fn test_opaque_flow() -> Result<()> {
-        let sql_pool = get_initialized_db().await;
-        crate::infra::logging::init_for_tests();
-        let config = get_default_config();
-        let backend_handler = SqlBackendHandler::new(config.clone(), sql_pool.clone());
-        let opaque_handler = SqlOpaqueHandler::new(config, sql_pool);
-        insert_user_no_password(&backend_handler, "bob").await;
-        insert_user_no_password(&backend_handler, "john").await;
-        attempt_login(&opaque_handler, "bob", "bob00")
-            .await
-            .unwrap_err();
-        register_password(
-            &opaque_handler,
-            UserId::new("bob"),
-            &secstr::SecUtf8::from("bob00"),
-        )
+    /// Remembers the device, and returns whether it's the first time the user logs in from it.
+    async fn record_device(&self, user_id: &UserId, fingerprint: &str) -> Result<bool> {
+        use model::UserDevicesColumn;
+        let fingerprint_hash = {
+            use sha2::{Digest, Sha256};
+            Sha256::digest(fingerprint.as_bytes())
+                .iter()
+                .map(|b| format!("{:02x}", b))
+                .collect::<String>()
+        };
+        let now = chrono::Utc::now().naive_utc();
+        let updated = model::UserDevices::update_many()
+            .col_expr(UserDevicesColumn::LastSeen, Expr::value(now))
+            .filter(UserDevicesColumn::UserId.eq(user_id))
+            .filter(UserDevicesColumn::FingerprintHash.eq(fingerprint_hash.as_str()))
+            .exec(&self.sql_pool)
+            .await?;
+        if updated.rows_affected > 0 {
+            return Ok(false);
+        }
+        model::user_devices::ActiveModel {
+            user_id: ActiveValue::Set(user_id.clone()),
+            fingerprint_hash: ActiveValue::Set(fingerprint_hash),
+            first_seen: ActiveValue::Set(now),
+            last_seen: ActiveValue::Set(now),
+        }
+        .insert(&self.sql_pool)
         .await?;
-        attempt_login(&opaque_handler, "bob", "wrong_password")
-            .await
-            .unwrap_err();
-        attempt_login(&opaque_handler, "bob", "bob00").await?;
+        Ok(true)
+    }
+
+    async fn record_successful_login(
+        &self,
+        user_id: &UserId,
+        source: LoginSource,
+        address: Option<IpAddr>,
+    ) -> Result<()> {
+        self.record_auth_event(user_id, source, None).await?;
+        // Not the counter of the address: a valid account would let a sprayer start over.
+        let source_key = address.map(|address| failed_login_source_key(&address));
+        for (key, _) in self.failed_login_limits(user_id, address) {
+            if Some(&key) != source_key.as_ref() {
+                self.throttle_store.reset(&key).await?;
+            }
+        }
+        if self.is_account_lockout_enabled() {
+            model::User::update_many()
+                .col_expr(UserColumn::FailedLoginCount, Expr::value(0))
+                .filter(UserColumn::UserId.eq(user_id))
+                .filter(UserColumn::FailedLoginCount.gt(0))
+                .exec(&self.sql_pool)
+                .await?;
+        }
+        if self.config.track_login_statistics {
+            // Increment in the database, to avoid losing updates with concurrent logins.
+            model::User::update_many()
+                .col_expr(
+                    UserColumn::LoginCount,
+                    Expr::col(UserColumn::LoginCount).add(1),
+                )
+                .col_expr(
+                    UserColumn::LastLoginDate,
+                    Expr::value(chrono::Utc::now().naive_utc()),
+                )
+                .filter(UserColumn::UserId.eq(user_id))
+                .exec(&self.sql_pool)
+                .await?;
+        }
         Ok(())
     }
 
-    #[tokio::test]
-    async fn test_bind_user() {
+    /// Refuses the login of members of the configured groups who haven't enrolled in TOTP.
+    async fn check_totp_policy(&self, user_id: &UserId) -> Result<()> {
+        if self.config.totp_required_groups.is_empty() {
+            return Ok(());
+        }
+        let user = model::User::find_by_id(user_id.clone())
+            .one(&self.sql_pool)
+            .await?
+            .ok_or_else(|| DomainError::EntityNotFound(user_id.to_string()))?;
+        if user.totp_secret.is_some() {
+            return Ok(());
+        }
+        let groups = user
+            .find_linked(model::memberships::UserToGroup)
+            .all(&self.sql_pool)
+            .await?;
+        if let Some(group) = groups.iter().find(|g| {
+            self.config
+                .totp_required_groups
+                .iter()
+                .any(|required| required.to_lowercase() == g.lowercase_display_name)
+        }) {
+            return Err(DomainError::AuthenticationError(format!(
+                "User '{}' is a member of '{}' and must enroll in TOTP before logging in",
+                user_id,
+                group.display_name.as_str()
+            )));
+        }
+        Ok(())
+    }
+
+    /// Whether the user is a member of the admin group. Unknown users aren't admins.
+    pub(crate) async fn is_admin(&self, user_id: &UserId) -> Result<bool> {
+        let user = match model::User::find_by_id(user_id.clone())
+            .one(&self.sql_pool)
+            .await?
+        {
+            Some(user) => user,
+            None => return Ok(false),
+        };
+        Ok(user
+            .find_linked(model::memberships::UserToGroup)
+            .filter(GroupColumn::LowercaseDisplayName.eq(ADMIN_GROUP))
+            .count(&self.sql_pool)
+            .await?
+            > 0)
+    }
+
+    /// The first override that lists the user, then the first one that lists one of their
+    /// groups, then the service account policy, then the global policy.
+    pub(crate) async fn password_policy_for(&self, user_id: &UserId) -> Result<PasswordPolicy> {
+        let overrides = &self.config.password_policy_overrides;
+        if let Some(policy_override) = overrides
+            .iter()
+            .find(|o| o.users.iter().any(|u| UserId::new(u) == *user_id))
+        {
+            return Ok(self.config.to_password_policy(&policy_override.policy));
+        }
+        if overrides.iter().any(|o| !o.groups.is_empty()) {
+            let groups = match model::User::find_by_id(user_id.clone())
+                .one(&self.sql_pool)
+                .await?
+            {
+                Some(user) => {
+                    user.find_linked(model::memberships::UserToGroup)
+                        .select_only()
+                        .column(GroupColumn::LowercaseDisplayName)
+                        .into_tuple::<String>()
+                        .all(&self.sql_pool)
+                        .await?
+                }
+                None => Vec::new(),
+            };
+            if let Some(policy_override) = overrides
+                .iter()
+                .find(|o| o.groups.iter().any(|g| groups.contains(&g.to_lowercase())))
+            {
+                return Ok(self.config.to_password_policy(&policy_override.policy));
+            }
+        }
+        if self.is_service_account(user_id).await? {
+            return Ok(self.config.service_account_password_policy());
+        }
+        Ok(self.config.password_policy())
+    }
+
+    /// When the user has a TOTP secret, requires a valid code from their authenticator.
+    async fn check_totp_code(
+        &self,
+        user_id: &UserId,
+        code: Option<&str>,
+        address: Option<IpAddr>,
+    ) -> Result<()> {
+        let secret = match model::User::find_by_id(user_id.clone())
+            .select_only()
+            .column(UserColumn::TotpSecret)
+            .into_tuple::<Option<String>>()
+            .one(&self.sql_pool)
+            .await?
+            .flatten()
+        {
+            Some(secret) => secret,
+            None => return Ok(()),
+        };
+        let code = match code {
+            Some(code) => code,
+            None => {
+                return Err(DomainError::TotpRequired(format!(
+                    "'{}' has to give a TOTP code",
+                    user_id
+                )))
+            }
+        };
+        let secret = totp::decode_secret(&secret).ok_or_else(|| {
+            DomainError::InternalError(format!("Invalid TOTP secret for '{}'", user_id))
+        })?;
+        if totp::verify_code(
+            &secret,
+            code,
+            chrono::Utc::now().timestamp(),
+            self.config.totp_period_seconds,
+        ) {
+            return Ok(());
+        }
+        // Counted like a wrong password, against brute force.
+        self.record_failed_login(
+            user_id,
+            LoginSource::Web,
+            address,
+            LoginFailureReason::InvalidTotpCode,
+        )
+        .await?;
+        Err(DomainError::TotpRequired(format!(
+            "Invalid TOTP code for '{}'",
+            user_id
+        )))
+    }
+
+    /// Whether the user is a member of one of the service account groups. Unknown users aren't.
+    pub(crate) async fn is_service_account(&self, user_id: &UserId) -> Result<bool> {
+        let service_account_groups = &self.config.password_policy_options.service_account_groups;
+        if service_account_groups.is_empty() {
+            return Ok(false);
+        }
+        let user = match model::User::find_by_id(user_id.clone())
+            .one(&self.sql_pool)
+            .await?
+        {
+            Some(user) => user,
+            None => return Ok(false),
+        };
+        Ok(user
+            .find_linked(model::memberships::UserToGroup)
+            .filter(
+                GroupColumn::LowercaseDisplayName
+                    .is_in(service_account_groups.iter().map(|g| g.to_lowercase())),
+            )
+            .count(&self.sql_pool)
+            .await?
+            > 0)
+    }
+
+    /// Admins can't use login links: access to their mailbox shouldn't be enough to take over
+    /// the server.
+    async fn check_login_link_policy(&self, user_id: &UserId) -> Result<()> {
+        model::User::find_by_id(user_id.clone())
+            .filter(UserColumn::IsEnabled.eq(true))
+            .one(&self.sql_pool)
+            .await?
+            .ok_or_else(|| {
+                DomainError::AuthenticationError(format!("Unknown or disabled user '{}'", user_id))
+            })?;
+        if self.is_admin(user_id).await? {
+            return Err(DomainError::AuthenticationError(format!(
+                "User '{}' is an admin and can't use login links",
+                user_id
+            )));
+        }
+        self.check_totp_policy(user_id).await
+    }
+
+    pub(crate) async fn create_login_link(&self, user_id: &UserId) -> Result<String> {
+        if !self.config.enable_login_links {
+            return Err(DomainError::AuthenticationError(
+                "Login links are disabled".to_owned(),
+            ));
+        }
+        self.check_login_link_policy(user_id).await?;
+        let link_id = uuid::Uuid::new_v4().to_string();
+        let expiry = chrono::Utc::now()
+            + chrono::Duration::seconds(self.config.login_link_validity_seconds as i64);
+        model::login_links::ActiveModel {
+            link_id: ActiveValue::Set(link_id.clone()),
+            user_id: ActiveValue::Set(user_id.clone()),
+            expiry_date: ActiveValue::Set(expiry.naive_utc()),
+        }
+        .insert(&self.sql_pool)
+        .await?;
+        let link = LoginLink {
+            purpose: LOGIN_LINK_PURPOSE.to_owned(),
+            link_id,
+            user_id: user_id.clone(),
+            expiry: expiry.timestamp(),
+        };
+        let sealed = orion::aead::seal(&self.get_orion_secret_key()?, &bincode::serialize(&link)?)?;
+        // The token goes in a URL.
+        Ok(base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(sealed))
+    }
+
+    /// Rejects a password that matches one of the last password files of the user.
+    async fn check_password_history(&self, user_id: &UserId, clear_password: &str) -> Result<()> {
+        if self.config.password_history_size == 0 {
+            return Ok(());
+        }
+        let password_files = model::PasswordHistory::find()
+            .filter(PasswordHistoryColumn::UserId.eq(user_id))
+            .select_only()
+            .column(PasswordHistoryColumn::PasswordFile)
+            .into_tuple::<Vec<u8>>()
+            .all(&self.sql_pool)
+            .await?;
+        if password_files.iter().any(|password_file| {
+            passwords_match(
+                Some(password_file),
+                clear_password,
+                self.config.get_server_setup(),
+                user_id,
+            )
+            .is_ok()
+        }) {
+            return Err(DomainError::PasswordReused(user_id.to_string()));
+        }
+        Ok(())
+    }
+
+    /// Adds the new password file to the history, and forgets the oldest ones.
+    async fn record_password_history(
+        &self,
+        user_id: &UserId,
+        password_file: Vec<u8>,
+    ) -> Result<()> {
+        if self.config.password_history_size == 0 {
+            return Ok(());
+        }
+        model::password_history::ActiveModel {
+            user_id: ActiveValue::Set(user_id.clone()),
+            password_file: ActiveValue::Set(password_file),
+            creation_date: ActiveValue::Set(chrono::Utc::now().naive_utc()),
+            ..Default::default()
+        }
+        .insert(&self.sql_pool)
+        .await?;
+        let stale_entries = model::PasswordHistory::find()
+            .filter(PasswordHistoryColumn::UserId.eq(user_id))
+            .order_by_desc(PasswordHistoryColumn::EntryId)
+            .offset(self.config.password_history_size)
+            .select_only()
+            .column(PasswordHistoryColumn::EntryId)
+            .into_tuple::<i32>()
+            .all(&self.sql_pool)
+            .await?;
+        if !stale_entries.is_empty() {
+            model::PasswordHistory::delete_many()
+                .filter(PasswordHistoryColumn::EntryId.is_in(stale_entries))
+                .exec(&self.sql_pool)
+                .await?;
+        }
+        Ok(())
+    }
+
+    fn check_maintenance_mode(&self, user_id: &UserId) -> Result<()> {
+        let mode = self.maintenance_mode.read().unwrap();
+        if !mode.enabled
+            || self
+                .config
+                .maintenance_break_glass_users
+                .iter()
+                .any(|u| UserId::new(u) == *user_id)
+        {
+            return Ok(());
+        }
+        debug!(r#"Refusing the login of "{}" during maintenance"#, user_id);
+        Err(DomainError::Maintenance(mode.message.clone()))
+    }
+
+    /// Checks the password of a bind, and records the attempt.
+    async fn check_bind(&self, request: &BindRequest) -> Result<()> {
+        self.check_maintenance_mode(&request.name)?;
+        self.check_login_throttle(&request.name, LoginSource::Ldap, request.source)
+            .await?;
+        self.check_account_lock(request, LoginSource::Ldap).await?;
+        match self.check_bind_password(request).await? {
+            None if self.must_change_password(&request.name).await? => {
+                // Binds can't change the password, only the web interface can.
+                Err(DomainError::PasswordExpired(format!(
+                    "User '{}' must set a new password on the web interface first",
+                    &request.name
+                )))
+            }
+            None => {
+                if let Err(e) = self.check_password_expiry(&request.name).await {
+                    self.record_failed_login(
+                        &request.name,
+                        LoginSource::Ldap,
+                        request.source,
+                        LoginFailureReason::PasswordExpired,
+                    )
+                    .await?;
+                    return Err(e);
+                }
+                self.record_successful_login(&request.name, LoginSource::Ldap, request.source)
+                    .await?;
+                // The imported hashes and the proposed policy are for the primary passwords.
+                if request.context.is_none() {
+                    self.upgrade_legacy_password(&request.name, &request.password)
+                        .await;
+                    self.record_policy_compliance(&request.name, &request.password)
+                        .await;
+                }
+                Ok(())
+            }
+            Some(failure_reason) => {
+                self.record_failed_login(
+                    &request.name,
+                    LoginSource::Ldap,
+                    request.source,
+                    failure_reason,
+                )
+                .await?;
+                Err(bind_error(&request.name, failure_reason))
+            }
+        }
+    }
+
+    /// Deletes the account of the user, after checking their password like a bind. The failures
+    /// count towards the lockout of the user.
+    pub(crate) async fn check_delete_own_account(&self, request: &BindRequest) -> Result<()> {
+        if !self.config.enable_self_service_deletion {
+            return Err(DomainError::AuthenticationError(
+                "Self-service account deletion is disabled".to_owned(),
+            ));
+        }
+        self.check_maintenance_mode(&request.name)?;
+        self.check_login_throttle(&request.name, LoginSource::Web, request.source)
+            .await?;
+        self.check_account_lock(request, LoginSource::Web).await?;
+        if let Some(failure_reason) = self.check_bind_password(request).await? {
+            self.record_failed_login(
+                &request.name,
+                LoginSource::Web,
+                request.source,
+                failure_reason,
+            )
+            .await?;
+            return Err(bind_error(&request.name, failure_reason));
+        }
+        if request.name == self.config.ldap_user_dn {
+            return Err(DomainError::AuthenticationError(format!(
+                "The admin '{}' from the configuration can't delete itself",
+                &request.name
+            )));
+        }
+        self.delete_user(&request.name).await
+    }
+
+    /// Finishes a web login, and records the attempt.
+    async fn check_login_finish(
+        &self,
+        request: login::ClientLoginFinishRequest,
+        source: Option<IpAddr>,
+    ) -> Result<SuccessfulLogin> {
+        let login::ServerData {
+            username,
+            server_login,
+            password_file_hash,
+            context,
+        } = self.open_server_data(&request.server_data)?;
+        if let Some(claimed_username) = request.username.filter(|u| u != &username) {
+            warn!(
+                target: TAMPERING_EVENT_TARGET,
+                user_id = %username,
+                r#"Login finish for "{}" with the server data of "{}""#,
+                &claimed_username,
+                &username
+            );
+            self.record_failed_login(
+                &username,
+                LoginSource::Web,
+                source,
+                LoginFailureReason::UserMismatch,
+            )
+            .await?;
+            return Err(DomainError::AuthenticationError(
+                "The login doesn't match the server data".to_owned(),
+            ));
+        }
+        if self.is_account_locked(&username).await? {
+            // Locked by other attempts since the start of the login.
+            let _ =
+                opaque::server::login::finish_login(server_login, request.credential_finalization);
+            self.record_auth_event(
+                &username,
+                LoginSource::Web,
+                Some(LoginFailureReason::AccountLocked),
+            )
+            .await?;
+            return Err(account_locked_error(&username));
+        }
+        let current_password_file = self
+            .lookup_password_file(username.clone(), context.as_deref())
+            .await?;
+        if current_password_file == Err(DummyPasswordFileReason::DisabledUser) {
+            // The account was disabled after the start of the login: finish it anyway, for the
+            // timing.
+            let _ =
+                opaque::server::login::finish_login(server_login, request.credential_finalization);
+            self.record_failed_login(
+                &username,
+                LoginSource::Web,
+                source,
+                LoginFailureReason::AccountDisabled,
+            )
+            .await?;
+            return Err(account_disabled_error(&username));
+        }
+        let current_password_file_hash = current_password_file
+            .as_deref()
+            .ok()
+            .map(hash_password_file);
+        if current_password_file_hash != password_file_hash {
+            debug!(
+                r#"The password of "{}" changed during the login"#,
+                &username
+            );
+            return Err(DomainError::CredentialsChanged(format!(
+                "The password of '{}' changed, start the login again",
+                username
+            )));
+        }
+        if let Some(banner) = &self.config.login_banner {
+            if request.consent.as_deref() != Some(login::banner_consent(banner).as_str()) {
+                self.record_auth_event(
+                    &username,
+                    LoginSource::Web,
+                    Some(LoginFailureReason::BannerNotAcknowledged),
+                )
+                .await?;
+                return Err(DomainError::AuthenticationError(format!(
+                    "The login banner was not acknowledged by '{}'",
+                    username
+                )));
+            }
+        }
+        // Finish the login: this makes sure the client data is correct, and gives a session key we
+        // don't need.
+        match opaque::server::login::finish_login(server_login, request.credential_finalization) {
+            Ok(_session_key) => {
+                if let Err(e) = self.check_totp_policy(&username).await {
+                    if matches!(e, DomainError::AuthenticationError(_)) {
+                        self.record_auth_event(
+                            &username,
+                            LoginSource::Web,
+                            Some(LoginFailureReason::TotpNotEnrolled),
+                        )
+                        .await?;
+                    }
+                    return Err(e);
+                }
+                self.check_totp_code(&username, request.totp_code.as_deref(), source)
+                    .await?;
+                if let Err(e) = self.check_password_expiry(&username).await {
+                    self.record_failed_login(
+                        &username,
+                        LoginSource::Web,
+                        source,
+                        LoginFailureReason::PasswordExpired,
+                    )
+                    .await?;
+                    return Err(e);
+                }
+                // The password stays valid until the user replaces it: the client asks for a new
+                // one first.
+                let must_change_password = self.must_change_password(&username).await?;
+                if must_change_password {
+                    info!(
+                        r#"User "{}" logged in with a password set by someone else, they must set a new one"#,
+                        &username
+                    );
+                }
+                self.record_successful_login(&username, LoginSource::Web, source)
+                    .await?;
+                if self.config.enable_device_tracking {
+                    if let Some(fingerprint) = request.device_fingerprint.as_deref() {
+                        if self.record_device(&username, fingerprint).await? {
+                            warn!(
+                                target: NEW_DEVICE_EVENT_TARGET,
+                                user_id = %username,
+                                r#"First login of "{}" from this device"#,
+                                &username
+                            );
+                        }
+                    }
+                }
+                Ok(SuccessfulLogin {
+                    user_id: username,
+                    must_change_password,
+                })
+            }
+            Err(e) => {
+                self.record_failed_login(
+                    &username,
+                    LoginSource::Web,
+                    source,
+                    LoginFailureReason::InvalidPassword,
+                )
+                .await?;
+                Err(e.into())
+            }
+        }
+    }
+
+    /// Returns the reason why the bind fails, if it does. There are no side effects.
+    pub(crate) async fn check_bind_password(
+        &self,
+        request: &BindRequest,
+    ) -> Result<Option<LoginFailureReason>> {
+        request.name.check_length(self.config.max_username_length)?;
+        let context = request.context.as_deref();
+        self.check_password_context(context)?;
+        let password_hash = match self
+            .lookup_password_file(request.name.clone(), context)
+            .await?
+        {
+            Ok(password_hash) => password_hash,
+            Err(DummyPasswordFileReason::DisabledUser) => {
+                // Same work as for an enabled user, so that the timing doesn't tell them apart.
+                let _ = passwords_match(
+                    None,
+                    &request.password,
+                    self.config.get_server_setup(),
+                    &request.name,
+                );
+                debug!(r#"User "{}" is disabled"#, &request.name);
+                return Ok(Some(LoginFailureReason::AccountDisabled));
+            }
+            // The imported passwords are primary passwords.
+            Err(_) if context.is_some() => return Ok(Some(LoginFailureReason::InvalidPassword)),
+            Err(_) => return self.check_legacy_password(request).await,
+        };
+        if let Err(e) = passwords_match(
+            Some(&password_hash),
+            &request.password,
+            self.config.get_server_setup(),
+            &self.login_identity(&request.name, context).await?,
+        ) {
+            debug!(r#"Invalid password for "{}": {}"#, &request.name, e);
+            return Ok(Some(LoginFailureReason::InvalidPassword));
+        }
+        Ok(None)
+    }
+
+    /// Fetches the previously registered password file from the DB, or the reason why there is
+    /// none. Disabled users get a dummy password file, so that their logins take as long as any
+    /// other, and deleted users are treated like unknown ones. With a context, the password of the
+    /// user for that context.
+    #[instrument(skip(self), level = "debug", err)]
+    async fn lookup_password_file(
+        &self,
+        user_id: UserId,
+        context: Option<&str>,
+    ) -> Result<std::result::Result<Vec<u8>, DummyPasswordFileReason>> {
+        let mut password_files = model::User::find()
+            .filter(UserColumn::UserId.eq(&user_id))
+            .filter(UserColumn::DeletedAt.is_null())
+            .select_only()
+            .column(UserColumn::PasswordHash)
+            .column(UserColumn::PasswordHashChecksum)
+            .column(UserColumn::IsEnabled)
+            .into_tuple::<(Option<Vec<u8>>, Option<Vec<u8>>, bool)>()
+            .limit(2)
+            .all(&self.sql_pool)
+            .await?;
+        if password_files.len() > 1 {
+            // The user ID is the primary key: this can only be a corrupted database.
+            error!(
+                r#"Several users with the ID "{}" were found, the database is corrupted"#,
+                &user_id
+            );
+            return Err(DomainError::InternalError("ambiguous user".to_owned()));
+        }
+        let (password_file, checksum) = match password_files.pop() {
+            None => return Ok(Err(DummyPasswordFileReason::UnknownUser)),
+            Some((_, _, false)) => return Ok(Err(DummyPasswordFileReason::DisabledUser)),
+            Some((password_file, checksum, true)) => match context {
+                None => (password_file, checksum),
+                Some(context) => match model::UserPasswordContexts::find_by_id((
+                    user_id.clone(),
+                    context.to_owned(),
+                ))
+                .one(&self.sql_pool)
+                .await?
+                {
+                    Some(row) => (Some(row.password_hash), Some(row.password_hash_checksum)),
+                    None => (None, None),
+                },
+            },
+        };
+        let password_file = match password_file {
+            Some(password_file) => password_file,
+            None => return Ok(Err(DummyPasswordFileReason::NoPassword)),
+        };
+        // Only the primary passwords go to the quarantine.
+        let quarantine = self.config.quarantine_corrupt_password_files && context.is_none();
+        match &checksum {
+            Some(checksum) => {
+                use hmac::Mac;
+                if self
+                    .password_file_mac(&self.opaque_identity(&user_id, context), &password_file)
+                    .verify_slice(checksum)
+                    .is_err()
+                {
+                    error!(
+                        r#"The password file of "{}" doesn't match its checksum, it was modified outside of LLDAP"#,
+                        &user_id
+                    );
+                    if quarantine {
+                        self.quarantine_password_file(
+                            &user_id,
+                            password_file,
+                            Some(checksum.clone()),
+                            "checksum_mismatch",
+                        )
+                        .await?;
+                        return Ok(Err(DummyPasswordFileReason::QuarantinedPasswordFile));
+                    }
+                    return Err(DomainError::TamperedPasswordFile(user_id.to_string()));
+                }
+            }
+            // Passwords set before the checksums were introduced get one when they are changed.
+            None => debug!(r#"The password file of "{}" has no checksum"#, &user_id),
+        }
+        if quarantine && opaque::server::ServerRegistration::deserialize(&password_file).is_err() {
+            error!(r#"The password file of "{}" is corrupted"#, &user_id);
+            self.quarantine_password_file(&user_id, password_file, checksum, "undeserializable")
+                .await?;
+            return Ok(Err(DummyPasswordFileReason::QuarantinedPasswordFile));
+        }
+        Ok(Ok(password_file))
+    }
+
+    /// Moves the password file of the user to the quarantine, leaving them without a password.
+    async fn quarantine_password_file(
+        &self,
+        user_id: &UserId,
+        password_file: Vec<u8>,
+        checksum: Option<Vec<u8>>,
+        reason: &'static str,
+    ) -> Result<()> {
+        let user_id = user_id.clone();
+        self.sql_pool
+            .transaction::<_, (), DomainError>(|transaction| {
+                Box::pin(async move {
+                    // Unless it changed in the meantime.
+                    let removed = model::User::update_many()
+                        .col_expr(UserColumn::PasswordHash, Expr::value(None::<Vec<u8>>))
+                        .col_expr(
+                            UserColumn::PasswordHashChecksum,
+                            Expr::value(None::<Vec<u8>>),
+                        )
+                        .filter(UserColumn::UserId.eq(&user_id))
+                        .filter(UserColumn::PasswordHash.eq(password_file.clone()))
+                        .exec(transaction)
+                        .await?;
+                    if removed.rows_affected == 0 {
+                        return Ok(());
+                    }
+                    model::quarantined_password_files::ActiveModel {
+                        user_id: ActiveValue::Set(user_id.clone()),
+                        password_file: ActiveValue::Set(password_file),
+                        checksum: ActiveValue::Set(checksum),
+                        reason: ActiveValue::Set(reason.to_owned()),
+                        quarantine_date: ActiveValue::Set(chrono::Utc::now().naive_utc()),
+                        ..Default::default()
+                    }
+                    .insert(transaction)
+                    .await?;
+                    warn!(
+                        r#"Moved the password file of "{}" to the quarantine ({}), the user has no password until it is reset"#,
+                        &user_id, reason
+                    );
+                    Ok(())
+                })
+            })
+            .await?;
+        Ok(())
+    }
+
+    /// Moves the primary password of `from` to `to`, along with the identity the file is bound to,
+    /// so that it verifies for `to`. `from` is left without a password.
+    pub(crate) async fn transfer_password_file(&self, from: &UserId, to: &UserId) -> Result<()> {
+        if !self.config.transferable_password_files {
+            // A plain copy would be bound to the wrong user ID: the logins would fail.
+            return Err(DomainError::InternalError(
+                "The password files are bound to their user ID, transferring them requires transferable_password_files".to_owned(),
+            ));
+        }
+        if from == to {
+            return Ok(());
+        }
+        let (password_file, checksum, identity) = model::User::find_by_id(from.clone())
+            .select_only()
+            .column(UserColumn::PasswordHash)
+            .column(UserColumn::PasswordHashChecksum)
+            .column(UserColumn::PasswordIdentity)
+            .into_tuple::<(Option<Vec<u8>>, Option<Vec<u8>>, Option<UserId>)>()
+            .one(&self.sql_pool)
+            .await?
+            .ok_or_else(|| DomainError::EntityNotFound(from.to_string()))?;
+        let password_file = password_file
+            .ok_or_else(|| DomainError::EntityNotFound(format!("The password of '{}'", from)))?;
+        if let Some(checksum) = checksum {
+            use hmac::Mac;
+            // Don't give a valid checksum to a tampered file.
+            if self
+                .password_file_mac(from, &password_file)
+                .verify_slice(&checksum)
+                .is_err()
+            {
+                return Err(DomainError::TamperedPasswordFile(from.to_string()));
+            }
+        }
+        let new_checksum = self.password_file_checksum(to, &password_file);
+        let identity = identity.unwrap_or_else(|| from.clone());
+        let (from, to) = (from.clone(), to.clone());
+        let (from, to) = self
+            .sql_pool
+            .transaction::<_, (UserId, UserId), DomainError>(|transaction| {
+                Box::pin(async move {
+                    // Unless it changed in the meantime.
+                    let removed = model::User::update_many()
+                        .col_expr(UserColumn::PasswordHash, Expr::value(None::<Vec<u8>>))
+                        .col_expr(
+                            UserColumn::PasswordHashChecksum,
+                            Expr::value(None::<Vec<u8>>),
+                        )
+                        .col_expr(
+                            UserColumn::PasswordIdentity,
+                            Expr::value(Option::<UserId>::None),
+                        )
+                        .filter(UserColumn::UserId.eq(&from))
+                        .filter(UserColumn::PasswordHash.eq(password_file.clone()))
+                        .exec(transaction)
+                        .await?;
+                    if removed.rows_affected == 0 {
+                        return Err(DomainError::CredentialsChanged(format!(
+                            "The password of '{}' changed during the transfer",
+                            from
+                        )));
+                    }
+                    let moved = model::User::update_many()
+                        .col_expr(UserColumn::PasswordHash, Expr::value(password_file))
+                        .col_expr(UserColumn::PasswordHashChecksum, Expr::value(new_checksum))
+                        .col_expr(UserColumn::PasswordIdentity, Expr::value(identity))
+                        .col_expr(UserColumn::MustChangePassword, Expr::value(false))
+                        .col_expr(
+                            UserColumn::LegacyPasswordHash,
+                            Expr::value(Option::<String>::None),
+                        )
+                        .col_expr(
+                            UserColumn::PasswordChangedAt,
+                            Expr::value(chrono::Utc::now().naive_utc()),
+                        )
+                        .col_expr(
+                            UserColumn::PasswordGraceLoginsRemaining,
+                            Expr::value(Option::<i32>::None),
+                        )
+                        .filter(UserColumn::UserId.eq(&to))
+                        .exec(transaction)
+                        .await?;
+                    if moved.rows_affected == 0 {
+                        // Rolls back the removal.
+                        return Err(DomainError::EntityNotFound(to.to_string()));
+                    }
+                    Ok((from, to))
+                })
+            })
+            .await?;
+        info!(r#"Transferred the password of "{}" to "{}""#, &from, &to);
+        // Neither user keeps the sessions opened with their previous password.
+        self.revoke_user_tokens(&from).await?;
+        self.revoke_user_tokens(&to).await?;
+        Ok(())
+    }
+
+    /// Removes every password file, and everything else bound to the server setup: the password
+    /// history and the quarantined files. The users must set a new password.
+    pub(crate) async fn invalidate_password_files(&self) -> Result<u64> {
+        let invalidated = self
+            .sql_pool
+            .transaction::<_, u64, DomainError>(|transaction| {
+                Box::pin(async move {
+                    let invalidated = model::User::update_many()
+                        .col_expr(UserColumn::PasswordHash, Expr::value(None::<Vec<u8>>))
+                        .col_expr(
+                            UserColumn::PasswordHashChecksum,
+                            Expr::value(None::<Vec<u8>>),
+                        )
+                        .col_expr(
+                            UserColumn::PasswordIdentity,
+                            Expr::value(Option::<UserId>::None),
+                        )
+                        .col_expr(UserColumn::MustChangePassword, Expr::value(true))
+                        .filter(UserColumn::PasswordHash.is_not_null())
+                        .exec(transaction)
+                        .await?
+                        .rows_affected;
+                    model::UserPasswordContexts::delete_many()
+                        .exec(transaction)
+                        .await?;
+                    model::PasswordHistory::delete_many()
+                        .exec(transaction)
+                        .await?;
+                    model::QuarantinedPasswordFiles::delete_many()
+                        .exec(transaction)
+                        .await?;
+                    model::RegistrationStates::delete_many()
+                        .exec(transaction)
+                        .await?;
+                    Ok(invalidated)
+                })
+            })
+            .await?;
+        warn!("Invalidated the passwords of {} users", invalidated);
+        Ok(invalidated)
+    }
+
+    /// Lets the next registration replace the password of the user, with
+    /// `reject_password_overwrite`.
+    pub(crate) async fn allow_next_password_overwrite(&self, user_id: &UserId) -> Result<()> {
+        let result = model::User::update_many()
+            .col_expr(UserColumn::PasswordOverwriteAllowed, Expr::value(true))
+            .filter(UserColumn::UserId.eq(user_id))
+            .exec(&self.sql_pool)
+            .await?;
+        if result.rows_affected == 0 {
+            return Err(DomainError::EntityNotFound(user_id.to_string()));
+        }
+        Ok(())
+    }
+
+    /// The end of the registration of a password for one of the `password_contexts`. The
+    /// checksum covers the context, through the identity the file is bound to.
+    async fn set_context_password_file(
+        &self,
+        user_id: &UserId,
+        context: String,
+        password_file: Vec<u8>,
+    ) -> Result<()> {
+        if !self.user_exists(user_id).await? {
+            if self.config.mask_registration_user_existence {
+                debug!(r#"Ignoring the registration of unknown user "{}""#, user_id);
+                return Ok(());
+            }
+            return Err(DomainError::EntityNotFound(format!(
+                "Cannot set the password of unknown user '{}'",
+                user_id
+            )));
+        }
+        let checksum = self.password_file_checksum(
+            &self.opaque_identity(user_id, Some(&context)),
+            &password_file,
+        );
+        model::UserPasswordContexts::insert(model::user_password_contexts::ActiveModel {
+            user_id: ActiveValue::Set(user_id.clone()),
+            context: ActiveValue::Set(context.clone()),
+            password_hash: ActiveValue::Set(password_file),
+            password_hash_checksum: ActiveValue::Set(checksum),
+        })
+        .on_conflict(
+            OnConflict::columns([
+                UserPasswordContextsColumn::UserId,
+                UserPasswordContextsColumn::Context,
+            ])
+            .update_columns([
+                UserPasswordContextsColumn::PasswordHash,
+                UserPasswordContextsColumn::PasswordHashChecksum,
+            ])
+            .to_owned(),
+        )
+        .exec(&self.sql_pool)
+        .await?;
+        // The sessions don't say which password opened them.
+        self.revoke_user_tokens(user_id).await?;
+        self.audit(
+            None,
+            AuditAction::PasswordChange,
+            Some(format!("{} ({})", user_id, context)),
+            true,
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn user_exists(&self, user_id: &UserId) -> Result<bool> {
+        Ok(model::User::find_by_id(user_id.clone())
+            .filter(UserColumn::DeletedAt.is_null())
+            .count(&self.sql_pool)
+            .await?
+            > 0)
+    }
+}
+
+impl SqlBackendHandler {
+    /// Refuses the password checks of a locked account, after the same work as a real one so
+    /// that the timing doesn't tell whether the lock is active.
+    async fn check_account_lock(&self, request: &BindRequest, source: LoginSource) -> Result<()> {
+        if !self.is_account_locked(&request.name).await? {
+            return Ok(());
+        }
+        let _ = passwords_match(
+            None,
+            &request.password,
+            self.config.get_server_setup(),
+            &request.name,
+        );
+        self.record_auth_event(
+            &request.name,
+            source,
+            Some(LoginFailureReason::AccountLocked),
+        )
+        .await?;
+        Err(account_locked_error(&request.name))
+    }
+}
+
+#[async_trait]
+impl LoginHandler for SqlBackendHandler {
+    #[instrument(skip_all, level = "debug", err, fields(correlation_id = %correlation_id()))]
+    async fn bind(&self, request: BindRequest) -> Result<()> {
+        let result = self.check_bind(&request).await;
+        metrics::record_bind(result.is_ok());
+        result
+    }
+
+    #[instrument(skip_all, level = "debug", err, fields(correlation_id = %correlation_id()))]
+    async fn bind_with_token(&self, token: &str) -> Result<UserId> {
+        let invalid_link =
+            || DomainError::AuthenticationError("Invalid or expired login link".to_owned());
+        if !self.config.enable_login_links {
+            return Err(invalid_link());
+        }
+        let secret_key = self.get_orion_secret_key()?;
+        let link: LoginLink = base64::engine::general_purpose::URL_SAFE_NO_PAD
+            .decode(token)
+            .ok()
+            .and_then(|sealed| orion::aead::open(&secret_key, &sealed).ok())
+            .and_then(|bytes| bincode::deserialize(&bytes).ok())
+            .ok_or_else(invalid_link)?;
+        let now = chrono::Utc::now();
+        if link.purpose != LOGIN_LINK_PURPOSE || link.expiry < now.timestamp() {
+            return Err(invalid_link());
+        }
+        self.check_maintenance_mode(&link.user_id)?;
+        self.check_login_throttle(&link.user_id, LoginSource::Web, None)
+            .await?;
+        // Whoever deletes the link first gets to log in.
+        let deleted = model::LoginLinks::delete_many()
+            .filter(LoginLinksColumn::LinkId.eq(link.link_id.as_str()))
+            .filter(LoginLinksColumn::UserId.eq(&link.user_id))
+            .filter(LoginLinksColumn::ExpiryDate.gt(now.naive_utc()))
+            .exec(&self.sql_pool)
+            .await?;
+        if deleted.rows_affected == 0 {
+            debug!(r#"The login link of "{}" was already used"#, &link.user_id);
+            return Err(invalid_link());
+        }
+        // The user may have been promoted since the link was sent.
+        self.check_login_link_policy(&link.user_id).await?;
+        self.record_successful_login(&link.user_id, LoginSource::Web, None)
+            .await?;
+        Ok(link.user_id)
+    }
+
+    #[instrument(skip_all, level = "debug", err, fields(correlation_id = %correlation_id()))]
+    async fn health_bind(&self, request: BindRequest) -> Result<()> {
+        match self.check_bind_password(&request).await? {
+            None => Ok(()),
+            Some(failure_reason) => Err(bind_error(&request.name, failure_reason)),
+        }
+    }
+
+    #[instrument(skip_all, level = "debug", err, fields(correlation_id = %correlation_id()))]
+    async fn compare_password(&self, request: BindRequest) -> Result<bool> {
+        self.check_maintenance_mode(&request.name)?;
+        self.check_login_throttle(&request.name, LoginSource::Ldap, request.source)
+            .await?;
+        self.check_account_lock(&request, LoginSource::Ldap).await?;
+        match self.check_bind_password(&request).await? {
+            None => Ok(true),
+            Some(failure_reason) => {
+                self.record_failed_login(
+                    &request.name,
+                    LoginSource::Ldap,
+                    request.source,
+                    failure_reason,
+                )
+                .await?;
+                Ok(false)
+            }
+        }
+    }
+}
+
+fn bind_error(user_id: &UserId, failure_reason: LoginFailureReason) -> DomainError {
+    match failure_reason {
+        LoginFailureReason::AccountDisabled => account_disabled_error(user_id),
+        _ => DomainError::AuthenticationError(format!(" for user '{}'", user_id)),
+    }
+}
+
+fn account_disabled_error(user_id: &UserId) -> DomainError {
+    DomainError::AccountDisabled(format!("The account of '{}' is disabled", user_id))
+}
+
+fn account_locked_error(user_id: &UserId) -> DomainError {
+    DomainError::AccountLocked(format!(
+        "The account of '{}' is locked after too many wrong passwords",
+        user_id
+    ))
+}
+
+#[async_trait]
+impl OpaqueHandler for SqlOpaqueHandler {
+    #[instrument(skip_all, level = "debug", err)]
+    async fn login_challenge(&self) -> Result<login::ServerLoginChallengeResponse> {
+        if !self.config.enable_login_challenge {
+            return Ok(login::ServerLoginChallengeResponse { challenge: None });
+        }
+        let expiry =
+            chrono::Utc::now().timestamp() + self.config.login_challenge_validity_seconds as i64;
+        Ok(login::ServerLoginChallengeResponse {
+            challenge: Some(self.seal_login_challenge(expiry)?),
+        })
+    }
+
+    #[instrument(
+        skip_all,
+        level = "debug",
+        err,
+        fields(correlation_id = %correlation_id(), password_file = tracing::field::Empty)
+    )]
+    async fn login_start(
+        &self,
+        request: login::ClientLoginStartRequest,
+        source: Option<IpAddr>,
+    ) -> Result<login::ServerLoginStartResponse> {
+        metrics::record_opaque_login_start();
+        self.check_login_challenge(request.challenge.as_deref())
+            .await?;
+        request
+            .username
+            .check_length(self.config.max_username_length)?;
+        self.check_maintenance_mode(&request.username)?;
+        self.check_password_context(request.context.as_deref())?;
+        self.check_login_throttle(&request.username, LoginSource::Web, source)
+            .await?;
+        let user_id = request.username;
+        let is_locked = self.is_account_locked(&user_id).await?;
+        let password_file_bytes = self
+            .lookup_password_file(user_id.clone(), request.context.as_deref())
+            .await?;
+        let password_file_hash = password_file_bytes.as_deref().ok().map(hash_password_file);
+        let maybe_password_file = password_file_bytes.and_then(|bytes| {
+            opaque::server::ServerRegistration::deserialize(&bytes).map_err(|_| {
+                error!(r#"The password file of "{}" is corrupted"#, &user_id);
+                DummyPasswordFileReason::CorruptPasswordFile
+            })
+        });
+        tracing::Span::current().record(
+            "password_file",
+            match &maybe_password_file {
+                Ok(_) => "real",
+                Err(reason) => Into::<&'static str>::into(*reason),
+            },
+        );
+        let is_disabled =
+            maybe_password_file.as_ref().err() == Some(&DummyPasswordFileReason::DisabledUser);
+        // A locked account gets the dummy exchange, so that the timing doesn't tell.
+        let maybe_password_file = maybe_password_file.ok().filter(|_| !is_locked);
+        let identity = match maybe_password_file {
+            Some(_) => {
+                self.login_identity(&user_id, request.context.as_deref())
+                    .await?
+            }
+            None => self.opaque_identity(&user_id, request.context.as_deref()),
+        };
+
+        let mut rng = rand::rngs::OsRng;
+        // Get the CredentialResponse for the user, or a dummy one if no user/no password.
+        let start_response = opaque_span().in_scope(|| {
+            opaque::server::login::start_login(
+                &mut rng,
+                self.config.get_server_setup(),
+                maybe_password_file,
+                request.login_start_request,
+                &identity,
+            )
+        })?;
+        if is_locked {
+            self.record_auth_event(
+                &user_id,
+                LoginSource::Web,
+                Some(LoginFailureReason::AccountLocked),
+            )
+            .await?;
+            return Err(account_locked_error(&user_id));
+        }
+        if is_disabled {
+            // Only now that the dummy exchange ran, so that the timing doesn't tell.
+            self.record_failed_login(
+                &user_id,
+                LoginSource::Web,
+                source,
+                LoginFailureReason::AccountDisabled,
+            )
+            .await?;
+            return Err(account_disabled_error(&user_id));
+        }
+        let secret_key = self.get_orion_secret_key()?;
+        let server_data = login::ServerData {
+            username: user_id,
+            server_login: start_response.state,
+            password_file_hash,
+            context: request.context,
+        };
+        let encrypted_state = orion::aead::seal(&secret_key, &bincode::serialize(&server_data)?)?;
+
+        Ok(login::ServerLoginStartResponse {
+            server_data: base64::engine::general_purpose::STANDARD.encode(encrypted_state),
+            credential_response: start_response.message,
+            banner: self.config.login_banner.clone(),
+        })
+    }
+
+    #[instrument(skip_all, level = "debug", err, fields(correlation_id = %correlation_id()))]
+    async fn login_finish(
+        &self,
+        request: login::ClientLoginFinishRequest,
+        source: Option<IpAddr>,
+    ) -> Result<SuccessfulLogin> {
+        let result = self.check_login_finish(request, source).await;
+        metrics::record_opaque_login_finish(result.is_ok());
+        result
+    }
+
+    #[instrument(skip_all, level = "debug", err, fields(correlation_id = %correlation_id()))]
+    async fn registration_start(
+        &self,
+        request: registration::ClientRegistrationStartRequest,
+        source: Option<IpAddr>,
+    ) -> Result<registration::ServerRegistrationStartResponse> {
+        request
+            .username
+            .check_length(self.config.max_username_length)?;
+        self.check_password_context(request.context.as_deref())?;
+        self.check_registration_throttle(source).await?;
+        // Generate the server-side key and derive the data to send back.
+        let start_response = opaque_span().in_scope(|| {
+            opaque::server::registration::start_registration(
+                self.config.get_server_setup(),
+                request.registration_start_request,
+                &self.opaque_identity(&request.username, request.context.as_deref()),
+            )
+        })?;
+        let server_data = bincode::serialize(&registration::ServerData {
+            username: request.username.clone(),
+            context: request.context,
+        })?;
+        let server_data = if self.config.enable_registration_handles {
+            self.store_registration_state(&request.username, server_data)
+                .await?
+        } else {
+            let secret_key = self.get_orion_secret_key()?;
+            let encrypted_state = orion::aead::seal(&secret_key, &server_data)?;
+            base64::engine::general_purpose::STANDARD.encode(encrypted_state)
+        };
+        Ok(registration::ServerRegistrationStartResponse {
+            server_data,
+            registration_response: start_response.message,
+        })
+    }
+
+    #[instrument(skip_all, level = "debug", err, fields(correlation_id = %correlation_id()))]
+    async fn registration_finish(
+        &self,
+        request: registration::ClientRegistrationFinishRequest,
+        actor: &UserId,
+        transport_secure: bool,
+    ) -> Result<()> {
+        if self.config.require_secure_transport_for_password_ops && !transport_secure {
+            return Err(DomainError::InsecureTransport(
+                "Passwords can only be set over an encrypted connection".to_owned(),
+            ));
+        }
+        let registration::ServerData { username, context } =
+            if self.config.enable_registration_handles {
+                self.take_registration_state(&request.server_data).await?
+            } else {
+                self.open_server_data(&request.server_data)?
+            };
+
+        let password_file =
+            opaque::server::registration::get_password_file(request.registration_upload)
+                .serialize();
+        if let Some(context) = context {
+            return self
+                .set_context_password_file(&username, context, password_file)
+                .await;
+        }
+        let checksum = self.password_file_checksum(&username, &password_file);
+        // Set the user password to the new password.
+        let mut update = model::User::update_many()
+            .col_expr(UserColumn::PasswordHash, Expr::value(password_file.clone()))
+            .col_expr(UserColumn::PasswordHashChecksum, Expr::value(checksum))
+            .col_expr(
+                UserColumn::LegacyPasswordHash,
+                Expr::value(Option::<String>::None),
+            )
+            .col_expr(
+                UserColumn::PasswordChangedAt,
+                Expr::value(chrono::Utc::now().naive_utc()),
+            )
+            // The new file is bound to the user ID.
+            .col_expr(
+                UserColumn::PasswordIdentity,
+                Expr::value(Option::<UserId>::None),
+            )
+            .col_expr(
+                UserColumn::PasswordGraceLoginsRemaining,
+                Expr::value(Option::<i32>::None),
+            )
+            .col_expr(UserColumn::PasswordOverwriteAllowed, Expr::value(false));
+        if self.config.reject_password_overwrite {
+            // In the same query, so that a reset only allows one overwrite. A temporary password
+            // is meant to be replaced.
+            update = update.filter(
+                Condition::any()
+                    .add(UserColumn::PasswordHash.is_null())
+                    .add(UserColumn::PasswordOverwriteAllowed.eq(true))
+                    .add(UserColumn::MustChangePassword.eq(true)),
+            );
+        }
+        // Only the users themselves clear the flag. With `force_password_change_after_admin_reset`,
+        // a password set by someone else has to be replaced: only the user should know the next
+        // one. The admin from the configuration is used by services, it keeps its password.
+        if actor == &username {
+            update = update.col_expr(UserColumn::MustChangePassword, Expr::value(false));
+        } else if self.config.force_password_change_after_admin_reset
+            && username != self.config.ldap_user_dn
+        {
+            update = update.col_expr(UserColumn::MustChangePassword, Expr::value(true));
+        }
+        let result = update
+            .filter(UserColumn::UserId.eq(&username))
+            .filter(UserColumn::DeletedAt.is_null())
+            .exec(&self.sql_pool)
+            .await?;
+        if result.rows_affected == 0 {
+            if self.config.reject_password_overwrite && self.user_exists(&username).await? {
+                return Err(DomainError::PasswordAlreadySet(format!(
+                    "'{}' already has a password, reset it first",
+                    username
+                )));
+            }
+            if self.config.mask_registration_user_existence {
+                // Don't reveal whether the user exists.
+                debug!(
+                    r#"Ignoring the registration of unknown user "{}""#,
+                    &username
+                );
+            } else {
+                return Err(DomainError::EntityNotFound(format!(
+                    "Cannot set the password of unknown user '{}'",
+                    username
+                )));
+            }
+            return Ok(());
+        }
+        self.record_password_history(&username, password_file)
+            .await?;
+        // Sessions opened with the previous password shouldn't outlive it.
+        self.revoke_user_tokens(&username).await?;
+        self.audit(
+            None,
+            AuditAction::PasswordChange,
+            Some(username.to_string()),
+            true,
+        )
+        .await?;
+        Ok(())
+    }
+}
+
+/// Whether the server can answer a login for this password file, whatever the password.
+fn can_start_login(
+    server_setup: &opaque::server::ServerSetup,
+    user_id: &UserId,
+    password_file: opaque::server::ServerRegistration,
+) -> bool {
+    let mut rng = rand::rngs::OsRng;
+    opaque::client::login::start_login("lldap_password_audit", &mut rng)
+        .ok()
+        .and_then(|client_login_start| {
+            opaque::server::login::start_login(
+                &mut rng,
+                server_setup,
+                Some(password_file),
+                client_login_start.message,
+                user_id,
+            )
+            .ok()
+        })
+        .is_some()
+}
+
+/// Registers a throwaway password and logs in with it, all in memory, to check that the server
+/// setup can answer logins.
+pub(crate) fn check_server_setup(server_setup: &opaque::server::ServerSetup) -> Result<()> {
+    let mut rng = rand::rngs::OsRng;
+    let username = UserId::new("lldap_health_check");
+    let password = "lldap_health_check_password";
+    let registration_start =
+        opaque::client::registration::start_registration(password.as_bytes(), &mut rng)?;
+    let start_response = opaque::server::registration::start_registration(
+        server_setup,
+        registration_start.message,
+        &username,
+    )?;
+    let registration_finish = opaque::client::registration::finish_registration(
+        registration_start.state,
+        start_response.message,
+        &mut rng,
+    )?;
+    let password_file =
+        opaque::server::registration::get_password_file(registration_finish.message).serialize();
+    passwords_match(
+        Some(password_file.as_slice()),
+        password,
+        server_setup,
+        &username,
+    )
+}
+
+/// The server side of `login_start` for a serialized client message, as if the user didn't exist.
+pub(crate) fn check_login_start_compatibility(
+    server_setup: &opaque::server::ServerSetup,
+    login_start_request: &[u8],
+) -> Result<bool> {
+    let credential_request =
+        match opaque::server::login::CredentialRequest::deserialize(login_start_request) {
+            Ok(request) => request,
+            Err(e) => {
+                debug!("Could not deserialize the login start request: {}", e);
+                return Ok(false);
+            }
+        };
+    let mut rng = rand::rngs::OsRng;
+    let start_response = match opaque::server::login::start_login(
+        &mut rng,
+        server_setup,
+        None,
+        credential_request,
+        &UserId::new("lldap_interop_self_test"),
+    ) {
+        Ok(response) => response,
+        Err(e) => {
+            debug!("Could not start the login: {}", e);
+            return Ok(false);
+        }
+    };
+    // The client must be able to read the response.
+    Ok(bincode::serialize(&start_response.message)
+        .and_then(|bytes| bincode::deserialize::<opaque::client::login::CredentialResponse>(&bytes))
+        .is_ok())
+}
+
+/// Runs both sides of a registration in memory, and returns the serialized password file.
+pub(crate) fn create_password_file(
+    server_setup: &opaque::server::ServerSetup,
+    user_id: &UserId,
+    password: &str,
+) -> Result<Vec<u8>> {
+    let mut rng = rand::rngs::OsRng;
+    let registration_start =
+        opaque::client::registration::start_registration(password.as_bytes(), &mut rng)?;
+    let start_response = opaque::server::registration::start_registration(
+        server_setup,
+        registration_start.message,
+        user_id,
+    )?;
+    let registration_finish = opaque::client::registration::finish_registration(
+        registration_start.state,
+        start_response.message,
+        &mut rng,
+    )?;
+    Ok(opaque::server::registration::get_password_file(registration_finish.message).serialize())
+}
+
+/// Who sets a password, to decide how much to say when it's rejected, and whether the user has
+/// to change it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PasswordActor {
+    /// The users themselves: they get the reason, to pick a better password.
+    SelfService,
+    /// An admin or a bulk operation: the reason is only logged.
+    Admin,
+}
+
+/// Convenience function to set a user's password.
+pub(crate) async fn register_password(
+    opaque_handler: &SqlOpaqueHandler,
+    username: UserId,
+    password: &SecUtf8,
+    actor: PasswordActor,
+) -> Result<()> {
+    register_password_for_context(opaque_handler, username, None, password, actor).await
+}
+
+/// Like [`register_password`], for one of the `password_contexts` if there is one. The password
+/// history only concerns the primary password.
+#[instrument(skip_all, level = "debug", err, fields(username = %username.as_str(), ?context))]
+pub(crate) async fn register_password_for_context(
+    opaque_handler: &SqlOpaqueHandler,
+    username: UserId,
+    context: Option<&str>,
+    password: &SecUtf8,
+    actor: PasswordActor,
+) -> Result<()> {
+    let violations = opaque_handler
+        .password_policy_for(&username)
+        .await?
+        .violations(username.as_str(), password.unsecure());
+    let check = if !violations.is_empty() {
+        Err(DomainError::PasswordPolicyViolation(violations))
+    } else if context.is_none() {
+        opaque_handler
+            .check_password_history(&username, password.unsecure())
+            .await
+    } else {
+        Ok(())
+    };
+    match check {
+        Ok(()) => {}
+        Err(err @ (DomainError::PasswordPolicyViolation(_) | DomainError::PasswordReused(_)))
+            if actor == PasswordActor::Admin =>
+        {
+            info!(r#"Rejected the password of "{}": {}"#, &username, err);
+            return Err(DomainError::WeakPassword(username.to_string()));
+        }
+        Err(err) => return Err(err),
+    }
+    if opaque_handler
+        .is_legacy_password(&username, password.unsecure())
+        .await?
+    {
+        return Err(match actor {
+            PasswordActor::SelfService => DomainError::PasswordReused(username.to_string()),
+            PasswordActor::Admin => {
+                info!(
+                    r#"Rejected the password of "{}": it is the legacy password"#,
+                    &username
+                );
+                DomainError::WeakPassword(username.to_string())
+            }
+        });
+    }
+    // The admin from the configuration stands for the admins and the bulk operations.
+    let actor_id = match actor {
+        PasswordActor::SelfService => username.clone(),
+        PasswordActor::Admin => opaque_handler.config.ldap_user_dn.clone(),
+    };
+    let mut rng = rand::rngs::OsRng;
+    use registration::*;
+    let registration_start =
+        opaque::client::registration::start_registration(password.unsecure().as_bytes(), &mut rng)?;
+    let start_response = opaque_handler
+        .registration_start(
+            ClientRegistrationStartRequest {
+                username: username.clone(),
+                registration_start_request: registration_start.message,
+                context: context.map(str::to_owned),
+            },
+            None,
+        )
+        .await?;
+    let registration_finish = opaque::client::registration::finish_registration(
+        registration_start.state,
+        start_response.registration_response,
+        &mut rng,
+    )?;
+    // The password doesn't leave the process.
+    opaque_handler
+        .registration_finish(
+            ClientRegistrationFinishRequest {
+                server_data: start_response.server_data,
+                registration_upload: registration_finish.message,
+            },
+            &actor_id,
+            true,
+        )
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::sql_backend_handler::tests::*;
+    use sea_orm::{ConnectionTrait, Statement};
+    use std::sync::{Arc, Mutex};
+
+    async fn attempt_login(
+        opaque_handler: &SqlOpaqueHandler,
+        username: &str,
+        password: &str,
+    ) -> Result<SuccessfulLogin> {
+        attempt_login_with_challenge(opaque_handler, username, password, None).await
+    }
+
+    async fn attempt_login_with_challenge(
+        opaque_handler: &SqlOpaqueHandler,
+        username: &str,
+        password: &str,
+        challenge: Option<String>,
+    ) -> Result<SuccessfulLogin> {
+        attempt_login_with_options(
+            opaque_handler,
+            username,
+            password,
+            challenge,
+            true,
+            None,
+            None,
+        )
+        .await
+    }
+
+    async fn attempt_login_with_options(
+        opaque_handler: &SqlOpaqueHandler,
+        username: &str,
+        password: &str,
+        challenge: Option<String>,
+        acknowledge_banner: bool,
+        device_fingerprint: Option<&str>,
+        totp_code: Option<&str>,
+    ) -> Result<SuccessfulLogin> {
+        let mut rng = rand::rngs::OsRng;
+        use login::*;
+        let login_start = opaque::client::login::start_login(password, &mut rng)?;
+        let start_response = opaque_handler
+            .login_start(
+                ClientLoginStartRequest {
+                    username: UserId::new(username),
+                    login_start_request: login_start.message,
+                    challenge,
+                    context: None,
+                },
+                None,
+            )
+            .await?;
+        let login_finish = opaque::client::login::finish_login(
+            login_start.state,
+            start_response.credential_response,
+        )?;
+        opaque_handler
+            .login_finish(
+                ClientLoginFinishRequest {
+                    server_data: start_response.server_data,
+                    credential_finalization: login_finish.message,
+                    consent: start_response
+                        .banner
+                        .as_deref()
+                        .filter(|_| acknowledge_banner)
+                        .map(login::banner_consent),
+                    device_fingerprint: device_fingerprint.map(str::to_owned),
+                    username: Some(UserId::new(username)),
+                    totp_code: totp_code.map(str::to_owned),
+                },
+                None,
+            )
+            .await
+    }
+
+    #[tokio::test]
+    async fn test_opaque_flow() -> Result<()> {
+        let sql_pool = get_initialized_db().await;
+        crate::infra::logging::init_for_tests();
+        let config = get_default_config();
+        let backend_handler = SqlBackendHandler::new(config.clone(), sql_pool.clone());
+        let opaque_handler = SqlOpaqueHandler::new(config, sql_pool);
+        insert_user_no_password(&backend_handler, "bob").await;
+        insert_user_no_password(&backend_handler, "john").await;
+        attempt_login(&opaque_handler, "bob", "bob00")
+            .await
+            .unwrap_err();
+        register_password(
+            &opaque_handler,
+            UserId::new("bob"),
+            &secstr::SecUtf8::from("bob00"),
+            PasswordActor::SelfService,
+        )
+        .await?;
+        attempt_login(&opaque_handler, "bob", "wrong_password")
+            .await
+            .unwrap_err();
+        attempt_login(&opaque_handler, "bob", "bob00").await?;
+        Ok(())
+    }
+
+    /// Also checks the column types of the other database kinds, e.g. BYTEA for the password
+    /// files on Postgres.
+    #[cfg(feature = "database_tests")]
+    #[tokio::test]
+    async fn test_opaque_flow_on_external_database() -> Result<()> {
+        let opaque_handler = SqlOpaqueHandler::new(get_default_config(), get_external_db().await);
+        let name = format!("bob_{}", uuid::Uuid::new_v4().simple());
+        insert_user_no_password(&opaque_handler, &name).await;
+        register_password(
+            &opaque_handler,
+            UserId::new(&name),
+            &secstr::SecUtf8::from("bob00"),
+            PasswordActor::SelfService,
+        )
+        .await?;
+        attempt_login(&opaque_handler, &name, "wrong_password")
+            .await
+            .unwrap_err();
+        attempt_login(&opaque_handler, &name, "bob00").await?;
+        bind(&opaque_handler, &name, "bob00").await?;
+        opaque_handler.delete_user(&UserId::new(&name)).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_bind_user() {
+        let sql_pool = get_initialized_db().await;
+        let config = get_default_config();
+        let handler = SqlOpaqueHandler::new(config, sql_pool.clone());
+        insert_user(&handler, "bob", "bob00").await;
+
+        handler
+            .bind(BindRequest {
+                name: UserId::new("bob"),
+                password: "bob00".to_string(),
+                source: None,
+                context: None,
+            })
+            .await
+            .unwrap();
+        handler
+            .bind(BindRequest {
+                name: UserId::new("andrew"),
+                password: "bob00".to_string(),
+                source: None,
+                context: None,
+            })
+            .await
+            .unwrap_err();
+        handler
+            .bind(BindRequest {
+                name: UserId::new("bob"),
+                password: "wrong_password".to_string(),
+                source: None,
+                context: None,
+            })
+            .await
+            .unwrap_err();
+    }
+
+    #[tokio::test]
+    async fn test_login_ignores_username_case() {
+        let sql_pool = get_initialized_db().await;
+        let handler = SqlOpaqueHandler::new(get_default_config(), sql_pool);
+        insert_user(&handler, "bob", "bob00").await;
+        bind(&handler, "BOB", "bob00").await.unwrap();
+        bind(&handler, " Bob ", "bob00").await.unwrap();
+        attempt_login(&handler, "BOB", "bob00").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_user_no_password() {
+        let sql_pool = get_initialized_db().await;
+        let config = get_default_config();
+        let handler = SqlBackendHandler::new(config, sql_pool.clone());
+        insert_user_no_password(&handler, "bob").await;
+
+        handler
+            .bind(BindRequest {
+                name: UserId::new("bob"),
+                password: "bob00".to_string(),
+                source: None,
+                context: None,
+            })
+            .await
+            .unwrap_err();
+    }
+
+    #[tokio::test]
+    async fn test_login_challenge_disabled() {
+        let sql_pool = get_initialized_db().await;
+        let config = get_default_config();
+        let handler = SqlOpaqueHandler::new(config, sql_pool);
+        insert_user(&handler, "bob", "bob00").await;
+        assert!(handler.login_challenge().await.unwrap().challenge.is_none());
+        attempt_login(&handler, "bob", "bob00").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_login_challenge_required() {
+        let sql_pool = get_initialized_db().await;
+        let mut config = get_default_config();
+        config.enable_login_challenge = true;
+        let handler = SqlOpaqueHandler::new(config, sql_pool);
+        insert_user(&handler, "bob", "bob00").await;
+        assert!(matches!(
+            attempt_login(&handler, "bob", "bob00").await.unwrap_err(),
+            DomainError::AuthenticationError(_)
+        ));
+        assert!(matches!(
+            attempt_login_with_challenge(&handler, "bob", "bob00", Some("garbage".to_owned()))
+                .await
+                .unwrap_err(),
+            DomainError::AuthenticationError(_)
+        ));
+        let challenge = handler.login_challenge().await.unwrap().challenge;
+        assert!(challenge.is_some());
+        attempt_login_with_challenge(&handler, "bob", "bob00", challenge)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_login_challenge_replayed() {
+        let sql_pool = get_initialized_db().await;
+        let mut config = get_default_config();
+        config.enable_login_challenge = true;
+        let handler = SqlOpaqueHandler::new(config, sql_pool);
+        insert_user(&handler, "bob", "bob00").await;
+        let challenge = handler.login_challenge().await.unwrap().challenge;
+        attempt_login_with_challenge(&handler, "bob", "bob00", challenge.clone())
+            .await
+            .unwrap();
+        assert!(matches!(
+            attempt_login_with_challenge(&handler, "bob", "bob00", challenge)
+                .await
+                .unwrap_err(),
+            DomainError::AuthenticationError(_)
+        ));
+        // A new challenge works.
+        let challenge = handler.login_challenge().await.unwrap().challenge;
+        attempt_login_with_challenge(&handler, "bob", "bob00", challenge)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_login_challenge_expired() {
+        let sql_pool = get_initialized_db().await;
+        let mut config = get_default_config();
+        config.enable_login_challenge = true;
+        let handler = SqlOpaqueHandler::new(config, sql_pool);
+        insert_user(&handler, "bob", "bob00").await;
+        let expired_challenge = handler
+            .seal_login_challenge(chrono::Utc::now().timestamp() - 10)
+            .unwrap();
+        assert!(matches!(
+            attempt_login_with_challenge(&handler, "bob", "bob00", Some(expired_challenge))
+                .await
+                .unwrap_err(),
+            DomainError::AuthenticationError(_)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_delete_own_account() {
+        let mut config = get_throttled_config();
+        config.enable_self_service_deletion = true;
+        let handler = SqlOpaqueHandler::new(config, get_initialized_db().await);
+        insert_user(&handler, "bob", "bob00").await;
+        let delete = |password: &str| {
+            handler.check_delete_own_account(&BindRequest {
+                name: UserId::new("bob"),
+                password: password.to_owned(),
+                source: None,
+                context: None,
+            })
+        };
+        let bob_exists = || async {
+            model::User::find_by_id(UserId::new("bob"))
+                .one(&handler.sql_pool)
+                .await
+                .unwrap()
+                .is_some()
+        };
+        assert!(matches!(
+            delete("wrong_password").await.unwrap_err(),
+            DomainError::AuthenticationError(_)
+        ));
+        assert!(bob_exists().await);
+        delete("bob00").await.unwrap();
+        assert!(!bob_exists().await);
+    }
+
+    #[tokio::test]
+    async fn test_delete_own_account_throttled() {
+        let mut config = get_throttled_config();
+        config.enable_self_service_deletion = true;
+        let handler = SqlOpaqueHandler::new(config, get_initialized_db().await);
+        insert_user(&handler, "bob", "bob00").await;
+        let request = |password: &str| BindRequest {
+            name: UserId::new("bob"),
+            password: password.to_owned(),
+            source: None,
+            context: None,
+        };
+        for _ in 0..3 {
+            handler
+                .check_delete_own_account(&request("wrong_password"))
+                .await
+                .unwrap_err();
+        }
+        assert!(matches!(
+            handler
+                .check_delete_own_account(&request("bob00"))
+                .await
+                .unwrap_err(),
+            DomainError::RateLimited(..)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_disabled_account() {
+        use crate::domain::handler::BackendHandler;
+        let handler = SqlOpaqueHandler::new(get_default_config(), get_initialized_db().await);
+        insert_user(&handler, "bob", "bob00").await;
+        let bob = UserId::new("bob");
+        handler.set_user_enabled(&bob, false).await.unwrap();
+        for password in ["bob00", "wrong"] {
+            let err = bind(&handler, "bob", password).await.unwrap_err();
+            assert!(matches!(err, DomainError::AccountDisabled(_)), "{}", err);
+        }
+        let err = attempt_login(&handler, "bob", "bob00").await.unwrap_err();
+        assert!(matches!(err, DomainError::AccountDisabled(_)), "{}", err);
+        handler.set_user_enabled(&bob, true).await.unwrap();
+        bind(&handler, "bob", "bob00").await.unwrap();
+        attempt_login(&handler, "bob", "bob00").await.unwrap();
+        assert!(matches!(
+            handler
+                .set_user_enabled(&UserId::new("unknown"), false)
+                .await,
+            Err(DomainError::EntityNotFound(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_account_disabled_during_login() {
+        use crate::domain::handler::BackendHandler;
+        let handler = SqlOpaqueHandler::new(get_default_config(), get_initialized_db().await);
+        insert_user(&handler, "bob", "bob00").await;
+        let mut rng = rand::rngs::OsRng;
+        let login_start = opaque::client::login::start_login("bob00", &mut rng).unwrap();
+        let start_response = handler
+            .login_start(
+                login::ClientLoginStartRequest {
+                    username: UserId::new("bob"),
+                    login_start_request: login_start.message,
+                    challenge: None,
+                    context: None,
+                },
+                None,
+            )
+            .await
+            .unwrap();
+        handler
+            .set_user_enabled(&UserId::new("bob"), false)
+            .await
+            .unwrap();
+        let login_finish = opaque::client::login::finish_login(
+            login_start.state,
+            start_response.credential_response,
+        )
+        .unwrap();
+        let err = handler
+            .login_finish(
+                login::ClientLoginFinishRequest {
+                    server_data: start_response.server_data,
+                    credential_finalization: login_finish.message,
+                    consent: None,
+                    device_fingerprint: None,
+                    username: Some(UserId::new("bob")),
+                    totp_code: None,
+                },
+                None,
+            )
+            .await
+            .unwrap_err();
+        assert!(matches!(err, DomainError::AccountDisabled(_)), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn test_delete_own_account_disabled() {
+        let handler = SqlOpaqueHandler::new(get_default_config(), get_initialized_db().await);
+        insert_user(&handler, "bob", "bob00").await;
+        handler
+            .check_delete_own_account(&BindRequest {
+                name: UserId::new("bob"),
+                password: "bob00".to_owned(),
+                source: None,
+                context: None,
+            })
+            .await
+            .unwrap_err();
+        assert!(model::User::find_by_id(UserId::new("bob"))
+            .one(&handler.sql_pool)
+            .await
+            .unwrap()
+            .is_some());
+    }
+
+    fn get_throttled_config() -> crate::infra::configuration::Configuration {
+        let mut config = get_default_config();
+        config.throttle_options.max_failed_logins = 3;
+        config
+    }
+
+    async fn bind(handler: &SqlOpaqueHandler, name: &str, password: &str) -> Result<()> {
+        handler
+            .bind(BindRequest {
+                name: UserId::new(name),
+                password: password.to_string(),
+                source: None,
+                context: None,
+            })
+            .await
+    }
+
+    #[tokio::test]
+    async fn test_bind_throttled_after_failures() {
+        let sql_pool = get_initialized_db().await;
+        let handler = SqlOpaqueHandler::new(get_throttled_config(), sql_pool);
+        insert_user(&handler, "bob", "bob00").await;
+        insert_user(&handler, "john", "john00").await;
+        for _ in 0..3 {
+            assert!(matches!(
+                bind(&handler, "bob", "wrong_password").await.unwrap_err(),
+                DomainError::AuthenticationError(_)
+            ));
+        }
+        assert!(matches!(
+            bind(&handler, "bob", "bob00").await.unwrap_err(),
+            DomainError::RateLimited(..)
+        ));
+        assert!(matches!(
+            attempt_login(&handler, "bob", "bob00").await.unwrap_err(),
+            DomainError::RateLimited(..)
+        ));
+        // Other users are not affected.
+        bind(&handler, "john", "john00").await.unwrap();
+    }
+
+    fn get_lockout_config() -> crate::infra::configuration::Configuration {
+        let mut config = get_default_config();
+        config.account_lockout_threshold = 3;
+        config.account_lockout_window_seconds = 600;
+        config
+    }
+
+    async fn get_failed_login_count(handler: &SqlOpaqueHandler, user: &str) -> i32 {
+        model::User::find_by_id(UserId::new(user))
+            .one(&handler.sql_pool)
+            .await
+            .unwrap()
+            .unwrap()
+            .failed_login_count
+    }
+
+    #[tokio::test]
+    async fn test_account_locked_after_wrong_passwords() {
+        let handler = SqlOpaqueHandler::new(get_lockout_config(), get_initialized_db().await);
+        insert_user(&handler, "bob", "bob00").await;
+        insert_user(&handler, "john", "john00").await;
+        // A successful login starts the count again.
+        for _ in 0..2 {
+            bind(&handler, "bob", "wrong_password").await.unwrap_err();
+        }
+        assert_eq!(get_failed_login_count(&handler, "bob").await, 2);
+        bind(&handler, "bob", "bob00").await.unwrap();
+        assert_eq!(get_failed_login_count(&handler, "bob").await, 0);
+        for _ in 0..3 {
+            bind(&handler, "bob", "wrong_password").await.unwrap_err();
+        }
+        // Even the right password is refused now.
+        assert!(matches!(
+            bind(&handler, "bob", "bob00").await.unwrap_err(),
+            DomainError::AccountLocked(_)
+        ));
+        assert!(matches!(
+            attempt_login(&handler, "bob", "bob00").await.unwrap_err(),
+            DomainError::AccountLocked(_)
+        ));
+        // Other users are not affected.
+        bind(&handler, "john", "john00").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_account_unlocked_after_window() {
+        let handler = SqlOpaqueHandler::new(get_lockout_config(), get_initialized_db().await);
+        insert_user(&handler, "bob", "bob00").await;
+        for _ in 0..3 {
+            bind(&handler, "bob", "wrong_password").await.unwrap_err();
+        }
+        assert!(matches!(
+            bind(&handler, "bob", "bob00").await.unwrap_err(),
+            DomainError::AccountLocked(_)
+        ));
+        // The window passes.
+        model::User::update_many()
+            .col_expr(
+                UserColumn::LockedUntil,
+                Expr::value(chrono::Utc::now().naive_utc() - chrono::Duration::seconds(1)),
+            )
+            .filter(UserColumn::UserId.eq("bob"))
+            .exec(&handler.sql_pool)
+            .await
+            .unwrap();
+        bind(&handler, "bob", "bob00").await.unwrap();
+        attempt_login(&handler, "bob", "bob00").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_account_lockout_disabled_by_default() {
+        let handler = SqlOpaqueHandler::new(get_default_config(), get_initialized_db().await);
+        insert_user(&handler, "bob", "bob00").await;
+        for _ in 0..10 {
+            bind(&handler, "bob", "wrong_password").await.unwrap_err();
+        }
+        bind(&handler, "bob", "bob00").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_health_bind_not_throttled() {
+        let sql_pool = get_initialized_db().await;
+        let mut config = get_throttled_config();
+        config.enable_auth_events = true;
+        let handler = SqlOpaqueHandler::new(config, sql_pool);
+        insert_user(&handler, "bob", "bob00").await;
+        let health_bind = |password: &'static str| {
+            handler.health_bind(BindRequest {
+                name: UserId::new("bob"),
+                password: password.to_string(),
+                source: None,
+                context: None,
+            })
+        };
+        for _ in 0..5 {
+            assert!(matches!(
+                health_bind("wrong_password").await.unwrap_err(),
+                DomainError::AuthenticationError(_)
+            ));
+        }
+        health_bind("bob00").await.unwrap();
+        bind(&handler, "bob", "bob00").await.unwrap();
+        let report = handler
+            .get_failed_login_report(&UserId::new("bob"), chrono::Duration::hours(1))
+            .await
+            .unwrap();
+        assert_eq!(report.failures, vec![]);
+        // Normal binds are still throttled.
+        for _ in 0..3 {
+            bind(&handler, "bob", "wrong_password").await.unwrap_err();
+        }
+        assert!(matches!(
+            bind(&handler, "bob", "bob00").await.unwrap_err(),
+            DomainError::RateLimited(..)
+        ));
+        health_bind("bob00").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_successful_bind_resets_throttle() {
+        let sql_pool = get_initialized_db().await;
+        let handler = SqlOpaqueHandler::new(get_throttled_config(), sql_pool);
+        insert_user(&handler, "bob", "bob00").await;
+        for _ in 0..2 {
+            bind(&handler, "bob", "wrong_password").await.unwrap_err();
+        }
+        bind(&handler, "bob", "bob00").await.unwrap();
+        for _ in 0..2 {
+            bind(&handler, "bob", "wrong_password").await.unwrap_err();
+        }
+        bind(&handler, "bob", "bob00").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_compare_password_has_no_side_effects() {
+        let sql_pool = get_initialized_db().await;
+        let mut config = get_throttled_config();
+        config.track_login_statistics = true;
+        let handler = SqlOpaqueHandler::new(config, sql_pool);
+        insert_user(&handler, "bob", "bob00").await;
+        let compare = |password: &'static str| {
+            handler.compare_password(BindRequest {
+                name: UserId::new("bob"),
+                password: password.to_string(),
+                source: None,
+                context: None,
+            })
+        };
+        bind(&handler, "bob", "wrong_password").await.unwrap_err();
+        assert!(!compare("wrong_password").await.unwrap());
+        assert!(compare("bob00").await.unwrap());
+        assert_eq!(
+            handler
+                .get_last_login_date(&UserId::new("bob"))
+                .await
+                .unwrap(),
+            None
+        );
+        // Unlike a bind, the match didn't reset the failures.
+        bind(&handler, "bob", "wrong_password").await.unwrap_err();
+        assert!(matches!(
+            bind(&handler, "bob", "bob00").await.unwrap_err(),
+            DomainError::RateLimited(..)
+        ));
+        assert!(matches!(
+            compare("bob00").await.unwrap_err(),
+            DomainError::RateLimited(..)
+        ));
+    }
+
+    async fn bind_from(
+        handler: &SqlOpaqueHandler,
+        name: &str,
+        password: &str,
+        source: &str,
+    ) -> Result<()> {
+        handler
+            .bind(BindRequest {
+                name: UserId::new(name),
+                password: password.to_string(),
+                source: Some(source.parse().unwrap()),
+                context: None,
+            })
+            .await
+    }
+
+    #[tokio::test]
+    async fn test_throttle_attack_distributed_over_addresses() {
+        let mut config = get_throttled_config();
+        config.throttle_options.max_failed_logins_per_source = 3;
+        let handler = SqlOpaqueHandler::new(config, get_initialized_db().await);
+        insert_user(&handler, "bob", "bob00").await;
+        for source in ["192.0.2.1", "192.0.2.2", "192.0.2.3"] {
+            bind_from(&handler, "bob", "wrong_password", source)
+                .await
+                .unwrap_err();
+        }
+        // No address reached its limit, but the user did.
+        assert!(matches!(
+            bind_from(&handler, "bob", "bob00", "192.0.2.4")
+                .await
+                .unwrap_err(),
+            DomainError::RateLimited(..)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_throttle_password_spraying() {
+        let mut config = get_default_config();
+        config.throttle_options.max_failed_logins_per_source = 3;
+        let handler = SqlOpaqueHandler::new(config, get_initialized_db().await);
+        for user in ["bob", "john", "jane", "eve"] {
+            insert_user(&handler, user, &format!("{}00", user)).await;
+        }
+        // One wrong password for each user, from the same address.
+        for user in ["bob", "john", "jane"] {
+            bind_from(&handler, user, "wrong_password", "192.0.2.1")
+                .await
+                .unwrap_err();
+        }
+        assert!(matches!(
+            bind_from(&handler, "eve", "eve00", "192.0.2.1")
+                .await
+                .unwrap_err(),
+            DomainError::RateLimited(..)
+        ));
+        let mut rng = rand::rngs::OsRng;
+        let login_start = opaque::client::login::start_login("eve00", &mut rng).unwrap();
+        assert!(matches!(
+            handler
+                .login_start(
+                    login::ClientLoginStartRequest {
+                        username: UserId::new("eve"),
+                        login_start_request: login_start.message,
+                        challenge: None,
+                        context: None,
+                    },
+                    Some("192.0.2.1".parse().unwrap()),
+                )
+                .await
+                .unwrap_err(),
+            DomainError::RateLimited(..)
+        ));
+        // The users can still log in from elsewhere, and that doesn't lift the limit.
+        bind_from(&handler, "bob", "bob00", "192.0.2.2")
+            .await
+            .unwrap();
+        bind(&handler, "bob", "bob00").await.unwrap();
+        assert!(matches!(
+            bind_from(&handler, "bob", "bob00", "192.0.2.1")
+                .await
+                .unwrap_err(),
+            DomainError::RateLimited(..)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_throttle_per_user_and_source() {
+        let mut config = get_default_config();
+        config
+            .throttle_options
+            .max_failed_logins_per_user_and_source = 2;
+        let handler = SqlOpaqueHandler::new(config, get_initialized_db().await);
+        insert_user(&handler, "bob", "bob00").await;
+        insert_user(&handler, "john", "john00").await;
+        for _ in 0..2 {
+            bind_from(&handler, "bob", "wrong_password", "192.0.2.1")
+                .await
+                .unwrap_err();
+        }
+        assert!(matches!(
+            bind_from(&handler, "bob", "bob00", "192.0.2.1")
+                .await
+                .unwrap_err(),
+            DomainError::RateLimited(..)
+        ));
+        bind_from(&handler, "bob", "bob00", "192.0.2.2")
+            .await
+            .unwrap();
+        bind_from(&handler, "john", "john00", "192.0.2.1")
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_throttle_shared_between_instances() {
+        use crate::domain::throttle::MemoryThrottleStore;
+        // Stands in for a distributed store: both instances see the same counters.
+        let shared_store = std::sync::Arc::new(MemoryThrottleStore::default());
+        let sql_pool = get_initialized_db().await;
+        let first = SqlOpaqueHandler::new(get_throttled_config(), sql_pool.clone())
+            .with_throttle_store(shared_store.clone());
+        let second = SqlOpaqueHandler::new(get_throttled_config(), sql_pool)
+            .with_throttle_store(shared_store);
+        insert_user(&first, "bob", "bob00").await;
+        bind(&first, "bob", "wrong_password").await.unwrap_err();
+        attempt_login(&second, "bob", "wrong_password")
+            .await
+            .unwrap_err();
+        bind(&first, "bob", "wrong_password").await.unwrap_err();
+        assert!(matches!(
+            bind(&second, "bob", "bob00").await.unwrap_err(),
+            DomainError::RateLimited(..)
+        ));
+    }
+
+    async fn attempt_login_in_context(
+        opaque_handler: &SqlOpaqueHandler,
+        username: &str,
+        password: &str,
+        context: Option<&str>,
+    ) -> Result<()> {
+        let mut rng = rand::rngs::OsRng;
+        use login::*;
+        let login_start = opaque::client::login::start_login(password, &mut rng)?;
+        let start_response = opaque_handler
+            .login_start(
+                ClientLoginStartRequest {
+                    username: UserId::new(username),
+                    login_start_request: login_start.message,
+                    challenge: None,
+                    context: context.map(str::to_owned),
+                },
+                None,
+            )
+            .await?;
+        let login_finish = opaque::client::login::finish_login(
+            login_start.state,
+            start_response.credential_response,
+        )?;
+        opaque_handler
+            .login_finish(
+                ClientLoginFinishRequest {
+                    server_data: start_response.server_data,
+                    credential_finalization: login_finish.message,
+                    consent: None,
+                    device_fingerprint: None,
+                    username: Some(UserId::new(username)),
+                    totp_code: None,
+                },
+                None,
+            )
+            .await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_invalidate_all_passwords() {
+        let handler = SqlOpaqueHandler::new(get_default_config(), get_initialized_db().await);
+        insert_user(&handler, "bob", "bob00").await;
+        insert_user(&handler, "john", "john00").await;
+        insert_user_no_password(&handler, "jane").await;
+        assert_eq!(handler.invalidate_all_passwords().await.unwrap(), 2);
+        bind(&handler, "bob", "bob00").await.unwrap_err();
+        attempt_login(&handler, "john", "john00").await.unwrap_err();
+        assert!(handler
+            .must_change_password(&UserId::new("bob"))
+            .await
+            .unwrap());
+        // Only the users who had a password are asked for a new one.
+        assert!(!handler
+            .must_change_password(&UserId::new("jane"))
+            .await
+            .unwrap());
+        register_password(
+            &handler,
+            UserId::new("bob"),
+            &SecUtf8::from("new_password"),
+            PasswordActor::SelfService,
+        )
+        .await
+        .unwrap();
+        bind(&handler, "bob", "new_password").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_login_in_flight_during_key_rotation() {
+        let sql_pool = get_initialized_db().await;
+        let old_config = get_default_config();
+        let old_handler = SqlOpaqueHandler::new(old_config.clone(), sql_pool.clone());
+        insert_user(&old_handler, "bob", "bob00").await;
+        let mut rng = rand::rngs::OsRng;
+        let login_start = opaque::client::login::start_login("bob00", &mut rng).unwrap();
+        let start_response = old_handler
+            .login_start(
+                login::ClientLoginStartRequest {
+                    username: UserId::new("bob"),
+                    login_start_request: login_start.message,
+                    challenge: None,
+                    context: None,
+                },
+                None,
+            )
+            .await
+            .unwrap();
+        old_handler.invalidate_all_passwords().await.unwrap();
+        let login_finish = opaque::client::login::finish_login(
+            login_start.state,
+            start_response.credential_response,
+        )
+        .unwrap();
+        let finish_request = login::ClientLoginFinishRequest {
+            server_data: start_response.server_data,
+            credential_finalization: login_finish.message,
+            consent: None,
+            device_fingerprint: None,
+            username: Some(UserId::new("bob")),
+            totp_code: None,
+        };
+        // Restarted with the new key.
+        let new_handler = SqlOpaqueHandler::new(get_default_config(), sql_pool.clone());
+        assert!(matches!(
+            new_handler
+                .login_finish(finish_request.clone(), None)
+                .await
+                .unwrap_err(),
+            DomainError::InvalidServerData(_)
+        ));
+        let mut new_config = get_default_config();
+        new_config.previous_server_setup = Some(old_config.get_server_setup().clone());
+        let new_handler = SqlOpaqueHandler::new(new_config, sql_pool);
+        assert!(matches!(
+            new_handler
+                .login_finish(finish_request, None)
+                .await
+                .unwrap_err(),
+            DomainError::CredentialsChanged(_)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_password_file_bound_to_context() {
+        let mut config = get_default_config();
+        config.password_contexts = vec!["api".to_owned(), "vpn".to_owned()];
+        let handler = SqlOpaqueHandler::new(config, get_initialized_db().await);
+        insert_user_no_password(&handler, "bob").await;
+        register_password_for_context(
+            &handler,
+            UserId::new("bob"),
+            Some("api"),
+            &SecUtf8::from("api_password"),
+            PasswordActor::SelfService,
+        )
+        .await
+        .unwrap();
+        attempt_login_in_context(&handler, "bob", "api_password", Some("api"))
+            .await
+            .unwrap();
+        // Only in its context: the other contexts and the primary password have their own files.
+        attempt_login_in_context(&handler, "bob", "api_password", Some("vpn"))
+            .await
+            .unwrap_err();
+        attempt_login_in_context(&handler, "bob", "api_password", None)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            attempt_login_in_context(&handler, "bob", "api_password", Some("web"))
+                .await
+                .unwrap_err(),
+            DomainError::EntityNotFound(_)
+        ));
+        assert!(matches!(
+            register_password_for_context(
+                &handler,
+                UserId::new("bob"),
+                Some("web"),
+                &SecUtf8::from("web_password"),
+                PasswordActor::SelfService,
+            )
+            .await
+            .unwrap_err(),
+            DomainError::EntityNotFound(_)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_password_contexts() {
+        let mut config = get_default_config();
+        config.password_contexts = vec!["api".to_owned()];
+        let handler = SqlOpaqueHandler::new(config, get_initialized_db().await);
+        insert_user(&handler, "bob", "bob00").await;
+        register_password_for_context(
+            &handler,
+            UserId::new("bob"),
+            Some("api"),
+            &SecUtf8::from("api_password"),
+            PasswordActor::SelfService,
+        )
+        .await
+        .unwrap();
+        let bind = |password: &str, context: Option<&str>| {
+            handler.bind(BindRequest {
+                name: UserId::new("bob"),
+                password: password.to_owned(),
+                source: None,
+                context: context.map(str::to_owned),
+            })
+        };
+        bind("bob00", None).await.unwrap();
+        bind("api_password", None).await.unwrap_err();
+        bind("api_password", Some("api")).await.unwrap();
+        bind("bob00", Some("api")).await.unwrap_err();
+        attempt_login_in_context(&handler, "bob", "bob00", None)
+            .await
+            .unwrap();
+        attempt_login_in_context(&handler, "bob", "bob00", Some("api"))
+            .await
+            .unwrap_err();
+        // Changing one password doesn't change the other.
+        register_password(
+            &handler,
+            UserId::new("bob"),
+            &SecUtf8::from("bob01"),
+            PasswordActor::SelfService,
+        )
+        .await
+        .unwrap();
+        bind("api_password", Some("api")).await.unwrap();
+        bind("bob01", None).await.unwrap();
+        assert!(matches!(
+            bind("bob01", Some("web")).await,
+            Err(DomainError::EntityNotFound(_))
+        ));
+        // Like the primary passwords, they all go with a change of the server setup.
+        handler.invalidate_password_files().await.unwrap();
+        bind("api_password", Some("api")).await.unwrap_err();
+    }
+
+    #[tokio::test]
+    async fn test_password_context_without_password() {
+        let mut config = get_default_config();
+        config.password_contexts = vec!["api".to_owned()];
+        let handler = SqlOpaqueHandler::new(config, get_initialized_db().await);
+        insert_user(&handler, "bob", "bob00").await;
+        handler
+            .bind(BindRequest {
+                name: UserId::new("bob"),
+                password: "bob00".to_owned(),
+                source: None,
+                context: Some("api".to_owned()),
+            })
+            .await
+            .unwrap_err();
+        attempt_login_in_context(&handler, "bob", "bob00", Some("api"))
+            .await
+            .unwrap_err();
+    }
+
+    #[tokio::test]
+    async fn test_transfer_credential_identity_bound() {
+        let handler = SqlOpaqueHandler::new(get_default_config(), get_initialized_db().await);
+        insert_user(&handler, "bob", "bob00").await;
+        insert_user_no_password(&handler, "alice").await;
+        let (bob, alice) = (UserId::new("bob"), UserId::new("alice"));
+        handler.transfer_credential(&bob, &alice).await.unwrap_err();
+        bind(&handler, "bob", "bob00").await.unwrap();
+        bind(&handler, "alice", "bob00").await.unwrap_err();
+
+        // That's why: a copy of the file, even with a valid checksum, doesn't verify for alice.
+        let password_file = model::User::find_by_id(bob)
+            .one(&handler.sql_pool)
+            .await
+            .unwrap()
+            .unwrap()
+            .password_hash
+            .unwrap();
+        model::User::update_many()
+            .col_expr(
+                UserColumn::PasswordHashChecksum,
+                Expr::value(handler.password_file_checksum(&alice, &password_file)),
+            )
+            .col_expr(UserColumn::PasswordHash, Expr::value(password_file))
+            .filter(UserColumn::UserId.eq(&alice))
+            .exec(&handler.sql_pool)
+            .await
+            .unwrap();
+        bind(&handler, "alice", "bob00").await.unwrap_err();
+        attempt_login(&handler, "alice", "bob00").await.unwrap_err();
+    }
+
+    #[tokio::test]
+    async fn test_transfer_credential_with_stable_identity() {
+        let mut config = get_default_config();
+        config.transferable_password_files = true;
+        let handler = SqlOpaqueHandler::new(config, get_initialized_db().await);
+        insert_user(&handler, "bob", "bob00").await;
+        insert_user_no_password(&handler, "alice").await;
+        insert_user(&handler, "carol", "carol00").await;
+        let (bob, alice, carol) = (
+            UserId::new("bob"),
+            UserId::new("alice"),
+            UserId::new("carol"),
+        );
+        handler.transfer_credential(&bob, &alice).await.unwrap();
+        bind(&handler, "alice", "bob00").await.unwrap();
+        attempt_login(&handler, "alice", "bob00").await.unwrap();
+        bind(&handler, "bob", "bob00").await.unwrap_err();
+        // Nothing left to transfer.
+        handler.transfer_credential(&bob, &carol).await.unwrap_err();
+        bind(&handler, "carol", "carol00").await.unwrap();
+
+        // The file keeps its identity when it moves again.
+        handler.transfer_credential(&alice, &carol).await.unwrap();
+        bind(&handler, "carol", "bob00").await.unwrap();
+        bind(&handler, "carol", "carol00").await.unwrap_err();
+
+        // A new password is bound to the user ID again.
+        register_password(
+            &handler,
+            carol.clone(),
+            &SecUtf8::from("carol01"),
+            PasswordActor::SelfService,
+        )
+        .await
+        .unwrap();
+        bind(&handler, "carol", "carol01").await.unwrap();
+        attempt_login(&handler, "carol", "carol01").await.unwrap();
+        let user = model::User::find_by_id(carol)
+            .one(&handler.sql_pool)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(user.password_identity, None);
+    }
+
+    #[tokio::test]
+    async fn test_transfer_credential_to_unknown_user() {
+        let mut config = get_default_config();
+        config.transferable_password_files = true;
+        let handler = SqlOpaqueHandler::new(config, get_initialized_db().await);
+        insert_user(&handler, "bob", "bob00").await;
+        handler
+            .transfer_credential(&UserId::new("bob"), &UserId::new("nobody"))
+            .await
+            .unwrap_err();
+        // The transaction was rolled back.
+        bind(&handler, "bob", "bob00").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_retry_after() {
+        let mut config = get_throttled_config();
+        config.throttle_options.window_seconds = 120;
+        config.throttle_options.max_registrations = 1;
+        let handler = SqlOpaqueHandler::new(config.clone(), get_initialized_db().await);
+        insert_user(&handler, "bob", "bob00").await;
+        for _ in 0..3 {
+            bind(&handler, "bob", "wrong_password").await.unwrap_err();
+        }
+        // Only when configured.
+        assert!(matches!(
+            bind(&handler, "bob", "bob00").await.unwrap_err(),
+            DomainError::RateLimited(_, None)
+        ));
+        config.throttle_options.send_retry_after = true;
+        let handler = SqlOpaqueHandler::new(config, handler.sql_pool.clone())
+            .with_throttle_store(handler.throttle_store.clone());
+        let retry_after = |e: DomainError| match e {
+            DomainError::RateLimited(_, Some(retry_after)) => retry_after.as_secs(),
+            e => panic!("Unexpected error: {}", e),
+        };
+        let remaining = retry_after(bind(&handler, "bob", "bob00").await.unwrap_err());
+        assert!((118..=120).contains(&remaining), "{}", remaining);
+        // The remaining window, not the full one.
+        handler
+            .throttle_store
+            .reset(&failed_login_key(&UserId::new("bob")))
+            .await
+            .unwrap();
+        handler
+            .throttle_store
+            .increment(
+                &failed_login_key(&UserId::new("bob")),
+                chrono::Duration::seconds(30),
+            )
+            .await
+            .unwrap();
+        for _ in 0..2 {
+            bind(&handler, "bob", "wrong_password").await.unwrap_err();
+        }
+        let remaining = retry_after(bind(&handler, "bob", "bob00").await.unwrap_err());
+        assert!((28..=30).contains(&remaining), "{}", remaining);
+        let source: IpAddr = "192.0.2.1".parse().unwrap();
+        registration_start_from(&handler, "bob", Some(source))
+            .await
+            .unwrap();
+        let remaining = retry_after(
+            registration_start_from(&handler, "bob", Some(source))
+                .await
+                .unwrap_err(),
+        );
+        assert!((118..=120).contains(&remaining), "{}", remaining);
+    }
+
+    async fn start_registration(
+        handler: &SqlOpaqueHandler,
+        username: &str,
+    ) -> (
+        opaque::client::registration::ClientRegistration,
+        registration::ServerRegistrationStartResponse,
+    ) {
+        let mut rng = rand::rngs::OsRng;
+        let registration_start =
+            opaque::client::registration::start_registration(b"password", &mut rng).unwrap();
+        let response = handler
+            .registration_start(
+                registration::ClientRegistrationStartRequest {
+                    username: UserId::new(username),
+                    registration_start_request: registration_start.message,
+                    context: None,
+                },
+                None,
+            )
+            .await
+            .unwrap();
+        (registration_start.state, response)
+    }
+
+    #[tokio::test]
+    async fn test_long_username_rejected_at_login() {
+        let sql_pool = get_initialized_db().await;
+        let mut config = get_default_config();
+        config.max_username_length = 8;
+        let handler = SqlOpaqueHandler::new(config, sql_pool);
+        let mut rng = rand::rngs::OsRng;
+        let login_start = opaque::client::login::start_login("password", &mut rng).unwrap();
+        let err = handler
+            .login_start(
+                login::ClientLoginStartRequest {
+                    username: UserId::new("bob_the_builder"),
+                    login_start_request: login_start.message,
+                    challenge: None,
+                    context: None,
+                },
+                None,
+            )
+            .await
+            .unwrap_err();
+        assert!(matches!(err, DomainError::InvalidUserId(_)), "{}", err);
+        assert!(matches!(
+            bind(&handler, "bob_the_builder", "password")
+                .await
+                .unwrap_err(),
+            DomainError::InvalidUserId(_)
+        ));
+        assert!(matches!(
+            registration_start_from(&handler, "bob_the_builder", None)
+                .await
+                .unwrap_err(),
+            DomainError::InvalidUserId(_)
+        ));
+        // Usernames within the limit go through the usual checks.
+        assert!(matches!(
+            bind(&handler, "bob", "password").await.unwrap_err(),
+            DomainError::AuthenticationError(_)
+        ));
+    }
+
+    async fn registration_start_from(
+        handler: &SqlOpaqueHandler,
+        username: &str,
+        source: Option<IpAddr>,
+    ) -> Result<registration::ServerRegistrationStartResponse> {
+        let mut rng = rand::rngs::OsRng;
+        let registration_start =
+            opaque::client::registration::start_registration(b"password", &mut rng).unwrap();
+        handler
+            .registration_start(
+                registration::ClientRegistrationStartRequest {
+                    username: UserId::new(username),
+                    registration_start_request: registration_start.message,
+                    context: None,
+                },
+                source,
+            )
+            .await
+    }
+
+    #[tokio::test]
+    async fn test_registration_throttle() {
+        let sql_pool = get_initialized_db().await;
+        let mut config = get_default_config();
+        config.throttle_options.max_registrations = 3;
+        let handler = SqlOpaqueHandler::new(config, sql_pool);
+        insert_user_no_password(&handler, "bob").await;
+        let source: IpAddr = "192.0.2.1".parse().unwrap();
+        for _ in 0..3 {
+            registration_start_from(&handler, "bob", Some(source))
+                .await
+                .unwrap();
+        }
+        assert!(matches!(
+            registration_start_from(&handler, "bob", Some(source))
+                .await
+                .unwrap_err(),
+            DomainError::RateLimited(..)
+        ));
+        // The limit is per source, and internal registrations are not limited.
+        registration_start_from(&handler, "bob", Some("192.0.2.2".parse().unwrap()))
+            .await
+            .unwrap();
+        registration_start_from(&handler, "bob", None)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_registration_throttle_disabled_by_default() {
+        let sql_pool = get_initialized_db().await;
+        let handler = SqlOpaqueHandler::new(get_default_config(), sql_pool);
+        insert_user_no_password(&handler, "bob").await;
+        for _ in 0..10 {
+            registration_start_from(&handler, "bob", Some("192.0.2.1".parse().unwrap()))
+                .await
+                .unwrap();
+        }
+    }
+
+    async fn finish_registration(
+        handler: &SqlOpaqueHandler,
+        username: &str,
+        client_state: opaque::client::registration::ClientRegistration,
+        response: registration::ServerRegistrationStartResponse,
+        server_data: String,
+    ) -> Result<()> {
+        finish_registration_with_transport(
+            handler,
+            username,
+            client_state,
+            response,
+            server_data,
+            true,
+        )
+        .await
+    }
+
+    async fn finish_registration_with_transport(
+        handler: &SqlOpaqueHandler,
+        username: &str,
+        client_state: opaque::client::registration::ClientRegistration,
+        response: registration::ServerRegistrationStartResponse,
+        server_data: String,
+        transport_secure: bool,
+    ) -> Result<()> {
+        let mut rng = rand::rngs::OsRng;
+        let registration_upload = opaque::client::registration::finish_registration(
+            client_state,
+            response.registration_response,
+            &mut rng,
+        )
+        .unwrap();
+        handler
+            .registration_finish(
+                registration::ClientRegistrationFinishRequest {
+                    server_data,
+                    registration_upload: registration_upload.message,
+                },
+                &UserId::new(username),
+                transport_secure,
+            )
+            .await
+    }
+
+    #[tokio::test]
+    async fn test_registration_requires_secure_transport() {
+        let sql_pool = get_initialized_db().await;
+        let mut config = get_default_config();
+        config.require_secure_transport_for_password_ops = true;
+        let handler = SqlOpaqueHandler::new(config, sql_pool);
+        insert_user_no_password(&handler, "bob").await;
+        let (client_state, response) = start_registration(&handler, "bob").await;
+        let server_data = response.server_data.clone();
+        assert!(matches!(
+            finish_registration_with_transport(
+                &handler,
+                "bob",
+                client_state,
+                response,
+                server_data,
+                false
+            )
+            .await
+            .unwrap_err(),
+            DomainError::InsecureTransport(_)
+        ));
+        attempt_login(&handler, "bob", "password")
+            .await
+            .unwrap_err();
+        let (client_state, response) = start_registration(&handler, "bob").await;
+        let server_data = response.server_data.clone();
+        finish_registration_with_transport(
+            &handler,
+            "bob",
+            client_state,
+            response,
+            server_data,
+            true,
+        )
+        .await
+        .unwrap();
+        attempt_login(&handler, "bob", "password").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_registration_insecure_transport_allowed_by_default() {
+        let sql_pool = get_initialized_db().await;
+        let handler = SqlOpaqueHandler::new(get_default_config(), sql_pool);
+        insert_user_no_password(&handler, "bob").await;
+        let (client_state, response) = start_registration(&handler, "bob").await;
+        let server_data = response.server_data.clone();
+        finish_registration_with_transport(
+            &handler,
+            "bob",
+            client_state,
+            response,
+            server_data,
+            false,
+        )
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_registration_finish_tampered_server_data() {
+        let sql_pool = get_initialized_db().await;
+        let handler = SqlOpaqueHandler::new(get_default_config(), sql_pool);
+        insert_user_no_password(&handler, "bob").await;
+        let (client_state, response) = start_registration(&handler, "bob").await;
+        let mut sealed = base64::engine::general_purpose::STANDARD
+            .decode(&response.server_data)
+            .unwrap();
+        let last = sealed.len() - 1;
+        sealed[last] ^= 1;
+        let tampered = base64::engine::general_purpose::STANDARD.encode(sealed);
+        assert!(matches!(
+            finish_registration(&handler, "bob", client_state, response, tampered)
+                .await
+                .unwrap_err(),
+            DomainError::InvalidServerData(_)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_registration_finish_database_failure() {
+        use sea_orm::ConnectionTrait;
+        let sql_pool = get_initialized_db().await;
+        let handler = SqlOpaqueHandler::new(get_default_config(), sql_pool.clone());
+        insert_user_no_password(&handler, "bob").await;
+        let (client_state, response) = start_registration(&handler, "bob").await;
+        sql_pool
+            .execute_unprepared("ALTER TABLE users RENAME TO users_backup")
+            .await
+            .unwrap();
+        let server_data = response.server_data.clone();
+        assert!(matches!(
+            finish_registration(&handler, "bob", client_state, response, server_data)
+                .await
+                .unwrap_err(),
+            DomainError::DatabaseError(_)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_login_banner_requires_consent() {
+        let sql_pool = get_initialized_db().await;
+        let mut config = get_default_config();
+        config.login_banner = Some("Authorized use only".to_owned());
+        let handler = SqlOpaqueHandler::new(config, sql_pool);
+        insert_user(&handler, "bob", "bob00").await;
+        assert!(matches!(
+            attempt_login_with_options(&handler, "bob", "bob00", None, false, None, None)
+                .await
+                .unwrap_err(),
+            DomainError::AuthenticationError(_)
+        ));
+        attempt_login_with_options(&handler, "bob", "bob00", None, true, None, None)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_login_banner_wrong_consent() {
+        let sql_pool = get_initialized_db().await;
+        let mut config = get_default_config();
+        config.login_banner = Some("Authorized use only".to_owned());
+        let handler = SqlOpaqueHandler::new(config, sql_pool);
+        insert_user(&handler, "bob", "bob00").await;
+        let mut rng = rand::rngs::OsRng;
+        let login_start = opaque::client::login::start_login("bob00", &mut rng).unwrap();
+        let start_response = handler
+            .login_start(
+                login::ClientLoginStartRequest {
+                    username: UserId::new("bob"),
+                    login_start_request: login_start.message,
+                    challenge: None,
+                    context: None,
+                },
+                None,
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            start_response.banner.as_deref(),
+            Some("Authorized use only")
+        );
+        let login_finish = opaque::client::login::finish_login(
+            login_start.state,
+            start_response.credential_response,
+        )
+        .unwrap();
+        handler
+            .login_finish(
+                login::ClientLoginFinishRequest {
+                    server_data: start_response.server_data,
+                    credential_finalization: login_finish.message,
+                    consent: Some(login::banner_consent("Another banner")),
+                    device_fingerprint: None,
+                    username: None,
+                    totp_code: None,
+                },
+                None,
+            )
+            .await
+            .unwrap_err();
+    }
+
+    #[tokio::test]
+    async fn test_login_finish_for_another_user() {
+        use crate::domain::handler::BackendHandler;
+        let sql_pool = get_initialized_db().await;
+        let mut config = get_default_config();
+        config.enable_auth_events = true;
+        let handler = SqlOpaqueHandler::new(config, sql_pool);
+        insert_user(&handler, "bob", "bob00").await;
+        insert_user(&handler, "john", "john00").await;
+        async fn start(
+            handler: &SqlOpaqueHandler,
+            username: &str,
+            password: &str,
+        ) -> (
+            opaque::client::login::ClientLogin,
+            login::ServerLoginStartResponse,
+        ) {
+            let mut rng = rand::rngs::OsRng;
+            let login_start = opaque::client::login::start_login(password, &mut rng).unwrap();
+            let response = handler
+                .login_start(
+                    login::ClientLoginStartRequest {
+                        username: UserId::new(username),
+                        login_start_request: login_start.message,
+                        challenge: None,
+                        context: None,
+                    },
+                    None,
+                )
+                .await
+                .unwrap();
+            (login_start.state, response)
+        }
+        let (bob_state, bob_response) = start(&handler, "bob", "bob00").await;
+        let (_, john_response) = start(&handler, "john", "john00").await;
+        let bob_finish =
+            opaque::client::login::finish_login(bob_state, bob_response.credential_response)
+                .unwrap();
+        let err = handler
+            .login_finish(
+                login::ClientLoginFinishRequest {
+                    server_data: john_response.server_data,
+                    credential_finalization: bob_finish.message,
+                    consent: None,
+                    device_fingerprint: None,
+                    username: Some(UserId::new("bob")),
+                    totp_code: None,
+                },
+                None,
+            )
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(err, DomainError::AuthenticationError(_)),
+            "{}",
+            err
+        );
+        let report = handler
+            .failed_login_report(&UserId::new("john"), chrono::Duration::hours(1))
+            .await
+            .unwrap();
+        assert_eq!(
+            report.failures_by_reason,
+            std::collections::BTreeMap::from([(LoginFailureReason::UserMismatch, 1)])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_logins_are_all_counted() {
+        let sql_pool = get_initialized_db().await;
+        let mut config = get_default_config();
+        config.track_login_statistics = true;
+        let handler = SqlOpaqueHandler::new(config, sql_pool);
+        insert_user(&handler, "bob", "bob00").await;
+        futures::future::join_all((0..20).map(|_| attempt_login(&handler, "bob", "bob00")))
+            .await
+            .into_iter()
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        let user = model::User::find_by_id(UserId::new("bob"))
+            .one(&handler.sql_pool)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(user.login_count, 20);
+        assert!(user.last_login_date.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_last_login_date_only_bumped_by_successful_logins() {
+        let mut config = get_default_config();
+        config.track_login_statistics = true;
+        let handler = SqlOpaqueHandler::new(config, get_initialized_db().await);
+        insert_user(&handler, "bob", "bob00").await;
+        let bob = UserId::new("bob");
+        assert_eq!(handler.get_last_login_date(&bob).await.unwrap(), None);
+        bind(&handler, "bob", "wrong_password").await.unwrap_err();
+        assert_eq!(handler.get_last_login_date(&bob).await.unwrap(), None);
+
+        let before = chrono::Utc::now().naive_utc();
+        attempt_login(&handler, "bob", "bob00").await.unwrap();
+        let last_login = handler.get_last_login_date(&bob).await.unwrap().unwrap();
+        assert!(last_login >= before);
+
+        bind(&handler, "bob", "wrong_password").await.unwrap_err();
+        assert_eq!(
+            handler.get_last_login_date(&bob).await.unwrap(),
+            Some(last_login)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_login_statistics_disabled_by_default() {
+        let sql_pool = get_initialized_db().await;
+        let handler = SqlOpaqueHandler::new(get_default_config(), sql_pool);
+        insert_user(&handler, "bob", "bob00").await;
+        attempt_login(&handler, "bob", "bob00").await.unwrap();
+        let user = model::User::find_by_id(UserId::new("bob"))
+            .one(&handler.sql_pool)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(user.login_count, 0);
+        assert_eq!(user.last_login_date, None);
+    }
+
+    #[tokio::test]
+    async fn test_registration_handle_round_trip() {
+        let sql_pool = get_initialized_db().await;
+        let mut config = get_default_config();
+        config.enable_registration_handles = true;
+        let handler = SqlOpaqueHandler::new(config, sql_pool);
+        insert_user_no_password(&handler, "bob").await;
+        let (client_state, response) = start_registration(&handler, "bob").await;
+        assert_eq!(response.server_data.len(), 32);
+        let handle = response.server_data.clone();
+        finish_registration(&handler, "bob", client_state, response, handle.clone())
+            .await
+            .unwrap();
+        attempt_login(&handler, "bob", "password").await.unwrap();
+        // The handle can only be used once.
+        let (client_state, response) = start_registration(&handler, "bob").await;
+        assert!(matches!(
+            finish_registration(&handler, "bob", client_state, response, handle)
+                .await
+                .unwrap_err(),
+            DomainError::InvalidServerData(_)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_registration_handle_expiry() {
+        let sql_pool = get_initialized_db().await;
+        let mut config = get_default_config();
+        config.enable_registration_handles = true;
+        let handler = SqlOpaqueHandler::new(config, sql_pool);
+        insert_user_no_password(&handler, "bob").await;
+        let (client_state, response) = start_registration(&handler, "bob").await;
+        model::RegistrationStates::update_many()
+            .col_expr(
+                model::RegistrationStatesColumn::ExpiryDate,
+                Expr::value(chrono::Utc::now().naive_utc() - chrono::Duration::seconds(1)),
+            )
+            .exec(&handler.sql_pool)
+            .await
+            .unwrap();
+        let handle = response.server_data.clone();
+        assert!(matches!(
+            finish_registration(&handler, "bob", client_state, response, handle)
+                .await
+                .unwrap_err(),
+            DomainError::InvalidServerData(_)
+        ));
+        assert!(model::RegistrationStates::find()
+            .all(&handler.sql_pool)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_too_many_outstanding_registrations() {
+        let sql_pool = get_initialized_db().await;
+        let mut config = get_default_config();
+        config.enable_registration_handles = true;
+        config.max_outstanding_registrations_per_user = 2;
+        let handler = SqlOpaqueHandler::new(config, sql_pool);
+        insert_user_no_password(&handler, "bob").await;
+        insert_user_no_password(&handler, "patrick").await;
+        let (client_state, response) = start_registration(&handler, "bob").await;
+        start_registration(&handler, "bob").await;
+        assert!(matches!(
+            registration_start_from(&handler, "bob", None)
+                .await
+                .unwrap_err(),
+            DomainError::TooManyOutstandingRegistrations(_)
+        ));
+        // Other users are not affected.
+        start_registration(&handler, "patrick").await;
+        // Finishing a registration frees a slot.
+        let handle = response.server_data.clone();
+        finish_registration(&handler, "bob", client_state, response, handle)
+            .await
+            .unwrap();
+        start_registration(&handler, "bob").await;
+    }
+
+    #[tokio::test]
+    async fn test_expired_registrations_are_not_outstanding() {
+        let sql_pool = get_initialized_db().await;
+        let mut config = get_default_config();
+        config.enable_registration_handles = true;
+        config.max_outstanding_registrations_per_user = 1;
+        let handler = SqlOpaqueHandler::new(config, sql_pool);
+        insert_user_no_password(&handler, "bob").await;
+        start_registration(&handler, "bob").await;
+        model::RegistrationStates::update_many()
+            .col_expr(
+                model::RegistrationStatesColumn::ExpiryDate,
+                Expr::value(chrono::Utc::now().naive_utc() - chrono::Duration::seconds(1)),
+            )
+            .exec(&handler.sql_pool)
+            .await
+            .unwrap();
+        let (client_state, response) = start_registration(&handler, "bob").await;
+        // The expired state was evicted.
+        assert_eq!(
+            model::RegistrationStates::find()
+                .all(&handler.sql_pool)
+                .await
+                .unwrap()
+                .len(),
+            1
+        );
+        let handle = response.server_data.clone();
+        finish_registration(&handler, "bob", client_state, response, handle)
+            .await
+            .unwrap();
+        attempt_login(&handler, "bob", "password").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_totp_required_for_admins() {
+        use crate::domain::handler::BackendHandler;
+        let sql_pool = get_initialized_db().await;
+        let mut config = get_default_config();
+        config.totp_required_groups = vec!["lldap_admin".to_owned()];
+        let handler = SqlOpaqueHandler::new(config, sql_pool);
+        insert_user(&handler, "admin", "admin00").await;
+        insert_user(&handler, "bob", "bob00").await;
+        let admin_group = insert_group(&handler, "lldap_admin").await;
+        insert_membership(&handler, admin_group, "admin").await;
+        assert!(matches!(
+            attempt_login(&handler, "admin", "admin00")
+                .await
+                .unwrap_err(),
+            DomainError::AuthenticationError(_)
+        ));
+        attempt_login(&handler, "bob", "bob00").await.unwrap();
+        // Once enrolled, the admin can log in.
+        handler
+            .set_totp_secret(&UserId::new("admin"), Some(TOTP_SECRET.to_owned()))
+            .await
+            .unwrap();
+        attempt_login_with_totp(&handler, "admin", "admin00", Some(&current_totp_code()))
+            .await
+            .unwrap();
+    }
+
+    async fn expire_password(handler: &SqlOpaqueHandler, user: &str) {
+        model::User::update_many()
+            .col_expr(
+                UserColumn::PasswordChangedAt,
+                Expr::value(chrono::Utc::now().naive_utc() - chrono::Duration::days(100)),
+            )
+            .filter(UserColumn::UserId.eq(user))
+            .exec(&handler.sql_pool)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_password_expiry_grace_logins() {
+        let sql_pool = get_initialized_db().await;
+        let mut config = get_default_config();
+        config.password_expiry_days = 90;
+        config.password_expiry_grace_logins = 2;
+        let handler = SqlOpaqueHandler::new(config, sql_pool);
+        insert_user(&handler, "bob", "bob00").await;
+        attempt_login(&handler, "bob", "bob00").await.unwrap();
+        bind(&handler, "bob", "bob00").await.unwrap();
+        expire_password(&handler, "bob").await;
+        // Both kinds of logins use up the grace logins.
+        attempt_login(&handler, "bob", "bob00").await.unwrap();
+        bind(&handler, "bob", "bob00").await.unwrap();
+        let err = attempt_login(&handler, "bob", "bob00").await.unwrap_err();
+        assert!(matches!(err, DomainError::PasswordExpired(_)), "{}", err);
+        let err = bind(&handler, "bob", "bob00").await.unwrap_err();
+        assert!(matches!(err, DomainError::PasswordExpired(_)), "{}", err);
+        // A new password comes with a new set of grace logins for its own expiry.
+        register_password(
+            &handler,
+            UserId::new("bob"),
+            &SecUtf8::from("bob01"),
+            PasswordActor::SelfService,
+        )
+        .await
+        .unwrap();
+        attempt_login(&handler, "bob", "bob01").await.unwrap();
+        expire_password(&handler, "bob").await;
+        bind(&handler, "bob", "bob01").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_password_expiry_without_grace_logins() {
+        let sql_pool = get_initialized_db().await;
+        let mut config = get_default_config();
+        config.password_expiry_days = 90;
+        let handler = SqlOpaqueHandler::new(config, sql_pool);
+        insert_user(&handler, "bob", "bob00").await;
+        expire_password(&handler, "bob").await;
+        let err = bind(&handler, "bob", "bob00").await.unwrap_err();
+        assert!(matches!(err, DomainError::PasswordExpired(_)), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn test_password_change_revokes_refresh_tokens() {
+        use crate::infra::tcp_backend_handler::TcpBackendHandler;
+        use std::hash::{Hash, Hasher};
+        fn hash(token: &str) -> u64 {
+            let mut s = std::collections::hash_map::DefaultHasher::new();
+            token.hash(&mut s);
+            s.finish()
+        }
+        let handler = SqlOpaqueHandler::new(get_default_config(), get_initialized_db().await);
+        insert_user(&handler, "bob", "bob00").await;
+        insert_user(&handler, "john", "john00").await;
+        let bob = UserId::new("bob");
+        let john = UserId::new("john");
+        attempt_login(&handler, "bob", "bob00").await.unwrap();
+        let (bob_token, _) = handler
+            .create_refresh_token(&bob, None, None)
+            .await
+            .unwrap();
+        let (john_token, _) = handler
+            .create_refresh_token(&john, None, None)
+            .await
+            .unwrap();
+        assert!(handler.check_token(hash(&bob_token), &bob).await.unwrap());
+        register_password(
+            &handler,
+            bob.clone(),
+            &SecUtf8::from("bob01"),
+            PasswordActor::SelfService,
+        )
+        .await
+        .unwrap();
+        assert!(!handler.check_token(hash(&bob_token), &bob).await.unwrap());
+        assert!(handler.check_token(hash(&john_token), &john).await.unwrap());
+        // Disabling the user revokes them too.
+        handler
+            .set_users_enabled(&[john.clone()], false)
+            .await
+            .unwrap();
+        assert!(!handler.check_token(hash(&john_token), &john).await.unwrap());
+    }
+
+    /// "12345678901234567890" in base32.
+    const TOTP_SECRET: &str = "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ";
+
+    fn totp_code_at(time: i64) -> String {
+        totp::code_at(&totp::decode_secret(TOTP_SECRET).unwrap(), time, 30)
+    }
+
+    fn current_totp_code() -> String {
+        totp_code_at(chrono::Utc::now().timestamp())
+    }
+
+    async fn attempt_login_with_totp(
+        opaque_handler: &SqlOpaqueHandler,
+        username: &str,
+        password: &str,
+        totp_code: Option<&str>,
+    ) -> Result<SuccessfulLogin> {
+        attempt_login_with_options(
+            opaque_handler,
+            username,
+            password,
+            None,
+            true,
+            None,
+            totp_code,
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn test_totp_code_required_with_secret() {
+        use crate::domain::handler::BackendHandler;
+        let handler = SqlOpaqueHandler::new(get_default_config(), get_initialized_db().await);
+        insert_user(&handler, "bob", "bob00").await;
+        // Without a secret, no code is needed.
+        attempt_login(&handler, "bob", "bob00").await.unwrap();
+        handler
+            .set_totp_secret(&UserId::new("bob"), Some(TOTP_SECRET.to_lowercase()))
+            .await
+            .unwrap();
+        let err = attempt_login(&handler, "bob", "bob00").await.unwrap_err();
+        assert!(matches!(err, DomainError::TotpRequired(_)), "{}", err);
+        attempt_login_with_totp(&handler, "bob", "bob00", Some(&current_totp_code()))
+            .await
+            .unwrap();
+        // The code doesn't replace the password.
+        let err = attempt_login_with_totp(&handler, "bob", "wrong", Some(&current_totp_code()))
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(err, DomainError::AuthenticationProtocolError(_)),
+            "{}",
+            err
+        );
+        handler
+            .set_totp_secret(&UserId::new("bob"), None)
+            .await
+            .unwrap();
+        attempt_login(&handler, "bob", "bob00").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_expired_totp_code() {
+        use crate::domain::handler::BackendHandler;
+        let sql_pool = get_initialized_db().await;
+        let mut config = get_default_config();
+        config.enable_auth_events = true;
+        let handler = SqlOpaqueHandler::new(config, sql_pool);
+        insert_user(&handler, "bob", "bob00").await;
+        handler
+            .set_totp_secret(&UserId::new("bob"), Some(TOTP_SECRET.to_owned()))
+            .await
+            .unwrap();
+        let expired_code = totp_code_at(chrono::Utc::now().timestamp() - 120);
+        let err = attempt_login_with_totp(&handler, "bob", "bob00", Some(&expired_code))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, DomainError::TotpRequired(_)), "{}", err);
+        let report = handler
+            .failed_login_report(&UserId::new("bob"), chrono::Duration::hours(1))
+            .await
+            .unwrap();
+        assert_eq!(
+            report.failures_by_reason,
+            std::collections::BTreeMap::from([(LoginFailureReason::InvalidTotpCode, 1)])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_set_invalid_totp_secret() {
+        use crate::domain::handler::BackendHandler;
+        let handler = SqlOpaqueHandler::new(get_default_config(), get_initialized_db().await);
+        insert_user(&handler, "bob", "bob00").await;
+        let err = handler
+            .set_totp_secret(&UserId::new("bob"), Some("secret".to_owned()))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, DomainError::InvalidTotpSecret(_)), "{}", err);
+        let err = handler
+            .set_totp_secret(&UserId::new("eve"), Some(TOTP_SECRET.to_owned()))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, DomainError::EntityNotFound(_)), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn test_registration_masks_user_existence() {
+        let sql_pool = get_initialized_db().await;
+        let mut config = get_default_config();
+        config.mask_registration_user_existence = true;
+        let handler = SqlOpaqueHandler::new(config, sql_pool);
+        insert_user_no_password(&handler, "bob").await;
+        let (bob_state, bob_response) = start_registration(&handler, "bob").await;
+        let (eve_state, eve_response) = start_registration(&handler, "eve").await;
+        assert_eq!(
+            bob_response.server_data.len(),
+            eve_response.server_data.len()
+        );
+        assert_eq!(
+            bincode::serialize(&bob_response.registration_response)
+                .unwrap()
+                .len(),
+            bincode::serialize(&eve_response.registration_response)
+                .unwrap()
+                .len()
+        );
+        let server_data = bob_response.server_data.clone();
+        finish_registration(&handler, "bob", bob_state, bob_response, server_data)
+            .await
+            .unwrap();
+        let server_data = eve_response.server_data.clone();
+        finish_registration(&handler, "eve", eve_state, eve_response, server_data)
+            .await
+            .unwrap();
+        attempt_login(&handler, "bob", "password").await.unwrap();
+        assert_eq!(get_user_names(&handler, None).await, vec!["bob"]);
+    }
+
+    #[tokio::test]
+    async fn test_registration_unknown_user_without_masking() {
+        let sql_pool = get_initialized_db().await;
+        let handler = SqlOpaqueHandler::new(get_default_config(), sql_pool);
+        let (client_state, response) = start_registration(&handler, "eve").await;
+        let server_data = response.server_data.clone();
+        assert!(matches!(
+            finish_registration(&handler, "eve", client_state, response, server_data)
+                .await
+                .unwrap_err(),
+            DomainError::EntityNotFound(_)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_registration_finish_deleted_user() {
+        let sql_pool = get_initialized_db().await;
+        let handler = SqlOpaqueHandler::new(get_default_config(), sql_pool);
+        insert_user_no_password(&handler, "bob").await;
+        let (client_state, response) = start_registration(&handler, "bob").await;
+        crate::domain::handler::UserBackendHandler::delete_user(&handler, &UserId::new("bob"))
+            .await
+            .unwrap();
+        let server_data = response.server_data.clone();
+        assert!(matches!(
+            finish_registration(&handler, "bob", client_state, response, server_data)
+                .await
+                .unwrap_err(),
+            DomainError::EntityNotFound(_)
+        ));
+    }
+
+    async fn get_overwrite_rejecting_handler() -> SqlOpaqueHandler {
+        let mut config = get_default_config();
+        config.reject_password_overwrite = true;
+        SqlOpaqueHandler::new(config, get_initialized_db().await)
+    }
+
+    #[tokio::test]
+    async fn test_reject_password_overwrite() {
+        let handler = get_overwrite_rejecting_handler().await;
+        insert_user_no_password(&handler, "bob").await;
+        let bob = UserId::new("bob");
+        // Setting the first password is allowed.
+        register_password(
+            &handler,
+            bob.clone(),
+            &SecUtf8::from("bob00"),
+            PasswordActor::SelfService,
+        )
+        .await
+        .unwrap();
+        assert!(matches!(
+            register_password(
+                &handler,
+                bob.clone(),
+                &SecUtf8::from("bob01"),
+                PasswordActor::SelfService
+            )
+            .await
+            .unwrap_err(),
+            DomainError::PasswordAlreadySet(_)
+        ));
+        attempt_login(&handler, "bob", "bob00").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_password_overwrite_allowed_once() {
+        let handler = get_overwrite_rejecting_handler().await;
+        insert_user(&handler, "bob", "bob00").await;
+        let bob = UserId::new("bob");
+        handler.allow_password_overwrite(&bob).await.unwrap();
+        register_password(
+            &handler,
+            bob.clone(),
+            &SecUtf8::from("bob01"),
+            PasswordActor::SelfService,
+        )
+        .await
+        .unwrap();
+        attempt_login(&handler, "bob", "bob01").await.unwrap();
+        assert!(matches!(
+            register_password(
+                &handler,
+                bob,
+                &SecUtf8::from("bob02"),
+                PasswordActor::SelfService
+            )
+            .await
+            .unwrap_err(),
+            DomainError::PasswordAlreadySet(_)
+        ));
+        assert!(matches!(
+            handler
+                .allow_password_overwrite(&UserId::new("eve"))
+                .await
+                .unwrap_err(),
+            DomainError::EntityNotFound(_)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_temporary_password_can_be_replaced() {
+        let handler = get_overwrite_rejecting_handler().await;
+        insert_user(&handler, "bob", "bob00").await;
+        let bob = UserId::new("bob");
+        handler.issue_temporary_password(&bob).await.unwrap();
+        register_password(
+            &handler,
+            bob,
+            &SecUtf8::from("bob-chosen"),
+            PasswordActor::SelfService,
+        )
+        .await
+        .unwrap();
+        attempt_login(&handler, "bob", "bob-chosen").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_public_server_setup_matches_login_key() {
+        let sql_pool = get_initialized_db().await;
+        let handler = SqlOpaqueHandler::new(get_default_config(), sql_pool);
+        insert_user(&handler, "bob", "bob00").await;
+        let mut rng = rand::rngs::OsRng;
+        let login_start = opaque::client::login::start_login("bob00", &mut rng).unwrap();
+        let start_response = handler
+            .login_start(
+                login::ClientLoginStartRequest {
+                    username: UserId::new("bob"),
+                    login_start_request: login_start.message,
+                    challenge: None,
+                    context: None,
+                },
+                None,
+            )
+            .await
+            .unwrap();
+        let login_finish = opaque::client::login::finish_login(
+            login_start.state,
+            start_response.credential_response,
+        )
+        .unwrap();
+        let public_key = base64::engine::general_purpose::STANDARD
+            .decode(handler.config.public_server_setup().public_key)
+            .unwrap();
+        assert_eq!(public_key, login_finish.server_s_pk.to_vec());
+    }
+
+    #[tokio::test]
+    async fn test_register_password_forbids_username() {
+        let sql_pool = get_initialized_db().await;
+        let mut config = get_default_config();
+        config.forbid_username_in_password = true;
+        let opaque_handler = SqlOpaqueHandler::new(config, sql_pool);
+        insert_user_no_password(&opaque_handler, "bob").await;
+        let err = register_password(
+            &opaque_handler,
+            UserId::new("bob"),
+            &SecUtf8::from("my-BOB-password"),
+            PasswordActor::SelfService,
+        )
+        .await
+        .unwrap_err();
+        assert!(
+            matches!(err, DomainError::PasswordPolicyViolation(_)),
+            "{}",
+            err
+        );
+        attempt_login(&opaque_handler, "bob", "my-BOB-password")
+            .await
+            .unwrap_err();
+        register_password(
+            &opaque_handler,
+            UserId::new("bob"),
+            &SecUtf8::from("correct horse"),
+            PasswordActor::SelfService,
+        )
+        .await
+        .unwrap();
+        attempt_login(&opaque_handler, "bob", "correct horse")
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_rejected_password_detail_depends_on_actor() {
+        let sql_pool = get_initialized_db().await;
+        let mut config = get_default_config();
+        config.forbid_username_in_password = true;
+        let opaque_handler = SqlOpaqueHandler::new(config, sql_pool);
+        insert_user_no_password(&opaque_handler, "bob").await;
+        let password = SecUtf8::from("bob-password");
+        let self_service_err = register_password(
+            &opaque_handler,
+            UserId::new("bob"),
+            &password,
+            PasswordActor::SelfService,
+        )
+        .await
+        .unwrap_err();
+        assert!(
+            matches!(
+                self_service_err,
+                DomainError::PasswordPolicyViolation(ref v)
+                    if v == &[lldap_auth::password_policy::PolicyViolation::ContainsUsername]
+            ),
+            "{}",
+            self_service_err
+        );
+        assert!(self_service_err
+            .to_string()
+            .contains("contains the username"));
+        let admin_err = register_password(
+            &opaque_handler,
+            UserId::new("bob"),
+            &password,
+            PasswordActor::Admin,
+        )
+        .await
+        .unwrap_err();
+        assert!(
+            matches!(&admin_err, DomainError::WeakPassword(user) if user == "bob"),
+            "{}",
+            admin_err
+        );
+        assert!(!admin_err.to_string().contains("username"));
+    }
+
+    #[tokio::test]
+    async fn test_register_password_lists_policy_violations() {
+        use lldap_auth::password_policy::PolicyViolation;
+        let sql_pool = get_initialized_db().await;
+        let mut config = get_default_config();
+        config.password_policy_options.min_length = 8;
+        config.password_policy_options.require_mixed_case = true;
+        config.password_policy_options.require_digit = true;
+        let opaque_handler = SqlOpaqueHandler::new(config, sql_pool);
+        insert_user_no_password(&opaque_handler, "bob").await;
+        async fn register(handler: &SqlOpaqueHandler, password: &str) -> Result<()> {
+            register_password(
+                handler,
+                UserId::new("bob"),
+                &SecUtf8::from(password),
+                PasswordActor::SelfService,
+            )
+            .await
+        }
+        assert!(matches!(
+            register(&opaque_handler, "").await.unwrap_err(),
+            DomainError::PasswordPolicyViolation(v) if v == [
+                PolicyViolation::TooShort { min_length: 8 },
+                PolicyViolation::MissingMixedCase,
+                PolicyViolation::MissingDigit,
+            ]
+        ));
+        assert!(matches!(
+            register(&opaque_handler, "Abcdef1").await.unwrap_err(),
+            DomainError::PasswordPolicyViolation(v) if v == [
+                PolicyViolation::TooShort { min_length: 8 },
+            ]
+        ));
+        register(&opaque_handler, "Abcdefg1").await.unwrap();
+        attempt_login(&opaque_handler, "bob", "Abcdefg1")
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_password_policy_overrides() {
+        use crate::infra::configuration::{PasswordPolicyOptions, PasswordPolicyOverride};
+        use lldap_auth::password_policy::PolicyViolation;
+        let mut config = get_default_config();
+        config.password_policy_options.min_length = 8;
+        config.password_policy_overrides = vec![
+            PasswordPolicyOverride {
+                users: vec!["Carol".to_owned()],
+                groups: Vec::new(),
+                policy: PasswordPolicyOptions {
+                    min_length: 4,
+                    ..Default::default()
+                },
+            },
+            PasswordPolicyOverride {
+                users: Vec::new(),
+                groups: vec!["Admins".to_owned()],
+                policy: PasswordPolicyOptions {
+                    min_length: 16,
+                    require_digit: true,
+                    ..Default::default()
+                },
+            },
+        ];
+        let opaque_handler = SqlOpaqueHandler::new(config, get_initialized_db().await);
+        for user in ["alice", "bob", "carol"] {
+            insert_user_no_password(&opaque_handler, user).await;
+        }
+        let group = insert_group(&opaque_handler, "admins").await;
+        insert_membership(&opaque_handler, group, "alice").await;
+        insert_membership(&opaque_handler, group, "carol").await;
+        async fn register(handler: &SqlOpaqueHandler, user: &str, password: &str) -> Result<()> {
+            register_password(
+                handler,
+                UserId::new(user),
+                &SecUtf8::from(password),
+                PasswordActor::SelfService,
+            )
+            .await
+        }
+        // The group override is stricter.
+        assert!(matches!(
+            register(&opaque_handler, "alice", "abcdefghij").await.unwrap_err(),
+            DomainError::PasswordPolicyViolation(v) if v == [
+                PolicyViolation::TooShort { min_length: 16 },
+                PolicyViolation::MissingDigit,
+            ]
+        ));
+        register(&opaque_handler, "alice", "abcdefghijklmno1")
+            .await
+            .unwrap();
+        // Others keep the global policy.
+        register(&opaque_handler, "bob", "abcdefghij")
+            .await
+            .unwrap();
+        // The override of the user comes before the one of their group.
+        register(&opaque_handler, "carol", "abcd").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_service_account_length_only_policy() {
+        use lldap_auth::password_policy::PolicyViolation;
+        let mut config = get_default_config();
+        config.password_policy_options.min_length = 8;
+        config.password_policy_options.require_mixed_case = true;
+        config.password_policy_options.require_digit = true;
+        config.password_policy_options.service_account_groups = vec!["Service_Accounts".to_owned()];
+        config.password_policy_options.service_account_min_length = 20;
+        let opaque_handler = SqlOpaqueHandler::new(config, get_initialized_db().await);
+        insert_user_no_password(&opaque_handler, "backup").await;
+        insert_user_no_password(&opaque_handler, "bob").await;
+        let group = insert_group(&opaque_handler, "service_accounts").await;
+        insert_membership(&opaque_handler, group, "backup").await;
+        async fn register(handler: &SqlOpaqueHandler, user: &str, password: &str) -> Result<()> {
+            register_password(
+                handler,
+                UserId::new(user),
+                &SecUtf8::from(password),
+                PasswordActor::SelfService,
+            )
+            .await
+        }
+        // Composition rules don't apply, but the length is higher.
+        assert!(matches!(
+            register(&opaque_handler, "backup", "Abcdefg1").await.unwrap_err(),
+            DomainError::PasswordPolicyViolation(v) if v == [
+                PolicyViolation::TooShort { min_length: 20 },
+            ]
+        ));
+        register(&opaque_handler, "backup", "abcdefghijklmnopqrst")
+            .await
+            .unwrap();
+        // Other users keep the human-oriented rules.
+        assert!(matches!(
+            register(&opaque_handler, "bob", "abcdefghijklmnopqrst").await.unwrap_err(),
+            DomainError::PasswordPolicyViolation(v) if v == [
+                PolicyViolation::MissingMixedCase,
+                PolicyViolation::MissingDigit,
+            ]
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_password_history_prevents_reuse() {
+        let sql_pool = get_initialized_db().await;
+        let mut config = get_default_config();
+        config.password_history_size = 2;
+        let opaque_handler = SqlOpaqueHandler::new(config, sql_pool);
+        insert_user_no_password(&opaque_handler, "bob").await;
+        async fn register(
+            handler: &SqlOpaqueHandler,
+            password: &str,
+            actor: PasswordActor,
+        ) -> Result<()> {
+            register_password(handler, UserId::new("bob"), &SecUtf8::from(password), actor).await
+        }
+        use PasswordActor::*;
+        register(&opaque_handler, "first", SelfService)
+            .await
+            .unwrap();
+        register(&opaque_handler, "second", SelfService)
+            .await
+            .unwrap();
+        for password in ["first", "second"] {
+            let err = register(&opaque_handler, password, SelfService)
+                .await
+                .unwrap_err();
+            assert!(matches!(err, DomainError::PasswordReused(_)), "{}", err);
+        }
+        // Admins don't learn the previous passwords.
+        let err = register(&opaque_handler, "first", Admin).await.unwrap_err();
+        assert!(matches!(err, DomainError::WeakPassword(_)), "{}", err);
+        attempt_login(&opaque_handler, "bob", "second")
+            .await
+            .unwrap();
+        // Only the last 2 are kept.
+        register(&opaque_handler, "third", SelfService)
+            .await
+            .unwrap();
+        assert_eq!(
+            model::PasswordHistory::find()
+                .count(&opaque_handler.sql_pool)
+                .await
+                .unwrap(),
+            2
+        );
+        register(&opaque_handler, "first", SelfService)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_password_history_disabled_by_default() {
+        let sql_pool = get_initialized_db().await;
+        let opaque_handler = SqlOpaqueHandler::new(get_default_config(), sql_pool);
+        insert_user_no_password(&opaque_handler, "bob").await;
+        for _ in 0..2 {
+            register_password(
+                &opaque_handler,
+                UserId::new("bob"),
+                &SecUtf8::from("password"),
+                PasswordActor::SelfService,
+            )
+            .await
+            .unwrap();
+        }
+        assert_eq!(
+            model::PasswordHistory::find()
+                .count(&opaque_handler.sql_pool)
+                .await
+                .unwrap(),
+            0
+        );
+    }
+
+    /// Records a field of every span created while it's the default subscriber.
+    #[derive(Clone)]
+    struct SpanFieldRecorder {
+        field: &'static str,
+        spans: Arc<Mutex<Vec<(&'static str, Option<String>)>>>,
+    }
+
+    impl SpanFieldRecorder {
+        fn new(field: &'static str) -> Self {
+            Self {
+                field,
+                spans: Default::default(),
+            }
+        }
+    }
+
+    struct FieldVisitor(&'static str, Option<String>);
+
+    impl tracing::field::Visit for FieldVisitor {
+        fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
+            if field.name() == self.0 {
+                self.1 = Some(format!("{:?}", value));
+            }
+        }
+    }
+
+    impl<S> tracing_subscriber::Layer<S> for SpanFieldRecorder
+    where
+        S: tracing::Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a>,
+    {
+        fn on_new_span(
+            &self,
+            attrs: &tracing::span::Attributes<'_>,
+            _: &tracing::span::Id,
+            _: tracing_subscriber::layer::Context<'_, S>,
+        ) {
+            let mut visitor = FieldVisitor(self.field, None);
+            attrs.record(&mut visitor);
+            self.spans
+                .lock()
+                .unwrap()
+                .push((attrs.metadata().name(), visitor.1));
+        }
+
+        /// Fields recorded after the creation of the span are added as another entry.
+        fn on_record(
+            &self,
+            id: &tracing::span::Id,
+            values: &tracing::span::Record<'_>,
+            ctx: tracing_subscriber::layer::Context<'_, S>,
+        ) {
+            let mut visitor = FieldVisitor(self.field, None);
+            values.record(&mut visitor);
+            if let (Some(value), Some(span)) = (visitor.1, ctx.span(id)) {
+                self.spans
+                    .lock()
+                    .unwrap()
+                    .push((span.metadata().name(), Some(value)));
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_correlation_id_in_login_spans() {
+        use crate::domain::correlation::with_correlation_id;
+        use tracing_subscriber::layer::SubscriberExt;
+        let recorder = SpanFieldRecorder::new("correlation_id");
+        let _guard =
+            tracing::subscriber::set_default(tracing_subscriber::registry().with(recorder.clone()));
         let sql_pool = get_initialized_db().await;
-        let config = get_default_config();
-        let handler = SqlOpaqueHandler::new(config, sql_pool.clone());
-        insert_user(&handler, "bob", "bob00").await;
+        let opaque_handler = SqlOpaqueHandler::new(get_default_config(), sql_pool);
+        insert_user_no_password(&opaque_handler, "bob").await;
+        with_correlation_id("req-1234".to_owned(), async {
+            register_password(
+                &opaque_handler,
+                UserId::new("bob"),
+                &SecUtf8::from("bob00"),
+                PasswordActor::SelfService,
+            )
+            .await
+            .unwrap();
+            attempt_login(&opaque_handler, "bob", "bob00")
+                .await
+                .unwrap();
+            opaque_handler
+                .bind(BindRequest {
+                    name: UserId::new("bob"),
+                    password: "bob00".to_owned(),
+                    source: None,
+                    context: None,
+                })
+                .await
+                .unwrap();
+        })
+        .await;
+        let spans = recorder.spans.lock().unwrap().clone();
+        for name in [
+            "registration_start",
+            "registration_finish",
+            "login_start",
+            "login_finish",
+            "bind",
+        ] {
+            let ids = spans
+                .iter()
+                .filter(|(span, _)| *span == name)
+                .map(|(_, id)| id.as_deref())
+                .collect::<Vec<_>>();
+            assert_eq!(ids, vec![Some("req-1234")], "{}", name);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_ciphersuite_in_registration_span() {
+        use tracing_subscriber::layer::SubscriberExt;
+        let recorder = SpanFieldRecorder::new("ciphersuite");
+        let _guard =
+            tracing::subscriber::set_default(tracing_subscriber::registry().with(recorder.clone()));
+        let sql_pool = get_initialized_db().await;
+        let opaque_handler = SqlOpaqueHandler::new(get_default_config(), sql_pool);
+        insert_user_no_password(&opaque_handler, "bob").await;
+        start_registration(&opaque_handler, "bob").await;
+        let spans = recorder.spans.lock().unwrap().clone();
+        assert_eq!(
+            spans
+                .iter()
+                .filter(|(span, _)| *span == "opaque")
+                .collect::<Vec<_>>(),
+            vec![&("opaque", Some(format!("{:?}", opaque::CIPHERSUITE_NAME)))]
+        );
+    }
 
+    #[tokio::test]
+    async fn test_dummy_password_file_reason_in_login_span() {
+        use crate::domain::handler::BackendHandler;
+        use tracing_subscriber::layer::SubscriberExt;
+        let recorder = SpanFieldRecorder::new("password_file");
+        let _guard =
+            tracing::subscriber::set_default(tracing_subscriber::registry().with(recorder.clone()));
+        let sql_pool = get_initialized_db().await;
+        let handler = SqlOpaqueHandler::new(get_default_config(), sql_pool);
+        insert_user(&handler, "bob", "bob00").await;
+        insert_user_no_password(&handler, "nopass").await;
+        insert_user(&handler, "disabled", "disabled00").await;
         handler
+            .set_users_enabled(&[UserId::new("disabled")], false)
+            .await
+            .unwrap();
+        insert_user(&handler, "eve", "eve00").await;
+        let garbage = b"not a password file".to_vec();
+        let checksum = handler.password_file_checksum(&UserId::new("eve"), &garbage);
+        set_password_columns(&handler, "eve", (Some(garbage), Some(checksum))).await;
+        attempt_login(&handler, "bob", "bob00").await.unwrap();
+        for (user, expected) in [
+            ("bob", "real"),
+            ("unknown", "unknown_user"),
+            ("nopass", "no_password"),
+            ("disabled", "disabled_user"),
+            ("eve", "corrupt_password_file"),
+        ] {
+            recorder.spans.lock().unwrap().clear();
+            let result = attempt_login(&handler, user, "wrong").await;
+            // The client can't tell the dummy files apart from a wrong password. Disabled accounts
+            // are rejected, but only after the dummy exchange.
+            assert!(
+                match user {
+                    "disabled" => matches!(result, Err(DomainError::AccountDisabled(_))),
+                    _ => matches!(result, Err(DomainError::AuthenticationProtocolError(_))),
+                },
+                "{}: {:?}",
+                user,
+                result
+            );
+            let recorded = recorder
+                .spans
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|(span, value)| *span == "login_start" && value.is_some())
+                .map(|(_, value)| value.clone().unwrap())
+                .collect::<Vec<_>>();
+            assert_eq!(recorded, vec![format!("{:?}", expected)], "{}", user);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_bind_ambiguous_user() {
+        let sql_pool = get_initialized_db().await;
+        let opaque_handler = SqlOpaqueHandler::new(get_default_config(), sql_pool.clone());
+        insert_user(&opaque_handler, "bob", "bob00").await;
+        // Recreate the table without its primary key, to duplicate the user.
+        for statement in [
+            "PRAGMA foreign_keys = OFF",
+            "ALTER TABLE users RENAME TO users_with_key",
+            "CREATE TABLE users AS SELECT * FROM users_with_key",
+            "INSERT INTO users SELECT * FROM users_with_key",
+        ] {
+            sql_pool
+                .execute(Statement::from_string(
+                    sea_orm::DbBackend::Sqlite,
+                    statement.to_owned(),
+                ))
+                .await
+                .unwrap();
+        }
+        let err = opaque_handler
             .bind(BindRequest {
                 name: UserId::new("bob"),
-                password: "bob00".to_string(),
+                password: "bob00".to_owned(),
+                source: None,
+                context: None,
             })
             .await
+            .unwrap_err();
+        assert!(
+            matches!(&err, DomainError::InternalError(e) if e == "ambiguous user"),
+            "{}",
+            err
+        );
+        attempt_login(&opaque_handler, "bob", "bob00")
+            .await
+            .unwrap_err();
+    }
+
+    #[tokio::test]
+    async fn test_password_changed_during_login() {
+        let sql_pool = get_initialized_db().await;
+        let opaque_handler = SqlOpaqueHandler::new(get_default_config(), sql_pool);
+        insert_user(&opaque_handler, "bob", "bob00").await;
+        let mut rng = rand::rngs::OsRng;
+        let login_start = opaque::client::login::start_login("bob00", &mut rng).unwrap();
+        let start_response = opaque_handler
+            .login_start(
+                login::ClientLoginStartRequest {
+                    username: UserId::new("bob"),
+                    login_start_request: login_start.message,
+                    challenge: None,
+                    context: None,
+                },
+                None,
+            )
+            .await
             .unwrap();
-        handler
-            .bind(BindRequest {
-                name: UserId::new("andrew"),
-                password: "bob00".to_string(),
-            })
+        register_password(
+            &opaque_handler,
+            UserId::new("bob"),
+            &SecUtf8::from("bob01"),
+            PasswordActor::SelfService,
+        )
+        .await
+        .unwrap();
+        // The client still has the old password file: the old password works on its side.
+        let login_finish = opaque::client::login::finish_login(
+            login_start.state,
+            start_response.credential_response,
+        )
+        .unwrap();
+        let err = opaque_handler
+            .login_finish(
+                login::ClientLoginFinishRequest {
+                    server_data: start_response.server_data,
+                    credential_finalization: login_finish.message,
+                    consent: None,
+                    device_fingerprint: None,
+                    username: None,
+                    totp_code: None,
+                },
+                None,
+            )
             .await
             .unwrap_err();
-        handler
+        assert!(matches!(err, DomainError::CredentialsChanged(_)), "{}", err);
+        attempt_login(&opaque_handler, "bob", "bob01")
+            .await
+            .unwrap();
+    }
+
+    async fn get_password_columns(
+        handler: &SqlOpaqueHandler,
+        user_id: &str,
+    ) -> (Option<Vec<u8>>, Option<Vec<u8>>) {
+        model::User::find_by_id(UserId::new(user_id))
+            .select_only()
+            .column(UserColumn::PasswordHash)
+            .column(UserColumn::PasswordHashChecksum)
+            .into_tuple()
+            .one(&handler.sql_pool)
+            .await
+            .unwrap()
+            .unwrap()
+    }
+
+    async fn set_password_columns(
+        handler: &SqlOpaqueHandler,
+        user_id: &str,
+        (password_file, checksum): (Option<Vec<u8>>, Option<Vec<u8>>),
+    ) {
+        model::User::update_many()
+            .col_expr(UserColumn::PasswordHash, Expr::value(password_file))
+            .col_expr(UserColumn::PasswordHashChecksum, Expr::value(checksum))
+            .filter(UserColumn::UserId.eq(user_id))
+            .exec(&handler.sql_pool)
+            .await
+            .unwrap();
+    }
+
+    async fn assert_tampered(handler: &SqlOpaqueHandler, user_id: &str, password: &str) {
+        let err = handler
             .bind(BindRequest {
-                name: UserId::new("bob"),
-                password: "wrong_password".to_string(),
+                name: UserId::new(user_id),
+                password: password.to_owned(),
+                source: None,
+                context: None,
             })
             .await
             .unwrap_err();
+        assert!(
+            matches!(&err, DomainError::TamperedPasswordFile(_)),
+            "{}",
+            err
+        );
+        assert!(matches!(
+            attempt_login(handler, user_id, password).await.unwrap_err(),
+            DomainError::TamperedPasswordFile(_)
+        ));
+    }
+
+    async fn get_quarantine(handler: &SqlOpaqueHandler) -> Vec<(String, Vec<u8>, String)> {
+        model::QuarantinedPasswordFiles::find()
+            .select_only()
+            .column(QuarantinedPasswordFilesColumn::UserId)
+            .column(QuarantinedPasswordFilesColumn::PasswordFile)
+            .column(QuarantinedPasswordFilesColumn::Reason)
+            .into_tuple()
+            .all(&handler.sql_pool)
+            .await
+            .unwrap()
     }
 
     #[tokio::test]
-    async fn test_user_no_password() {
+    async fn test_quarantine_corrupt_password_file() {
         let sql_pool = get_initialized_db().await;
-        let config = get_default_config();
-        let handler = SqlBackendHandler::new(config, sql_pool.clone());
+        let mut config = get_default_config();
+        config.quarantine_corrupt_password_files = true;
+        let handler = SqlOpaqueHandler::new(config, sql_pool);
+        insert_user(&handler, "bob", "bob00").await;
+        let garbage = b"not a password file".to_vec();
+        let checksum = handler.password_file_checksum(&UserId::new("bob"), &garbage);
+        set_password_columns(&handler, "bob", (Some(garbage.clone()), Some(checksum))).await;
+        assert!(matches!(
+            attempt_login(&handler, "bob", "bob00").await.unwrap_err(),
+            DomainError::AuthenticationProtocolError(_)
+        ));
+        assert_eq!(get_password_columns(&handler, "bob").await, (None, None));
+        assert_eq!(
+            get_quarantine(&handler).await,
+            vec![("bob".to_owned(), garbage, "undeserializable".to_owned())]
+        );
+        // The password can be set again.
+        register_password(
+            &handler,
+            UserId::new("bob"),
+            &SecUtf8::from("bob01"),
+            PasswordActor::Admin,
+        )
+        .await
+        .unwrap();
+        attempt_login(&handler, "bob", "bob01").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_quarantine_tampered_password_file() {
+        let sql_pool = get_initialized_db().await;
+        let mut config = get_default_config();
+        config.quarantine_corrupt_password_files = true;
+        let handler = SqlOpaqueHandler::new(config, sql_pool);
+        insert_user(&handler, "bob", "bob00").await;
+        insert_user(&handler, "eve", "eve00").await;
+        // Eve copies her password file to Bob.
+        let eve_columns = get_password_columns(&handler, "eve").await;
+        set_password_columns(&handler, "bob", eve_columns.clone()).await;
+        assert!(matches!(
+            bind(&handler, "bob", "eve00").await.unwrap_err(),
+            DomainError::AuthenticationError(_)
+        ));
+        assert_eq!(get_password_columns(&handler, "bob").await, (None, None));
+        assert_eq!(
+            get_quarantine(&handler).await,
+            vec![(
+                "bob".to_owned(),
+                eve_columns.0.unwrap(),
+                "checksum_mismatch".to_owned()
+            )]
+        );
+        bind(&handler, "eve", "eve00").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_audit_password_store() {
+        use crate::domain::handler::BackendHandler;
+        let sql_pool = get_initialized_db().await;
+        let handler = SqlOpaqueHandler::new(get_default_config(), sql_pool);
+        for user in ["bob", "john", "patrick", "eve"] {
+            insert_user(&handler, user, "password").await;
+        }
+        insert_user_no_password(&handler, "nopass").await;
+        // Set before the checksums.
+        let (john_file, _) = get_password_columns(&handler, "john").await;
+        set_password_columns(&handler, "john", (john_file, None)).await;
+        // Copied from another user.
+        set_password_columns(
+            &handler,
+            "patrick",
+            get_password_columns(&handler, "bob").await,
+        )
+        .await;
+        // Corrupted by LLDAP itself, the checksum matches.
+        let garbage = b"not a password file".to_vec();
+        let checksum = handler.password_file_checksum(&UserId::new("eve"), &garbage);
+        set_password_columns(&handler, "eve", (Some(garbage), Some(checksum))).await;
+        let audit = |user_id: &str, deserializes, checksum_matches, compatible_with_setup| {
+            PasswordFileAudit {
+                user_id: UserId::new(user_id),
+                deserializes,
+                checksum_matches,
+                compatible_with_setup,
+            }
+        };
+        let report = handler.audit_password_store().await.unwrap();
+        assert_eq!(
+            report,
+            vec![
+                audit("bob", true, Some(true), true),
+                audit("eve", false, Some(true), false),
+                audit("john", true, None, true),
+                audit("patrick", true, Some(false), true),
+            ]
+        );
+        assert_eq!(
+            report
+                .iter()
+                .filter(|a| a.is_healthy())
+                .map(|a| a.user_id.as_str())
+                .collect::<Vec<_>>(),
+            vec!["bob", "john"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_password_file_checksum_is_stored() {
+        let sql_pool = get_initialized_db().await;
+        let opaque_handler = SqlOpaqueHandler::new(get_default_config(), sql_pool);
+        insert_user(&opaque_handler, "bob", "bob00").await;
+        let (password_file, checksum) = get_password_columns(&opaque_handler, "bob").await;
+        assert_eq!(
+            checksum,
+            Some(
+                opaque_handler.password_file_checksum(&UserId::new("bob"), &password_file.unwrap())
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn test_tampered_password_file() {
+        let sql_pool = get_initialized_db().await;
+        let opaque_handler = SqlOpaqueHandler::new(get_default_config(), sql_pool);
+        insert_user(&opaque_handler, "bob", "bob00").await;
+        let (password_file, checksum) = get_password_columns(&opaque_handler, "bob").await;
+        let mut password_file = password_file.unwrap();
+        *password_file.last_mut().unwrap() ^= 1;
+        set_password_columns(&opaque_handler, "bob", (Some(password_file), checksum)).await;
+        assert_tampered(&opaque_handler, "bob", "bob00").await;
+    }
+
+    #[tokio::test]
+    async fn test_password_file_swapped_between_users() {
+        let sql_pool = get_initialized_db().await;
+        let opaque_handler = SqlOpaqueHandler::new(get_default_config(), sql_pool);
+        insert_user(&opaque_handler, "bob", "bob00").await;
+        insert_user(&opaque_handler, "patrick", "pass").await;
+        let patrick_columns = get_password_columns(&opaque_handler, "patrick").await;
+        set_password_columns(&opaque_handler, "bob", patrick_columns).await;
+        assert_tampered(&opaque_handler, "bob", "pass").await;
+    }
+
+    #[tokio::test]
+    async fn test_password_file_without_checksum() {
+        let sql_pool = get_initialized_db().await;
+        let opaque_handler = SqlOpaqueHandler::new(get_default_config(), sql_pool);
+        insert_user(&opaque_handler, "bob", "bob00").await;
+        // Passwords set before the checksums were introduced.
+        let (password_file, _) = get_password_columns(&opaque_handler, "bob").await;
+        set_password_columns(&opaque_handler, "bob", (password_file, None)).await;
+        attempt_login(&opaque_handler, "bob", "bob00")
+            .await
+            .unwrap();
+        register_password(
+            &opaque_handler,
+            UserId::new("bob"),
+            &SecUtf8::from("bob01"),
+            PasswordActor::SelfService,
+        )
+        .await
+        .unwrap();
+        assert!(get_password_columns(&opaque_handler, "bob")
+            .await
+            .1
+            .is_some());
+    }
+
+    /// A `login_start_request` built from the opaque-ke 0.6 wire format: the blinded element,
+    /// then the client nonce and ephemeral public key of the 3DH key exchange. Both group
+    /// elements are the Ristretto basepoint.
+    const LOGIN_START_REQUEST_FIXTURE: &str = concat!(
+        "e2f2ae0a6abc4e71a884a961c500515f58e30b6aa582dd8db6a65945e08d2d76",
+        "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f",
+        "e2f2ae0a6abc4e71a884a961c500515f58e30b6aa582dd8db6a65945e08d2d76",
+    );
+
+    fn decode_hex(hex: &str) -> Vec<u8> {
+        (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_interop_self_test() {
+        use crate::domain::handler::BackendHandler;
+        let sql_pool = get_initialized_db().await;
+        let handler = SqlBackendHandler::new(get_default_config(), sql_pool);
+        let fixture = decode_hex(LOGIN_START_REQUEST_FIXTURE);
+        assert!(handler.interop_self_test(&fixture).await.unwrap());
+        // Truncated message.
+        assert!(!handler
+            .interop_self_test(&fixture[..fixture.len() - 1])
+            .await
+            .unwrap());
+        // Not a valid group element.
+        let mut invalid_element = fixture.clone();
+        invalid_element[..32].fill(0xff);
+        assert!(!handler.interop_self_test(&invalid_element).await.unwrap());
+    }
+
+    /// Counts the log events for logins from new devices.
+    #[derive(Clone, Default)]
+    struct NewDeviceRecorder(Arc<Mutex<usize>>);
+
+    impl<S: tracing::Subscriber> tracing_subscriber::Layer<S> for NewDeviceRecorder {
+        fn on_event(
+            &self,
+            event: &tracing::Event<'_>,
+            _: tracing_subscriber::layer::Context<'_, S>,
+        ) {
+            if event.metadata().target() == NEW_DEVICE_EVENT_TARGET {
+                *self.0.lock().unwrap() += 1;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_new_device_event() {
+        use tracing_subscriber::layer::SubscriberExt;
+        let recorder = NewDeviceRecorder::default();
+        let _guard =
+            tracing::subscriber::set_default(tracing_subscriber::registry().with(recorder.clone()));
+        let sql_pool = get_initialized_db().await;
+        let mut config = get_default_config();
+        config.enable_device_tracking = true;
+        let handler = SqlOpaqueHandler::new(config, sql_pool);
+        insert_user(&handler, "bob", "bob00").await;
+        insert_user(&handler, "patrick", "pass").await;
+        let login = |user, password, fingerprint| {
+            attempt_login_with_options(&handler, user, password, None, true, fingerprint, None)
+        };
+        login("bob", "bob00", Some("laptop")).await.unwrap();
+        assert_eq!(*recorder.0.lock().unwrap(), 1);
+        login("bob", "bob00", Some("laptop")).await.unwrap();
+        assert_eq!(*recorder.0.lock().unwrap(), 1);
+        // Failed logins don't register the device.
+        login("bob", "wrong", Some("phone")).await.unwrap_err();
+        assert_eq!(*recorder.0.lock().unwrap(), 1);
+        login("bob", "bob00", Some("phone")).await.unwrap();
+        assert_eq!(*recorder.0.lock().unwrap(), 2);
+        // The devices are per user.
+        login("patrick", "pass", Some("laptop")).await.unwrap();
+        assert_eq!(*recorder.0.lock().unwrap(), 3);
+        login("bob", "bob00", None).await.unwrap();
+        assert_eq!(*recorder.0.lock().unwrap(), 3);
+        assert_eq!(
+            model::UserDevices::find()
+                .filter(model::UserDevicesColumn::UserId.eq("bob"))
+                .count(&handler.sql_pool)
+                .await
+                .unwrap(),
+            2
+        );
+    }
+
+    #[tokio::test]
+    async fn test_device_tracking_disabled_by_default() {
+        let sql_pool = get_initialized_db().await;
+        let handler = SqlOpaqueHandler::new(get_default_config(), sql_pool);
+        insert_user(&handler, "bob", "bob00").await;
+        attempt_login_with_options(&handler, "bob", "bob00", None, true, Some("laptop"), None)
+            .await
+            .unwrap();
+        assert!(model::UserDevices::find()
+            .all(&handler.sql_pool)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    fn get_forced_password_change_config() -> crate::infra::configuration::Configuration {
+        let mut config = get_default_config();
+        config.force_password_change_after_admin_reset = true;
+        config
+    }
+
+    #[tokio::test]
+    async fn test_validate_imported_files() {
+        let sql_pool = get_initialized_db().await;
+        let handler = SqlOpaqueHandler::new(get_default_config(), sql_pool);
+        for (user, password) in [("bob", "bob00"), ("john", "john00"), ("eve", "eve00")] {
+            insert_user(&handler, user, password).await;
+        }
+        // A broken import: john got eve's file, without a checksum like older files.
+        let (eve_file, _) = get_password_columns(&handler, "eve").await;
+        set_password_columns(&handler, "john", (eve_file, None)).await;
+        let canaries = [
+            ("bob", "bob00"),
+            ("john", "john00"),
+            ("eve", "eve00"),
+            ("patrick", "patrick00"),
+        ]
+        .into_iter()
+        .map(|(user, password)| (UserId::new(user), SecUtf8::from(password)))
+        .collect();
+        assert_eq!(
+            handler.validate_imported_files(canaries).await.unwrap(),
+            vec![
+                (UserId::new("john"), LoginFailureReason::InvalidPassword),
+                (UserId::new("patrick"), LoginFailureReason::UnknownUser),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_admin_set_password_must_be_changed() {
+        let sql_pool = get_initialized_db().await;
+        let handler = SqlOpaqueHandler::new(get_forced_password_change_config(), sql_pool);
+        insert_user_no_password(&handler, "bob").await;
+        let bob = UserId::new("bob");
+        register_password(
+            &handler,
+            bob.clone(),
+            &SecUtf8::from("admin-set"),
+            PasswordActor::Admin,
+        )
+        .await
+        .unwrap();
+        assert!(handler.must_change_password(&bob).await.unwrap());
+        assert!(matches!(
+            bind(&handler, "bob", "admin-set").await.unwrap_err(),
+            DomainError::PasswordExpired(_)
+        ));
+        // The web logins work, and tell the client to ask for a new password.
+        for _ in 0..2 {
+            assert!(
+                attempt_login(&handler, "bob", "admin-set")
+                    .await
+                    .unwrap()
+                    .must_change_password
+            );
+        }
+        register_password(
+            &handler,
+            bob.clone(),
+            &SecUtf8::from("bob-chosen"),
+            PasswordActor::SelfService,
+        )
+        .await
+        .unwrap();
+        assert!(!handler.must_change_password(&bob).await.unwrap());
+        bind(&handler, "bob", "bob-chosen").await.unwrap();
+        assert!(
+            !attempt_login(&handler, "bob", "bob-chosen")
+                .await
+                .unwrap()
+                .must_change_password
+        );
+    }
+
+    #[tokio::test]
+    async fn test_admin_set_password_kept_by_default() {
+        let sql_pool = get_initialized_db().await;
+        let handler = SqlOpaqueHandler::new(get_default_config(), sql_pool);
+        insert_user_no_password(&handler, "bob").await;
+        register_password(
+            &handler,
+            UserId::new("bob"),
+            &SecUtf8::from("admin-set"),
+            PasswordActor::Admin,
+        )
+        .await
+        .unwrap();
+        assert!(!handler
+            .must_change_password(&UserId::new("bob"))
+            .await
+            .unwrap());
+        bind(&handler, "bob", "admin-set").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_admin_set_password_for_configured_admin() {
+        let sql_pool = get_initialized_db().await;
+        let config = get_forced_password_change_config();
+        let handler = SqlOpaqueHandler::new(config.clone(), sql_pool);
+        insert_user_no_password(&handler, config.ldap_user_dn.as_str()).await;
+        // The admin from the configuration is used by services, it keeps its password.
+        register_password(
+            &handler,
+            config.ldap_user_dn.clone(),
+            &SecUtf8::from("admin-pass"),
+            PasswordActor::Admin,
+        )
+        .await
+        .unwrap();
+        assert!(!handler
+            .must_change_password(&config.ldap_user_dn)
+            .await
+            .unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_temporary_password_must_be_changed() {
+        use crate::domain::handler::BackendHandler;
+        let sql_pool = get_initialized_db().await;
+        let opaque_handler = SqlOpaqueHandler::new(get_default_config(), sql_pool);
+        insert_user(&opaque_handler, "bob", "bob00").await;
+        let bob = UserId::new("bob");
+        let password = opaque_handler.issue_temporary_password(&bob).await.unwrap();
+        assert!(opaque_handler.must_change_password(&bob).await.unwrap());
+        attempt_login(&opaque_handler, "bob", "bob00")
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            bind(&opaque_handler, "bob", password.unsecure())
+                .await
+                .unwrap_err(),
+            DomainError::PasswordExpired(_)
+        ));
+        // The password isn't lost until the user sets a new one.
+        for _ in 0..2 {
+            assert!(
+                attempt_login(&opaque_handler, "bob", password.unsecure())
+                    .await
+                    .unwrap()
+                    .must_change_password
+            );
+        }
+        assert!(opaque_handler.must_change_password(&bob).await.unwrap());
+        register_password(
+            &opaque_handler,
+            bob.clone(),
+            &SecUtf8::from("new-password"),
+            PasswordActor::SelfService,
+        )
+        .await
+        .unwrap();
+        assert!(!opaque_handler.must_change_password(&bob).await.unwrap());
+        attempt_login(&opaque_handler, "bob", "new-password")
+            .await
+            .unwrap();
+        bind(&opaque_handler, "bob", "new-password").await.unwrap();
+    }
+
+    fn get_login_link_config() -> crate::infra::configuration::Configuration {
+        let mut config = get_default_config();
+        config.enable_login_links = true;
+        config
+    }
+
+    #[tokio::test]
+    async fn test_login_link_works_once() {
+        let sql_pool = get_initialized_db().await;
+        let handler = SqlOpaqueHandler::new(get_login_link_config(), sql_pool);
+        insert_user_no_password(&handler, "bob").await;
+        let token = handler
+            .create_login_link(&UserId::new("bob"))
+            .await
+            .unwrap();
+        assert_eq!(
+            handler.bind_with_token(&token).await.unwrap(),
+            UserId::new("bob")
+        );
+        handler.bind_with_token(&token).await.unwrap_err();
+        handler.bind_with_token("not a token").await.unwrap_err();
+    }
+
+    #[tokio::test]
+    async fn test_login_link_expiry() {
+        let sql_pool = get_initialized_db().await;
+        let handler = SqlOpaqueHandler::new(get_login_link_config(), sql_pool);
+        insert_user_no_password(&handler, "bob").await;
+        let token = handler
+            .create_login_link(&UserId::new("bob"))
+            .await
+            .unwrap();
+        model::LoginLinks::update_many()
+            .col_expr(
+                LoginLinksColumn::ExpiryDate,
+                Expr::value(chrono::Utc::now().naive_utc() - chrono::Duration::seconds(1)),
+            )
+            .exec(&handler.sql_pool)
+            .await
+            .unwrap();
+        handler.bind_with_token(&token).await.unwrap_err();
+    }
+
+    #[tokio::test]
+    async fn test_login_link_refused_to_admins() {
+        let sql_pool = get_initialized_db().await;
+        let handler = SqlOpaqueHandler::new(get_login_link_config(), sql_pool);
+        insert_user_no_password(&handler, "admin").await;
         insert_user_no_password(&handler, "bob").await;
+        let admin_group = insert_group(&handler, "lldap_admin").await;
+        insert_membership(&handler, admin_group, "admin").await;
+        handler
+            .create_login_link(&UserId::new("admin"))
+            .await
+            .unwrap_err();
+        // Promoted after the link was sent.
+        let token = handler
+            .create_login_link(&UserId::new("bob"))
+            .await
+            .unwrap();
+        insert_membership(&handler, admin_group, "bob").await;
+        handler.bind_with_token(&token).await.unwrap_err();
+    }
 
+    #[tokio::test]
+    async fn test_login_links_disabled_by_default() {
+        let sql_pool = get_initialized_db().await;
+        let handler = SqlOpaqueHandler::new(get_default_config(), sql_pool);
+        insert_user_no_password(&handler, "bob").await;
         handler
-            .bind(BindRequest {
-                name: UserId::new("bob"),
-                password: "bob00".to_string(),
-            })
+            .create_login_link(&UserId::new("bob"))
             .await
             .unwrap_err();
     }
+
+    fn get_maintenance_config() -> crate::infra::configuration::Configuration {
+        let mut config = get_default_config();
+        config.maintenance_break_glass_users = vec!["Rescue".to_owned()];
+        config
+    }
+
+    #[tokio::test]
+    async fn test_maintenance_mode_refuses_logins() {
+        use crate::domain::handler::{BackendHandler, MaintenanceMode};
+        let sql_pool = get_initialized_db().await;
+        let handler = SqlOpaqueHandler::new(get_maintenance_config(), sql_pool);
+        insert_user(&handler, "bob", "bob00").await;
+        insert_user(&handler, "rescue", "rescue00").await;
+        handler
+            .set_maintenance_mode(MaintenanceMode {
+                enabled: true,
+                message: Some("Back soon".to_owned()),
+            })
+            .await;
+        assert!(matches!(
+            bind(&handler, "bob", "bob00").await.unwrap_err(),
+            DomainError::Maintenance(Some(message)) if message == "Back soon"
+        ));
+        assert!(matches!(
+            attempt_login(&handler, "bob", "bob00").await.unwrap_err(),
+            DomainError::Maintenance(_)
+        ));
+        // The break-glass user can still log in, with the right password.
+        bind(&handler, "rescue", "rescue00").await.unwrap();
+        attempt_login(&handler, "rescue", "rescue00").await.unwrap();
+        assert!(matches!(
+            bind(&handler, "rescue", "wrong").await.unwrap_err(),
+            DomainError::AuthenticationError(_)
+        ));
+        // The mode is shared with the clones of the handler.
+        handler
+            .clone()
+            .set_maintenance_mode(MaintenanceMode::default())
+            .await;
+        bind(&handler, "bob", "bob00").await.unwrap();
+        attempt_login(&handler, "bob", "bob00").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_maintenance_mode_from_config() {
+        use crate::domain::handler::{BackendHandler, MaintenanceMode};
+        let sql_pool = get_initialized_db().await;
+        let mut config = get_maintenance_config();
+        config.maintenance_mode = true;
+        let handler = SqlOpaqueHandler::new(config, sql_pool);
+        insert_user(&handler, "bob", "bob00").await;
+        assert!(matches!(
+            bind(&handler, "bob", "bob00").await.unwrap_err(),
+            DomainError::Maintenance(None)
+        ));
+        assert_eq!(
+            handler.get_maintenance_mode().await,
+            MaintenanceMode {
+                enabled: true,
+                message: None
+            }
+        );
+    }
 }
@@ -0,0 +1,182 @@
+use crate::domain::{
+    error::Result,
+    handler::PolicyComplianceReport,
+    model::{self, PasswordPolicyComplianceColumn},
+    sql_backend_handler::SqlBackendHandler,
+    types::UserId,
+};
+use sea_orm::{
+    sea_query::OnConflict, ColumnTrait, EntityTrait, PaginatorTrait, QueryFilter, QueryOrder,
+    QuerySelect, Set,
+};
+use tracing::{instrument, warn};
+
+impl SqlBackendHandler {
+    /// Checks the password against the proposed policy, if any, and keeps the result for the
+    /// report. This never fails: the login shouldn't depend on a dry run.
+    pub(crate) async fn record_policy_compliance(&self, user_id: &UserId, password: &str) {
+        let policy = match self.config.proposed_password_policy() {
+            Some(policy) => policy,
+            None => return,
+        };
+        let result = async {
+            // Service accounts have their own rules.
+            if self.is_service_account(user_id).await? {
+                return Ok(());
+            }
+            let compliant = policy.violations(user_id.as_str(), password).is_empty();
+            model::PasswordPolicyCompliance::insert(
+                model::password_policy_compliance::ActiveModel {
+                    user_id: Set(user_id.clone()),
+                    compliant: Set(compliant),
+                    checked_date: Set(chrono::Utc::now().naive_utc()),
+                },
+            )
+            .on_conflict(
+                OnConflict::column(PasswordPolicyComplianceColumn::UserId)
+                    .update_columns([
+                        PasswordPolicyComplianceColumn::Compliant,
+                        PasswordPolicyComplianceColumn::CheckedDate,
+                    ])
+                    .to_owned(),
+            )
+            .exec(&self.sql_pool)
+            .await?;
+            Result::Ok(())
+        }
+        .await;
+        if let Err(e) = result {
+            warn!(
+                r#"Could not check the password of "{}" against the proposed policy: {}"#,
+                user_id, e
+            );
+        }
+    }
+
+    #[instrument(skip(self), level = "debug", err)]
+    pub(crate) async fn get_policy_compliance_report(&self) -> Result<PolicyComplianceReport> {
+        let compliant = model::PasswordPolicyCompliance::find()
+            .filter(PasswordPolicyComplianceColumn::Compliant.eq(true))
+            .count(&self.sql_pool)
+            .await?;
+        let non_compliant: Vec<UserId> = model::PasswordPolicyCompliance::find()
+            .select_only()
+            .column(PasswordPolicyComplianceColumn::UserId)
+            .filter(PasswordPolicyComplianceColumn::Compliant.eq(false))
+            .order_by_asc(PasswordPolicyComplianceColumn::UserId)
+            .into_tuple()
+            .all(&self.sql_pool)
+            .await?;
+        let users = model::User::find().count(&self.sql_pool).await?;
+        Ok(PolicyComplianceReport {
+            compliant,
+            unchecked: users.saturating_sub(compliant + non_compliant.len() as u64),
+            non_compliant,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        domain::{
+            handler::{BackendHandler, BindRequest, LoginHandler},
+            sql_backend_handler::tests::*,
+            sql_opaque_handler::{register_password, PasswordActor},
+        },
+        infra::configuration::PasswordPolicyOptionsBuilder,
+    };
+    use pretty_assertions::assert_eq;
+    use secstr::SecUtf8;
+
+    async fn bind(handler: &SqlBackendHandler, name: &str, password: &str) {
+        handler
+            .bind(BindRequest {
+                name: UserId::new(name),
+                password: password.to_owned(),
+                context: None,
+                source: None,
+            })
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_report_accumulates() {
+        let mut config = get_default_config();
+        config.proposed_password_policy_options = Some(
+            PasswordPolicyOptionsBuilder::default()
+                .min_length(10)
+                .build()
+                .unwrap(),
+        );
+        let handler = SqlBackendHandler::new(config, get_initialized_db().await);
+        insert_user(&handler, "bob", "bob00").await;
+        insert_user(&handler, "john", "a long password").await;
+        insert_user(&handler, "patrick", "pass").await;
+        let report = || async { handler.proposed_policy_compliance_report().await.unwrap() };
+        assert_eq!(
+            report().await,
+            PolicyComplianceReport {
+                compliant: 0,
+                non_compliant: vec![],
+                unchecked: 3,
+            }
+        );
+        // The non-compliant passwords still log in.
+        bind(&handler, "bob", "bob00").await;
+        bind(&handler, "john", "a long password").await;
+        assert_eq!(
+            report().await,
+            PolicyComplianceReport {
+                compliant: 1,
+                non_compliant: vec![UserId::new("bob")],
+                unchecked: 1,
+            }
+        );
+        // Failed binds don't count.
+        handler
+            .bind(BindRequest {
+                name: UserId::new("patrick"),
+                password: "wrong password".to_owned(),
+                context: None,
+                source: None,
+            })
+            .await
+            .unwrap_err();
+        // The last bind wins.
+        register_password(
+            &handler,
+            UserId::new("bob"),
+            &SecUtf8::from("a better password"),
+            PasswordActor::SelfService,
+        )
+        .await
+        .unwrap();
+        bind(&handler, "bob", "a better password").await;
+        assert_eq!(
+            report().await,
+            PolicyComplianceReport {
+                compliant: 2,
+                non_compliant: vec![],
+                unchecked: 1,
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_no_proposed_policy() {
+        let handler = SqlBackendHandler::new(get_default_config(), get_initialized_db().await);
+        insert_user(&handler, "bob", "bob00").await;
+        bind(&handler, "bob", "bob00").await;
+        assert_eq!(
+            handler.proposed_policy_compliance_report().await.unwrap(),
+            PolicyComplianceReport {
+                compliant: 0,
+                non_compliant: vec![],
+                unchecked: 1,
+            }
+        );
+    }
+}
@@ -0,0 +1,266 @@
+use crate::domain::{
+    error::{DomainError, Result},
+    model::{self, PasswordResetTokensColumn, UserColumn},
+    sql_backend_handler::SqlBackendHandler,
+    sql_opaque_handler::{register_password, PasswordActor},
+    totp::constant_time_eq,
+    types::UserId,
+};
+use sea_orm::{ActiveModelTrait, ColumnTrait, EntityTrait, IntoActiveModel, QueryFilter};
+use secstr::SecUtf8;
+use tracing::{debug, info};
+
+const RESET_TOKEN_LENGTH: usize = 100;
+
+/// Only the hash of the tokens is stored, so that a copy of the database can't be used to reset
+/// passwords.
+pub(crate) fn hash_reset_token(token: &str) -> String {
+    use sha2::{Digest, Sha256};
+    Sha256::digest(token.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+fn invalid_token() -> DomainError {
+    DomainError::EntityNotFound("Invalid or expired reset token".to_owned())
+}
+
+impl SqlBackendHandler {
+    /// Stores the hash of a new token for the user, valid for
+    /// `password_reset_token_validity_seconds`, and returns the clear token.
+    pub(crate) async fn create_password_reset_token(&self, user_id: &UserId) -> Result<String> {
+        use rand::{distributions::Alphanumeric, Rng};
+        let token: String = rand::rngs::OsRng
+            .sample_iter(&Alphanumeric)
+            .take(RESET_TOKEN_LENGTH)
+            .map(char::from)
+            .collect();
+        model::password_reset_tokens::Model {
+            token: hash_reset_token(&token),
+            user_id: user_id.clone(),
+            expiry_date: chrono::Utc::now().naive_utc()
+                + chrono::Duration::seconds(
+                    self.config.password_reset_token_validity_seconds as i64,
+                ),
+        }
+        .into_active_model()
+        .insert(&self.sql_pool)
+        .await?;
+        Ok(token)
+    }
+
+    /// The unexpired token with this clear value, if any.
+    pub(crate) async fn find_password_reset_token(
+        &self,
+        token: &str,
+    ) -> Result<Option<model::password_reset_tokens::Model>> {
+        let hash = hash_reset_token(token);
+        Ok(model::PasswordResetTokens::find_by_id(hash.clone())
+            .filter(PasswordResetTokensColumn::ExpiryDate.gt(chrono::Utc::now().naive_utc()))
+            .one(&self.sql_pool)
+            .await?
+            // The lookup can ignore the case on some databases: compare the whole hash again.
+            .filter(|row| constant_time_eq(row.token.as_bytes(), hash.as_bytes())))
+    }
+
+    pub(crate) async fn generate_password_reset_token(&self, user_id: &UserId) -> Result<String> {
+        if model::User::find_by_id(user_id.clone())
+            .filter(UserColumn::DeletedAt.is_null())
+            .one(&self.sql_pool)
+            .await?
+            .is_none()
+        {
+            return Err(DomainError::EntityNotFound(format!(
+                "No such user: '{}'",
+                user_id
+            )));
+        }
+        self.create_password_reset_token(user_id).await
+    }
+
+    /// Consumes the token, then sets the password. If the password is rejected, the token can be
+    /// used again.
+    pub(crate) async fn reset_password_with_reset_token(
+        &self,
+        token: &str,
+        new_password: &SecUtf8,
+    ) -> Result<UserId> {
+        let row = self
+            .find_password_reset_token(token)
+            .await?
+            .ok_or_else(invalid_token)?;
+        // Whoever deletes the token first gets to use it.
+        let deleted = model::PasswordResetTokens::delete_by_id(row.token.clone())
+            .exec(&self.sql_pool)
+            .await?;
+        if deleted.rows_affected == 0 {
+            debug!(r#"The reset token of "{}" was already used"#, &row.user_id);
+            return Err(invalid_token());
+        }
+        let user_id = row.user_id.clone();
+        if let Err(e) = register_password(
+            self,
+            user_id.clone(),
+            new_password,
+            PasswordActor::SelfService,
+        )
+        .await
+        {
+            row.into_active_model().insert(&self.sql_pool).await?;
+            return Err(e);
+        }
+        info!(r#"Password of "{}" reset with a token"#, &user_id);
+        Ok(user_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::{
+        handler::{BackendHandler, BindRequest, LoginHandler},
+        sql_backend_handler::tests::*,
+    };
+    use pretty_assertions::assert_eq;
+    use sea_orm::sea_query::Expr;
+
+    async fn bind(handler: &SqlBackendHandler, name: &str, password: &str) -> Result<()> {
+        handler
+            .bind(BindRequest {
+                name: UserId::new(name),
+                password: password.to_owned(),
+                source: None,
+                context: None,
+            })
+            .await
+    }
+
+    async fn get_handler() -> SqlBackendHandler {
+        let handler = SqlBackendHandler::new(get_default_config(), get_initialized_db().await);
+        insert_user(&handler, "bob", "bob00").await;
+        handler
+    }
+
+    #[tokio::test]
+    async fn test_reset_password_with_token() {
+        let handler = get_handler().await;
+        let bob = UserId::new("bob");
+        let token = handler.generate_reset_token(&bob).await.unwrap();
+        handler
+            .reset_password_with_token(token.unsecure(), SecUtf8::from("bob01"))
+            .await
+            .unwrap();
+        bind(&handler, "bob", "bob00").await.unwrap_err();
+        bind(&handler, "bob", "bob01").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_reset_tokens_are_hashed() {
+        let handler = get_handler().await;
+        let token = handler
+            .generate_reset_token(&UserId::new("bob"))
+            .await
+            .unwrap();
+        let rows = model::PasswordResetTokens::find()
+            .all(&handler.sql_pool)
+            .await
+            .unwrap();
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0].token, hash_reset_token(token.unsecure()));
+        assert_ne!(rows[0].token, token.unsecure());
+    }
+
+    #[tokio::test]
+    async fn test_expired_reset_token() {
+        let handler = get_handler().await;
+        let token = handler
+            .generate_reset_token(&UserId::new("bob"))
+            .await
+            .unwrap();
+        model::PasswordResetTokens::update_many()
+            .col_expr(
+                PasswordResetTokensColumn::ExpiryDate,
+                Expr::value(chrono::Utc::now().naive_utc() - chrono::Duration::seconds(1)),
+            )
+            .exec(&handler.sql_pool)
+            .await
+            .unwrap();
+        assert!(matches!(
+            handler
+                .reset_password_with_token(token.unsecure(), SecUtf8::from("bob01"))
+                .await,
+            Err(DomainError::EntityNotFound(_))
+        ));
+        bind(&handler, "bob", "bob00").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_reused_reset_token() {
+        let handler = get_handler().await;
+        let token = handler
+            .generate_reset_token(&UserId::new("bob"))
+            .await
+            .unwrap();
+        handler
+            .reset_password_with_token(token.unsecure(), SecUtf8::from("bob01"))
+            .await
+            .unwrap();
+        assert!(matches!(
+            handler
+                .reset_password_with_token(token.unsecure(), SecUtf8::from("bob02"))
+                .await,
+            Err(DomainError::EntityNotFound(_))
+        ));
+        bind(&handler, "bob", "bob01").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_reset_token_invalidated_by_password_change() {
+        let handler = get_handler().await;
+        let bob = UserId::new("bob");
+        let token = handler.generate_reset_token(&bob).await.unwrap();
+        register_password(
+            &handler,
+            bob.clone(),
+            &SecUtf8::from("bob01"),
+            PasswordActor::SelfService,
+        )
+        .await
+        .unwrap();
+        handler
+            .reset_password_with_token(token.unsecure(), SecUtf8::from("bob02"))
+            .await
+            .unwrap_err();
+        bind(&handler, "bob", "bob01").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_rejected_password_keeps_the_token() {
+        let mut config = get_default_config();
+        config.password_policy_options.min_length = 8;
+        let handler = SqlBackendHandler::new(config, get_initialized_db().await);
+        insert_user(&handler, "bob", "bob00000").await;
+        let token = handler
+            .generate_reset_token(&UserId::new("bob"))
+            .await
+            .unwrap();
+        handler
+            .reset_password_with_token(token.unsecure(), SecUtf8::from("short"))
+            .await
+            .unwrap_err();
+        handler
+            .reset_password_with_token(token.unsecure(), SecUtf8::from("long enough"))
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_reset_token_for_unknown_user() {
+        let handler = get_handler().await;
+        assert!(matches!(
+            handler.generate_reset_token(&UserId::new("eve")).await,
+            Err(DomainError::EntityNotFound(_))
+        ));
+    }
+}
@@ -2,7 +2,8 @@ use crate::domain::sql_migrations::{
     get_schema_version, migrate_from_version, upgrade_to_v1, Metadata,
 };
 use sea_orm::{
-    sea_query::Query, ConnectionTrait, DeriveValueType, Iden, QueryResult, TryGetable, Value,
+    sea_query::Query, ConnectionTrait, DatabaseBackend, DeriveValueType, Iden, QueryResult,
+    TryGetable, Value,
 };
 use serde::{Deserialize, Serialize};
 
@@ -11,7 +12,7 @@ pub type DbConnection = sea_orm::DatabaseConnection;
 #[derive(Copy, PartialEq, Eq, Debug, Clone, PartialOrd, Ord, DeriveValueType)]
 pub struct SchemaVersion(pub i16);
 
-pub const LAST_SCHEMA_VERSION: SchemaVersion = SchemaVersion(8);
+pub const LAST_SCHEMA_VERSION: SchemaVersion = SchemaVersion(34);
 
 #[derive(Copy, PartialEq, Eq, Debug, Clone, PartialOrd, Ord)]
 pub struct PrivateKeyHash(pub [u8; 32]);
@@ -56,6 +57,32 @@ pub async fn init_table(pool: &DbConnection) -> anyhow::Result<()> {
     Ok(())
 }
 
+/// Opens `min_connections` connections right away, instead of letting the pool open them in the
+/// background or on the first requests.
+pub async fn warm_up_pool(pool: &DbConnection, min_connections: u32) -> anyhow::Result<()> {
+    async fn acquire_all<DB: sea_orm::sqlx::Database>(
+        pool: &sea_orm::sqlx::Pool<DB>,
+        count: u32,
+    ) -> Result<(), sea_orm::sqlx::Error> {
+        // Hold all of them at once, so that the pool can't hand the same one out twice.
+        let connections = futures::future::try_join_all((0..count).map(|_| pool.acquire())).await?;
+        drop(connections);
+        Ok(())
+    }
+    match pool.get_database_backend() {
+        DatabaseBackend::Sqlite => {
+            acquire_all(pool.get_sqlite_connection_pool(), min_connections).await?
+        }
+        DatabaseBackend::Postgres => {
+            acquire_all(pool.get_postgres_connection_pool(), min_connections).await?
+        }
+        DatabaseBackend::MySql => {
+            acquire_all(pool.get_mysql_connection_pool(), min_connections).await?
+        }
+    }
+    Ok(())
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 pub enum ConfigLocation {
     ConfigFile(String),
@@ -121,6 +148,41 @@ pub async fn set_private_key_info(pool: &DbConnection, info: PrivateKeyInfo) ->
     Ok(())
 }
 
+/// The fingerprint of the OPAQUE server setup shared by the instances, if one was stored.
+pub async fn get_server_setup_fingerprint(
+    pool: &DbConnection,
+) -> anyhow::Result<Option<PrivateKeyHash>> {
+    Ok(pool
+        .query_one(
+            pool.get_database_backend().build(
+                Query::select()
+                    .column(Metadata::ServerSetupFingerprint)
+                    .from(Metadata::Table),
+            ),
+        )
+        .await?
+        .and_then(|result| {
+            result
+                .try_get("", &Metadata::ServerSetupFingerprint.to_string())
+                .ok()
+        }))
+}
+
+pub async fn set_server_setup_fingerprint(
+    pool: &DbConnection,
+    fingerprint: PrivateKeyHash,
+) -> anyhow::Result<()> {
+    pool.execute(
+        pool.get_database_backend().build(
+            Query::update()
+                .table(Metadata::Table)
+                .value(Metadata::ServerSetupFingerprint, Value::from(fingerprint)),
+        ),
+    )
+    .await?;
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use crate::domain::{
@@ -140,6 +202,20 @@ mod tests {
         Database::connect(sql_opt).await.unwrap()
     }
 
+    #[tokio::test]
+    async fn test_warm_up_pool() {
+        let mut sql_opt = sea_orm::ConnectOptions::new("sqlite::memory:".to_owned());
+        sql_opt
+            .max_connections(5)
+            .min_connections(3)
+            .sqlx_logging(false);
+        let sql_pool = Database::connect(sql_opt).await.unwrap();
+        warm_up_pool(&sql_pool, 3).await.unwrap();
+        let pool = sql_pool.get_sqlite_connection_pool();
+        assert!(pool.size() >= 3);
+        assert!(pool.num_idle() >= 3);
+    }
+
     fn raw_statement(sql: &str) -> sea_orm::Statement {
         sea_orm::Statement::from_string(DbBackend::Sqlite, sql.to_owned())
     }
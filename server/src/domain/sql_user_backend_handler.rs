@@ -1,23 +1,27 @@
 use crate::domain::{
+    deserialize::deserialize_attribute_value,
     error::{DomainError, Result},
+    events::LifecycleEventType,
     handler::{
-        CreateUserRequest, UpdateUserRequest, UserBackendHandler, UserListerBackendHandler,
-        UserRequestFilter,
+        AuditAction, CreateUserRequest, ReadSchemaBackendHandler, UpdateUserRequest,
+        UserBackendHandler, UserListerBackendHandler, UserPage, UserRequestFilter,
     },
     model::{self, GroupColumn, UserColumn},
-    sql_backend_handler::SqlBackendHandler,
+    sql_backend_handler::{revoke_tokens, SqlBackendHandler},
     types::{
-        AttributeName, AttributeValue, GroupDetails, GroupId, Serialized, User, UserAndGroups,
-        UserId, Uuid,
+        AttributeName, AttributeValue, Email, GroupDetails, GroupId, Serialized, User,
+        UserAndGroups, UserId, Uuid,
     },
 };
+use crate::infra::configuration::EmailNormalizationOptions;
 use async_trait::async_trait;
 use sea_orm::{
     sea_query::{
         query::OnConflict, Alias, Cond, Expr, Func, IntoColumnRef, IntoCondition, SimpleExpr,
     },
-    ActiveModelTrait, ActiveValue, ColumnTrait, DatabaseTransaction, EntityTrait, IntoActiveValue,
-    ModelTrait, QueryFilter, QueryOrder, QuerySelect, QueryTrait, Set, TransactionTrait,
+    ActiveModelTrait, ActiveValue, ColumnTrait, ConnectionTrait, DatabaseTransaction, EntityTrait,
+    IntoActiveValue, ModelTrait, PaginatorTrait, QueryFilter, QueryOrder, QuerySelect, QueryTrait,
+    Set, TransactionTrait,
 };
 use std::collections::HashSet;
 use tracing::instrument;
@@ -62,6 +66,8 @@ fn get_user_filter_expr(filter: UserRequestFilter) -> Cond {
             } else if column == UserColumn::Email {
                 ColumnTrait::eq(&UserColumn::LowercaseEmail, value.as_str().to_lowercase())
                     .into_condition()
+            } else if column == UserColumn::IsEnabled {
+                ColumnTrait::eq(&UserColumn::IsEnabled, value == "true").into_condition()
             } else {
                 ColumnTrait::eq(&column, value).into_condition()
             }
@@ -95,32 +101,183 @@ fn to_value(opt_name: &Option<String>) -> ActiveValue<Option<String>> {
     }
 }
 
+/// The Unicode skeleton of the name: two names with the same skeleton look alike.
+fn username_skeleton(user_id: &UserId) -> String {
+    unicode_security::skeleton(user_id.as_str())
+        .collect::<String>()
+        .to_lowercase()
+}
+
+/// Returns an error if the new user looks like an existing one, but isn't the same.
+async fn check_confusable_username(
+    transaction: &DatabaseTransaction,
+    user_id: &UserId,
+) -> Result<()> {
+    let skeleton = username_skeleton(user_id);
+    let existing = model::User::find()
+        .select_only()
+        .column(UserColumn::UserId)
+        .filter(UserColumn::UserId.ne(user_id))
+        .into_tuple::<(UserId,)>()
+        .all(transaction)
+        .await?;
+    if let Some((existing,)) = existing
+        .into_iter()
+        .find(|(existing,)| username_skeleton(existing) == skeleton)
+    {
+        return Err(DomainError::ConfusableUsername(format!(
+            "{} looks like {}",
+            user_id, existing
+        )));
+    }
+    Ok(())
+}
+
+/// The imported password hashes are checked by the bind, they have to be bcrypt hashes.
+fn check_legacy_password_hash(request: &CreateUserRequest) -> Result<()> {
+    match &request.legacy_password_hash {
+        Some(hash) if hash.parse::<bcrypt::HashParts>().is_err() => {
+            Err(DomainError::InternalError(format!(
+                "The password hash of {} is not a bcrypt hash",
+                request.user_id
+            )))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// The deleted users are kept until they are purged, in case they are restored, but they can't be
+/// changed until then.
+async fn check_user_not_deleted(connection: &impl ConnectionTrait, user_id: &UserId) -> Result<()> {
+    if model::User::find_by_id(user_id.clone())
+        .filter(UserColumn::DeletedAt.is_null())
+        .count(connection)
+        .await?
+        == 0
+    {
+        return Err(DomainError::EntityNotFound(format!(
+            "No such user: '{}'",
+            user_id
+        )));
+    }
+    Ok(())
+}
+
+/// Returns the email to store, and the email as entered if it should be kept for display.
+fn normalize_email(email: Email, options: &EmailNormalizationOptions) -> (Email, Option<String>) {
+    if !options.enabled {
+        return (email, None);
+    }
+    let normalized = match email.as_str().rsplit_once('@') {
+        Some((local_part, domain)) => {
+            let local_part = if options.strip_plus_addressing {
+                local_part
+                    .split_once('+')
+                    .map(|(local_part, _)| local_part)
+                    .unwrap_or(local_part)
+            } else {
+                local_part
+            };
+            format!("{}@{}", local_part, domain.to_lowercase())
+        }
+        None => email.as_str().to_owned(),
+    };
+    (Email::from(normalized), Some(email.into_string()))
+}
+
 #[async_trait]
 impl UserListerBackendHandler for SqlBackendHandler {
     #[instrument(skip(self), level = "debug", ret, err)]
     async fn list_users(
         &self,
         filters: Option<UserRequestFilter>,
-        // To simplify the query, we always fetch groups. TODO: cleanup.
-        _get_groups: bool,
+        // To simplify the query, we always fetch the direct groups. TODO: cleanup.
+        get_groups: bool,
     ) -> Result<Vec<UserAndGroups>> {
+        let mut users = self
+            .list_users_with_condition(get_user_list_condition(filters))
+            .await?;
+        if get_groups {
+            self.add_inherited_groups(&mut users).await?;
+        }
+        Ok(users)
+    }
+
+    #[instrument(skip(self), level = "debug", err)]
+    async fn list_users_page(
+        &self,
+        filters: Option<UserRequestFilter>,
+        get_groups: bool,
+        first: u64,
+        after: Option<UserId>,
+    ) -> Result<UserPage> {
+        // Keyset pagination: the pages stay consistent when users are added or removed.
+        let mut query = model::User::find()
+            .select_only()
+            .column(UserColumn::UserId)
+            .filter(get_user_list_condition(filters));
+        if let Some(after) = after {
+            query = query.filter(UserColumn::UserId.gt(after));
+        }
+        let mut user_ids: Vec<UserId> = query
+            .order_by_asc(UserColumn::UserId)
+            .limit(first + 1)
+            .into_tuple()
+            .all(&self.sql_pool)
+            .await?;
+        let has_next_page = user_ids.len() as u64 > first;
+        user_ids.truncate(first as usize);
+        let mut users = if user_ids.is_empty() {
+            Vec::new()
+        } else {
+            self.list_users_with_condition(UserColumn::UserId.is_in(user_ids).into_condition())
+                .await?
+        };
+        if get_groups {
+            self.add_inherited_groups(&mut users).await?;
+        }
+        Ok(UserPage {
+            users,
+            has_next_page,
+        })
+    }
+}
+
+fn get_user_list_condition(filters: Option<UserRequestFilter>) -> Cond {
+    let not_deleted = UserColumn::DeletedAt.is_null().into_condition();
+    match filters {
+        Some(f) => not_deleted.add(
+            UserColumn::UserId.in_subquery(
+                model::User::find()
+                    .find_also_linked(model::memberships::UserToGroup)
+                    .select_only()
+                    .column(UserColumn::UserId)
+                    .filter(get_user_filter_expr(f))
+                    .into_query(),
+            ),
+        ),
+        None => not_deleted,
+    }
+}
+
+/// Hard-deletes the users that were soft-deleted more than `retention_days` ago. Their
+/// memberships and attributes go with them.
+pub(crate) async fn purge_deleted_users(
+    connection: &impl ConnectionTrait,
+    retention_days: u64,
+) -> Result<u64> {
+    let limit = chrono::Utc::now().naive_utc() - chrono::Duration::days(retention_days as i64);
+    Ok(model::User::delete_many()
+        .filter(UserColumn::DeletedAt.lte(limit))
+        .exec(connection)
+        .await?
+        .rows_affected)
+}
+
+impl SqlBackendHandler {
+    async fn list_users_with_condition(&self, condition: Cond) -> Result<Vec<UserAndGroups>> {
         let mut users: Vec<_> = model::User::find()
-            .filter(
-                filters
-                    .map(|f| {
-                        UserColumn::UserId
-                            .in_subquery(
-                                model::User::find()
-                                    .find_also_linked(model::memberships::UserToGroup)
-                                    .select_only()
-                                    .column(UserColumn::UserId)
-                                    .filter(get_user_filter_expr(f))
-                                    .into_query(),
-                            )
-                            .into_condition()
-                    })
-                    .unwrap_or_else(|| SimpleExpr::Value(true.into()).into_condition()),
-            )
+            .filter(condition)
             .order_by_asc(UserColumn::UserId)
             .find_with_linked(model::memberships::UserToGroup)
             .order_by_asc(SimpleExpr::Column(
@@ -161,15 +318,161 @@ impl UserListerBackendHandler for SqlBackendHandler {
 }
 
 impl SqlBackendHandler {
+    async fn create_user_with_transaction(
+        transaction: &DatabaseTransaction,
+        request: CreateUserRequest,
+        email_options: &EmailNormalizationOptions,
+        reject_confusable_usernames: bool,
+    ) -> Result<()> {
+        let now = chrono::Utc::now().naive_utc();
+        let uuid = Uuid::from_name_and_date(request.user_id.as_str(), &now);
+        let (email, display_email) = normalize_email(request.email, email_options);
+        let lower_email = email.as_str().to_lowercase();
+        let new_user = model::users::ActiveModel {
+            user_id: Set(request.user_id.clone()),
+            email: Set(email),
+            lowercase_email: Set(lower_email),
+            display_email: Set(display_email),
+            display_name: to_value(&request.display_name),
+            creation_date: ActiveValue::Set(now),
+            uuid: ActiveValue::Set(uuid),
+            legacy_password_hash: Set(request.legacy_password_hash),
+            ..Default::default()
+        };
+        let mut new_user_attributes = Vec::new();
+        if let Some(first_name) = request.first_name {
+            new_user_attributes.push(model::user_attributes::ActiveModel {
+                user_id: Set(request.user_id.clone()),
+                attribute_name: Set("first_name".into()),
+                value: Set(Serialized::from(&first_name)),
+            });
+        }
+        if let Some(last_name) = request.last_name {
+            new_user_attributes.push(model::user_attributes::ActiveModel {
+                user_id: Set(request.user_id.clone()),
+                attribute_name: Set("last_name".into()),
+                value: Set(Serialized::from(&last_name)),
+            });
+        }
+        if let Some(avatar) = request.avatar {
+            new_user_attributes.push(model::user_attributes::ActiveModel {
+                user_id: Set(request.user_id.clone()),
+                attribute_name: Set("avatar".into()),
+                value: Set(Serialized::from(&avatar)),
+            });
+        }
+        if reject_confusable_usernames {
+            check_confusable_username(transaction, &request.user_id).await?;
+        }
+        // The new user doesn't inherit anything from a deleted one with the same ID.
+        model::User::delete_many()
+            .filter(UserColumn::UserId.eq(&request.user_id))
+            .filter(UserColumn::DeletedAt.is_not_null())
+            .exec(transaction)
+            .await?;
+        let schema = Self::get_schema_with_transaction(transaction).await?;
+        for attribute in request.attributes {
+            if schema
+                .user_attributes
+                .get_attribute_type(&attribute.name)
+                .is_some()
+            {
+                new_user_attributes.push(model::user_attributes::ActiveModel {
+                    user_id: Set(request.user_id.clone()),
+                    attribute_name: Set(attribute.name),
+                    value: Set(attribute.value),
+                });
+            } else {
+                return Err(DomainError::InternalError(format!(
+                    "Attribute name {} doesn't exist in the user schema,
+                        yet was attempted to be inserted in the database",
+                    &attribute.name
+                )));
+            }
+        }
+        new_user.insert(transaction).await?;
+        if !new_user_attributes.is_empty() {
+            model::UserAttributes::insert_many(new_user_attributes)
+                .exec(transaction)
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Creates all the users or none.
+    pub(crate) async fn create_users_in_one_transaction(
+        &self,
+        requests: Vec<CreateUserRequest>,
+    ) -> Result<()> {
+        for request in &requests {
+            request
+                .user_id
+                .check_length(self.config.max_username_length)?;
+            check_legacy_password_hash(request)?;
+        }
+        let email_options = self.config.email_normalization.clone();
+        let reject_confusable_usernames = self.config.reject_confusable_usernames;
+        let new_users: Vec<(UserId, String, Option<String>)> = requests
+            .iter()
+            .map(|r| {
+                (
+                    r.user_id.clone(),
+                    r.email.as_str().to_owned(),
+                    r.display_name.clone(),
+                )
+            })
+            .collect();
+        self.sql_pool
+            .transaction::<_, (), DomainError>(|transaction| {
+                Box::pin(async move {
+                    for request in requests {
+                        Self::create_user_with_transaction(
+                            transaction,
+                            request,
+                            &email_options,
+                            reject_confusable_usernames,
+                        )
+                        .await?;
+                    }
+                    Ok(())
+                })
+            })
+            .await?;
+        for (user_id, welcome_email, display_name) in new_users {
+            self.audit(
+                None,
+                AuditAction::CreateUser,
+                Some(user_id.to_string()),
+                true,
+            )
+            .await?;
+            self.notify_lifecycle_event(LifecycleEventType::UserCreated, &user_id, None);
+            if let Some(welcome_notifier) = &self.welcome_notifier {
+                welcome_notifier.welcome(&user_id, &welcome_email, display_name.as_deref());
+            }
+        }
+        Ok(())
+    }
+
     async fn update_user_with_transaction(
         transaction: &DatabaseTransaction,
         request: UpdateUserRequest,
+        email_options: &EmailNormalizationOptions,
     ) -> Result<()> {
-        let lower_email = request.email.as_ref().map(|s| s.as_str().to_lowercase());
+        check_user_not_deleted(transaction, &request.user_id).await?;
+        let (email, display_email) = match request.email {
+            None => (None, None),
+            Some(email) => {
+                let (email, display_email) = normalize_email(email, email_options);
+                (Some(email), Some(display_email))
+            }
+        };
+        let lower_email = email.as_ref().map(|s| s.as_str().to_lowercase());
         let update_user = model::users::ActiveModel {
             user_id: ActiveValue::Set(request.user_id.clone()),
-            email: request.email.map(ActiveValue::Set).unwrap_or_default(),
+            email: email.map(ActiveValue::Set).unwrap_or_default(),
             lowercase_email: lower_email.map(ActiveValue::Set).unwrap_or_default(),
+            display_email: display_email.map(ActiveValue::Set).unwrap_or_default(),
             display_name: to_value(&request.display_name),
             ..Default::default()
         };
@@ -255,6 +558,64 @@ impl SqlBackendHandler {
         }
         Ok(())
     }
+
+    pub(crate) async fn set_typed_user_attribute(
+        &self,
+        user_id: &UserId,
+        name: AttributeName,
+        values: Vec<String>,
+    ) -> Result<()> {
+        let (attribute_type, is_list) = self
+            .get_schema()
+            .await?
+            .user_attributes
+            .get_attribute_type(&name)
+            .ok_or_else(|| {
+                DomainError::EntityNotFound(format!("No such user attribute: '{}'", name))
+            })?;
+        let value = deserialize_attribute_value(&values, attribute_type, is_list).map_err(|e| {
+            DomainError::AttributeTypeMismatch(format!(
+                "Invalid value for {:?} attribute '{}': {:#}",
+                attribute_type, name, e
+            ))
+        })?;
+        self.update_user(UpdateUserRequest {
+            user_id: user_id.clone(),
+            insert_attributes: vec![AttributeValue { name, value }],
+            ..Default::default()
+        })
+        .await
+    }
+
+    pub(crate) async fn restore_deleted_user(&self, user_id: &UserId) -> Result<()> {
+        let limit = chrono::Utc::now().naive_utc()
+            - chrono::Duration::days(self.config.deleted_user_retention_days as i64);
+        let res = model::User::update_many()
+            .col_expr(
+                UserColumn::DeletedAt,
+                Expr::value(Option::<chrono::NaiveDateTime>::None),
+            )
+            .filter(UserColumn::UserId.eq(user_id))
+            .filter(UserColumn::DeletedAt.gt(limit))
+            .exec(&self.sql_pool)
+            .await?;
+        if res.rows_affected == 0 {
+            return Err(DomainError::EntityNotFound(format!(
+                "No recently deleted user: '{}'",
+                user_id
+            )));
+        }
+        self.audit(
+            None,
+            AuditAction::RestoreUser,
+            Some(user_id.to_string()),
+            true,
+        )
+        .await?;
+        // For the systems that mirror LLDAP, the user is back.
+        self.notify_lifecycle_event(LifecycleEventType::UserCreated, user_id, None);
+        Ok(())
+    }
 }
 
 #[async_trait]
@@ -263,6 +624,7 @@ impl UserBackendHandler for SqlBackendHandler {
     async fn get_user_details(&self, user_id: &UserId) -> Result<User> {
         let mut user = User::from(
             model::User::find_by_id(user_id.to_owned())
+                .filter(UserColumn::DeletedAt.is_null())
                 .one(&self.sql_pool)
                 .await?
                 .ok_or_else(|| DomainError::EntityNotFound(user_id.to_string()))?,
@@ -279,6 +641,7 @@ impl UserBackendHandler for SqlBackendHandler {
     #[instrument(skip_all, level = "debug", ret, err, fields(user_id = ?user_id.as_str()))]
     async fn get_user_groups(&self, user_id: &UserId) -> Result<HashSet<GroupDetails>> {
         let user = model::User::find_by_id(user_id.to_owned())
+            .filter(UserColumn::DeletedAt.is_null())
             .one(&self.sql_pool)
             .await?
             .ok_or_else(|| DomainError::EntityNotFound(user_id.to_string()))?;
@@ -291,111 +654,149 @@ impl UserBackendHandler for SqlBackendHandler {
         ))
     }
 
+    #[instrument(skip_all, level = "debug", ret, err, fields(user_id = ?user_id.as_str()))]
+    async fn get_password_changed_at(
+        &self,
+        user_id: &UserId,
+    ) -> Result<Option<chrono::NaiveDateTime>> {
+        model::User::find_by_id(user_id.to_owned())
+            .filter(UserColumn::DeletedAt.is_null())
+            .select_only()
+            .column(UserColumn::PasswordChangedAt)
+            .into_tuple::<Option<chrono::NaiveDateTime>>()
+            .one(&self.sql_pool)
+            .await?
+            .ok_or_else(|| DomainError::EntityNotFound(user_id.to_string()))
+    }
+
+    #[instrument(skip_all, level = "debug", ret, err, fields(user_id = ?user_id.as_str()))]
+    async fn get_last_login_date(&self, user_id: &UserId) -> Result<Option<chrono::NaiveDateTime>> {
+        model::User::find_by_id(user_id.to_owned())
+            .select_only()
+            .column(UserColumn::LastLoginDate)
+            .into_tuple::<Option<chrono::NaiveDateTime>>()
+            .one(&self.sql_pool)
+            .await?
+            .ok_or_else(|| DomainError::EntityNotFound(user_id.to_string()))
+    }
+
     #[instrument(skip(self), level = "debug", err, fields(user_id = ?request.user_id.as_str()))]
     async fn create_user(&self, request: CreateUserRequest) -> Result<()> {
-        let now = chrono::Utc::now().naive_utc();
-        let uuid = Uuid::from_name_and_date(request.user_id.as_str(), &now);
-        let lower_email = request.email.as_str().to_lowercase();
-        let new_user = model::users::ActiveModel {
-            user_id: Set(request.user_id.clone()),
-            email: Set(request.email),
-            lowercase_email: Set(lower_email),
-            display_name: to_value(&request.display_name),
-            creation_date: ActiveValue::Set(now),
-            uuid: ActiveValue::Set(uuid),
-            ..Default::default()
-        };
-        let mut new_user_attributes = Vec::new();
-        if let Some(first_name) = request.first_name {
-            new_user_attributes.push(model::user_attributes::ActiveModel {
-                user_id: Set(request.user_id.clone()),
-                attribute_name: Set("first_name".into()),
-                value: Set(Serialized::from(&first_name)),
-            });
-        }
-        if let Some(last_name) = request.last_name {
-            new_user_attributes.push(model::user_attributes::ActiveModel {
-                user_id: Set(request.user_id.clone()),
-                attribute_name: Set("last_name".into()),
-                value: Set(Serialized::from(&last_name)),
-            });
-        }
-        if let Some(avatar) = request.avatar {
-            new_user_attributes.push(model::user_attributes::ActiveModel {
-                user_id: Set(request.user_id.clone()),
-                attribute_name: Set("avatar".into()),
-                value: Set(Serialized::from(&avatar)),
-            });
-        }
+        request
+            .user_id
+            .check_length(self.config.max_username_length)?;
+        check_legacy_password_hash(&request)?;
+        let email_options = self.config.email_normalization.clone();
+        // Welcome the user at the address they were created with, before any normalization.
+        let welcome_email = request.email.as_str().to_owned();
+        let display_name = request.display_name.clone();
+        let reject_confusable_usernames = self.config.reject_confusable_usernames;
+        let user_id = request.user_id.clone();
         self.sql_pool
             .transaction::<_, (), DomainError>(|transaction| {
                 Box::pin(async move {
-                    let schema = Self::get_schema_with_transaction(transaction).await?;
-                    for attribute in request.attributes {
-                        if schema
-                            .user_attributes
-                            .get_attribute_type(&attribute.name)
-                            .is_some()
-                        {
-                            new_user_attributes.push(model::user_attributes::ActiveModel {
-                                user_id: Set(request.user_id.clone()),
-                                attribute_name: Set(attribute.name),
-                                value: Set(attribute.value),
-                            });
-                        } else {
-                            return Err(DomainError::InternalError(format!(
-                                "Attribute name {} doesn't exist in the user schema,
-                                    yet was attempted to be inserted in the database",
-                                &attribute.name
-                            )));
-                        }
-                    }
-                    new_user.insert(transaction).await?;
-                    if !new_user_attributes.is_empty() {
-                        model::UserAttributes::insert_many(new_user_attributes)
-                            .exec(transaction)
-                            .await?;
-                    }
-                    Ok(())
+                    Self::create_user_with_transaction(
+                        transaction,
+                        request,
+                        &email_options,
+                        reject_confusable_usernames,
+                    )
+                    .await
                 })
             })
             .await?;
+        self.audit(
+            None,
+            AuditAction::CreateUser,
+            Some(user_id.to_string()),
+            true,
+        )
+        .await?;
+        self.notify_lifecycle_event(LifecycleEventType::UserCreated, &user_id, None);
+        if let Some(welcome_notifier) = &self.welcome_notifier {
+            welcome_notifier.welcome(&user_id, &welcome_email, display_name.as_deref());
+        }
         Ok(())
     }
 
     #[instrument(skip(self), level = "debug", err, fields(user_id = ?request.user_id.as_str()))]
     async fn update_user(&self, request: UpdateUserRequest) -> Result<()> {
+        let email_options = self.config.email_normalization.clone();
+        let user_id = request.user_id.clone();
         self.sql_pool
             .transaction::<_, (), DomainError>(|transaction| {
-                Box::pin(
-                    async move { Self::update_user_with_transaction(transaction, request).await },
-                )
+                Box::pin(async move {
+                    Self::update_user_with_transaction(transaction, request, &email_options).await
+                })
             })
             .await?;
+        self.notify_lifecycle_event(LifecycleEventType::UserUpdated, &user_id, None);
         Ok(())
     }
 
     #[instrument(skip_all, level = "debug", err, fields(user_id = ?user_id.as_str()))]
     async fn delete_user(&self, user_id: &UserId) -> Result<()> {
-        let res = model::User::delete_by_id(user_id.clone())
-            .exec(&self.sql_pool)
-            .await?;
-        if res.rows_affected == 0 {
+        // The JWTs are deleted with the user, only the in-memory blacklist keeps them out.
+        revoke_tokens(
+            &self.sql_pool,
+            &self.jwt_blacklist,
+            std::slice::from_ref(user_id),
+        )
+        .await?;
+        let rows_affected = if self.config.deleted_user_retention_days == 0 {
+            model::User::delete_by_id(user_id.clone())
+                .exec(&self.sql_pool)
+                .await?
+                .rows_affected
+        } else {
+            model::User::update_many()
+                .col_expr(
+                    UserColumn::DeletedAt,
+                    Expr::value(chrono::Utc::now().naive_utc()),
+                )
+                .filter(UserColumn::UserId.eq(user_id))
+                .filter(UserColumn::DeletedAt.is_null())
+                .exec(&self.sql_pool)
+                .await?
+                .rows_affected
+        };
+        if rows_affected == 0 {
             return Err(DomainError::EntityNotFound(format!(
                 "No such user: '{}'",
                 user_id
             )));
         }
+        self.audit(
+            None,
+            AuditAction::DeleteUser,
+            Some(user_id.to_string()),
+            true,
+        )
+        .await?;
+        self.notify_lifecycle_event(LifecycleEventType::UserDeleted, user_id, None);
         Ok(())
     }
 
     #[instrument(skip_all, level = "debug", err, fields(user_id = ?user_id.as_str(), group_id))]
     async fn add_user_to_group(&self, user_id: &UserId, group_id: GroupId) -> Result<()> {
+        check_user_not_deleted(&self.sql_pool, user_id).await?;
         let new_membership = model::memberships::ActiveModel {
             user_id: ActiveValue::Set(user_id.clone()),
             group_id: ActiveValue::Set(group_id),
         };
         new_membership.insert(&self.sql_pool).await?;
+        self.audit(
+            None,
+            AuditAction::AddUserToGroup,
+            Some(format!("{} in group {}", user_id, group_id.0)),
+            true,
+        )
+        .await?;
+        self.notify_lifecycle_event(
+            LifecycleEventType::UserAddedToGroup,
+            user_id,
+            Some(group_id),
+        );
         Ok(())
     }
 
@@ -410,6 +811,18 @@ impl UserBackendHandler for SqlBackendHandler {
                 user_id, group_id
             )));
         }
+        self.audit(
+            None,
+            AuditAction::RemoveUserFromGroup,
+            Some(format!("{} in group {}", user_id, group_id.0)),
+            true,
+        )
+        .await?;
+        self.notify_lifecycle_event(
+            LifecycleEventType::UserRemovedFromGroup,
+            user_id,
+            Some(group_id),
+        );
         Ok(())
     }
 }
@@ -418,7 +831,7 @@ impl UserBackendHandler for SqlBackendHandler {
 mod tests {
     use super::*;
     use crate::domain::{
-        handler::SubStringFilter,
+        handler::{BackendHandler, SubStringFilter},
         sql_backend_handler::tests::*,
         types::{JpegPhoto, UserColumn},
     };
@@ -532,6 +945,61 @@ mod tests {
         assert_eq!(users, Vec::<String>::new());
     }
 
+    #[tokio::test]
+    async fn test_list_users_page() {
+        let handler = SqlBackendHandler::new(get_default_config(), get_initialized_db().await);
+        for i in 0..25 {
+            insert_user_no_password(&handler, &format!("user{:02}", i)).await;
+        }
+        let mut seen = Vec::new();
+        let mut after = None;
+        let mut page_sizes = Vec::new();
+        loop {
+            let page = handler
+                .list_users_page(None, false, 10, after)
+                .await
+                .unwrap();
+            page_sizes.push(page.users.len());
+            seen.extend(page.users.iter().map(|u| u.user.user_id.to_string()));
+            after = page.users.last().map(|u| u.user.user_id.clone());
+            if !page.has_next_page {
+                break;
+            }
+            // A user inserted before the cursor doesn't shift the next pages.
+            if page_sizes.len() == 1 {
+                insert_user_no_password(&handler, "user00a").await;
+            }
+        }
+        assert_eq!(page_sizes, vec![10, 10, 5]);
+        assert_eq!(
+            seen,
+            (0..25).map(|i| format!("user{:02}", i)).collect::<Vec<_>>()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_list_users_page_filter() {
+        let fixture = TestFixture::new().await;
+        let page = fixture
+            .handler
+            .list_users_page(
+                Some(UserRequestFilter::MemberOf("Best Group".into())),
+                false,
+                1,
+                Some(UserId::new("bob")),
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            page.users
+                .into_iter()
+                .map(|u| u.user.user_id.to_string())
+                .collect::<Vec<_>>(),
+            vec!["patrick"]
+        );
+        assert!(!page.has_next_page);
+    }
+
     #[tokio::test]
     async fn test_list_users_member_of() {
         let fixture = TestFixture::new().await;
@@ -767,6 +1235,150 @@ mod tests {
         );
     }
 
+    async fn get_soft_delete_handler() -> SqlBackendHandler {
+        let mut config = get_default_config();
+        config.deleted_user_retention_days = 30;
+        let handler = SqlBackendHandler::new(config, get_initialized_db().await);
+        insert_user(&handler, "bob", "bob00").await;
+        insert_user_no_password(&handler, "patrick").await;
+        let group = insert_group(&handler, "Best Group").await;
+        insert_membership(&handler, group, "bob").await;
+        handler
+    }
+
+    async fn bob_can_bind(handler: &SqlBackendHandler) -> bool {
+        use crate::domain::handler::{BindRequest, LoginHandler};
+        handler
+            .bind(BindRequest {
+                name: UserId::new("bob"),
+                password: "bob00".to_owned(),
+                context: None,
+                source: None,
+            })
+            .await
+            .is_ok()
+    }
+
+    async fn get_group_members(handler: &SqlBackendHandler) -> Vec<UserId> {
+        use crate::domain::handler::GroupListerBackendHandler;
+        handler.list_groups(None).await.unwrap()[0].users.clone()
+    }
+
+    #[tokio::test]
+    async fn test_soft_delete_and_restore() {
+        let handler = get_soft_delete_handler().await;
+        let bob = UserId::new("bob");
+        handler.delete_user(&bob).await.unwrap();
+        assert_eq!(get_user_names(&handler, None).await, vec!["patrick"]);
+        assert!(matches!(
+            handler.get_user_details(&bob).await,
+            Err(DomainError::EntityNotFound(_))
+        ));
+        assert!(!bob_can_bind(&handler).await);
+        assert_eq!(get_group_members(&handler).await, vec![]);
+        // Already deleted.
+        assert!(matches!(
+            handler.delete_user(&bob).await,
+            Err(DomainError::EntityNotFound(_))
+        ));
+
+        handler.restore_deleted_user(&bob).await.unwrap();
+        assert_eq!(get_user_names(&handler, None).await, vec!["bob", "patrick"]);
+        assert!(bob_can_bind(&handler).await);
+        assert_eq!(get_group_members(&handler).await, vec![bob.clone()]);
+        // Not deleted anymore.
+        assert!(matches!(
+            handler.restore_deleted_user(&bob).await,
+            Err(DomainError::EntityNotFound(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_deleted_user_cannot_be_changed() {
+        use crate::domain::sql_opaque_handler::{register_password, PasswordActor};
+        let handler = get_soft_delete_handler().await;
+        let bob = UserId::new("bob");
+        handler.delete_user(&bob).await.unwrap();
+        assert!(matches!(
+            handler
+                .update_user(UpdateUserRequest {
+                    user_id: bob.clone(),
+                    display_name: Some("Bobby".to_owned()),
+                    ..Default::default()
+                })
+                .await,
+            Err(DomainError::EntityNotFound(_))
+        ));
+        let group = insert_group(&handler, "Other Group").await;
+        assert!(matches!(
+            handler.add_user_to_group(&bob, group).await,
+            Err(DomainError::EntityNotFound(_))
+        ));
+        assert!(matches!(
+            register_password(
+                &handler,
+                bob.clone(),
+                &secstr::SecUtf8::from("bob01"),
+                PasswordActor::SelfService,
+            )
+            .await,
+            Err(DomainError::EntityNotFound(_))
+        ));
+        // Nothing changed once restored.
+        handler.restore_deleted_user(&bob).await.unwrap();
+        assert!(bob_can_bind(&handler).await);
+        assert_eq!(
+            handler.get_user_details(&bob).await.unwrap().display_name,
+            Some("display bob".to_owned())
+        );
+        assert_eq!(handler.get_user_groups(&bob).await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_purge_deleted_users() {
+        let handler = get_soft_delete_handler().await;
+        let bob = UserId::new("bob");
+        handler.delete_user(&bob).await.unwrap();
+        assert_eq!(purge_deleted_users(&handler.sql_pool, 30).await.unwrap(), 0);
+        model::User::update_many()
+            .col_expr(
+                UserColumn::DeletedAt,
+                Expr::value(chrono::Utc::now().naive_utc() - chrono::Duration::days(31)),
+            )
+            .filter(UserColumn::UserId.eq(&bob))
+            .exec(&handler.sql_pool)
+            .await
+            .unwrap();
+        // Past the retention window.
+        assert!(matches!(
+            handler.restore_deleted_user(&bob).await,
+            Err(DomainError::EntityNotFound(_))
+        ));
+        assert_eq!(purge_deleted_users(&handler.sql_pool, 30).await.unwrap(), 1);
+        assert!(model::User::find_by_id(bob)
+            .one(&handler.sql_pool)
+            .await
+            .unwrap()
+            .is_none());
+        assert_eq!(get_group_members(&handler).await, vec![]);
+    }
+
+    #[tokio::test]
+    async fn test_create_user_replaces_deleted_user() {
+        let handler = get_soft_delete_handler().await;
+        let bob = UserId::new("bob");
+        handler.delete_user(&bob).await.unwrap();
+        insert_user_no_password(&handler, "bob").await;
+        // A brand new user: no password, no groups, and the old one can't be restored.
+        assert!(!bob_can_bind(&handler).await);
+        assert!(handler.get_user_groups(&bob).await.unwrap().is_empty());
+        assert!(matches!(
+            handler.restore_deleted_user(&bob).await,
+            Err(DomainError::EntityNotFound(_))
+        ));
+        assert_eq!(get_user_names(&handler, None).await, vec!["bob", "patrick"]);
+    }
+
     #[tokio::test]
     async fn test_get_user_groups() {
         let fixture = TestFixture::new().await;
@@ -790,6 +1402,39 @@ mod tests {
         assert_eq!(get_group_ids("nogroup").await, vec![]);
     }
 
+    #[tokio::test]
+    async fn test_get_password_changed_at() {
+        use crate::domain::sql_opaque_handler::{register_password, PasswordActor};
+        let fixture = TestFixture::new().await;
+        let bob = UserId::new("bob");
+        assert_eq!(
+            fixture.handler.get_password_changed_at(&bob).await.unwrap(),
+            None
+        );
+        let before = chrono::Utc::now().naive_utc();
+        register_password(
+            &fixture.handler,
+            bob.clone(),
+            &secstr::SecUtf8::from("bob00"),
+            PasswordActor::SelfService,
+        )
+        .await
+        .unwrap();
+        let changed_at = fixture
+            .handler
+            .get_password_changed_at(&bob)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(changed_at >= before);
+        assert!(changed_at <= chrono::Utc::now().naive_utc());
+        fixture
+            .handler
+            .get_password_changed_at(&UserId::new("nobody"))
+            .await
+            .unwrap_err();
+    }
+
     #[tokio::test]
     async fn test_update_user_all_values() {
         let fixture = TestFixture::new().await;
@@ -1043,6 +1688,7 @@ mod tests {
                     name: "first_name".into(),
                     value: Serialized::from("First Name"),
                 }],
+                legacy_password_hash: None,
             })
             .await
             .unwrap();
@@ -1073,6 +1719,99 @@ mod tests {
         );
     }
 
+    fn imported_user(name: &str) -> CreateUserRequest {
+        CreateUserRequest {
+            user_id: UserId::new(name),
+            email: format!("{}@example.com", name).into(),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_create_users_bulk() {
+        let fixture = TestFixture::new().await;
+        let hash = bcrypt::hash("password", 4).unwrap();
+        fixture
+            .handler
+            .create_users_bulk(vec![
+                CreateUserRequest {
+                    legacy_password_hash: Some(hash.clone()),
+                    ..imported_user("alice")
+                },
+                imported_user("carol"),
+            ])
+            .await
+            .unwrap();
+        assert_eq!(
+            get_user_names(&fixture.handler, None).await,
+            vec!["alice", "bob", "carol", "john", "nogroup", "patrick"]
+        );
+        let alice = model::User::find_by_id(UserId::new("alice"))
+            .one(&fixture.handler.sql_pool)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(alice.legacy_password_hash, Some(hash));
+        assert_eq!(alice.password_hash, None);
+    }
+
+    #[tokio::test]
+    async fn test_create_users_bulk_rolls_back_on_existing_user() {
+        let fixture = TestFixture::new().await;
+        fixture
+            .handler
+            .create_users_bulk(vec![
+                imported_user("alice"),
+                imported_user("bob"),
+                imported_user("carol"),
+            ])
+            .await
+            .unwrap_err();
+        assert_eq!(
+            get_user_names(&fixture.handler, None).await,
+            vec!["bob", "john", "nogroup", "patrick"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_create_users_bulk_rolls_back_on_duplicate_in_batch() {
+        let fixture = TestFixture::new().await;
+        fixture
+            .handler
+            .create_users_bulk(vec![
+                imported_user("alice"),
+                imported_user("carol"),
+                imported_user("alice"),
+                imported_user("dave"),
+            ])
+            .await
+            .unwrap_err();
+        assert_eq!(
+            get_user_names(&fixture.handler, None).await,
+            vec!["bob", "john", "nogroup", "patrick"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_create_users_bulk_rejects_invalid_hash() {
+        let fixture = TestFixture::new().await;
+        fixture
+            .handler
+            .create_users_bulk(vec![
+                imported_user("alice"),
+                CreateUserRequest {
+                    legacy_password_hash: Some("{SSHA}abcdef".to_owned()),
+                    ..imported_user("carol")
+                },
+            ])
+            .await
+            .unwrap_err();
+        assert_eq!(
+            get_user_names(&fixture.handler, None).await,
+            vec!["bob", "john", "nogroup", "patrick"]
+        );
+    }
+
     #[tokio::test]
     async fn test_remove_user_from_group() {
         let fixture = TestFixture::new().await;
@@ -1120,4 +1859,260 @@ mod tests {
             .await
             .expect_err("Should have failed");
     }
+
+    async fn get_handler_with_email_normalization(
+        enabled: bool,
+        strip_plus_addressing: bool,
+    ) -> SqlBackendHandler {
+        let mut config = get_default_config();
+        config.email_normalization = EmailNormalizationOptions {
+            enabled,
+            strip_plus_addressing,
+        };
+        SqlBackendHandler::new(config, get_initialized_db().await)
+    }
+
+    #[tokio::test]
+    async fn test_create_user_rejects_confusable_username() {
+        let mut config = get_default_config();
+        config.reject_confusable_usernames = true;
+        let handler = SqlBackendHandler::new(config, get_initialized_db().await);
+        insert_user_no_password(&handler, "paypal").await;
+        // With a Cyrillic "а" and "р".
+        let err = handler
+            .create_user(CreateUserRequest {
+                user_id: UserId::new("\u{0440}\u{0430}yp\u{0430}l"),
+                ..Default::default()
+            })
+            .await
+            .unwrap_err();
+        assert!(matches!(err, DomainError::ConfusableUsername(_)), "{}", err);
+        insert_user_no_password(&handler, "paypal2").await;
+        assert_eq!(
+            get_user_names(&handler, None).await,
+            vec!["paypal".to_owned(), "paypal2".to_owned()]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_create_user_rejects_same_normalized_id() {
+        let fixture = TestFixture::new().await;
+        insert_user_no_password(&fixture.handler, "bob").await;
+        fixture
+            .handler
+            .create_user(CreateUserRequest {
+                user_id: UserId::new("Bob "),
+                ..Default::default()
+            })
+            .await
+            .unwrap_err();
+        assert_eq!(
+            get_user_names(&fixture.handler, None).await,
+            vec!["bob".to_owned()]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_create_user_rejects_long_username() {
+        let mut config = get_default_config();
+        config.max_username_length = 8;
+        let handler = SqlBackendHandler::new(config, get_initialized_db().await);
+        let err = handler
+            .create_user(CreateUserRequest {
+                user_id: UserId::new("bob_the_builder"),
+                ..Default::default()
+            })
+            .await
+            .unwrap_err();
+        assert!(matches!(err, DomainError::InvalidUserId(_)), "{}", err);
+        insert_user_no_password(&handler, "bob").await;
+        assert_eq!(get_user_names(&handler, None).await, vec!["bob".to_owned()]);
+    }
+
+    #[tokio::test]
+    async fn test_create_user_confusable_username_allowed_by_default() {
+        let fixture = TestFixture::new().await;
+        insert_user_no_password(&fixture.handler, "paypal").await;
+        insert_user_no_password(&fixture.handler, "\u{0440}\u{0430}yp\u{0430}l").await;
+    }
+
+    async fn create_user_with_email(handler: &SqlBackendHandler, email: &str) {
+        handler
+            .create_user(CreateUserRequest {
+                user_id: UserId::new("bob"),
+                email: email.into(),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+    }
+
+    async fn get_stored_emails(handler: &SqlBackendHandler) -> (String, Option<String>) {
+        let user = model::User::find_by_id(UserId::new("bob"))
+            .one(&handler.sql_pool)
+            .await
+            .unwrap()
+            .unwrap();
+        (user.email.into_string(), user.display_email)
+    }
+
+    #[tokio::test]
+    async fn test_create_user_email_normalization_disabled() {
+        let handler = get_handler_with_email_normalization(false, true).await;
+        create_user_with_email(&handler, "Bob+LLDAP@Example.COM").await;
+        assert_eq!(
+            get_stored_emails(&handler).await,
+            ("Bob+LLDAP@Example.COM".to_owned(), None)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_create_user_email_normalization_mixed_case() {
+        let handler = get_handler_with_email_normalization(true, false).await;
+        create_user_with_email(&handler, "Bob+LLDAP@Example.COM").await;
+        assert_eq!(
+            get_stored_emails(&handler).await,
+            (
+                "Bob+LLDAP@example.com".to_owned(),
+                Some("Bob+LLDAP@Example.COM".to_owned())
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn test_create_user_email_normalization_strip_plus() {
+        let handler = get_handler_with_email_normalization(true, true).await;
+        create_user_with_email(&handler, "Bob+LLDAP@Example.COM").await;
+        assert_eq!(
+            get_stored_emails(&handler).await,
+            (
+                "Bob@example.com".to_owned(),
+                Some("Bob+LLDAP@Example.COM".to_owned())
+            )
+        );
+        let users = get_user_names(
+            &handler,
+            Some(UserRequestFilter::Equality(
+                UserColumn::Email,
+                "bob@example.com".to_string(),
+            )),
+        )
+        .await;
+        assert_eq!(users, vec!["bob"]);
+    }
+
+    #[tokio::test]
+    async fn test_update_user_email_normalization() {
+        let handler = get_handler_with_email_normalization(true, true).await;
+        create_user_with_email(&handler, "bob@bob.bob").await;
+        handler
+            .update_user(UpdateUserRequest {
+                user_id: UserId::new("bob"),
+                email: Some("Robert+Work@Bob.BOB".into()),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(
+            get_stored_emails(&handler).await,
+            (
+                "Robert@bob.bob".to_owned(),
+                Some("Robert+Work@Bob.BOB".to_owned())
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn test_user_details_show_the_display_email() {
+        let handler = get_handler_with_email_normalization(true, true).await;
+        create_user_with_email(&handler, "Bob+LLDAP@Example.COM").await;
+        let bob = UserId::new("bob");
+        assert_eq!(
+            handler.get_user_details(&bob).await.unwrap().email.as_str(),
+            "Bob+LLDAP@Example.COM"
+        );
+        let users = handler
+            .list_users(
+                Some(UserRequestFilter::Equality(
+                    UserColumn::Email,
+                    "bob@example.com".to_string(),
+                )),
+                false,
+            )
+            .await
+            .unwrap();
+        assert_eq!(users.len(), 1);
+        assert_eq!(users[0].user.email.as_str(), "Bob+LLDAP@Example.COM");
+    }
+
+    #[tokio::test]
+    async fn test_update_user_email_normalization_disabled() {
+        let handler = get_handler_with_email_normalization(false, false).await;
+        create_user_with_email(&handler, "bob@bob.bob").await;
+        handler
+            .update_user(UpdateUserRequest {
+                user_id: UserId::new("bob"),
+                email: Some("Robert+Work@Bob.BOB".into()),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(
+            get_stored_emails(&handler).await,
+            ("Robert+Work@Bob.BOB".to_owned(), None)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_set_typed_user_attribute() {
+        use crate::domain::{
+            handler::{BackendHandler, CreateAttributeRequest, SchemaBackendHandler},
+            types::AttributeType,
+        };
+        let fixture = TestFixture::new().await;
+        fixture
+            .handler
+            .add_user_attribute(CreateAttributeRequest {
+                name: "employee_number".into(),
+                attribute_type: AttributeType::Integer,
+                is_list: false,
+                is_visible: true,
+                is_editable: false,
+            })
+            .await
+            .unwrap();
+        let bob = UserId::new("bob");
+        fixture
+            .handler
+            .set_user_attribute(&bob, "employee_number".into(), vec!["42".to_owned()])
+            .await
+            .unwrap();
+        let err = fixture
+            .handler
+            .set_user_attribute(&bob, "employee_number".into(), vec!["abc".to_owned()])
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(err, DomainError::AttributeTypeMismatch(_)),
+            "{:?}",
+            err
+        );
+        assert!(matches!(
+            fixture
+                .handler
+                .set_user_attribute(&bob, "unknown".into(), vec!["42".to_owned()])
+                .await,
+            Err(DomainError::EntityNotFound(_))
+        ));
+        assert!(fixture
+            .handler
+            .get_user_details(&bob)
+            .await
+            .unwrap()
+            .attributes
+            .contains(&AttributeValue {
+                name: "employee_number".into(),
+                value: Serialized::from(&42i64),
+            }));
+    }
 }
@@ -0,0 +1,217 @@
+use crate::domain::{
+    error::{DomainError, Result},
+    model::{self, ThrottleCountersColumn},
+    sql_tables::DbConnection,
+};
+use async_trait::async_trait;
+use chrono::{Duration, NaiveDateTime};
+use sea_orm::{
+    sea_query::Expr, ActiveModelTrait, ColumnTrait, EntityTrait, QueryFilter, Set, TransactionTrait,
+};
+use std::{collections::HashMap, sync::Mutex};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ThrottleCounter {
+    pub count: u32,
+    /// When the counter goes back to 0.
+    pub expiry_date: NaiveDateTime,
+}
+
+/// Stores the counters used for rate limiting and lockouts.
+///
+/// The counters are fixed windows: the first increment sets the expiry date, and the following
+/// ones don't change it.
+#[async_trait]
+pub trait ThrottleStore: Send + Sync {
+    /// Increments the counter for the key, and returns its new value.
+    async fn increment(&self, key: &str, window: Duration) -> Result<ThrottleCounter>;
+    /// Returns the current counter for the key, if it exists and hasn't expired.
+    async fn get(&self, key: &str) -> Result<Option<ThrottleCounter>>;
+    async fn reset(&self, key: &str) -> Result<()>;
+}
+
+/// Keeps the counters in the memory of the current instance.
+#[derive(Default)]
+pub struct MemoryThrottleStore {
+    counters: Mutex<HashMap<String, ThrottleCounter>>,
+}
+
+#[async_trait]
+impl ThrottleStore for MemoryThrottleStore {
+    async fn increment(&self, key: &str, window: Duration) -> Result<ThrottleCounter> {
+        let now = chrono::Utc::now().naive_utc();
+        let mut counters = self.counters.lock().unwrap();
+        let counter = counters
+            .entry(key.to_owned())
+            .and_modify(|c| {
+                if c.expiry_date <= now {
+                    *c = ThrottleCounter {
+                        count: 0,
+                        expiry_date: now + window,
+                    };
+                }
+            })
+            .or_insert(ThrottleCounter {
+                count: 0,
+                expiry_date: now + window,
+            });
+        counter.count += 1;
+        Ok(*counter)
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<ThrottleCounter>> {
+        let now = chrono::Utc::now().naive_utc();
+        Ok(self
+            .counters
+            .lock()
+            .unwrap()
+            .get(key)
+            .filter(|c| c.expiry_date > now)
+            .copied())
+    }
+
+    async fn reset(&self, key: &str) -> Result<()> {
+        self.counters.lock().unwrap().remove(key);
+        Ok(())
+    }
+}
+
+/// Keeps the counters in the database, to share them between instances.
+pub struct SqlThrottleStore {
+    sql_pool: DbConnection,
+}
+
+impl SqlThrottleStore {
+    pub fn new(sql_pool: DbConnection) -> Self {
+        Self { sql_pool }
+    }
+}
+
+#[async_trait]
+impl ThrottleStore for SqlThrottleStore {
+    async fn increment(&self, key: &str, window: Duration) -> Result<ThrottleCounter> {
+        let key = key.to_owned();
+        let now = chrono::Utc::now().naive_utc();
+        Ok(self
+            .sql_pool
+            .transaction::<_, ThrottleCounter, DomainError>(|transaction| {
+                Box::pin(async move {
+                    let updated = model::ThrottleCounters::update_many()
+                        .col_expr(
+                            ThrottleCountersColumn::Count,
+                            Expr::col(ThrottleCountersColumn::Count).add(1),
+                        )
+                        .filter(ThrottleCountersColumn::ThrottleKey.eq(key.as_str()))
+                        .filter(ThrottleCountersColumn::ExpiryDate.gt(now))
+                        .exec(transaction)
+                        .await?;
+                    if updated.rows_affected == 0 {
+                        // Either there was no counter, or it expired.
+                        model::ThrottleCounters::delete_by_id(key.clone())
+                            .exec(transaction)
+                            .await?;
+                        model::throttle_counters::ActiveModel {
+                            throttle_key: Set(key.clone()),
+                            count: Set(1),
+                            expiry_date: Set(now + window),
+                        }
+                        .insert(transaction)
+                        .await?;
+                    }
+                    let counter = model::ThrottleCounters::find_by_id(key.clone())
+                        .one(transaction)
+                        .await?
+                        .ok_or_else(|| {
+                            DomainError::InternalError(format!("Missing throttle counter {}", key))
+                        })?;
+                    Ok(ThrottleCounter {
+                        count: counter.count as u32,
+                        expiry_date: counter.expiry_date,
+                    })
+                })
+            })
+            .await?)
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<ThrottleCounter>> {
+        let now = chrono::Utc::now().naive_utc();
+        Ok(model::ThrottleCounters::find_by_id(key.to_owned())
+            .filter(ThrottleCountersColumn::ExpiryDate.gt(now))
+            .one(&self.sql_pool)
+            .await?
+            .map(|c| ThrottleCounter {
+                count: c.count as u32,
+                expiry_date: c.expiry_date,
+            }))
+    }
+
+    async fn reset(&self, key: &str) -> Result<()> {
+        model::ThrottleCounters::delete_by_id(key.to_owned())
+            .exec(&self.sql_pool)
+            .await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::sql_backend_handler::tests::get_initialized_db;
+    use pretty_assertions::assert_eq;
+
+    async fn check_store(store: &dyn ThrottleStore) {
+        let window = Duration::minutes(5);
+        assert_eq!(store.get("bob").await.unwrap(), None);
+        assert_eq!(store.increment("bob", window).await.unwrap().count, 1);
+        assert_eq!(store.increment("bob", window).await.unwrap().count, 2);
+        assert_eq!(store.increment("john", window).await.unwrap().count, 1);
+        assert_eq!(store.get("bob").await.unwrap().unwrap().count, 2);
+        store.reset("bob").await.unwrap();
+        assert_eq!(store.get("bob").await.unwrap(), None);
+        assert_eq!(store.get("john").await.unwrap().unwrap().count, 1);
+    }
+
+    async fn check_expiry(store: &dyn ThrottleStore) {
+        assert_eq!(
+            store
+                .increment("bob", Duration::seconds(-1))
+                .await
+                .unwrap()
+                .count,
+            1
+        );
+        assert_eq!(store.get("bob").await.unwrap(), None);
+        assert_eq!(
+            store
+                .increment("bob", Duration::minutes(5))
+                .await
+                .unwrap()
+                .count,
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn test_memory_store() {
+        check_store(&MemoryThrottleStore::default()).await;
+        check_expiry(&MemoryThrottleStore::default()).await;
+    }
+
+    #[tokio::test]
+    async fn test_sql_store() {
+        check_store(&SqlThrottleStore::new(get_initialized_db().await)).await;
+        check_expiry(&SqlThrottleStore::new(get_initialized_db().await)).await;
+    }
+
+    #[tokio::test]
+    async fn test_sql_store_shared_between_instances() {
+        let sql_pool = get_initialized_db().await;
+        let first = SqlThrottleStore::new(sql_pool.clone());
+        let second = SqlThrottleStore::new(sql_pool);
+        let window = Duration::minutes(5);
+        first.increment("bob", window).await.unwrap();
+        assert_eq!(second.increment("bob", window).await.unwrap().count, 2);
+        first.reset("bob").await.unwrap();
+        assert_eq!(second.get("bob").await.unwrap(), None);
+    }
+}
@@ -0,0 +1,127 @@
+//! Time-based one-time passwords (RFC 6238) with HMAC-SHA1 and 6 digits, as generated by the
+//! common authenticator apps.
+
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+
+const DIGITS: usize = 6;
+/// The codes of the previous and next periods are accepted too, for clock drift.
+const ALLOWED_DRIFT_STEPS: i64 = 1;
+/// 80 bits, the minimum recommended by RFC 4226.
+const MIN_SECRET_LENGTH: usize = 10;
+
+/// Decodes a secret as shown to the users: base32 (RFC 4648), case-insensitive, with or without
+/// padding. Returns None if it's invalid or too short.
+pub fn decode_secret(secret: &str) -> Option<Vec<u8>> {
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+    let mut bytes = Vec::new();
+    for c in secret.trim_end_matches('=').bytes() {
+        let value = match c.to_ascii_uppercase() {
+            c @ b'A'..=b'Z' => c - b'A',
+            c @ b'2'..=b'7' => c - b'2' + 26,
+            _ => return None,
+        };
+        buffer = (buffer << 5) | value as u32;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((buffer >> bits) as u8);
+            buffer &= (1 << bits) - 1;
+        }
+    }
+    Some(bytes).filter(|b| b.len() >= MIN_SECRET_LENGTH)
+}
+
+/// The HOTP code (RFC 4226) for the counter.
+fn generate_code(secret: &[u8], counter: u64) -> String {
+    let mut mac = Hmac::<Sha1>::new_from_slice(secret).expect("HMAC accepts keys of any size");
+    mac.update(&counter.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+    let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+    let truncated = u32::from_be_bytes([
+        hash[offset],
+        hash[offset + 1],
+        hash[offset + 2],
+        hash[offset + 3],
+    ]) & 0x7fff_ffff;
+    format!(
+        "{:0width$}",
+        truncated % 10u32.pow(DIGITS as u32),
+        width = DIGITS
+    )
+}
+
+/// The code at the given Unix time.
+pub fn code_at(secret: &[u8], unix_time: i64, period_seconds: u64) -> String {
+    generate_code(secret, unix_time.div_euclid(period_seconds as i64) as u64)
+}
+
+/// Whether the code is valid at the given Unix time, give or take one period.
+pub fn verify_code(secret: &[u8], code: &str, unix_time: i64, period_seconds: u64) -> bool {
+    if code.len() != DIGITS || !code.bytes().all(|b| b.is_ascii_digit()) {
+        return false;
+    }
+    let step = unix_time.div_euclid(period_seconds as i64);
+    (-ALLOWED_DRIFT_STEPS..=ALLOWED_DRIFT_STEPS)
+        .filter_map(|drift| u64::try_from(step + drift).ok())
+        .fold(false, |valid, counter| {
+            // Check all the steps, to take the same time whichever matches.
+            constant_time_eq(generate_code(secret, counter).as_bytes(), code.as_bytes()) | valid
+        })
+}
+
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    /// "12345678901234567890", the secret of the RFC 6238 test vectors.
+    const RFC_SECRET: &str = "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ";
+
+    #[test]
+    fn test_decode_secret() {
+        assert_eq!(
+            decode_secret(RFC_SECRET).unwrap(),
+            b"12345678901234567890".to_vec()
+        );
+        assert_eq!(
+            decode_secret(&RFC_SECRET.to_lowercase()),
+            decode_secret(RFC_SECRET)
+        );
+        assert_eq!(decode_secret("GEZDGNBVGY3TQOJQ"), None);
+        assert_eq!(decode_secret("not base32!"), None);
+    }
+
+    #[test]
+    fn test_rfc_6238_vectors() {
+        let secret = decode_secret(RFC_SECRET).unwrap();
+        // The last 6 digits of the 8-digit codes of the RFC.
+        for (time, code) in [
+            (59, "287082"),
+            (1111111109, "081804"),
+            (1234567890, "005924"),
+            (2000000000, "279037"),
+        ] {
+            assert_eq!(code_at(&secret, time, 30), code, "{}", time);
+        }
+    }
+
+    #[test]
+    fn test_verify_code() {
+        let secret = decode_secret(RFC_SECRET).unwrap();
+        let code = code_at(&secret, 1111111109, 30);
+        assert!(verify_code(&secret, &code, 1111111109, 30));
+        // One period of drift.
+        assert!(verify_code(&secret, &code, 1111111109 - 30, 30));
+        assert!(verify_code(&secret, &code, 1111111109 + 30, 30));
+        // Expired.
+        assert!(!verify_code(&secret, &code, 1111111109 + 90, 30));
+        assert!(!verify_code(&secret, "12345", 1111111109, 30));
+        assert!(!verify_code(&secret, "08180a", 1111111109, 30));
+    }
+}
@@ -409,6 +409,7 @@ pub struct User {
     pub creation_date: NaiveDateTime,
     pub uuid: Uuid,
     pub attributes: Vec<AttributeValue>,
+    pub is_enabled: bool,
 }
 
 #[cfg(test)]
@@ -422,6 +423,7 @@ impl Default for User {
             creation_date: epoch,
             uuid: Uuid::from_name_and_date("", &epoch),
             attributes: Vec::new(),
+            is_enabled: true,
         }
     }
 }
@@ -7,9 +7,10 @@ use crate::domain::{
     error::Result,
     handler::{
         AttributeSchema, BackendHandler, CreateAttributeRequest, CreateGroupRequest,
-        CreateUserRequest, GroupBackendHandler, GroupListerBackendHandler, GroupRequestFilter,
-        ReadSchemaBackendHandler, Schema, SchemaBackendHandler, UpdateGroupRequest,
-        UpdateUserRequest, UserBackendHandler, UserListerBackendHandler, UserRequestFilter,
+        CreateUserRequest, GroupBackendHandler, GroupListerBackendHandler, GroupPage,
+        GroupRequestFilter, ReadSchemaBackendHandler, Schema, SchemaBackendHandler,
+        UpdateGroupRequest, UpdateUserRequest, UserBackendHandler, UserListerBackendHandler,
+        UserPage, UserRequestFilter,
     },
     schema::PublicSchema,
     types::{AttributeName, Group, GroupDetails, GroupId, GroupName, User, UserAndGroups, UserId},
@@ -75,6 +76,11 @@ impl ValidationResults {
 pub trait UserReadableBackendHandler: ReadSchemaBackendHandler {
     async fn get_user_details(&self, user_id: &UserId) -> Result<User>;
     async fn get_user_groups(&self, user_id: &UserId) -> Result<HashSet<GroupDetails>>;
+    async fn get_password_changed_at(
+        &self,
+        user_id: &UserId,
+    ) -> Result<Option<chrono::NaiveDateTime>>;
+    async fn get_last_login_date(&self, user_id: &UserId) -> Result<Option<chrono::NaiveDateTime>>;
     async fn get_schema(&self) -> Result<PublicSchema>;
 }
 
@@ -85,6 +91,13 @@ pub trait ReadonlyBackendHandler: UserReadableBackendHandler {
         filters: Option<UserRequestFilter>,
         get_groups: bool,
     ) -> Result<Vec<UserAndGroups>>;
+    async fn list_users_page(
+        &self,
+        filters: Option<UserRequestFilter>,
+        get_groups: bool,
+        first: u64,
+        after: Option<UserId>,
+    ) -> Result<UserPage>;
     async fn list_groups(&self, filters: Option<GroupRequestFilter>) -> Result<Vec<Group>>;
     async fn get_group_details(&self, group_id: GroupId) -> Result<GroupDetails>;
 }
@@ -103,8 +116,15 @@ pub trait AdminBackendHandler:
 {
     async fn create_user(&self, request: CreateUserRequest) -> Result<()>;
     async fn delete_user(&self, user_id: &UserId) -> Result<()>;
+    async fn restore_user(&self, user_id: &UserId) -> Result<()>;
     async fn add_user_to_group(&self, user_id: &UserId, group_id: GroupId) -> Result<()>;
     async fn remove_user_from_group(&self, user_id: &UserId, group_id: GroupId) -> Result<()>;
+    async fn add_group_to_group(&self, group_id: GroupId, parent_group_id: GroupId) -> Result<()>;
+    async fn remove_group_from_group(
+        &self,
+        group_id: GroupId,
+        parent_group_id: GroupId,
+    ) -> Result<()>;
     async fn update_group(&self, request: UpdateGroupRequest) -> Result<()>;
     async fn create_group(&self, request: CreateGroupRequest) -> Result<GroupId>;
     async fn delete_group(&self, group_id: GroupId) -> Result<()>;
@@ -122,6 +142,15 @@ impl<Handler: BackendHandler> UserReadableBackendHandler for Handler {
     async fn get_user_groups(&self, user_id: &UserId) -> Result<HashSet<GroupDetails>> {
         <Handler as UserBackendHandler>::get_user_groups(self, user_id).await
     }
+    async fn get_password_changed_at(
+        &self,
+        user_id: &UserId,
+    ) -> Result<Option<chrono::NaiveDateTime>> {
+        <Handler as UserBackendHandler>::get_password_changed_at(self, user_id).await
+    }
+    async fn get_last_login_date(&self, user_id: &UserId) -> Result<Option<chrono::NaiveDateTime>> {
+        <Handler as UserBackendHandler>::get_last_login_date(self, user_id).await
+    }
     async fn get_schema(&self) -> Result<PublicSchema> {
         Ok(PublicSchema::from(
             <Handler as ReadSchemaBackendHandler>::get_schema(self).await?,
@@ -138,6 +167,18 @@ impl<Handler: BackendHandler> ReadonlyBackendHandler for Handler {
     ) -> Result<Vec<UserAndGroups>> {
         <Handler as UserListerBackendHandler>::list_users(self, filters, get_groups).await
     }
+    async fn list_users_page(
+        &self,
+        filters: Option<UserRequestFilter>,
+        get_groups: bool,
+        first: u64,
+        after: Option<UserId>,
+    ) -> Result<UserPage> {
+        <Handler as UserListerBackendHandler>::list_users_page(
+            self, filters, get_groups, first, after,
+        )
+        .await
+    }
     async fn list_groups(&self, filters: Option<GroupRequestFilter>) -> Result<Vec<Group>> {
         <Handler as GroupListerBackendHandler>::list_groups(self, filters).await
     }
@@ -160,12 +201,25 @@ impl<Handler: BackendHandler> AdminBackendHandler for Handler {
     async fn delete_user(&self, user_id: &UserId) -> Result<()> {
         <Handler as UserBackendHandler>::delete_user(self, user_id).await
     }
+    async fn restore_user(&self, user_id: &UserId) -> Result<()> {
+        <Handler as BackendHandler>::restore_user(self, user_id).await
+    }
     async fn add_user_to_group(&self, user_id: &UserId, group_id: GroupId) -> Result<()> {
         <Handler as UserBackendHandler>::add_user_to_group(self, user_id, group_id).await
     }
     async fn remove_user_from_group(&self, user_id: &UserId, group_id: GroupId) -> Result<()> {
         <Handler as UserBackendHandler>::remove_user_from_group(self, user_id, group_id).await
     }
+    async fn add_group_to_group(&self, group_id: GroupId, parent_group_id: GroupId) -> Result<()> {
+        <Handler as BackendHandler>::add_group_to_group(self, group_id, parent_group_id).await
+    }
+    async fn remove_group_from_group(
+        &self,
+        group_id: GroupId,
+        parent_group_id: GroupId,
+    ) -> Result<()> {
+        <Handler as BackendHandler>::remove_group_from_group(self, group_id, parent_group_id).await
+    }
     async fn update_group(&self, request: UpdateGroupRequest) -> Result<()> {
         <Handler as GroupBackendHandler>::update_group(self, request).await
     }
@@ -320,36 +374,77 @@ impl<'a, Handler: UserListerBackendHandler + Sync> UserListerBackendHandler
         filters: Option<UserRequestFilter>,
         get_groups: bool,
     ) -> Result<Vec<UserAndGroups>> {
+        self.handler
+            .list_users(self.restrict_user_filter(filters), get_groups)
+            .await
+    }
+
+    async fn list_users_page(
+        &self,
+        filters: Option<UserRequestFilter>,
+        get_groups: bool,
+        first: u64,
+        after: Option<UserId>,
+    ) -> Result<UserPage> {
+        self.handler
+            .list_users_page(self.restrict_user_filter(filters), get_groups, first, after)
+            .await
+    }
+}
+
+impl<'a, Handler> UserRestrictedListerBackendHandler<'a, Handler> {
+    fn restrict_user_filter(
+        &self,
+        filters: Option<UserRequestFilter>,
+    ) -> Option<UserRequestFilter> {
         let user_filter = self
             .user_filter
             .as_ref()
             .map(|u| UserRequestFilter::UserId(u.clone()));
-        let filters = match (filters, user_filter) {
+        match (filters, user_filter) {
             (None, None) => None,
             (None, u) => u,
             (f, None) => f,
             (Some(f), Some(u)) => Some(UserRequestFilter::And(vec![f, u])),
-        };
-        self.handler.list_users(filters, get_groups).await
+        }
     }
-}
 
-#[async_trait]
-impl<'a, Handler: GroupListerBackendHandler + Sync> GroupListerBackendHandler
-    for UserRestrictedListerBackendHandler<'a, Handler>
-{
-    async fn list_groups(&self, filters: Option<GroupRequestFilter>) -> Result<Vec<Group>> {
+    fn restrict_group_filter(
+        &self,
+        filters: Option<GroupRequestFilter>,
+    ) -> Option<GroupRequestFilter> {
         let group_filter = self
             .user_filter
             .as_ref()
             .map(|u| GroupRequestFilter::Member(u.clone()));
-        let filters = match (filters, group_filter) {
+        match (filters, group_filter) {
             (None, None) => None,
             (None, u) => u,
             (f, None) => f,
             (Some(f), Some(u)) => Some(GroupRequestFilter::And(vec![f, u])),
-        };
-        self.handler.list_groups(filters).await
+        }
+    }
+}
+
+#[async_trait]
+impl<'a, Handler: GroupListerBackendHandler + Sync> GroupListerBackendHandler
+    for UserRestrictedListerBackendHandler<'a, Handler>
+{
+    async fn list_groups(&self, filters: Option<GroupRequestFilter>) -> Result<Vec<Group>> {
+        self.handler
+            .list_groups(self.restrict_group_filter(filters))
+            .await
+    }
+
+    async fn list_groups_page(
+        &self,
+        filters: Option<GroupRequestFilter>,
+        first: u64,
+        after: Option<GroupId>,
+    ) -> Result<GroupPage> {
+        self.handler
+            .list_groups_page(self.restrict_group_filter(filters), first, after)
+            .await
     }
 }
 
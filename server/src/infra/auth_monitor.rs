@@ -0,0 +1,145 @@
+use crate::{
+    domain::handler::{AuthSuccessRatio, BackendHandler},
+    infra::configuration::AuthAlertOptions,
+};
+use actix::prelude::{Actor, AsyncContext, Context};
+use std::time::Duration;
+use tracing::{error, info, instrument, warn};
+
+/// Periodically checks the proportion of failed authentications, and warns when it spikes.
+pub struct AuthMonitor<Backend> {
+    options: AuthAlertOptions,
+    backend_handler: Backend,
+}
+
+impl<Backend> Actor for AuthMonitor<Backend>
+where
+    Backend: BackendHandler + Clone + 'static,
+{
+    type Context = Context<Self>;
+
+    fn started(&mut self, context: &mut Context<Self>) {
+        info!("Authentication monitor started");
+        context.run_interval(
+            Duration::from_secs(self.options.check_interval_seconds),
+            |this, ctx| {
+                let future = actix::fut::wrap_future::<_, Self>(Self::check(
+                    this.backend_handler.clone(),
+                    this.options.clone(),
+                ));
+                ctx.spawn(future);
+            },
+        );
+    }
+}
+
+impl<Backend> AuthMonitor<Backend>
+where
+    Backend: BackendHandler + Clone + 'static,
+{
+    pub fn new(options: AuthAlertOptions, backend_handler: Backend) -> Self {
+        Self {
+            options,
+            backend_handler,
+        }
+    }
+
+    #[instrument(skip_all)]
+    async fn check(backend_handler: Backend, options: AuthAlertOptions) {
+        match backend_handler
+            .current_auth_success_ratio(chrono::Duration::seconds(options.window_seconds as i64))
+            .await
+        {
+            Ok(ratio) => {
+                if is_failure_spike(&ratio, &options) {
+                    warn!(
+                        "{} out of {} authentications failed in the last {} seconds, this could be an attack or a misconfigured client",
+                        ratio.failures,
+                        ratio.attempts(),
+                        options.window_seconds
+                    );
+                }
+            }
+            Err(e) => error!("Could not compute the authentication success ratio: {}", e),
+        }
+    }
+}
+
+fn is_failure_spike(ratio: &AuthSuccessRatio, options: &AuthAlertOptions) -> bool {
+    options.failure_ratio_threshold > 0.0
+        && ratio.attempts() >= options.min_attempts
+        && ratio
+            .failure_ratio()
+            .map(|r| r > options.failure_ratio_threshold)
+            .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::{
+        handler::{LoginFailureReason, LoginSource},
+        sql_backend_handler::{tests::*, SqlBackendHandler},
+        types::UserId,
+    };
+    use crate::infra::configuration::AuthAlertOptionsBuilder;
+
+    async fn record(handler: &SqlBackendHandler, successes: usize, failures: usize) {
+        let bob = UserId::new("bob");
+        for _ in 0..successes {
+            handler
+                .record_auth_event(&bob, LoginSource::Ldap, None)
+                .await
+                .unwrap();
+        }
+        for _ in 0..failures {
+            handler
+                .record_auth_event(
+                    &bob,
+                    LoginSource::Ldap,
+                    Some(LoginFailureReason::InvalidPassword),
+                )
+                .await
+                .unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_failure_spike() {
+        let mut config = get_default_config();
+        config.enable_auth_events = true;
+        let handler = SqlBackendHandler::new(config, get_initialized_db().await);
+        let options = AuthAlertOptionsBuilder::default()
+            .failure_ratio_threshold(0.5)
+            .min_attempts(4)
+            .build()
+            .unwrap();
+        let window = chrono::Duration::minutes(5);
+        let check = || async {
+            is_failure_spike(
+                &handler.current_auth_success_ratio(window).await.unwrap(),
+                &options,
+            )
+        };
+        // Not enough attempts yet.
+        record(&handler, 0, 3).await;
+        assert!(!check().await);
+        record(&handler, 1, 0).await;
+        assert!(check().await);
+        record(&handler, 3, 0).await;
+        assert!(!check().await);
+        record(&handler, 0, 2).await;
+        assert!(check().await);
+    }
+
+    #[test]
+    fn test_disabled_by_default() {
+        assert!(!is_failure_spike(
+            &AuthSuccessRatio {
+                successes: 0,
+                failures: 100,
+            },
+            &AuthAlertOptions::default()
+        ));
+    }
+}
@@ -11,6 +11,7 @@ use futures::future::{ok, Ready};
 use futures_util::FutureExt;
 use hmac::Hmac;
 use jwt::{SignWithKey, VerifyWithKey};
+use serde::Deserialize;
 use sha2::Sha512;
 use std::{
     collections::HashSet,
@@ -19,9 +20,12 @@ use std::{
     task::{Context, Poll},
 };
 use time::ext::NumericalDuration;
-use tracing::{debug, info, instrument, warn};
+use tracing::{debug, instrument, warn};
 
-use lldap_auth::{login, password_reset, registration, JWTClaims};
+use lldap_auth::{
+    login, password_policy::PasswordPolicy, password_reset, registration,
+    server_setup::PublicServerSetup, JWTClaims,
+};
 
 use crate::{
     domain::{
@@ -53,6 +57,7 @@ async fn create_jwt<Handler: TcpBackendHandler>(
     key: &Hmac<Sha512>,
     user: &UserId,
     groups: HashSet<GroupDetails>,
+    auth_time: Option<DateTime<Utc>>,
 ) -> SignedToken {
     let claims = JWTClaims {
         exp: Utc::now() + chrono::Duration::days(1),
@@ -62,6 +67,7 @@ async fn create_jwt<Handler: TcpBackendHandler>(
             .into_iter()
             .map(|g| g.display_name.into_string())
             .collect(),
+        auth_time,
     };
     let expiry = claims.exp.naive_utc();
     let header = jwt::Header {
@@ -120,7 +126,7 @@ where
         path.push('/');
     };
     let groups = data.get_readonly_handler().get_user_groups(&user).await?;
-    let token = create_jwt(data.get_tcp_handler(), jwt_key, &user, groups).await;
+    let token = create_jwt(data.get_tcp_handler(), jwt_key, &user, groups, None).await;
     Ok(HttpResponse::Ok()
         .cookie(
             Cookie::build("token", token.as_str())
@@ -133,6 +139,7 @@ where
         .json(&login::ServerLoginResponse {
             token: token.as_str().to_owned(),
             refresh_token: None,
+            must_change_password: false,
         }))
 }
 
@@ -148,6 +155,19 @@ where
         .unwrap_or_else(error_to_http_response)
 }
 
+/// The user of a password reset or login link request: by user ID, or by email if allowed.
+fn user_lookup_filter<Backend>(data: &AppState<Backend>, user_string: &str) -> UserRequestFilter {
+    let by_user_id = UserRequestFilter::UserId(UserId::new(user_string));
+    if data.allow_email_login {
+        UserRequestFilter::Or(vec![
+            by_user_id,
+            UserRequestFilter::Equality(UserColumn::Email, user_string.to_owned()),
+        ])
+    } else {
+        by_user_id
+    }
+}
+
 #[instrument(skip_all, level = "debug")]
 async fn get_password_reset_step1<Backend>(
     data: web::Data<AppState<Backend>>,
@@ -162,13 +182,7 @@ where
         .ok_or_else(|| TcpError::BadRequest("Missing user ID".to_string()))?;
     let user_results = data
         .get_readonly_handler()
-        .list_users(
-            Some(UserRequestFilter::Or(vec![
-                UserRequestFilter::UserId(UserId::new(user_string)),
-                UserRequestFilter::Equality(UserColumn::Email, user_string.to_owned()),
-            ])),
-            false,
-        )
+        .list_users(Some(user_lookup_filter(&data, user_string)), false)
         .await?;
     if user_results.is_empty() {
         return Ok(());
@@ -186,24 +200,14 @@ where
         None => return Ok(()),
         Some(token) => token,
     };
-    if let Err(e) = super::mail::send_password_reset_email(
+    // Sent in the background: the answer doesn't depend on the SMTP server.
+    data.mailer.send_password_reset_email(
         user.display_name
             .as_deref()
             .unwrap_or_else(|| user.user_id.as_str()),
         user.email.as_str(),
         &token,
-        &data.server_url,
-        &data.mail_options,
-    )
-    .await
-    {
-        warn!("Error sending email: {:#?}", e);
-        info!("Reset token: {}", token);
-        return Err(TcpError::InternalServerError(format!(
-            "Could not send email: {}",
-            e
-        )));
-    }
+    );
     Ok(())
 }
 
@@ -244,8 +248,20 @@ where
         .get_tcp_handler()
         .delete_password_reset_token(token)
         .await;
+    data.backend_handler
+        .unsafe_get_handler()
+        .allow_password_overwrite(&user_id)
+        .await?;
     let groups = HashSet::new();
-    let token = create_jwt(data.get_tcp_handler(), &data.jwt_key, &user_id, groups).await;
+    let token = create_jwt(
+        data.get_tcp_handler(),
+        &data.jwt_key,
+        &user_id,
+        groups,
+        // The reset token was just used: this counts as a recent authentication.
+        Some(Utc::now()),
+    )
+    .await;
     let mut path = data.server_url.path().to_string();
     if !path.ends_with('/') {
         path.push('/');
@@ -278,6 +294,103 @@ where
         .unwrap_or_else(error_to_http_response)
 }
 
+#[instrument(skip_all, level = "debug")]
+async fn get_login_link_step1<Backend>(
+    data: web::Data<AppState<Backend>>,
+    request: HttpRequest,
+) -> TcpResult<()>
+where
+    Backend: TcpBackendHandler + BackendHandler + 'static,
+{
+    let user_string = request
+        .match_info()
+        .get("user_id")
+        .ok_or_else(|| TcpError::BadRequest("Missing user ID".to_string()))?;
+    let user_results = data
+        .get_readonly_handler()
+        .list_users(Some(user_lookup_filter(&data, user_string)), false)
+        .await?;
+    // The answer is the same whether or not a link is sent, to not reveal who has an account.
+    if user_results.len() != 1 {
+        if user_results.len() > 1 {
+            warn!(
+                r#"Ambiguous user id or email "{}" for a login link"#,
+                user_string
+            );
+        }
+        return Ok(());
+    }
+    let user = &user_results[0].user;
+    let token = match data.get_tcp_handler().issue_login_link(&user.user_id).await {
+        Ok(token) => token,
+        Err(e) => {
+            debug!("No login link for {}: {:#}", &user.user_id, e);
+            return Ok(());
+        }
+    };
+    // Sent in the background: neither the answer nor its timing depend on the SMTP server.
+    data.mailer.send_login_link_email(
+        user.display_name
+            .as_deref()
+            .unwrap_or_else(|| user.user_id.as_str()),
+        user.email.as_str(),
+        &token,
+    );
+    Ok(())
+}
+
+async fn get_login_link_step1_handler<Backend>(
+    data: web::Data<AppState<Backend>>,
+    request: HttpRequest,
+) -> HttpResponse
+where
+    Backend: TcpBackendHandler + BackendHandler + 'static,
+{
+    get_login_link_step1(data, request)
+        .await
+        .map(|()| HttpResponse::Ok().finish())
+        .unwrap_or_else(error_to_http_response)
+}
+
+#[instrument(skip_all, level = "debug")]
+async fn get_login_link_step2<Backend>(
+    data: web::Data<AppState<Backend>>,
+    request: HttpRequest,
+) -> TcpResult<HttpResponse>
+where
+    Backend: TcpBackendHandler + BackendHandler + LoginHandler + 'static,
+{
+    let token = request
+        .match_info()
+        .get("token")
+        .ok_or_else(|| TcpError::BadRequest("Missing login link token".to_owned()))?;
+    let name = data.get_login_handler().bind_with_token(token).await?;
+    // The link is opened from the email: send the browser to the app, which picks up the session
+    // with the refresh token.
+    // A link can leak (forwarded email, logs), so it doesn't count as a recent authentication:
+    // changing the password takes a password login or a reset token.
+    let mut response = get_login_successful_response(&data, &request, &name, None, false).await?;
+    *response.status_mut() = actix_web::http::StatusCode::FOUND;
+    response.headers_mut().insert(
+        actix_web::http::header::LOCATION,
+        actix_web::http::header::HeaderValue::from_str(data.server_url.as_str())
+            .map_err(|e| TcpError::InternalServerError(e.to_string()))?,
+    );
+    Ok(response)
+}
+
+async fn get_login_link_step2_handler<Backend>(
+    data: web::Data<AppState<Backend>>,
+    request: HttpRequest,
+) -> HttpResponse
+where
+    Backend: TcpBackendHandler + BackendHandler + LoginHandler + 'static,
+{
+    get_login_link_step2(data, request)
+        .await
+        .unwrap_or_else(error_to_http_response)
+}
+
 #[instrument(skip_all, level = "debug")]
 async fn get_logout<Backend>(
     data: web::Data<AppState<Backend>>,
@@ -290,16 +403,16 @@ where
     data.get_tcp_handler()
         .delete_refresh_token(refresh_token_hash)
         .await?;
-    let new_blacklisted_jwt_hashes = data.get_tcp_handler().blacklist_jwts(&user).await?;
-    let mut jwt_blacklist = data.jwt_blacklist.write().unwrap();
-    for jwt_hash in new_blacklisted_jwt_hashes {
-        jwt_blacklist.insert(jwt_hash);
-    }
+    data.get_tcp_handler().blacklist_jwts(&user).await?;
+    Ok(clear_session_cookies(&data))
+}
+
+fn clear_session_cookies<Backend>(data: &AppState<Backend>) -> HttpResponse {
     let mut path = data.server_url.path().to_string();
     if !path.ends_with('/') {
         path.push('/');
     };
-    Ok(HttpResponse::Ok()
+    HttpResponse::Ok()
         .cookie(
             Cookie::build("token", "")
                 .max_age(0.days())
@@ -316,7 +429,7 @@ where
                 .same_site(SameSite::Strict)
                 .finish(),
         )
-        .finish())
+        .finish()
 }
 
 async fn get_logout_handler<Backend>(
@@ -337,16 +450,48 @@ pub(crate) fn error_to_api_response<T, E: Into<TcpError>>(error: E) -> ApiResult
 
 pub type ApiResult<M> = actix_web::Either<web::Json<M>, HttpResponse>;
 
+#[instrument(skip_all, level = "debug")]
+async fn opaque_login_challenge<Backend>(
+    data: web::Data<AppState<Backend>>,
+) -> ApiResult<login::ServerLoginChallengeResponse>
+where
+    Backend: OpaqueHandler + 'static,
+{
+    data.get_opaque_handler()
+        .login_challenge()
+        .await
+        .map(|res| ApiResult::Left(web::Json(res)))
+        .unwrap_or_else(error_to_api_response)
+}
+
+#[instrument(skip_all, level = "debug")]
+async fn opaque_public_setup<Backend>(
+    data: web::Data<AppState<Backend>>,
+) -> web::Json<PublicServerSetup> {
+    web::Json(data.public_server_setup.clone())
+}
+
+#[instrument(skip_all, level = "debug")]
+async fn get_password_policy<Backend>(
+    data: web::Data<AppState<Backend>>,
+) -> web::Json<PasswordPolicy> {
+    web::Json(data.password_policy.clone())
+}
+
 #[instrument(skip_all, level = "debug")]
 async fn opaque_login_start<Backend>(
+    http_request: HttpRequest,
     data: web::Data<AppState<Backend>>,
     request: web::Json<login::ClientLoginStartRequest>,
 ) -> ApiResult<login::ServerLoginStartResponse>
 where
-    Backend: OpaqueHandler + 'static,
+    Backend: BackendHandler + OpaqueHandler + 'static,
 {
+    if let Err(e) = check_rate_limit(&data, &http_request) {
+        return error_to_api_response(e);
+    }
     data.get_opaque_handler()
-        .login_start(request.into_inner())
+        .login_start(request.into_inner(), get_source_ip(&data, &http_request))
         .await
         .map(|res| ApiResult::Left(web::Json(res)))
         .unwrap_or_else(error_to_api_response)
@@ -355,7 +500,10 @@ where
 #[instrument(skip_all, level = "debug")]
 async fn get_login_successful_response<Backend>(
     data: &web::Data<AppState<Backend>>,
+    http_request: &HttpRequest,
     name: &UserId,
+    auth_time: Option<DateTime<Utc>>,
+    must_change_password: bool,
 ) -> TcpResult<HttpResponse>
 where
     Backend: TcpBackendHandler + BackendHandler,
@@ -363,8 +511,23 @@ where
     // The authentication was successful, we need to fetch the groups to create the JWT
     // token.
     let groups = data.get_readonly_handler().get_user_groups(name).await?;
-    let (refresh_token, max_age) = data.get_tcp_handler().create_refresh_token(name).await?;
-    let token = create_jwt(data.get_tcp_handler(), &data.jwt_key, name, groups).await;
+    let device = http_request
+        .headers()
+        .get(actix_web::http::header::USER_AGENT)
+        .and_then(|h| h.to_str().ok());
+    let source = get_source_ip(data, http_request).map(|ip| ip.to_string());
+    let (refresh_token, max_age) = data
+        .get_tcp_handler()
+        .create_refresh_token(name, device, source.as_deref())
+        .await?;
+    let token = create_jwt(
+        data.get_tcp_handler(),
+        &data.jwt_key,
+        name,
+        groups,
+        auth_time,
+    )
+    .await;
     let refresh_token_plus_name = refresh_token + "+" + name.as_str();
     let mut path = data.server_url.path().to_string();
     if !path.ends_with('/') {
@@ -390,67 +553,136 @@ where
         .json(&login::ServerLoginResponse {
             token: token.as_str().to_owned(),
             refresh_token: Some(refresh_token_plus_name),
+            must_change_password,
         }))
 }
 
 #[instrument(skip_all, level = "debug")]
 async fn opaque_login_finish<Backend>(
+    http_request: HttpRequest,
     data: web::Data<AppState<Backend>>,
     request: web::Json<login::ClientLoginFinishRequest>,
 ) -> TcpResult<HttpResponse>
 where
     Backend: TcpBackendHandler + BackendHandler + OpaqueHandler + 'static,
 {
-    let name = data
+    check_rate_limit(&data, &http_request)?;
+    let login = data
         .get_opaque_handler()
-        .login_finish(request.into_inner())
+        .login_finish(request.into_inner(), get_source_ip(&data, &http_request))
         .await?;
-    get_login_successful_response(&data, &name).await
+    get_login_successful_response(
+        &data,
+        &http_request,
+        &login.user_id,
+        Some(Utc::now()),
+        login.must_change_password,
+    )
+    .await
 }
 
 async fn opaque_login_finish_handler<Backend>(
+    http_request: HttpRequest,
     data: web::Data<AppState<Backend>>,
     request: web::Json<login::ClientLoginFinishRequest>,
 ) -> HttpResponse
 where
     Backend: TcpBackendHandler + BackendHandler + OpaqueHandler + 'static,
 {
-    opaque_login_finish(data, request)
-        .await
-        .unwrap_or_else(error_to_http_response)
+    match opaque_login_finish(http_request, data, request).await {
+        Ok(response) => response,
+        Err(TcpError::DomainError(DomainError::TotpRequired(_))) => HttpResponse::Unauthorized()
+            .json(login::ServerLoginFinishTotpResponse {
+                requires_totp: true,
+            }),
+        Err(e) => error_to_http_response(e),
+    }
 }
 
 #[instrument(skip_all, level = "debug")]
 async fn simple_login<Backend>(
+    http_request: HttpRequest,
     data: web::Data<AppState<Backend>>,
     request: web::Json<login::ClientSimpleLoginRequest>,
 ) -> TcpResult<HttpResponse>
 where
     Backend: TcpBackendHandler + BackendHandler + OpaqueHandler + LoginHandler + 'static,
 {
+    check_rate_limit(&data, &http_request)?;
     let login::ClientSimpleLoginRequest { username, password } = request.into_inner();
     let bind_request = BindRequest {
         name: username.clone(),
         password,
+        source: get_source_ip(&data, &http_request),
+        context: None,
     };
     data.get_login_handler().bind(bind_request).await?;
-    get_login_successful_response(&data, &username).await
+    get_login_successful_response(&data, &http_request, &username, Some(Utc::now()), false).await
 }
 
 async fn simple_login_handler<Backend>(
+    http_request: HttpRequest,
     data: web::Data<AppState<Backend>>,
     request: web::Json<login::ClientSimpleLoginRequest>,
 ) -> HttpResponse
 where
     Backend: TcpBackendHandler + BackendHandler + OpaqueHandler + LoginHandler + 'static,
 {
-    simple_login(data, request)
+    simple_login(http_request, data, request)
+        .await
+        .unwrap_or_else(error_to_http_response)
+}
+
+#[derive(Deserialize)]
+struct DeleteAccountRequest {
+    password: String,
+}
+
+/// The account is always the one of the JWT, never one from the request.
+#[instrument(skip_all, level = "debug")]
+async fn post_delete_account<Backend>(
+    data: web::Data<AppState<Backend>>,
+    http_request: HttpRequest,
+    bearer: BearerAuth,
+    request: web::Json<DeleteAccountRequest>,
+) -> TcpResult<HttpResponse>
+where
+    Backend: TcpBackendHandler + BackendHandler + 'static,
+{
+    let validation_result = check_if_token_is_valid(&data, bearer.token())
+        .map_err(|_| TcpError::UnauthorizedError("Invalid JWT".to_string()))?;
+    if let Some(max_age) = data.step_up_auth_max_age {
+        require_recent_auth(&data, bearer.token(), max_age)?;
+    }
+    data.backend_handler
+        .unsafe_get_handler()
+        .delete_own_account(BindRequest {
+            name: validation_result.user,
+            password: request.into_inner().password,
+            source: get_source_ip(&data, &http_request),
+            context: None,
+        })
+        .await?;
+    Ok(clear_session_cookies(&data))
+}
+
+async fn post_delete_account_handler<Backend>(
+    data: web::Data<AppState<Backend>>,
+    http_request: HttpRequest,
+    bearer: BearerAuth,
+    request: web::Json<DeleteAccountRequest>,
+) -> HttpResponse
+where
+    Backend: TcpBackendHandler + BackendHandler + 'static,
+{
+    post_delete_account(data, http_request, bearer, request)
         .await
         .unwrap_or_else(error_to_http_response)
 }
 
 #[instrument(skip_all, level = "debug", fields(name = %request.name))]
 async fn post_authorize<Backend>(
+    http_request: HttpRequest,
     data: web::Data<AppState<Backend>>,
     request: web::Json<BindRequest>,
 ) -> TcpResult<HttpResponse>
@@ -458,18 +690,23 @@ where
     Backend: TcpBackendHandler + BackendHandler + LoginHandler + 'static,
 {
     let name = request.name.clone();
-    data.get_login_handler().bind(request.into_inner()).await?;
-    get_login_successful_response(&data, &name).await
+    let bind_request = BindRequest {
+        source: get_source_ip(&data, &http_request),
+        ..request.into_inner()
+    };
+    data.get_login_handler().bind(bind_request).await?;
+    get_login_successful_response(&data, &http_request, &name, Some(Utc::now()), false).await
 }
 
 async fn post_authorize_handler<Backend>(
+    http_request: HttpRequest,
     data: web::Data<AppState<Backend>>,
     request: web::Json<BindRequest>,
 ) -> HttpResponse
 where
     Backend: TcpBackendHandler + BackendHandler + LoginHandler + 'static,
 {
-    post_authorize(data, request)
+    post_authorize(http_request, data, request)
         .await
         .unwrap_or_else(error_to_http_response)
 }
@@ -484,14 +721,15 @@ where
     Backend: BackendHandler + OpaqueHandler + 'static,
 {
     use actix_web::FromRequest;
+    check_rate_limit(&data, &request)?;
     let inner_payload = &mut payload.into_inner();
-    let validation_result = BearerAuth::from_request(&request, inner_payload)
+    let unauthorized =
+        || TcpError::UnauthorizedError("Not authorized to change the user's password".to_string());
+    let bearer = BearerAuth::from_request(&request, inner_payload)
         .await
-        .ok()
-        .and_then(|bearer| check_if_token_is_valid(&data, bearer.token()).ok())
-        .ok_or_else(|| {
-            TcpError::UnauthorizedError("Not authorized to change the user's password".to_string())
-        })?;
+        .map_err(|_| unauthorized())?;
+    let validation_result =
+        check_if_token_is_valid(&data, bearer.token()).map_err(|_| unauthorized())?;
     let registration_start_request =
         web::Json::<registration::ClientRegistrationStartRequest>::from_request(
             &request,
@@ -501,23 +739,64 @@ where
         .map_err(|e| TcpError::BadRequest(format!("{:#?}", e)))?
         .into_inner();
     let user_id = &registration_start_request.username;
-    let user_is_admin = data
-        .get_readonly_handler()
-        .get_user_groups(user_id)
-        .await?
-        .iter()
-        .any(|g| g.display_name == "lldap_admin".into());
+    let user_is_admin = match data.get_readonly_handler().get_user_groups(user_id).await {
+        Ok(groups) => groups
+            .iter()
+            .any(|g| g.display_name == "lldap_admin".into()),
+        // Treat unknown users like regular users: only admins can go further, and
+        // registration_finish doesn't reveal whether the user exists.
+        Err(DomainError::EntityNotFound(_)) if data.mask_registration_user_existence => false,
+        Err(e) => return Err(e.into()),
+    };
     if !validation_result.can_change_password(user_id, user_is_admin) {
-        return Err(TcpError::UnauthorizedError(
-            "Not authorized to change the user's password".to_string(),
-        ));
+        return Err(unauthorized());
+    }
+    if let Some(max_age) = data.step_up_auth_max_age {
+        require_recent_auth(&data, bearer.token(), max_age)?;
     }
     Ok(data
         .get_opaque_handler()
-        .registration_start(registration_start_request)
+        .registration_start(registration_start_request, get_source_ip(&data, &request))
         .await?)
 }
 
+/// The address of the client. Behind a trusted reverse proxy, it comes from the forwarding
+/// headers; anyone else could put any address in them.
+fn get_source_ip<Backend>(
+    data: &AppState<Backend>,
+    request: &HttpRequest,
+) -> Option<std::net::IpAddr> {
+    if !data.is_from_trusted_proxy(request) {
+        return request.peer_addr().map(|peer| peer.ip());
+    }
+    let connection_info = request.connection_info();
+    let address = connection_info.realip_remote_addr()?;
+    address
+        .parse::<std::net::SocketAddr>()
+        .map(|a| a.ip())
+        .or_else(|_| address.parse())
+        .ok()
+}
+
+/// Takes a token from the rate limiting bucket of the client address. The clients with a valid
+/// JWT have separate limits.
+fn check_rate_limit<Backend: BackendHandler>(
+    data: &AppState<Backend>,
+    request: &HttpRequest,
+) -> TcpResult<()> {
+    if let (Some(rate_limiter), Some(source)) = (&data.rate_limiter, get_source_ip(data, request)) {
+        let authenticated = request
+            .headers()
+            .get(actix_web::http::header::AUTHORIZATION)
+            .and_then(|h| h.to_str().ok())
+            .and_then(|h| h.strip_prefix("Bearer "))
+            .map(|token| check_if_token_is_valid(data, token).is_ok())
+            .unwrap_or(false);
+        rate_limiter.check(source, authenticated)?;
+    }
+    Ok(())
+}
+
 async fn opaque_register_start_handler<Backend>(
     request: actix_web::HttpRequest,
     payload: actix_web::web::Payload,
@@ -534,26 +813,45 @@ where
 
 #[instrument(skip_all, level = "debug")]
 async fn opaque_register_finish<Backend>(
+    http_request: HttpRequest,
     data: web::Data<AppState<Backend>>,
     request: web::Json<registration::ClientRegistrationFinishRequest>,
 ) -> TcpResult<HttpResponse>
 where
     Backend: TcpBackendHandler + BackendHandler + OpaqueHandler + 'static,
 {
+    check_rate_limit(&data, &http_request)?;
+    use actix_web::FromRequest;
+    let unauthorized =
+        || TcpError::UnauthorizedError("Not authorized to change the user's password".to_string());
+    let bearer = BearerAuth::extract(&http_request)
+        .await
+        .map_err(|_| unauthorized())?;
+    // The permissions were checked by `opaque_register_start`, the token tells who is acting.
+    let actor = check_if_token_is_valid(&data, bearer.token())
+        .map_err(|_| unauthorized())?
+        .user;
+    // Anyone can send an X-Forwarded-Proto header: it only counts coming from a trusted proxy.
+    let transport_secure = if data.is_from_trusted_proxy(&http_request) {
+        http_request.connection_info().scheme() == "https"
+    } else {
+        http_request.app_config().secure()
+    };
     data.get_opaque_handler()
-        .registration_finish(request.into_inner())
+        .registration_finish(request.into_inner(), &actor, transport_secure)
         .await?;
     Ok(HttpResponse::Ok().finish())
 }
 
 async fn opaque_register_finish_handler<Backend>(
+    http_request: HttpRequest,
     data: web::Data<AppState<Backend>>,
     request: web::Json<registration::ClientRegistrationFinishRequest>,
 ) -> HttpResponse
 where
     Backend: TcpBackendHandler + BackendHandler + OpaqueHandler + 'static,
 {
-    opaque_register_finish(data, request)
+    opaque_register_finish(http_request, data, request)
         .await
         .unwrap_or_else(error_to_http_response)
 }
@@ -644,11 +942,50 @@ pub(crate) fn check_if_token_is_valid<Backend: BackendHandler>(
     ))
 }
 
-pub fn configure_server<Backend>(cfg: &mut web::ServiceConfig, enable_password_reset: bool)
-where
+fn check_recent_auth(
+    claims: &JWTClaims,
+    max_age: chrono::Duration,
+    now: DateTime<Utc>,
+) -> TcpResult<()> {
+    match claims.auth_time {
+        Some(auth_time) if now - auth_time <= max_age => Ok(()),
+        _ => Err(TcpError::UnauthorizedError(
+            "This operation requires logging in again".to_string(),
+        )),
+    }
+}
+
+/// For sensitive operations: checks that the user logged in with their password less than
+/// `max_age` ago, rather than with a long-lived session.
+pub(crate) fn require_recent_auth<Backend>(
+    state: &AppState<Backend>,
+    token_str: &str,
+    max_age: chrono::Duration,
+) -> TcpResult<()> {
+    let token: Token<_> = VerifyWithKey::verify_with_key(token_str, &state.jwt_key)
+        .map_err(|_| TcpError::UnauthorizedError("Invalid JWT".to_string()))?;
+    check_recent_auth(token.claims(), max_age, Utc::now())
+}
+
+pub fn configure_server<Backend>(
+    cfg: &mut web::ServiceConfig,
+    enable_password_reset: bool,
+    enable_login_links: bool,
+    enable_self_service_deletion: bool,
+) where
     Backend: TcpBackendHandler + LoginHandler + OpaqueHandler + BackendHandler + 'static,
 {
     cfg.service(web::resource("").route(web::post().to(post_authorize_handler::<Backend>)))
+        .service(
+            web::resource("/opaque/setup").route(web::get().to(opaque_public_setup::<Backend>)),
+        )
+        .service(
+            web::resource("/password_policy").route(web::get().to(get_password_policy::<Backend>)),
+        )
+        .service(
+            web::resource("/opaque/login/challenge")
+                .route(web::get().to(opaque_login_challenge::<Backend>)),
+        )
         .service(
             web::resource("/opaque/login/start")
                 .route(web::post().to(opaque_login_start::<Backend>)),
@@ -684,4 +1021,65 @@ where
                 .route(web::get().to(get_password_reset_step2_handler::<Backend>)),
         );
     }
+    if enable_self_service_deletion {
+        cfg.service(
+            web::resource("/delete_account")
+                .wrap(CookieToHeaderTranslatorFactory)
+                .route(web::post().to(post_delete_account_handler::<Backend>)),
+        );
+    }
+    if enable_login_links {
+        cfg.service(
+            web::resource("/login_link/step1/{user_id}")
+                .route(web::get().to(get_login_link_step1_handler::<Backend>)),
+        )
+        .service(
+            web::resource("/login_link/step2/{token}")
+                .route(web::get().to(get_login_link_step2_handler::<Backend>)),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn claims_with_auth_time(auth_time: Option<DateTime<Utc>>) -> JWTClaims {
+        JWTClaims {
+            exp: Utc::now() + chrono::Duration::days(1),
+            iat: Utc::now(),
+            user: "bob".to_string(),
+            groups: HashSet::new(),
+            auth_time,
+        }
+    }
+
+    #[test]
+    fn test_check_recent_auth() {
+        let now = Utc::now();
+        let max_age = chrono::Duration::minutes(5);
+        check_recent_auth(
+            &claims_with_auth_time(Some(now - chrono::Duration::minutes(1))),
+            max_age,
+            now,
+        )
+        .unwrap();
+        check_recent_auth(
+            &claims_with_auth_time(Some(now - chrono::Duration::minutes(10))),
+            max_age,
+            now,
+        )
+        .unwrap_err();
+        // Refreshed tokens don't count as a recent login.
+        check_recent_auth(&claims_with_auth_time(None), max_age, now).unwrap_err();
+    }
+
+    #[test]
+    fn test_claims_without_auth_time() {
+        let claims: JWTClaims = serde_json::from_str(
+            r#"{"exp":"2030-01-01T00:00:00Z","iat":"2029-12-31T00:00:00Z","user":"bob","groups":[]}"#,
+        )
+        .unwrap();
+        assert_eq!(claims.auth_time, None);
+    }
 }
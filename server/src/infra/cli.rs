@@ -32,6 +32,12 @@ pub enum Command {
     /// Create database schema.
     #[clap(name = "create_schema")]
     CreateSchema(RunOpts),
+    /// Replace the server key, and invalidate all the passwords: the users have to set a new one.
+    #[clap(name = "rotate_keys")]
+    RotateKeys(RotateKeysOpts),
+    /// Create the users listed in a CSV file, e.g. exported from another directory.
+    #[clap(name = "import_users")]
+    ImportUsers(ImportUsersOpts),
 }
 
 #[derive(Debug, Parser, Clone)]
@@ -119,6 +125,29 @@ pub struct TestEmailOpts {
     pub smtp_opts: SmtpOpts,
 }
 
+#[derive(Debug, Parser, Clone)]
+pub struct RotateKeysOpts {
+    #[clap(flatten)]
+    pub run_opts: RunOpts,
+
+    /// When the server key comes from a seed, the new seed. Put it in the configuration before
+    /// restarting the server.
+    #[clap(long)]
+    pub new_key_seed: Option<String>,
+}
+
+#[derive(Debug, Parser, Clone)]
+pub struct ImportUsersOpts {
+    #[clap(flatten)]
+    pub run_opts: RunOpts,
+
+    /// The CSV file, with a header line: `uid,email,display_name,password_hash`. The display name
+    /// and the bcrypt hash of the password are optional. Either all the users are created, or
+    /// none.
+    #[clap(long)]
+    pub csv_file: String,
+}
+
 #[derive(Debug, Parser, Clone)]
 #[clap(next_help_heading = Some("LDAPS"))]
 pub struct LdapsOpts {
@@ -4,7 +4,10 @@ use crate::{
         types::{AttributeName, UserId},
     },
     infra::{
-        cli::{GeneralConfigOpts, LdapsOpts, RunOpts, SmtpEncryption, SmtpOpts, TestEmailOpts},
+        cli::{
+            GeneralConfigOpts, ImportUsersOpts, LdapsOpts, RotateKeysOpts, RunOpts, SmtpEncryption,
+            SmtpOpts, TestEmailOpts,
+        },
         database_string::DatabaseUrl,
     },
 };
@@ -15,9 +18,13 @@ use figment::{
 };
 use figment_file_provider_adapter::FileAdapter;
 use lettre::message::Mailbox;
-use lldap_auth::opaque::{server::ServerSetup, KeyPair};
+use lldap_auth::{
+    opaque::{server::ServerSetup, KeyPair},
+    server_setup::{self, PublicServerSetup},
+};
 use secstr::SecUtf8;
 use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
 use url::Url;
 
 #[derive(Clone, Debug, Deserialize, Serialize, derive_builder::Builder)]
@@ -39,6 +46,15 @@ pub struct MailOptions {
     pub password: SecUtf8,
     #[builder(default = "SmtpEncryption::Tls")]
     pub smtp_encryption: SmtpEncryption,
+    /// Send an email to the users created through LLDAP.
+    #[builder(default = "false")]
+    pub enable_welcome_email: bool,
+    /// Body of the password reset email, with `{username}` and `{link}` placeholders.
+    #[builder(default = "None")]
+    pub password_reset_template: Option<String>,
+    /// Body of the welcome email, with `{username}` and `{link}` placeholders.
+    #[builder(default = "None")]
+    pub welcome_template: Option<String>,
     /// Deprecated.
     #[builder(default = "None")]
     pub tls_required: Option<bool>,
@@ -69,6 +85,245 @@ impl std::default::Default for LdapsOptions {
     }
 }
 
+#[derive(Clone, Debug, Deserialize, Serialize, derive_builder::Builder)]
+#[builder(pattern = "owned")]
+pub struct EmailNormalizationOptions {
+    /// Lowercase the domain part of the emails before storing them. The email as entered is kept
+    /// as the display email.
+    #[builder(default = "false")]
+    pub enabled: bool,
+    /// Also remove the "+tag" part of the local part: "bob+lldap@example.com" becomes
+    /// "bob@example.com".
+    #[builder(default = "false")]
+    pub strip_plus_addressing: bool,
+}
+
+impl std::default::Default for EmailNormalizationOptions {
+    fn default() -> Self {
+        EmailNormalizationOptionsBuilder::default().build().unwrap()
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum DatabaseTlsVerify {
+    /// Encrypt the connection, without checking the server certificate.
+    None,
+    /// Check that the server certificate is signed by a trusted CA.
+    Ca,
+    /// Also check that the certificate matches the host name.
+    Full,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, derive_builder::Builder)]
+#[builder(pattern = "owned")]
+pub struct DatabaseTlsOptions {
+    /// Refuse to connect to the database without TLS. Only for MySQL and PostgreSQL.
+    #[builder(default = "false")]
+    pub require_tls: bool,
+    /// CA certificate used to verify the database server.
+    #[builder(default)]
+    pub ca_cert_file: Option<String>,
+    #[builder(default = "DatabaseTlsVerify::Full")]
+    pub verify: DatabaseTlsVerify,
+}
+
+impl std::default::Default for DatabaseTlsOptions {
+    fn default() -> Self {
+        DatabaseTlsOptionsBuilder::default().build().unwrap()
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ThrottleStoreKind {
+    /// Per instance.
+    Memory,
+    /// Shared between all the instances using the same database.
+    Database,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, derive_builder::Builder)]
+#[builder(pattern = "owned")]
+pub struct ThrottleOptions {
+    #[builder(default = "ThrottleStoreKind::Memory")]
+    pub store: ThrottleStoreKind,
+    /// Number of failed logins for a user within the window after which the logins are refused.
+    /// 0 disables the limit.
+    #[builder(default = "0")]
+    pub max_failed_logins: u32,
+    /// Number of failed logins from a single address within the window, for any user, after
+    /// which the logins from that address are refused. 0 disables the limit.
+    #[builder(default = "0")]
+    pub max_failed_logins_per_source: u32,
+    /// Number of failed logins for a user from a single address within the window after which
+    /// the logins of that user from that address are refused. 0 disables the limit.
+    #[builder(default = "0")]
+    pub max_failed_logins_per_user_and_source: u32,
+    /// Number of password registrations from a single address within the window after which
+    /// they are refused. 0 disables the limit.
+    #[builder(default = "0")]
+    pub max_registrations: u32,
+    #[builder(default = "300")]
+    pub window_seconds: u64,
+    /// Tell the rate-limited clients when to retry (Retry-After header for HTTP, in the message
+    /// for LDAP). The failed logins are counted for unknown users too, so this doesn't reveal
+    /// which users exist.
+    #[builder(default = "false")]
+    pub send_retry_after: bool,
+}
+
+impl std::default::Default for ThrottleOptions {
+    fn default() -> Self {
+        ThrottleOptionsBuilder::default().build().unwrap()
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, derive_builder::Builder)]
+#[builder(pattern = "owned")]
+pub struct RateLimitOptions {
+    /// Requests per second allowed from a single address on the LDAP binds and the OPAQUE
+    /// routes, on average. 0 disables the limit.
+    #[builder(default = "0.0")]
+    pub rate: f64,
+    /// Requests allowed at once from a single address, before the rate applies.
+    #[builder(default = "20")]
+    pub burst: u32,
+    /// Same as `rate`, for the clients already logged in. 0 disables the limit.
+    #[builder(default = "0.0")]
+    pub authenticated_rate: f64,
+    #[builder(default = "100")]
+    pub authenticated_burst: u32,
+    /// How often to forget the addresses that stopped sending requests.
+    #[builder(default = "60")]
+    pub eviction_interval_seconds: u64,
+}
+
+impl std::default::Default for RateLimitOptions {
+    fn default() -> Self {
+        RateLimitOptionsBuilder::default().build().unwrap()
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, derive_builder::Builder)]
+#[builder(pattern = "owned")]
+pub struct AuthAlertOptions {
+    /// Log a warning when the proportion of failed authentications within the window goes above
+    /// this value, between 0 and 1. 0 disables the alert. Requires `enable_auth_events`.
+    #[builder(default = "0.0")]
+    pub failure_ratio_threshold: f64,
+    /// Below this number of attempts in the window, the ratio is not significant.
+    #[builder(default = "10")]
+    pub min_attempts: u64,
+    #[builder(default = "300")]
+    pub window_seconds: u64,
+    /// How often to compute the ratio.
+    #[builder(default = "60")]
+    pub check_interval_seconds: u64,
+}
+
+impl std::default::Default for AuthAlertOptions {
+    fn default() -> Self {
+        AuthAlertOptionsBuilder::default().build().unwrap()
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, derive_builder::Builder)]
+#[builder(pattern = "owned")]
+#[serde(default)]
+pub struct PasswordPolicyOptions {
+    /// In characters. 0 accepts any length.
+    #[builder(default = "0")]
+    pub min_length: usize,
+    #[builder(default = "false")]
+    pub require_mixed_case: bool,
+    #[builder(default = "false")]
+    pub require_digit: bool,
+    /// Reject passwords with a lower estimated entropy, in bits.
+    #[builder(default)]
+    pub min_entropy_bits: Option<u32>,
+    /// Members of these groups are service accounts, with long random secrets: instead of the
+    /// rules above, their passwords only need `service_account_min_length` characters.
+    #[builder(default)]
+    pub service_account_groups: Vec<String>,
+    #[builder(default = "32")]
+    pub service_account_min_length: usize,
+}
+
+impl std::default::Default for PasswordPolicyOptions {
+    fn default() -> Self {
+        PasswordPolicyOptionsBuilder::default().build().unwrap()
+    }
+}
+
+/// Another password policy for some users, instead of the global one.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct PasswordPolicyOverride {
+    #[serde(default)]
+    pub users: Vec<String>,
+    /// The members of these groups are held to this policy, unless an override lists them.
+    #[serde(default)]
+    pub groups: Vec<String>,
+    #[serde(flatten)]
+    pub policy: PasswordPolicyOptions,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, derive_builder::Builder)]
+#[builder(pattern = "owned")]
+pub struct OpenTelemetryOptions {
+    /// OTLP (gRPC) endpoint of the collector. The spans are only exported when it is set, and
+    /// LLDAP was built with the "opentelemetry" feature.
+    #[builder(default)]
+    pub endpoint: Option<String>,
+    /// Proportion of the traces to export, between 0 and 1.
+    #[builder(default = "1.0")]
+    pub sampling_ratio: f64,
+}
+
+impl std::default::Default for OpenTelemetryOptions {
+    fn default() -> Self {
+        OpenTelemetryOptionsBuilder::default().build().unwrap()
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, derive_builder::Builder)]
+#[builder(pattern = "owned")]
+pub struct LifecycleWebhookOptions {
+    /// The creations, updates and deletions of users and the membership changes are POSTed to
+    /// this URL, if set.
+    #[builder(default)]
+    pub url: Option<Url>,
+    /// Signs the payloads with HMAC-SHA256, in the "X-LLDAP-Signature" header.
+    #[builder(default)]
+    pub secret: Option<SecUtf8>,
+    /// Failed deliveries are retried with an exponential backoff, from 1 second.
+    #[builder(default = "5")]
+    pub max_attempts: u32,
+}
+
+impl std::default::Default for LifecycleWebhookOptions {
+    fn default() -> Self {
+        LifecycleWebhookOptionsBuilder::default().build().unwrap()
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, derive_builder::Builder)]
+#[builder(pattern = "owned")]
+pub struct MetricsOptions {
+    /// Serve Prometheus metrics on /metrics.
+    #[builder(default = "false")]
+    pub enable_metrics: bool,
+    /// Serve them on this address (e.g. "127.0.0.1:9090") instead of the HTTP port.
+    #[builder(default)]
+    pub bind_address: Option<String>,
+}
+
+impl std::default::Default for MetricsOptions {
+    fn default() -> Self {
+        MetricsOptionsBuilder::default().build().unwrap()
+    }
+}
+
 #[derive(Clone, Debug, Deserialize, Serialize, derive_builder::Builder)]
 #[builder(pattern = "owned", build_fn(name = "private_build"))]
 pub struct Configuration {
@@ -97,6 +352,26 @@ pub struct Configuration {
     #[builder(default = r#"DatabaseUrl::from("sqlite://users.db?mode=rwc")"#)]
     pub database_url: DatabaseUrl,
     #[builder(default)]
+    pub database_tls_options: DatabaseTlsOptions,
+    /// Key used to open a SQLCipher-encrypted SQLite database.
+    #[builder(default)]
+    pub database_encryption_key: Option<SecUtf8>,
+    /// Size of the pool of database connections.
+    #[builder(default = "5")]
+    pub db_max_connections: u32,
+    /// Database connections kept open even when idle.
+    #[builder(default = "0")]
+    pub db_min_connections: u32,
+    /// Open the `db_min_connections` at startup, rather than in the background.
+    #[builder(default = "false")]
+    pub db_warm_up_pool: bool,
+    /// How long a request waits for a free connection before failing.
+    #[builder(default = "30")]
+    pub db_acquire_timeout_seconds: u64,
+    /// Idle connections above `db_min_connections` are closed after this. 0 keeps them open.
+    #[builder(default = "600")]
+    pub db_idle_timeout_seconds: u64,
+    #[builder(default)]
     pub ignored_user_attributes: Vec<AttributeName>,
     #[builder(default)]
     pub ignored_group_attributes: Vec<AttributeName>,
@@ -108,15 +383,200 @@ pub struct Configuration {
     // "***SECRET***".
     #[builder(default)]
     pub key_seed: Option<SecUtf8>,
+    /// After `rotate_keys`, how long the logins started with the previous server setup can still
+    /// be opened, in seconds.
+    #[builder(default = "600")]
+    pub key_rotation_grace_seconds: u64,
     #[builder(default)]
     pub smtp_options: MailOptions,
     #[builder(default)]
     pub ldaps_options: LdapsOptions,
+    #[builder(default)]
+    pub email_normalization: EmailNormalizationOptions,
+    /// Refuse to start if several users share an email, since emails identify users for password
+    /// resets. Otherwise, only warn.
+    #[builder(default = "false")]
+    pub require_unique_emails: bool,
+    /// Let the users give their email instead of their user ID to reset their password. Turned off
+    /// at startup if several users share an email.
+    #[builder(default = "true")]
+    pub allow_email_login: bool,
+    /// Refuse to create users whose name looks like an existing one, e.g. with Cyrillic letters.
+    #[builder(default = "false")]
+    pub reject_confusable_usernames: bool,
+    /// Longest accepted username, in characters, when creating users and logging in. SQLite
+    /// doesn't enforce the size of the column.
+    #[builder(default = "255")]
+    pub max_username_length: usize,
+    /// Require clients to fetch a challenge and echo it before starting an OPAQUE login.
+    #[builder(default = "false")]
+    pub enable_login_challenge: bool,
+    #[builder(default = "60")]
+    pub login_challenge_validity_seconds: u64,
+    #[builder(default)]
+    pub throttle_options: ThrottleOptions,
+    #[builder(default)]
+    pub rate_limit_options: RateLimitOptions,
+    /// Lock an account after this many wrong passwords in a row, until the end of the window.
+    /// Unlike the throttle, this is stored with the user. 0 never locks them.
+    #[builder(default = "0")]
+    pub account_lockout_threshold: u32,
+    #[builder(default = "900")]
+    pub account_lockout_window_seconds: u64,
+    /// Answer password registrations identically whether or not the user exists.
+    #[builder(default = "false")]
+    pub mask_registration_user_existence: bool,
+    /// Refuse to replace the password of a user who has one, unless it was reset with a token,
+    /// allowed by an admin, or is temporary.
+    #[builder(default = "false")]
+    pub reject_password_overwrite: bool,
+    /// Keep the OPAQUE registration state in the database, and give clients a short handle to it
+    /// instead of the sealed state.
+    #[builder(default = "false")]
+    pub enable_registration_handles: bool,
+    #[builder(default = "300")]
+    pub registration_handle_validity_seconds: u64,
+    /// Maximum number of unexpired registration handles per user. 0 means no limit.
+    #[builder(default = "0")]
+    pub max_outstanding_registrations_per_user: u64,
+    /// The period of the TOTP codes, in seconds.
+    #[builder(default = "30")]
+    pub totp_period_seconds: u64,
+    /// Members of these groups can only log in once they have enrolled a TOTP secret.
+    #[builder(default)]
+    pub totp_required_groups: Vec<String>,
+    /// Record each authentication attempt, for investigations.
+    #[builder(default = "false")]
+    pub enable_auth_events: bool,
+    /// Serve the health report of all the subsystems on /health/report.
+    #[builder(default = "false")]
+    pub enable_health_report: bool,
+    #[builder(default)]
+    pub auth_alert_options: AuthAlertOptions,
+    #[builder(default)]
+    pub opentelemetry_options: OpenTelemetryOptions,
+    #[builder(default)]
+    pub metrics_options: MetricsOptions,
+    #[builder(default)]
+    pub lifecycle_webhook_options: LifecycleWebhookOptions,
+    /// Count the successful logins of each user, and remember the last one.
+    #[builder(default = "false")]
+    pub track_login_statistics: bool,
+    /// Remember where and when each session (refresh token) was opened, for the users to review
+    /// and revoke them.
+    #[builder(default = "false")]
+    pub track_sessions: bool,
+    /// Append the logins and the administrative actions to the `audit_log` table.
+    #[builder(default = "false")]
+    pub enable_audit_log: bool,
+    /// Chain each audit log entry to the previous one with a hash, to detect deleted entries.
+    #[builder(default = "false")]
+    pub audit_log_hash_chain: bool,
+    /// Every login attempt is POSTed as JSON to this URL, if set.
+    #[builder(default)]
+    pub login_event_webhook_url: Option<Url>,
+    /// Disable the users that haven't logged in for this many days. 0 never disables them.
+    /// Requires `track_login_statistics`.
+    #[builder(default = "0")]
+    pub disable_inactive_users_after_days: u64,
+    /// Remember the devices users log in from on the web, and log the logins from new devices.
+    #[builder(default = "false")]
+    pub enable_device_tracking: bool,
+    /// Allow non-admin users to log in on the web with a single-use link sent by email.
+    #[builder(default = "false")]
+    pub enable_login_links: bool,
+    /// How long the password reset tokens are valid.
+    #[builder(default = "600")]
+    pub password_reset_token_validity_seconds: u64,
+    #[builder(default = "900")]
+    pub login_link_validity_seconds: u64,
+    /// Let the users delete their own account, confirming with their password.
+    #[builder(default = "false")]
+    pub enable_self_service_deletion: bool,
+    /// Deleted users are kept for this many days, during which they can be restored. 0 deletes
+    /// them right away.
+    #[builder(default = "0")]
+    pub deleted_user_retention_days: u64,
+    /// Refuse to set passwords over plain HTTP or LDAP.
+    #[builder(default = "false")]
+    pub require_secure_transport_for_password_ops: bool,
+    /// Reverse proxies whose forwarding headers are believed.
+    #[builder(default)]
+    pub trusted_proxies: Vec<IpAddr>,
+    /// Refuse the LDAP binds over a plain connection, before StartTLS.
+    #[builder(default = "false")]
+    pub require_tls_for_bind: bool,
+    /// Reject passwords that contain the username, when the cleartext password is known.
+    #[builder(default = "false")]
+    pub forbid_username_in_password: bool,
+    /// Refuse the legacy password hashes of the admins, they must log in with OPAQUE.
+    #[builder(default = "false")]
+    pub require_opaque_for_admins: bool,
+    /// Make the users whose legacy password hash is migrated choose a new password.
+    #[builder(default = "false")]
+    pub forbid_legacy_password_reuse: bool,
+    /// Move the corrupt password files to the quarantine table instead of failing the logins. The
+    /// user is then left without a password, to be reset.
+    #[builder(default = "false")]
+    pub quarantine_corrupt_password_files: bool,
+    /// Keep the last password files of each user, and reject a password that matches one of them
+    /// when the cleartext password is known. 0 keeps none.
+    #[builder(default = "0")]
+    pub password_history_size: u64,
+    /// The contexts (e.g. one per downstream service) a password can be registered for, next to
+    /// the primary password. The OPAQUE identity of such a password is qualified with the context.
+    #[builder(default)]
+    pub password_contexts: Vec<String>,
+    /// Allow `transfer_credential` to move the password of a user to another one. The password
+    /// file keeps the identity it was registered under, stored along with it.
+    #[builder(default = "false")]
+    pub transferable_password_files: bool,
+    /// When an admin sets the password of another user, the user has to change it: web logins
+    /// ask for a new one, and LDAP binds are refused. The admin user from the configuration is
+    /// exempt.
+    #[builder(default = "false")]
+    pub force_password_change_after_admin_reset: bool,
+    /// Passwords expire this many days after they were set. 0 means they never expire.
+    #[builder(default = "0")]
+    pub password_expiry_days: u64,
+    /// Number of logins allowed with an expired password before they are refused.
+    #[builder(default = "0")]
+    pub password_expiry_grace_logins: u32,
+    /// Checked when the cleartext password is known, like `forbid_username_in_password`.
+    #[builder(default)]
+    pub password_policy_options: PasswordPolicyOptions,
+    /// Checked in order: the first one that lists the user applies, then the first one that
+    /// lists one of their groups.
+    #[builder(default)]
+    pub password_policy_overrides: Vec<PasswordPolicyOverride>,
+    /// Dry run of a stricter policy: the passwords are checked against it, without rejecting
+    /// any, to see how many users would have to change theirs.
+    #[builder(default)]
+    pub proposed_password_policy_options: Option<PasswordPolicyOptions>,
+    /// Require a login at most this many seconds old to change a password.
+    #[builder(default)]
+    pub step_up_auth_max_age_seconds: Option<u64>,
+    /// Text that users have to acknowledge before logging in.
+    #[builder(default)]
+    pub login_banner: Option<String>,
+    /// Refuse new logins from startup, except for the break-glass users. This can be changed
+    /// while running.
+    #[builder(default = "false")]
+    pub maintenance_mode: bool,
+    #[builder(default)]
+    pub maintenance_message: Option<String>,
+    /// Users that can still log in during maintenance, e.g. an emergency admin account.
+    #[builder(default)]
+    pub maintenance_break_glass_users: Vec<String>,
     #[builder(default = r#"Url::parse("http://localhost").unwrap()"#)]
     pub http_url: Url,
     #[serde(skip)]
     #[builder(field(private), default = "None")]
     server_setup: Option<ServerSetupConfig>,
+    /// The server setup replaced by `rotate_keys`, during the grace window.
+    #[serde(skip)]
+    #[builder(default = "None")]
+    pub previous_server_setup: Option<ServerSetup>,
 }
 
 impl std::default::Default for Configuration {
@@ -159,15 +619,147 @@ fn stable_hash(val: &[u8]) -> [u8; 32] {
     hasher.finalize().into()
 }
 
+/// A reason why the server private key might be easy to guess.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KeyWeakness {
+    AllZeroKey,
+    LowEntropyKey,
+    ExampleKeySeed,
+    ShortKeySeed,
+}
+
+impl std::fmt::Display for KeyWeakness {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            KeyWeakness::AllZeroKey => "The server private key is all zeros.",
+            KeyWeakness::LowEntropyKey => "The server private key has very little entropy.",
+            KeyWeakness::ExampleKeySeed => {
+                "The key_seed is the one from the example configuration."
+            }
+            KeyWeakness::ShortKeySeed => "The key_seed is shorter than 12 characters.",
+        })
+    }
+}
+
+const EXAMPLE_KEY_SEED: &str = "RanD0m STR1ng";
+const MIN_KEY_SEED_LENGTH: usize = 12;
+
+fn assess_private_key(private_key: &[u8]) -> Option<KeyWeakness> {
+    if private_key.iter().all(|b| *b == 0) {
+        return Some(KeyWeakness::AllZeroKey);
+    }
+    // A random 32-byte key has almost always more than 20 distinct bytes.
+    let distinct_bytes = private_key
+        .iter()
+        .collect::<std::collections::HashSet<_>>()
+        .len();
+    if distinct_bytes < private_key.len() / 2 {
+        return Some(KeyWeakness::LowEntropyKey);
+    }
+    None
+}
+
 impl Configuration {
+    /// The pool settings of the configuration, for the given URL.
+    pub fn db_connect_options(&self, database_url: String) -> sea_orm::ConnectOptions {
+        let mut options = sea_orm::ConnectOptions::new(database_url);
+        options
+            .max_connections(self.db_max_connections)
+            .min_connections(self.db_min_connections)
+            .acquire_timeout(std::time::Duration::from_secs(
+                self.db_acquire_timeout_seconds,
+            ));
+        if self.db_idle_timeout_seconds > 0 {
+            options.idle_timeout(std::time::Duration::from_secs(self.db_idle_timeout_seconds));
+        }
+        options
+    }
+
     pub fn get_server_setup(&self) -> &ServerSetup {
         &self.server_setup.as_ref().unwrap().server_setup
     }
 
+    pub fn password_policy(&self) -> lldap_auth::password_policy::PasswordPolicy {
+        self.to_password_policy(&self.password_policy_options)
+    }
+
+    pub fn proposed_password_policy(&self) -> Option<lldap_auth::password_policy::PasswordPolicy> {
+        self.proposed_password_policy_options
+            .as_ref()
+            .map(|options| self.to_password_policy(options))
+    }
+
+    pub(crate) fn to_password_policy(
+        &self,
+        options: &PasswordPolicyOptions,
+    ) -> lldap_auth::password_policy::PasswordPolicy {
+        lldap_auth::password_policy::PasswordPolicy {
+            forbid_username_in_password: self.forbid_username_in_password,
+            min_length: options.min_length,
+            require_mixed_case: options.require_mixed_case,
+            require_digit: options.require_digit,
+            min_entropy_bits: options.min_entropy_bits,
+        }
+    }
+
+    /// The rules for the passwords of service accounts: only a minimum length.
+    pub fn service_account_password_policy(&self) -> lldap_auth::password_policy::PasswordPolicy {
+        lldap_auth::password_policy::PasswordPolicy {
+            min_length: self.password_policy_options.service_account_min_length,
+            ..Default::default()
+        }
+    }
+
     pub fn get_server_keys(&self) -> &KeyPair {
         self.get_server_setup().keypair()
     }
 
+    /// Replaces the server setup with a new one: a random one written to the key file, or the one
+    /// generated from `new_key_seed` when the key comes from a seed. The previous one is kept
+    /// next to the key file for the grace window, see `key_rotation_grace_seconds`.
+    pub fn rotate_server_setup(&mut self, new_key_seed: Option<&str>) -> Result<()> {
+        let server_setup = match (&self.key_seed, new_key_seed) {
+            (Some(_), None) => bail!("The server key comes from key_seed, give a new seed"),
+            (None, Some(_)) => bail!("The server key comes from key_file, it doesn't use a seed"),
+            (Some(key_seed), Some(new_key_seed)) => {
+                if key_seed.unsecure() == new_key_seed {
+                    bail!("The new key seed is the same as the current one");
+                }
+                server_setup_from_seed(new_key_seed)
+            }
+            (None, None) => generate_random_private_key(),
+        };
+        let previous_file = previous_key_file(&self.key_file);
+        let previous_path = std::path::Path::new(&previous_file);
+        if previous_path.exists() {
+            std::fs::remove_file(previous_path)
+                .context(format!("Could not remove `{}`", previous_file))?;
+        }
+        let previous_server_setup = self.get_server_setup().clone();
+        write_to_readonly_file(previous_path, &previous_server_setup.serialize()).context(
+            format!(
+                "Could not write the previous server setup to `{}`",
+                previous_file
+            ),
+        )?;
+        match new_key_seed {
+            Some(new_key_seed) => self.key_seed = Some(SecUtf8::from(new_key_seed)),
+            None => {
+                let path = std::path::Path::new(&self.key_file);
+                std::fs::remove_file(path)
+                    .context(format!("Could not remove `{}`", self.key_file))?;
+                write_to_readonly_file(path, &server_setup.serialize()).context(format!(
+                    "Could not write the new server setup to `{}`",
+                    self.key_file
+                ))?;
+            }
+        }
+        let server_setup_config = self.server_setup.as_mut().unwrap();
+        server_setup_config.server_setup = server_setup;
+        self.previous_server_setup = Some(previous_server_setup);
+        Ok(())
+    }
+
     pub fn get_private_key_info(&self) -> PrivateKeyInfo {
         PrivateKeyInfo {
             private_key_hash: PrivateKeyHash(stable_hash(self.get_server_keys().private())),
@@ -179,6 +771,38 @@ impl Configuration {
                 .clone(),
         }
     }
+
+    /// Identifies the whole OPAQUE setup (keys and OPRF seed). All the instances sharing a database
+    /// must have the same one, otherwise logins started on one can't finish on another.
+    pub fn cluster_setup_fingerprint(&self) -> PrivateKeyHash {
+        PrivateKeyHash(stable_hash(&self.get_server_setup().serialize()))
+    }
+
+    /// Returns the public part of the OPAQUE setup, that can be shared with clients. It never
+    /// contains any secret.
+    pub fn public_server_setup(&self) -> PublicServerSetup {
+        use base64::Engine;
+        PublicServerSetup {
+            cipher_suite: server_setup::CIPHER_SUITE.to_owned(),
+            public_key: base64::engine::general_purpose::STANDARD
+                .encode(&self.get_server_keys().public()[..]),
+        }
+    }
+
+    /// Returns the reasons why the server private key, which seals all the server data, could be
+    /// guessed by an attacker.
+    pub fn assess_key_strength(&self) -> Vec<KeyWeakness> {
+        let mut weaknesses = Vec::new();
+        weaknesses.extend(assess_private_key(self.get_server_keys().private()));
+        if let Some(seed) = self.key_seed.as_ref().map(SecUtf8::unsecure) {
+            if seed == EXAMPLE_KEY_SEED {
+                weaknesses.push(KeyWeakness::ExampleKeySeed);
+            } else if seed.chars().count() < MIN_KEY_SEED_LENGTH {
+                weaknesses.push(KeyWeakness::ShortKeySeed);
+            }
+        }
+        weaknesses
+    }
 }
 
 /// Returns whether the private key is entirely new.
@@ -224,11 +848,52 @@ pub fn compare_private_key_hashes(
     }
 }
 
+/// Returns whether the fingerprint should be stored, i.e. this is the first instance to check it.
+pub fn compare_cluster_setup_fingerprints(
+    stored: Option<&PrivateKeyHash>,
+    fingerprint: &PrivateKeyHash,
+) -> Result<bool> {
+    match stored {
+        None => Ok(true),
+        Some(stored) if stored == fingerprint => Ok(false),
+        Some(_) => bail!("The OPAQUE server setup differs from the one used by the other instances sharing this database, so logins started on one instance would fail on another. Make sure all the instances use the same key file or key seed."),
+    }
+}
+
 fn generate_random_private_key() -> ServerSetup {
     let mut rng = rand::rngs::OsRng;
     ServerSetup::new(&mut rng)
 }
 
+fn server_setup_from_seed(key_seed: &str) -> ServerSetup {
+    use rand::SeedableRng;
+    let mut rng = rand_chacha::ChaCha20Rng::from_seed(stable_hash(key_seed.as_bytes()));
+    ServerSetup::new(&mut rng)
+}
+
+/// Where `rotate_keys` keeps the previous server setup.
+fn previous_key_file(key_file: &str) -> String {
+    format!("{}.previous", key_file)
+}
+
+/// The server setup replaced by `rotate_keys`, if it was less than `grace_seconds` ago.
+fn get_previous_server_setup(key_file: &str, grace_seconds: u64) -> Result<Option<ServerSetup>> {
+    let file_path = previous_key_file(key_file);
+    let path = std::path::Path::new(&file_path);
+    if !path.exists() {
+        return Ok(None);
+    }
+    let age = path.metadata()?.modified()?.elapsed().unwrap_or_default();
+    if age > std::time::Duration::from_secs(grace_seconds) {
+        return Ok(None);
+    }
+    let bytes = std::fs::read(path).context(format!("Could not read key file `{}`", file_path))?;
+    Ok(Some(ServerSetup::deserialize(&bytes).context(format!(
+        "while parsing the contents of the `{}` file",
+        file_path
+    ))?))
+}
+
 fn write_to_readonly_file(path: &std::path::Path, buffer: &[u8]) -> Result<()> {
     use std::{fs::File, io::Write};
     assert!(!path.exists());
@@ -341,10 +1006,8 @@ fn get_server_setup<L: Into<PrivateKeyLocationOrFigment>>(
         } else {
             println!("Generating the key from the key_seed");
         }
-        use rand::SeedableRng;
-        let mut rng = rand_chacha::ChaCha20Rng::from_seed(stable_hash(key_seed.as_bytes()));
         Ok(ServerSetupConfig {
-            server_setup: ServerSetup::new(&mut rng),
+            server_setup: server_setup_from_seed(key_seed),
             private_key_location: private_key_location.for_key_seed(),
         })
     } else if path.exists() {
@@ -389,6 +1052,18 @@ impl TopLevelCommandOpts for TestEmailOpts {
     }
 }
 
+impl TopLevelCommandOpts for RotateKeysOpts {
+    fn general_config(&self) -> &GeneralConfigOpts {
+        &self.run_opts.general_config
+    }
+}
+
+impl TopLevelCommandOpts for ImportUsersOpts {
+    fn general_config(&self) -> &GeneralConfigOpts {
+        &self.run_opts.general_config
+    }
+}
+
 impl ConfigOverrider for RunOpts {
     fn override_config(&self, config: &mut Configuration) {
         self.general_config.override_config(config);
@@ -436,6 +1111,18 @@ impl ConfigOverrider for TestEmailOpts {
     }
 }
 
+impl ConfigOverrider for RotateKeysOpts {
+    fn override_config(&self, config: &mut Configuration) {
+        self.run_opts.override_config(config);
+    }
+}
+
+impl ConfigOverrider for ImportUsersOpts {
+    fn override_config(&self, config: &mut Configuration) {
+        self.run_opts.override_config(config);
+    }
+}
+
 impl ConfigOverrider for LdapsOpts {
     fn override_config(&self, config: &mut Configuration) {
         if let Some(enabled) = self.ldaps_enabled {
@@ -525,6 +1212,11 @@ where
             .unwrap_or_default(),
         figment_config,
     )?);
+    config.previous_server_setup =
+        get_previous_server_setup(&config.key_file, config.key_rotation_grace_seconds)?;
+    for weakness in config.assess_key_strength() {
+        println!("WARNING: {} Anyone who guesses the private key can forge server data, generate a new random one!", weakness);
+    }
     if config.jwt_secret == SecUtf8::from("secretjwtsecret") {
         println!("WARNING: Default JWT secret used! This is highly unsafe and can allow attackers to log in as admin.");
     }
@@ -544,6 +1236,65 @@ mod tests {
     use figment::Jail;
     use pretty_assertions::assert_eq;
 
+    #[test]
+    fn test_assess_private_key() {
+        assert_eq!(
+            assess_private_key(&[0u8; 32]),
+            Some(KeyWeakness::AllZeroKey)
+        );
+        assert_eq!(
+            assess_private_key(&[1, 2, 3, 4].repeat(8)),
+            Some(KeyWeakness::LowEntropyKey)
+        );
+        use rand::RngCore;
+        let mut random_key = [0u8; 32];
+        rand::rngs::OsRng.fill_bytes(&mut random_key);
+        assert_eq!(assess_private_key(&random_key), None);
+    }
+
+    #[test]
+    fn test_public_server_setup() {
+        use base64::Engine;
+        let config = ConfigurationBuilder::for_tests();
+        let public_setup = config.public_server_setup();
+        let public_key = base64::engine::general_purpose::STANDARD
+            .decode(&public_setup.public_key)
+            .unwrap();
+        assert_eq!(public_key, config.get_server_keys().public()[..].to_vec());
+        assert_ne!(public_key, config.get_server_keys().private()[..].to_vec());
+        let json = serde_json::to_value(&public_setup).unwrap();
+        assert_eq!(
+            json.as_object()
+                .unwrap()
+                .keys()
+                .map(String::as_str)
+                .collect::<Vec<_>>(),
+            vec!["cipher_suite", "public_key"]
+        );
+        assert_eq!(
+            serde_json::from_value::<PublicServerSetup>(json).unwrap(),
+            public_setup
+        );
+    }
+
+    #[test]
+    fn test_assess_key_strength() {
+        let mut config = ConfigurationBuilder::for_tests();
+        assert_eq!(config.assess_key_strength(), vec![]);
+        config.key_seed = Some(SecUtf8::from(EXAMPLE_KEY_SEED));
+        assert_eq!(
+            config.assess_key_strength(),
+            vec![KeyWeakness::ExampleKeySeed]
+        );
+        config.key_seed = Some(SecUtf8::from("short"));
+        assert_eq!(
+            config.assess_key_strength(),
+            vec![KeyWeakness::ShortKeySeed]
+        );
+        config.key_seed = Some(SecUtf8::from("a long and random key seed"));
+        assert_eq!(config.assess_key_strength(), vec![]);
+    }
+
     #[test]
     fn check_generated_server_key() {
         assert_eq!(
@@ -570,6 +1321,25 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_cluster_setup_fingerprint() {
+        let config = ConfigurationBuilder::for_tests();
+        let fingerprint = config.cluster_setup_fingerprint();
+        assert!(compare_cluster_setup_fingerprints(None, &fingerprint).unwrap());
+        assert!(!compare_cluster_setup_fingerprints(Some(&fingerprint), &fingerprint).unwrap());
+        let same_seed = |seed| {
+            let mut config = ConfigurationBuilder::for_tests();
+            config.server_setup =
+                Some(get_server_setup("/doesnt/exist", seed, PrivateKeyLocation::Tests).unwrap());
+            config.cluster_setup_fingerprint()
+        };
+        assert_eq!(same_seed("key seed"), same_seed("key seed"));
+        // A node with another setup is flagged.
+        compare_cluster_setup_fingerprints(Some(&same_seed("key seed")), &fingerprint).unwrap_err();
+        compare_cluster_setup_fingerprints(Some(&same_seed("key seed")), &same_seed("other seed"))
+            .unwrap_err();
+    }
+
     fn default_run_opts() -> RunOpts {
         RunOpts::parse_from::<_, std::ffi::OsString>([])
     }
@@ -718,4 +1488,65 @@ mod tests {
             Ok(())
         });
     }
+
+    #[test]
+    fn test_rotate_server_setup_key_file() {
+        Jail::expect_with(|jail| {
+            jail.create_file("lldap_config.toml", r#"key_file = "test""#)?;
+            write_random_key(jail, "test");
+            let mut config = init(default_run_opts()).unwrap();
+            let old_setup = config.get_server_setup().serialize();
+            config.rotate_server_setup(Some("seed")).unwrap_err();
+            config.rotate_server_setup(None).unwrap();
+            assert_ne!(config.get_server_setup().serialize(), old_setup);
+            assert_eq!(
+                config.previous_server_setup.as_ref().unwrap().serialize(),
+                old_setup
+            );
+            // The new key is the one the next start uses, with the previous one for the logins in
+            // flight.
+            let restarted = init(default_run_opts()).unwrap();
+            assert_eq!(
+                restarted.get_server_setup().serialize(),
+                config.get_server_setup().serialize()
+            );
+            assert_eq!(
+                restarted.previous_server_setup.unwrap().serialize(),
+                old_setup
+            );
+            jail.create_file(
+                "lldap_config.toml",
+                "key_file = \"test\"\nkey_rotation_grace_seconds = 0",
+            )?;
+            assert!(init(default_run_opts())
+                .unwrap()
+                .previous_server_setup
+                .is_none());
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_rotate_server_setup_key_seed() {
+        Jail::expect_with(|jail| {
+            jail.create_file("lldap_config.toml", r#"key_seed = "old seed""#)?;
+            let mut config = init(default_run_opts()).unwrap();
+            let old_setup = config.get_server_setup().serialize();
+            config.rotate_server_setup(None).unwrap_err();
+            config.rotate_server_setup(Some("old seed")).unwrap_err();
+            config.rotate_server_setup(Some("new seed")).unwrap();
+            jail.create_file("lldap_config.toml", r#"key_seed = "new seed""#)?;
+            let restarted = init(default_run_opts()).unwrap();
+            assert_eq!(
+                restarted.get_server_setup().serialize(),
+                config.get_server_setup().serialize()
+            );
+            assert_ne!(restarted.get_server_setup().serialize(), old_setup);
+            assert_eq!(
+                restarted.previous_server_setup.unwrap().serialize(),
+                old_setup
+            );
+            Ok(())
+        });
+    }
 }
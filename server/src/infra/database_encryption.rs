@@ -0,0 +1,205 @@
+use crate::infra::database_string::DatabaseUrl;
+use anyhow::{bail, Context, Result};
+use sea_orm::{
+    sqlx::sqlite::{SqliteConnectOptions, SqlitePoolOptions},
+    ConnectionTrait, DatabaseConnection, DbBackend, SqlxSqliteConnector, Statement,
+};
+use secstr::SecUtf8;
+use std::{io::Read, path::PathBuf, str::FromStr};
+
+/// Every plaintext SQLite database starts with this header.
+const PLAINTEXT_SQLITE_HEADER: &[u8] = b"SQLite format 3\0";
+
+fn quote_key(key: &SecUtf8) -> String {
+    format!("'{}'", key.unsecure().replace('\'', "''"))
+}
+
+/// Returns the path of the database file, or None for in-memory databases.
+fn sqlite_file_path(database_url: &str) -> Option<PathBuf> {
+    let path = database_url
+        .strip_prefix("sqlite://")
+        .or_else(|| database_url.strip_prefix("sqlite:"))?;
+    let path = path.split('?').next().unwrap_or_default();
+    if path.is_empty() || path == ":memory:" {
+        None
+    } else {
+        Some(PathBuf::from(path))
+    }
+}
+
+/// Whether the file is a plaintext SQLite database. New, empty files are not.
+fn is_plaintext_sqlite(path: &std::path::Path) -> Result<bool> {
+    let mut header = [0u8; PLAINTEXT_SQLITE_HEADER.len()];
+    match std::fs::File::open(path) {
+        Ok(mut file) => match file.read_exact(&mut header) {
+            Ok(()) => Ok(header == PLAINTEXT_SQLITE_HEADER),
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => Ok(false),
+            Err(e) => Err(e).context(format!("while reading {}", path.display())),
+        },
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e).context(format!("while opening {}", path.display())),
+    }
+}
+
+async fn verify_encryption(sql_pool: &DatabaseConnection, database_url: &str) -> Result<()> {
+    // SQLCipher only checks the key when reading the first page.
+    sql_pool
+        .query_one(Statement::from_string(
+            DbBackend::Sqlite,
+            "SELECT count(*) FROM sqlite_master",
+        ))
+        .await
+        .context("could not read the database, is the encryption key correct?")?;
+    if sql_pool
+        .query_one(Statement::from_string(
+            DbBackend::Sqlite,
+            "PRAGMA cipher_version",
+        ))
+        .await?
+        .is_none()
+    {
+        bail!(
+            "An encryption key was configured, but LLDAP was built without SQLCipher support \
+             (the \"sqlcipher\" feature)"
+        );
+    }
+    if let Some(path) = sqlite_file_path(database_url) {
+        if is_plaintext_sqlite(&path)? {
+            bail!(
+                "An encryption key was configured, but the database {} is not encrypted",
+                path.display()
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Opens a SQLCipher database with the given key, and checks that it is actually encrypted.
+pub async fn connect_encrypted(
+    database_url: &DatabaseUrl,
+    key: &SecUtf8,
+    pool_options: SqlitePoolOptions,
+) -> Result<DatabaseConnection> {
+    let database_url = database_url.to_string();
+    if !database_url.starts_with("sqlite:") {
+        bail!("Database encryption is only supported for SQLite databases");
+    }
+    let options = SqliteConnectOptions::from_str(&database_url)
+        .context("while parsing the database URL")?
+        .pragma("key", quote_key(key));
+    let sql_pool = SqlxSqliteConnector::from_sqlx_sqlite_pool(
+        pool_options
+            .connect_with(options)
+            .await
+            .context("while opening the encrypted database")?,
+    );
+    verify_encryption(&sql_pool, &database_url).await?;
+    Ok(sql_pool)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sea_orm::{ConnectOptions, Database};
+
+    fn temp_database_url() -> (PathBuf, DatabaseUrl) {
+        let path = std::env::temp_dir().join(format!("lldap_{}.db", uuid::Uuid::new_v4()));
+        let url = DatabaseUrl::from(format!("sqlite://{}?mode=rwc", path.display()).as_str());
+        (path, url)
+    }
+
+    fn single_connection() -> SqlitePoolOptions {
+        SqlitePoolOptions::new().max_connections(1)
+    }
+
+    async fn create_plaintext_database(database_url: &DatabaseUrl) {
+        let sql_pool = Database::connect(ConnectOptions::new(database_url.to_string()))
+            .await
+            .unwrap();
+        crate::domain::sql_tables::init_table(&sql_pool)
+            .await
+            .unwrap();
+    }
+
+    #[test]
+    fn test_sqlite_file_path() {
+        assert_eq!(
+            sqlite_file_path("sqlite:///data/users.db?mode=rwc"),
+            Some(PathBuf::from("/data/users.db"))
+        );
+        assert_eq!(
+            sqlite_file_path("sqlite://users.db"),
+            Some(PathBuf::from("users.db"))
+        );
+        assert_eq!(sqlite_file_path("sqlite::memory:"), None);
+        assert_eq!(sqlite_file_path("postgres://localhost/lldap"), None);
+    }
+
+    #[test]
+    fn test_quote_key() {
+        assert_eq!(quote_key(&SecUtf8::from("it's")), "'it''s'");
+    }
+
+    #[tokio::test]
+    async fn test_detects_plaintext_database() {
+        let (path, url) = temp_database_url();
+        assert!(!is_plaintext_sqlite(&path).unwrap());
+        create_plaintext_database(&url).await;
+        assert!(is_plaintext_sqlite(&path).unwrap());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_refuses_plaintext_database() {
+        let (path, url) = temp_database_url();
+        create_plaintext_database(&url).await;
+        connect_encrypted(&url, &SecUtf8::from("key"), single_connection())
+            .await
+            .unwrap_err();
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_refuses_non_sqlite_database() {
+        connect_encrypted(
+            &DatabaseUrl::from("postgres://localhost/lldap"),
+            &SecUtf8::from("key"),
+            single_connection(),
+        )
+        .await
+        .unwrap_err();
+    }
+
+    #[cfg(feature = "sqlcipher")]
+    #[tokio::test]
+    async fn test_encrypted_database() {
+        let (path, url) = temp_database_url();
+        {
+            let sql_pool =
+                connect_encrypted(&url, &SecUtf8::from("right key"), single_connection())
+                    .await
+                    .unwrap();
+            crate::domain::sql_tables::init_table(&sql_pool)
+                .await
+                .unwrap();
+        }
+        assert!(!is_plaintext_sqlite(&path).unwrap());
+        connect_encrypted(&url, &SecUtf8::from("right key"), single_connection())
+            .await
+            .unwrap();
+        connect_encrypted(&url, &SecUtf8::from("wrong key"), single_connection())
+            .await
+            .unwrap_err();
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[cfg(not(feature = "sqlcipher"))]
+    #[tokio::test]
+    async fn test_requires_sqlcipher() {
+        let (path, url) = temp_database_url();
+        connect_encrypted(&url, &SecUtf8::from("key"), single_connection())
+            .await
+            .unwrap_err();
+        let _ = std::fs::remove_file(path);
+    }
+}
@@ -1,6 +1,10 @@
 use crate::domain::{
-    model::{self, JwtRefreshStorageColumn, JwtStorageColumn, PasswordResetTokensColumn},
+    model::{
+        self, JwtRefreshStorageColumn, JwtStorageColumn, LoginLinksColumn,
+        PasswordResetTokensColumn, RegistrationStatesColumn, UserSessionsColumn,
+    },
     sql_tables::DbConnection,
+    sql_user_backend_handler::purge_deleted_users,
 };
 use actix::prelude::{Actor, AsyncContext, Context};
 use cron::Schedule;
@@ -12,6 +16,7 @@ use tracing::{error, info, instrument};
 pub struct Scheduler {
     schedule: Schedule,
     sql_pool: DbConnection,
+    deleted_user_retention_days: u64,
 }
 
 // Provide Actor implementation for our actor
@@ -32,13 +37,24 @@ impl Actor for Scheduler {
 }
 
 impl Scheduler {
-    pub fn new(cron_expression: &str, sql_pool: DbConnection) -> Self {
+    pub fn new(
+        cron_expression: &str,
+        sql_pool: DbConnection,
+        deleted_user_retention_days: u64,
+    ) -> Self {
         let schedule = Schedule::from_str(cron_expression).unwrap();
-        Self { schedule, sql_pool }
+        Self {
+            schedule,
+            sql_pool,
+            deleted_user_retention_days,
+        }
     }
 
     fn schedule_task(&self, ctx: &mut Context<Self>) {
-        let future = actix::fut::wrap_future::<_, Self>(Self::cleanup_db(self.sql_pool.clone()));
+        let future = actix::fut::wrap_future::<_, Self>(Self::cleanup_db(
+            self.sql_pool.clone(),
+            self.deleted_user_retention_days,
+        ));
         ctx.spawn(future);
 
         ctx.run_later(self.duration_until_next(), move |this, ctx| {
@@ -47,7 +63,7 @@ impl Scheduler {
     }
 
     #[instrument(skip_all)]
-    async fn cleanup_db(sql_pool: DbConnection) {
+    async fn cleanup_db(sql_pool: DbConnection, deleted_user_retention_days: u64) {
         if let Err(e) = model::JwtRefreshStorage::delete_many()
             .filter(JwtRefreshStorageColumn::ExpiryDate.lt(chrono::Utc::now().naive_utc()))
             .exec(&sql_pool)
@@ -55,6 +71,13 @@ impl Scheduler {
         {
             error!("DB error while cleaning up JWT refresh tokens: {}", e);
         }
+        if let Err(e) = model::UserSessions::delete_many()
+            .filter(UserSessionsColumn::ExpiryDate.lt(chrono::Utc::now().naive_utc()))
+            .exec(&sql_pool)
+            .await
+        {
+            error!("DB error while cleaning up user sessions: {}", e);
+        }
         if let Err(e) = model::JwtStorage::delete_many()
             .filter(JwtStorageColumn::ExpiryDate.lt(chrono::Utc::now().naive_utc()))
             .exec(&sql_pool)
@@ -69,6 +92,27 @@ impl Scheduler {
         {
             error!("DB error while cleaning up password reset tokens: {}", e);
         };
+        if let Err(e) = model::LoginLinks::delete_many()
+            .filter(LoginLinksColumn::ExpiryDate.lt(chrono::Utc::now().naive_utc()))
+            .exec(&sql_pool)
+            .await
+        {
+            error!("DB error while cleaning up login links: {}", e);
+        };
+        if let Err(e) = model::RegistrationStates::delete_many()
+            .filter(RegistrationStatesColumn::ExpiryDate.lt(chrono::Utc::now().naive_utc()))
+            .exec(&sql_pool)
+            .await
+        {
+            error!("DB error while cleaning up registration states: {}", e);
+        };
+        if deleted_user_retention_days > 0 {
+            match purge_deleted_users(&sql_pool, deleted_user_retention_days).await {
+                Ok(0) => {}
+                Ok(count) => info!("Purged {} deleted users", count),
+                Err(e) => error!("DB error while purging deleted users: {}", e),
+            }
+        }
     }
 
     fn duration_until_next(&self) -> Duration {
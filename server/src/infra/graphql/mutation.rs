@@ -158,6 +158,7 @@ impl<Handler: BackendHandler> Mutation<Handler> {
                 last_name: user.last_name,
                 avatar,
                 attributes,
+                legacy_password_hash: None,
             })
             .instrument(span.clone())
             .await?;
@@ -331,6 +332,50 @@ impl<Handler: BackendHandler> Mutation<Handler> {
         Ok(Success::new())
     }
 
+    async fn add_group_to_group(
+        context: &Context<Handler>,
+        group_id: i32,
+        parent_group_id: i32,
+    ) -> FieldResult<Success> {
+        let span = debug_span!("[GraphQL mutation] add_group_to_group");
+        span.in_scope(|| {
+            debug!(?group_id, ?parent_group_id);
+        });
+        let handler = context
+            .get_admin_handler()
+            .ok_or_else(field_error_callback(
+                &span,
+                "Unauthorized group membership modification",
+            ))?;
+        handler
+            .add_group_to_group(GroupId(group_id), GroupId(parent_group_id))
+            .instrument(span)
+            .await?;
+        Ok(Success::new())
+    }
+
+    async fn remove_group_from_group(
+        context: &Context<Handler>,
+        group_id: i32,
+        parent_group_id: i32,
+    ) -> FieldResult<Success> {
+        let span = debug_span!("[GraphQL mutation] remove_group_from_group");
+        span.in_scope(|| {
+            debug!(?group_id, ?parent_group_id);
+        });
+        let handler = context
+            .get_admin_handler()
+            .ok_or_else(field_error_callback(
+                &span,
+                "Unauthorized group membership modification",
+            ))?;
+        handler
+            .remove_group_from_group(GroupId(group_id), GroupId(parent_group_id))
+            .instrument(span)
+            .await?;
+        Ok(Success::new())
+    }
+
     async fn delete_user(context: &Context<Handler>, user_id: String) -> FieldResult<Success> {
         let span = debug_span!("[GraphQL mutation] delete_user");
         span.in_scope(|| {
@@ -348,6 +393,21 @@ impl<Handler: BackendHandler> Mutation<Handler> {
         Ok(Success::new())
     }
 
+    async fn restore_user(context: &Context<Handler>, user_id: String) -> FieldResult<Success> {
+        let span = debug_span!("[GraphQL mutation] restore_user");
+        span.in_scope(|| {
+            debug!(?user_id);
+        });
+        let handler = context
+            .get_admin_handler()
+            .ok_or_else(field_error_callback(&span, "Unauthorized user restoration"))?;
+        handler
+            .restore_user(&UserId::new(&user_id))
+            .instrument(span)
+            .await?;
+        Ok(Success::new())
+    }
+
     async fn delete_group(context: &Context<Handler>, group_id: i32) -> FieldResult<Success> {
         let span = debug_span!("[GraphQL mutation] delete_group");
         span.in_scope(|| {
@@ -15,8 +15,9 @@ use crate::{
     },
 };
 use anyhow::Context as AnyhowContext;
+use base64::Engine;
 use chrono::{NaiveDateTime, TimeZone};
-use juniper::{graphql_object, FieldError, FieldResult, GraphQLInputObject};
+use juniper::{graphql_object, FieldError, FieldResult, GraphQLInputObject, GraphQLObject};
 use serde::{Deserialize, Serialize};
 use tracing::{debug, debug_span, Instrument, Span};
 
@@ -177,6 +178,54 @@ impl<Handler: BackendHandler> Query<Handler> {
             .collect()
     }
 
+    /// A page of users, sorted by ID. To get the next page, pass the `endCursor` of the previous
+    /// one as `after`.
+    async fn users_connection(
+        context: &Context<Handler>,
+        #[graphql(name = "where")] filters: Option<RequestFilter>,
+        first: i32,
+        after: Option<String>,
+    ) -> FieldResult<UserConnection<Handler>> {
+        let span = debug_span!("[GraphQL query] users_connection");
+        span.in_scope(|| {
+            debug!(?filters, ?first, ?after);
+        });
+        if !(1..=MAX_PAGE_SIZE).contains(&first) {
+            return Err(format!("`first` must be between 1 and {}", MAX_PAGE_SIZE).into());
+        }
+        let after = after.as_deref().map(decode_cursor).transpose()?;
+        let handler = context
+            .get_readonly_handler()
+            .ok_or_else(field_error_callback(
+                &span,
+                "Unauthorized access to user list",
+            ))?;
+        let schema = Arc::new(self.get_schema(context, span.clone()).await?);
+        let page = handler
+            .list_users_page(
+                filters
+                    .map(|f| f.try_into_domain_filter(&schema))
+                    .transpose()?,
+                false,
+                first as u64,
+                after,
+            )
+            .instrument(span)
+            .await?;
+        let end_cursor = page.users.last().map(|u| encode_cursor(&u.user.user_id));
+        Ok(UserConnection {
+            users: page
+                .users
+                .into_iter()
+                .map(|u| User::<Handler>::from_user_and_groups(u, schema.clone()))
+                .collect::<FieldResult<_>>()?,
+            page_info: PageInfo {
+                has_next_page: page.has_next_page,
+                end_cursor,
+            },
+        })
+    }
+
     async fn groups(context: &Context<Handler>) -> FieldResult<Vec<Group<Handler>>> {
         let span = debug_span!("[GraphQL query] groups");
         let handler = context
@@ -235,6 +284,47 @@ impl<Handler: BackendHandler> Query<Handler> {
     }
 }
 
+/// The largest page of users that can be requested at once.
+const MAX_PAGE_SIZE: i32 = 1000;
+
+/// The cursors are opaque to the clients, but they're just the last user ID of the page.
+fn encode_cursor(user_id: &UserId) -> String {
+    base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(user_id.as_str())
+}
+
+fn decode_cursor(cursor: &str) -> FieldResult<UserId> {
+    base64::engine::general_purpose::URL_SAFE_NO_PAD
+        .decode(cursor)
+        .ok()
+        .and_then(|bytes| String::from_utf8(bytes).ok())
+        .map(|user_id| UserId::new(&user_id))
+        .ok_or_else(|| "Invalid cursor".into())
+}
+
+#[derive(PartialEq, Eq, Debug, GraphQLObject)]
+pub struct PageInfo {
+    has_next_page: bool,
+    /// The cursor to pass as `after` to get the next page.
+    end_cursor: Option<String>,
+}
+
+/// A page of users.
+pub struct UserConnection<Handler: BackendHandler> {
+    users: Vec<User<Handler>>,
+    page_info: PageInfo,
+}
+
+#[graphql_object(context = Context<Handler>)]
+impl<Handler: BackendHandler> UserConnection<Handler> {
+    fn users(&self) -> &[User<Handler>] {
+        &self.users
+    }
+
+    fn page_info(&self) -> &PageInfo {
+        &self.page_info
+    }
+}
+
 #[derive(PartialEq, Eq, Debug, Serialize, Deserialize)]
 /// Represents a single user.
 pub struct User<Handler: BackendHandler> {
@@ -325,6 +415,44 @@ impl<Handler: BackendHandler> User<Handler> {
         chrono::Utc.from_utc_datetime(&self.user.creation_date)
     }
 
+    /// When the password was last changed, if known.
+    async fn password_changed_at(
+        &self,
+        context: &Context<Handler>,
+    ) -> FieldResult<Option<chrono::DateTime<chrono::Utc>>> {
+        let span = debug_span!("[GraphQL query] user::password_changed_at");
+        span.in_scope(|| {
+            debug!(user_id = ?self.user.user_id);
+        });
+        let handler = context
+            .get_readable_handler(&self.user.user_id)
+            .expect("We shouldn't be able to get there without readable permission");
+        Ok(handler
+            .get_password_changed_at(&self.user.user_id)
+            .instrument(span)
+            .await?
+            .map(|date| chrono::Utc.from_utc_datetime(&date)))
+    }
+
+    /// When the user last logged in, if login statistics are tracked.
+    async fn last_login_date(
+        &self,
+        context: &Context<Handler>,
+    ) -> FieldResult<Option<chrono::DateTime<chrono::Utc>>> {
+        let span = debug_span!("[GraphQL query] user::last_login_date");
+        span.in_scope(|| {
+            debug!(user_id = ?self.user.user_id);
+        });
+        let handler = context
+            .get_readable_handler(&self.user.user_id)
+            .expect("We shouldn't be able to get there without readable permission");
+        Ok(handler
+            .get_last_login_date(&self.user.user_id)
+            .instrument(span)
+            .await?
+            .map(|date| chrono::Utc.from_utc_datetime(&date)))
+    }
+
     fn uuid(&self) -> &str {
         self.user.uuid.as_str()
     }
@@ -847,6 +975,126 @@ mod tests {
         );
     }
 
+    #[tokio::test]
+    async fn get_user_password_changed_at() {
+        const QUERY: &str = r#"{
+          bob: user(userId: "bob") {
+            passwordChangedAt
+          }
+          john: user(userId: "john") {
+            passwordChangedAt
+          }
+        }"#;
+
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_get_schema().returning(|| {
+            Ok(crate::domain::handler::Schema {
+                user_attributes: DomainAttributeList {
+                    attributes: Vec::new(),
+                },
+                group_attributes: DomainAttributeList {
+                    attributes: Vec::new(),
+                },
+            })
+        });
+        mock.expect_get_user_details().returning(|user_id| {
+            Ok(DomainUser {
+                user_id: user_id.clone(),
+                ..Default::default()
+            })
+        });
+        mock.expect_get_password_changed_at()
+            .with(eq(UserId::new("bob")))
+            .return_once(|_| {
+                Ok(Some(
+                    chrono::Utc.timestamp_millis_opt(42).unwrap().naive_utc(),
+                ))
+            });
+        mock.expect_get_password_changed_at()
+            .with(eq(UserId::new("john")))
+            .return_once(|_| Ok(None));
+
+        let context =
+            Context::<MockTestBackendHandler>::new_for_tests(mock, ValidationResults::admin());
+
+        let schema = schema(Query::<MockTestBackendHandler>::new());
+        assert_eq!(
+            execute(QUERY, None, &schema, &Variables::new(), &context).await,
+            Ok((
+                graphql_value!(
+                {
+                    "bob": {
+                        "passwordChangedAt": "1970-01-01T00:00:00.042+00:00",
+                    },
+                    "john": {
+                        "passwordChangedAt": None,
+                    }
+                }),
+                vec![]
+            ))
+        );
+    }
+
+    #[tokio::test]
+    async fn get_user_last_login_date() {
+        const QUERY: &str = r#"{
+          bob: user(userId: "bob") {
+            lastLoginDate
+          }
+          john: user(userId: "john") {
+            lastLoginDate
+          }
+        }"#;
+
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_get_schema().returning(|| {
+            Ok(crate::domain::handler::Schema {
+                user_attributes: DomainAttributeList {
+                    attributes: Vec::new(),
+                },
+                group_attributes: DomainAttributeList {
+                    attributes: Vec::new(),
+                },
+            })
+        });
+        mock.expect_get_user_details().returning(|user_id| {
+            Ok(DomainUser {
+                user_id: user_id.clone(),
+                ..Default::default()
+            })
+        });
+        mock.expect_get_last_login_date()
+            .with(eq(UserId::new("bob")))
+            .return_once(|_| {
+                Ok(Some(
+                    chrono::Utc.timestamp_millis_opt(42).unwrap().naive_utc(),
+                ))
+            });
+        mock.expect_get_last_login_date()
+            .with(eq(UserId::new("john")))
+            .return_once(|_| Ok(None));
+
+        let context =
+            Context::<MockTestBackendHandler>::new_for_tests(mock, ValidationResults::admin());
+
+        let schema = schema(Query::<MockTestBackendHandler>::new());
+        assert_eq!(
+            execute(QUERY, None, &schema, &Variables::new(), &context).await,
+            Ok((
+                graphql_value!(
+                {
+                    "bob": {
+                        "lastLoginDate": "1970-01-01T00:00:00.042+00:00",
+                    },
+                    "john": {
+                        "lastLoginDate": None,
+                    }
+                }),
+                vec![]
+            ))
+        );
+    }
+
     #[tokio::test]
     async fn list_users() {
         const QUERY: &str = r#"{
@@ -933,6 +1181,65 @@ mod tests {
         );
     }
 
+    #[tokio::test]
+    async fn list_users_connection() {
+        let query = format!(
+            r#"{{
+              usersConnection(first: 1, after: "{}") {{
+                users {{ id }}
+                pageInfo {{ hasNextPage endCursor }}
+              }}
+            }}"#,
+            encode_cursor(&UserId::new("bob"))
+        );
+
+        let mut mock = MockTestBackendHandler::new();
+        setup_default_schema(&mut mock);
+        mock.expect_list_users_page()
+            .with(eq(None), eq(false), eq(1), eq(Some(UserId::new("bob"))))
+            .return_once(|_, _, _, _| {
+                Ok(crate::domain::handler::UserPage {
+                    users: vec![DomainUserAndGroups {
+                        user: DomainUser {
+                            user_id: UserId::new("robert"),
+                            ..Default::default()
+                        },
+                        groups: None,
+                    }],
+                    has_next_page: true,
+                })
+            });
+
+        let context =
+            Context::<MockTestBackendHandler>::new_for_tests(mock, ValidationResults::admin());
+
+        let schema = schema(Query::<MockTestBackendHandler>::new());
+        let end_cursor = encode_cursor(&UserId::new("robert"));
+        assert_eq!(
+            execute(&query, None, &schema, &Variables::new(), &context).await,
+            Ok((
+                graphql_value!(
+                {
+                    "usersConnection": {
+                        "users": [{"id": "robert"}],
+                        "pageInfo": {
+                            "hasNextPage": true,
+                            "endCursor": end_cursor,
+                        },
+                    }
+                }),
+                vec![]
+            ))
+        );
+    }
+
+    #[test]
+    fn test_cursor() {
+        let cursor = encode_cursor(&UserId::new("bob"));
+        assert_eq!(decode_cursor(&cursor).unwrap(), UserId::new("bob"));
+        decode_cursor("not a cursor!").unwrap_err();
+    }
+
     #[tokio::test]
     async fn get_schema() {
         const QUERY: &str = r#"{
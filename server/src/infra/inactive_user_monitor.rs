@@ -0,0 +1,59 @@
+use crate::domain::handler::BackendHandler;
+use actix::prelude::{Actor, AsyncContext, Context};
+use std::time::Duration;
+use tracing::{error, info, instrument};
+
+/// How often to look for inactive users.
+const CHECK_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// Periodically disables the users that haven't logged in for a while.
+pub struct InactiveUserMonitor<Backend> {
+    max_inactive_days: u64,
+    backend_handler: Backend,
+}
+
+impl<Backend> Actor for InactiveUserMonitor<Backend>
+where
+    Backend: BackendHandler + Clone + 'static,
+{
+    type Context = Context<Self>;
+
+    fn started(&mut self, context: &mut Context<Self>) {
+        info!(
+            "Inactive user monitor started, disabling users after {} days",
+            self.max_inactive_days
+        );
+        context.run_interval(CHECK_INTERVAL, |this, ctx| {
+            let future = actix::fut::wrap_future::<_, Self>(Self::check(
+                this.backend_handler.clone(),
+                this.max_inactive_days,
+            ));
+            ctx.spawn(future);
+        });
+    }
+}
+
+impl<Backend> InactiveUserMonitor<Backend>
+where
+    Backend: BackendHandler + Clone + 'static,
+{
+    pub fn new(max_inactive_days: u64, backend_handler: Backend) -> Self {
+        Self {
+            max_inactive_days,
+            backend_handler,
+        }
+    }
+
+    #[instrument(skip_all)]
+    async fn check(backend_handler: Backend, max_inactive_days: u64) {
+        let since =
+            chrono::Utc::now().naive_utc() - chrono::Duration::days(max_inactive_days as i64);
+        match backend_handler.disable_inactive_users(since).await {
+            Ok(disabled) if !disabled.is_empty() => {
+                info!("Disabled {} inactive users", disabled.len())
+            }
+            Ok(_) => {}
+            Err(e) => error!("Could not disable the inactive users: {}", e),
+        }
+    }
+}
@@ -1,36 +1,47 @@
 use crate::{
     domain::{
+        error::DomainError,
         handler::{
             BackendHandler, BindRequest, CreateUserRequest, LoginHandler, ReadSchemaBackendHandler,
         },
         ldap::{
             error::{LdapError, LdapResult},
-            group::{convert_groups_to_ldap_op, get_groups_list},
-            user::{convert_users_to_ldap_op, get_user_list},
+            group::{convert_groups_to_ldap_op, get_groups_list, get_groups_page},
+            user::{convert_users_to_ldap_op, get_user_list, get_user_page},
             utils::{
                 get_user_id_from_distinguished_name, is_subtree, parse_distinguished_name, LdapInfo,
             },
         },
         opaque_handler::OpaqueHandler,
         schema::PublicSchema,
-        types::{AttributeName, Email, Group, JpegPhoto, UserAndGroups, UserId},
+        types::{AttributeName, Email, Group, GroupId, JpegPhoto, UserAndGroups, UserId},
     },
-    infra::access_control::{
-        AccessControlledBackendHandler, AdminBackendHandler, UserAndGroupListerBackendHandler,
-        UserReadableBackendHandler, ValidationResults,
+    infra::{
+        access_control::{
+            AccessControlledBackendHandler, AdminBackendHandler, UserAndGroupListerBackendHandler,
+            UserReadableBackendHandler, ValidationResults,
+        },
+        rate_limiter::RateLimiter,
     },
 };
 use anyhow::Result;
-use ldap3_proto::proto::{
-    LdapAddRequest, LdapBindCred, LdapBindRequest, LdapBindResponse, LdapCompareRequest,
-    LdapDerefAliases, LdapExtendedRequest, LdapExtendedResponse, LdapFilter, LdapModify,
-    LdapModifyRequest, LdapModifyType, LdapOp, LdapPartialAttribute, LdapPasswordModifyRequest,
-    LdapResult as LdapResultOp, LdapResultCode, LdapSearchRequest, LdapSearchResultEntry,
-    LdapSearchScope,
+use ldap3_proto::{
+    control::LdapControl,
+    proto::{
+        LdapAddRequest, LdapBindCred, LdapBindRequest, LdapBindResponse, LdapCompareRequest,
+        LdapDerefAliases, LdapExtendedRequest, LdapExtendedResponse, LdapFilter, LdapModify,
+        LdapModifyRequest, LdapModifyType, LdapOp, LdapPartialAttribute, LdapPasswordModifyRequest,
+        LdapResult as LdapResultOp, LdapResultCode, LdapSearchRequest, LdapSearchResultEntry,
+        LdapSearchScope,
+    },
 };
-use std::collections::HashMap;
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, net::IpAddr, sync::Arc};
 use tracing::{debug, instrument, warn};
 
+/// The extended operation to upgrade the connection to TLS (RFC 4511, 4.14).
+pub const START_TLS_OID: &str = "1.3.6.1.4.1.1466.20037";
+
 #[derive(Debug, PartialEq, Eq, Clone)]
 struct LdapDn(String);
 
@@ -53,6 +64,42 @@ enum InternalSearchResults {
     Empty,
 }
 
+enum SearchTarget {
+    /// Search the users and/or the groups with these filters.
+    Entries {
+        users: Option<LdapFilter>,
+        groups: Option<LdapFilter>,
+    },
+    Results(InternalSearchResults),
+}
+
+/// Where a paged search resumes: strictly after the given user or group, users first.
+#[derive(Debug, Serialize, Deserialize)]
+enum PagedSearchPosition {
+    Users(Option<UserId>),
+    Groups(Option<GroupId>),
+}
+
+/// The paged results cookie, sealed with the key of the session.
+#[derive(Debug, Serialize, Deserialize)]
+struct PagedSearchCookie {
+    /// The cookie is only valid for the same search.
+    search_digest: Vec<u8>,
+    position: PagedSearchPosition,
+}
+
+fn get_search_digest(request: &LdapSearchRequest) -> Vec<u8> {
+    use sha2::{Digest, Sha256};
+    Sha256::digest(format!("{:?}", request).as_bytes()).to_vec()
+}
+
+fn requests_member_of(request: &LdapSearchRequest) -> bool {
+    request
+        .attrs
+        .iter()
+        .any(|s| s.to_ascii_lowercase() == "memberof")
+}
+
 fn get_search_scope(
     base_dn: &[(String, String)],
     dn_parts: &[(String, String)],
@@ -128,6 +175,24 @@ fn make_search_error(code: LdapResultCode, message: String) -> LdapOp {
     })
 }
 
+fn is_root_dse_request(request: &LdapSearchRequest) -> bool {
+    match &request.filter {
+        LdapFilter::Present(attribute) => {
+            request.base.is_empty()
+                && request.scope == LdapSearchScope::Base
+                && attribute.to_ascii_lowercase() == "objectclass"
+        }
+        _ => false,
+    }
+}
+
+fn get_paged_results_control(controls: &[LdapControl]) -> Option<(i64, &[u8])> {
+    controls.iter().find_map(|control| match control {
+        LdapControl::SimplePagedResults { size, cookie } => Some((*size, cookie.as_slice())),
+        _ => None,
+    })
+}
+
 fn make_add_error(code: LdapResultCode, message: String) -> LdapOp {
     LdapOp::AddResponse(LdapResultOp {
         code,
@@ -150,6 +215,15 @@ fn make_extended_response(code: LdapResultCode, message: String) -> LdapOp {
     })
 }
 
+fn make_compare_result(code: LdapResultCode, request: &LdapCompareRequest) -> LdapOp {
+    LdapOp::CompareResult(LdapResultOp {
+        code,
+        matcheddn: request.dn.clone(),
+        message: "".to_string(),
+        referral: vec![],
+    })
+}
+
 fn make_modify_response(code: LdapResultCode, message: String) -> LdapOp {
     LdapOp::ModifyResponse(LdapResultOp {
         code,
@@ -159,7 +233,12 @@ fn make_modify_response(code: LdapResultCode, message: String) -> LdapOp {
     })
 }
 
-fn root_dse_response(base_dn: &str) -> LdapOp {
+fn root_dse_response(base_dn: &str, start_tls_available: bool) -> LdapOp {
+    // Password modification extension.
+    let mut extensions = vec![b"1.3.6.1.4.1.4203.1.11.1".to_vec()];
+    if start_tls_available {
+        extensions.push(START_TLS_OID.as_bytes().to_vec());
+    }
     LdapOp::SearchResultEntry(LdapSearchResultEntry {
         dn: "".to_string(),
         attributes: vec![
@@ -183,8 +262,7 @@ fn root_dse_response(base_dn: &str) -> LdapOp {
             },
             LdapPartialAttribute {
                 atype: "supportedExtension".to_string(),
-                // Password modification extension.
-                vals: vec![b"1.3.6.1.4.1.4203.1.11.1".to_vec()],
+                vals: extensions,
             },
             LdapPartialAttribute {
                 atype: "supportedControl".to_string(),
@@ -215,6 +293,20 @@ pub struct LdapHandler<Backend> {
     user_info: Option<ValidationResults>,
     backend_handler: AccessControlledBackendHandler<Backend>,
     ldap_info: LdapInfo,
+    /// Whether the session is over LDAPS, or was upgraded with StartTLS.
+    transport_secure: bool,
+    /// Whether the connection can be upgraded with StartTLS.
+    start_tls_available: bool,
+    /// Set when StartTLS was accepted: the connection has to be upgraded before the next request.
+    start_tls_requested: bool,
+    /// Refuse the binds until the connection is encrypted.
+    require_tls_for_bind: bool,
+    /// The address of the client, for the throttling of the failed binds.
+    client_address: Option<IpAddr>,
+    /// Limits the binds from `client_address`.
+    rate_limiter: Option<Arc<RateLimiter>>,
+    /// Seals the paged results cookies.
+    paging_key: orion::aead::SecretKey,
 }
 
 impl<Backend: LoginHandler> LdapHandler<Backend> {
@@ -235,6 +327,7 @@ impl<Backend: BackendHandler + LoginHandler + OpaqueHandler> LdapHandler<Backend
         mut ldap_base_dn: String,
         ignored_user_attributes: Vec<AttributeName>,
         ignored_group_attributes: Vec<AttributeName>,
+        transport_secure: bool,
     ) -> Self {
         ldap_base_dn.make_ascii_lowercase();
         Self {
@@ -251,9 +344,59 @@ impl<Backend: BackendHandler + LoginHandler + OpaqueHandler> LdapHandler<Backend
                 ignored_user_attributes,
                 ignored_group_attributes,
             },
+            transport_secure,
+            start_tls_available: false,
+            start_tls_requested: false,
+            require_tls_for_bind: false,
+            client_address: None,
+            rate_limiter: None,
+            paging_key: orion::aead::SecretKey::default(),
+        }
+    }
+
+    /// Limits the binds from the client address, if there is one.
+    pub fn with_rate_limiter(self, rate_limiter: Arc<RateLimiter>) -> Self {
+        Self {
+            rate_limiter: Some(rate_limiter),
+            ..self
         }
     }
 
+    /// Accept StartTLS requests. The caller upgrades the connection when
+    /// `take_start_tls_request` says so.
+    pub fn with_start_tls(self) -> Self {
+        Self {
+            start_tls_available: true,
+            ..self
+        }
+    }
+
+    pub fn with_tls_required_for_bind(self, require_tls_for_bind: bool) -> Self {
+        Self {
+            require_tls_for_bind,
+            ..self
+        }
+    }
+
+    pub fn with_client_address(self, client_address: Option<IpAddr>) -> Self {
+        Self {
+            client_address,
+            ..self
+        }
+    }
+
+    /// Whether StartTLS was accepted since the last call: the response was sent in the clear, and
+    /// the TLS handshake comes next.
+    pub fn take_start_tls_request(&mut self) -> bool {
+        std::mem::take(&mut self.start_tls_requested)
+    }
+
+    /// The TLS handshake after StartTLS succeeded.
+    pub fn start_tls_established(&mut self) {
+        self.transport_secure = true;
+        self.start_tls_available = false;
+    }
+
     #[cfg(test)]
     pub fn new_for_tests(backend_handler: Backend, ldap_base_dn: &str) -> Self {
         Self::new(
@@ -261,6 +404,7 @@ impl<Backend: BackendHandler + LoginHandler + OpaqueHandler> LdapHandler<Backend
             ldap_base_dn.to_string(),
             vec![],
             vec![],
+            true,
         )
     }
 
@@ -282,14 +426,33 @@ impl<Backend: BackendHandler + LoginHandler + OpaqueHandler> LdapHandler<Backend
                 "SASL not supported".to_string(),
             );
         };
-        match self
-            .get_login_handler()
-            .bind(BindRequest {
-                name: user_id.clone(),
-                password: password.clone(),
-            })
-            .await
-        {
+        if self.require_tls_for_bind && !self.transport_secure {
+            return (
+                LdapResultCode::ConfidentialityRequired,
+                "Binds require an encrypted connection: use LDAPS or StartTLS".to_string(),
+            );
+        }
+        let rate_limit = match (&self.rate_limiter, self.client_address) {
+            // A bound connection gets the limits of the authenticated clients.
+            (Some(rate_limiter), Some(source)) => {
+                rate_limiter.check(source, self.user_info.is_some())
+            }
+            _ => Ok(()),
+        };
+        let result = match rate_limit {
+            Ok(()) => {
+                self.get_login_handler()
+                    .bind(BindRequest {
+                        name: user_id.clone(),
+                        password: password.clone(),
+                        source: self.client_address,
+                        context: None,
+                    })
+                    .await
+            }
+            Err(e) => Err(e),
+        };
+        match result {
             Ok(()) => {
                 self.user_info = self
                     .backend_handler
@@ -299,6 +462,17 @@ impl<Backend: BackendHandler + LoginHandler + OpaqueHandler> LdapHandler<Backend
                 debug!("Success!");
                 (LdapResultCode::Success, "".to_string())
             }
+            Err(DomainError::Maintenance(message)) => (
+                LdapResultCode::Unavailable,
+                message.unwrap_or_else(|| "Under maintenance".to_string()),
+            ),
+            Err(DomainError::RateLimited(_, Some(retry_after))) => (
+                LdapResultCode::InvalidCredentials,
+                format!(
+                    "Too many attempts, retry in {} seconds",
+                    retry_after.as_secs()
+                ),
+            ),
             Err(_) => (LdapResultCode::InvalidCredentials, "".to_string()),
         }
     }
@@ -306,6 +480,7 @@ impl<Backend: BackendHandler + LoginHandler + OpaqueHandler> LdapHandler<Backend
     async fn change_password<B: OpaqueHandler>(
         &self,
         backend_handler: &B,
+        actor: &UserId,
         user: UserId,
         password: &[u8],
     ) -> Result<()> {
@@ -316,8 +491,10 @@ impl<Backend: BackendHandler + LoginHandler + OpaqueHandler> LdapHandler<Backend
         let req = registration::ClientRegistrationStartRequest {
             username: user.clone(),
             registration_start_request: registration_start_request.message,
+            context: None,
         };
-        let registration_start_response = backend_handler.registration_start(req).await?;
+        // Only bound users can change passwords, and the LDAP peer address isn't tracked.
+        let registration_start_response = backend_handler.registration_start(req, None).await?;
         let registration_finish = opaque::client::registration::finish_registration(
             registration_start_request.state,
             registration_start_response.registration_response,
@@ -327,7 +504,9 @@ impl<Backend: BackendHandler + LoginHandler + OpaqueHandler> LdapHandler<Backend
             server_data: registration_start_response.server_data,
             registration_upload: registration_finish.message,
         };
-        backend_handler.registration_finish(req).await?;
+        backend_handler
+            .registration_finish(req, actor, self.transport_secure)
+            .await?;
         Ok(())
     }
 
@@ -371,7 +550,12 @@ impl<Backend: BackendHandler + LoginHandler + OpaqueHandler> LdapHandler<Backend
                                 ),
                             })
                         } else if let Err(e) = self
-                            .change_password(self.get_opaque_handler(), uid, password.as_bytes())
+                            .change_password(
+                                self.get_opaque_handler(),
+                                &credentials.user,
+                                uid,
+                                password.as_bytes(),
+                            )
                             .await
                         {
                             Err(LdapError {
@@ -398,7 +582,40 @@ impl<Backend: BackendHandler + LoginHandler + OpaqueHandler> LdapHandler<Backend
         }
     }
 
+    fn do_start_tls(&mut self) -> LdapResult<Vec<LdapOp>> {
+        if self.transport_secure {
+            return Err(LdapError {
+                code: LdapResultCode::OperationsError,
+                message: "The connection is already encrypted".to_string(),
+            });
+        }
+        if !self.start_tls_available {
+            return Err(LdapError {
+                code: LdapResultCode::Unavailable,
+                message: "StartTLS is not configured".to_string(),
+            });
+        }
+        // Nothing from the plaintext part of the session carries over to the encrypted one.
+        self.user_info = None;
+        self.start_tls_requested = true;
+        Ok(vec![LdapOp::ExtendedResponse(LdapExtendedResponse {
+            res: LdapResultOp {
+                code: LdapResultCode::Success,
+                matcheddn: "".to_string(),
+                message: "".to_string(),
+                referral: vec![],
+            },
+            name: Some(START_TLS_OID.to_string()),
+            value: None,
+        })])
+    }
+
     async fn do_extended_request(&mut self, request: &LdapExtendedRequest) -> Vec<LdapOp> {
+        if request.name == START_TLS_OID {
+            return self
+                .do_start_tls()
+                .unwrap_or_else(|e: LdapError| vec![make_extended_response(e.code, e.message)]);
+        }
         match LdapPasswordModifyRequest::try_from(request) {
             Ok(password_request) => self
                 .do_password_modification(&password_request)
@@ -439,7 +656,7 @@ impl<Backend: BackendHandler + LoginHandler + OpaqueHandler> LdapHandler<Backend
             });
         }
         if let [value] = &change.modification.vals.as_slice() {
-            self.change_password(self.get_opaque_handler(), user_id, value)
+            self.change_password(self.get_opaque_handler(), &credentials.user, user_id, value)
                 .await
                 .map_err(|e| LdapError {
                     code: LdapResultCode::Other,
@@ -513,115 +730,59 @@ impl<Backend: BackendHandler + LoginHandler + OpaqueHandler> LdapHandler<Backend
         &mut self,
         request: &LdapSearchRequest,
     ) -> LdapResult<Vec<LdapOp>> {
-        if request.base.is_empty() && request.scope == LdapSearchScope::Base {
-            if let LdapFilter::Present(attribute) = &request.filter {
-                if attribute.to_ascii_lowercase() == "objectclass" {
-                    debug!("rootDSE request");
-                    return Ok(vec![
-                        root_dse_response(&self.ldap_info.base_dn_str),
-                        make_search_success(),
-                    ]);
-                }
-            }
+        if is_root_dse_request(request) {
+            debug!("rootDSE request");
+            return Ok(vec![
+                root_dse_response(&self.ldap_info.base_dn_str, self.start_tls_available),
+                make_search_success(),
+            ]);
         }
         self.do_search(request).await
     }
 
-    async fn do_search_internal(
-        &self,
-        backend_handler: &impl UserAndGroupListerBackendHandler,
-        request: &LdapSearchRequest,
-        schema: &PublicSchema,
-    ) -> LdapResult<InternalSearchResults> {
+    /// Which users and groups the search is about, or its results if it's about neither.
+    fn get_search_target(&self, request: &LdapSearchRequest) -> LdapResult<SearchTarget> {
         let dn_parts = parse_distinguished_name(&request.base.to_ascii_lowercase())?;
         let scope = get_search_scope(&self.ldap_info.base_dn, &dn_parts, &request.scope);
         debug!(?request.base, ?scope);
-        // Disambiguate the lifetimes.
-        fn cast<'a, T, R>(x: T) -> T
-        where
-            T: Fn(&'a LdapFilter) -> R + 'a,
-        {
-            x
-        }
-
-        let get_user_list = cast(|filter: &LdapFilter| async {
-            let need_groups = request
-                .attrs
-                .iter()
-                .any(|s| s.to_ascii_lowercase() == "memberof");
-            get_user_list(
-                &self.ldap_info,
-                filter,
-                need_groups,
-                &request.base,
-                backend_handler,
-                schema,
-            )
-            .await
-        });
-        let get_group_list = cast(|filter: &LdapFilter| async {
-            get_groups_list(
-                &self.ldap_info,
-                filter,
-                &request.base,
-                backend_handler,
-                schema,
-            )
-            .await
-        });
         Ok(match scope {
-            SearchScope::Global => {
-                let users = get_user_list(&request.filter).await;
-                let groups = get_group_list(&request.filter).await;
-                match (users, groups) {
-                    (Ok(users), Err(e)) => {
-                        warn!("Error while getting groups: {:#}", e);
-                        InternalSearchResults::UsersAndGroups(users, Vec::new())
-                    }
-                    (Err(e), Ok(groups)) => {
-                        warn!("Error while getting users: {:#}", e);
-                        InternalSearchResults::UsersAndGroups(Vec::new(), groups)
-                    }
-                    (Err(user_error), Err(_)) => {
-                        InternalSearchResults::Raw(vec![make_search_error(
-                            user_error.code,
-                            user_error.message,
-                        )])
-                    }
-                    (Ok(users), Ok(groups)) => InternalSearchResults::UsersAndGroups(users, groups),
-                }
-            }
-            SearchScope::Users => InternalSearchResults::UsersAndGroups(
-                get_user_list(&request.filter).await?,
-                Vec::new(),
-            ),
-            SearchScope::Groups => InternalSearchResults::UsersAndGroups(
-                Vec::new(),
-                get_group_list(&request.filter).await?,
-            ),
-            SearchScope::User(filter) => {
-                let filter = LdapFilter::And(vec![request.filter.clone(), filter]);
-                InternalSearchResults::UsersAndGroups(get_user_list(&filter).await?, Vec::new())
-            }
-            SearchScope::Group(filter) => {
-                let filter = LdapFilter::And(vec![request.filter.clone(), filter]);
-                InternalSearchResults::UsersAndGroups(Vec::new(), get_group_list(&filter).await?)
-            }
+            SearchScope::Global => SearchTarget::Entries {
+                users: Some(request.filter.clone()),
+                groups: Some(request.filter.clone()),
+            },
+            SearchScope::Users => SearchTarget::Entries {
+                users: Some(request.filter.clone()),
+                groups: None,
+            },
+            SearchScope::Groups => SearchTarget::Entries {
+                users: None,
+                groups: Some(request.filter.clone()),
+            },
+            SearchScope::User(filter) => SearchTarget::Entries {
+                users: Some(LdapFilter::And(vec![request.filter.clone(), filter])),
+                groups: None,
+            },
+            SearchScope::Group(filter) => SearchTarget::Entries {
+                users: None,
+                groups: Some(LdapFilter::And(vec![request.filter.clone(), filter])),
+            },
             SearchScope::UserOuOnly | SearchScope::GroupOuOnly => {
-                InternalSearchResults::Raw(vec![LdapOp::SearchResultEntry(LdapSearchResultEntry {
-                    dn: request.base.clone(),
-                    attributes: vec![LdapPartialAttribute {
-                        atype: "objectClass".to_owned(),
-                        vals: vec![b"top".to_vec(), b"organizationalUnit".to_vec()],
-                    }],
-                })])
+                SearchTarget::Results(InternalSearchResults::Raw(vec![LdapOp::SearchResultEntry(
+                    LdapSearchResultEntry {
+                        dn: request.base.clone(),
+                        attributes: vec![LdapPartialAttribute {
+                            atype: "objectClass".to_owned(),
+                            vals: vec![b"top".to_vec(), b"organizationalUnit".to_vec()],
+                        }],
+                    },
+                )]))
             }
             SearchScope::Unknown => {
                 warn!(
                     r#"The requested search tree "{}" matches neither the user subtree "ou=people,{}" nor the group subtree "ou=groups,{}""#,
                     &request.base, &self.ldap_info.base_dn_str, &self.ldap_info.base_dn_str
                 );
-                InternalSearchResults::Empty
+                SearchTarget::Results(InternalSearchResults::Empty)
             }
             SearchScope::Invalid => {
                 // Search path is not in our tree, just return an empty success.
@@ -629,11 +790,185 @@ impl<Backend: BackendHandler + LoginHandler + OpaqueHandler> LdapHandler<Backend
                     "The specified search tree {:?} is not under the common subtree {:?}",
                     &dn_parts, &self.ldap_info.base_dn
                 );
-                InternalSearchResults::Empty
+                SearchTarget::Results(InternalSearchResults::Empty)
             }
         })
     }
 
+    async fn do_search_internal(
+        &self,
+        backend_handler: &impl UserAndGroupListerBackendHandler,
+        request: &LdapSearchRequest,
+        schema: &PublicSchema,
+    ) -> LdapResult<InternalSearchResults> {
+        let (user_filter, group_filter) = match self.get_search_target(request)? {
+            SearchTarget::Results(results) => return Ok(results),
+            SearchTarget::Entries { users, groups } => (users, groups),
+        };
+        let users = match &user_filter {
+            Some(filter) => Some(
+                get_user_list(
+                    &self.ldap_info,
+                    filter,
+                    requests_member_of(request),
+                    &request.base,
+                    backend_handler,
+                    schema,
+                )
+                .await,
+            ),
+            None => None,
+        };
+        let groups = match &group_filter {
+            Some(filter) => Some(
+                get_groups_list(
+                    &self.ldap_info,
+                    filter,
+                    &request.base,
+                    backend_handler,
+                    schema,
+                )
+                .await,
+            ),
+            None => None,
+        };
+        Ok(match (users, groups) {
+            // When searching for both, the filter may only make sense for one of them.
+            (Some(Ok(users)), Some(Err(e))) => {
+                warn!("Error while getting groups: {:#}", e);
+                InternalSearchResults::UsersAndGroups(users, Vec::new())
+            }
+            (Some(Err(e)), Some(Ok(groups))) => {
+                warn!("Error while getting users: {:#}", e);
+                InternalSearchResults::UsersAndGroups(Vec::new(), groups)
+            }
+            (Some(Err(user_error)), Some(Err(_))) => {
+                InternalSearchResults::Raw(vec![make_search_error(
+                    user_error.code,
+                    user_error.message,
+                )])
+            }
+            (users, groups) => InternalSearchResults::UsersAndGroups(
+                users.transpose()?.unwrap_or_default(),
+                groups.transpose()?.unwrap_or_default(),
+            ),
+        })
+    }
+
+    /// Like [`Self::do_search_internal`], for at most `size` entries from `position`. Also returns
+    /// the position of the next page, if there is one.
+    async fn do_paged_search_internal(
+        &self,
+        backend_handler: &impl UserAndGroupListerBackendHandler,
+        request: &LdapSearchRequest,
+        schema: &PublicSchema,
+        size: u64,
+        position: PagedSearchPosition,
+    ) -> LdapResult<(InternalSearchResults, Option<PagedSearchPosition>)> {
+        let (user_filter, group_filter) = match self.get_search_target(request)? {
+            SearchTarget::Results(results) => return Ok((results, None)),
+            SearchTarget::Entries { users, groups } => (users, groups),
+        };
+        let mut users = Vec::new();
+        let mut user_error = None;
+        let mut group_position = None;
+        match (position, &user_filter) {
+            (PagedSearchPosition::Users(after), Some(filter)) => {
+                match get_user_page(
+                    &self.ldap_info,
+                    filter,
+                    requests_member_of(request),
+                    backend_handler,
+                    schema,
+                    size,
+                    after,
+                )
+                .await
+                {
+                    Ok(page) if page.has_next_page => {
+                        let last = page.users.last().map(|u| u.user.user_id.clone());
+                        return Ok((
+                            InternalSearchResults::UsersAndGroups(page.users, Vec::new()),
+                            Some(PagedSearchPosition::Users(last)),
+                        ));
+                    }
+                    Ok(page) => users = page.users,
+                    Err(e) if group_filter.is_some() => {
+                        warn!("Error while getting users: {:#}", e);
+                        user_error = Some(e);
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+            (PagedSearchPosition::Users(_), None) => (),
+            (PagedSearchPosition::Groups(after), _) => group_position = after,
+        }
+        // The groups come after the users, in the same page if there is room left.
+        if let Some(filter) = &group_filter {
+            match get_groups_page(
+                &self.ldap_info,
+                filter,
+                backend_handler,
+                schema,
+                size - users.len() as u64,
+                group_position,
+            )
+            .await
+            {
+                Ok(page) => {
+                    let next_position = page.has_next_page.then(|| {
+                        PagedSearchPosition::Groups(
+                            page.groups.last().map(|g| g.id).or(group_position),
+                        )
+                    });
+                    return Ok((
+                        InternalSearchResults::UsersAndGroups(users, page.groups),
+                        next_position,
+                    ));
+                }
+                Err(e) => match user_error {
+                    Some(user_error) => return Err(user_error),
+                    None if user_filter.is_some() => {
+                        warn!("Error while getting groups: {:#}", e)
+                    }
+                    None => return Err(e),
+                },
+            }
+        }
+        Ok((
+            InternalSearchResults::UsersAndGroups(users, Vec::new()),
+            None,
+        ))
+    }
+
+    fn search_results_to_ldap_op(
+        &self,
+        search_results: InternalSearchResults,
+        request: &LdapSearchRequest,
+        user_filter: &Option<UserId>,
+        schema: &PublicSchema,
+    ) -> Vec<LdapOp> {
+        let mut results = match search_results {
+            InternalSearchResults::UsersAndGroups(users, groups) => {
+                convert_users_to_ldap_op(users, &request.attrs, &self.ldap_info, schema)
+                    .chain(convert_groups_to_ldap_op(
+                        groups,
+                        &request.attrs,
+                        &self.ldap_info,
+                        user_filter,
+                        schema,
+                    ))
+                    .collect()
+            }
+            InternalSearchResults::Raw(raw_results) => raw_results,
+            InternalSearchResults::Empty => Vec::new(),
+        };
+        if !matches!(results.last(), Some(LdapOp::SearchResultDone(_))) {
+            results.push(make_search_success());
+        }
+        results
+    }
+
     #[instrument(skip_all, level = "debug")]
     pub async fn do_search(&self, request: &LdapSearchRequest) -> LdapResult<Vec<LdapOp>> {
         let user_info = self.user_info.as_ref().ok_or_else(|| LdapError {
@@ -652,25 +987,95 @@ impl<Backend: BackendHandler + LoginHandler + OpaqueHandler> LdapHandler<Backend
         let search_results = self
             .do_search_internal(&backend_handler, request, &schema)
             .await?;
-        let mut results = match search_results {
-            InternalSearchResults::UsersAndGroups(users, groups) => {
-                convert_users_to_ldap_op(users, &request.attrs, &self.ldap_info, &schema)
-                    .chain(convert_groups_to_ldap_op(
-                        groups,
-                        &request.attrs,
-                        &self.ldap_info,
-                        &backend_handler.user_filter,
-                        &schema,
-                    ))
-                    .collect()
-            }
-            InternalSearchResults::Raw(raw_results) => raw_results,
-            InternalSearchResults::Empty => Vec::new(),
+        Ok(self.search_results_to_ldap_op(
+            search_results,
+            request,
+            &backend_handler.user_filter,
+            &schema,
+        ))
+    }
+
+    /// A search with the simple paged results control (RFC 2696): returns at most `size` entries
+    /// from the position in the cookie, or from the start if it's empty, and the cookie of the
+    /// next page, empty after the last one.
+    #[instrument(skip_all, level = "debug")]
+    pub async fn do_paged_search(
+        &self,
+        request: &LdapSearchRequest,
+        size: i64,
+        cookie: &[u8],
+    ) -> LdapResult<(Vec<LdapOp>, Vec<u8>)> {
+        let user_info = self.user_info.as_ref().ok_or_else(|| LdapError {
+            code: LdapResultCode::InsufficentAccessRights,
+            message: "No user currently bound".to_string(),
+        })?;
+        let search_digest = get_search_digest(request);
+        let position = if cookie.is_empty() {
+            PagedSearchPosition::Users(None)
+        } else {
+            self.open_paging_cookie(&search_digest, cookie)?
         };
-        if !matches!(results.last(), Some(LdapOp::SearchResultDone(_))) {
-            results.push(make_search_success());
+        // A size of 0 abandons the search.
+        if size <= 0 {
+            return Ok((vec![make_search_success()], Vec::new()));
         }
-        Ok(results)
+        let backend_handler = self
+            .backend_handler
+            .get_user_restricted_lister_handler(user_info);
+        let schema =
+            PublicSchema::from(backend_handler.get_schema().await.map_err(|e| LdapError {
+                code: LdapResultCode::OperationsError,
+                message: format!("Unable to get schema: {:#}", e),
+            })?);
+        let (search_results, next_position) = self
+            .do_paged_search_internal(&backend_handler, request, &schema, size as u64, position)
+            .await?;
+        let next_cookie = match next_position {
+            Some(position) => self.seal_paging_cookie(search_digest, position)?,
+            None => Vec::new(),
+        };
+        Ok((
+            self.search_results_to_ldap_op(
+                search_results,
+                request,
+                &backend_handler.user_filter,
+                &schema,
+            ),
+            next_cookie,
+        ))
+    }
+
+    fn open_paging_cookie(
+        &self,
+        search_digest: &[u8],
+        cookie: &[u8],
+    ) -> LdapResult<PagedSearchPosition> {
+        orion::aead::open(&self.paging_key, cookie)
+            .ok()
+            .and_then(|cookie| bincode::deserialize::<PagedSearchCookie>(&cookie).ok())
+            .filter(|cookie| cookie.search_digest == search_digest)
+            .map(|cookie| cookie.position)
+            .ok_or_else(|| LdapError {
+                code: LdapResultCode::UnwillingToPerform,
+                message: "Unknown paged results cookie".to_string(),
+            })
+    }
+
+    fn seal_paging_cookie(
+        &self,
+        search_digest: Vec<u8>,
+        position: PagedSearchPosition,
+    ) -> LdapResult<Vec<u8>> {
+        bincode::serialize(&PagedSearchCookie {
+            search_digest,
+            position,
+        })
+        .ok()
+        .and_then(|cookie| orion::aead::seal(&self.paging_key, &cookie).ok())
+        .ok_or_else(|| LdapError {
+            code: LdapResultCode::OperationsError,
+            message: "Could not create the paged results cookie".to_string(),
+        })
     }
 
     async fn do_create_user(&self, request: LdapAddRequest) -> LdapResult<Vec<LdapOp>> {
@@ -758,7 +1163,75 @@ impl<Backend: BackendHandler + LoginHandler + OpaqueHandler> LdapHandler<Backend
         Ok(vec![make_add_error(LdapResultCode::Success, String::new())])
     }
 
+    /// Compares the value with the password of the user. Only the user themselves and the admins
+    /// can do it: wrong passwords count as failed logins, the same as for the binds, but a match
+    /// doesn't log the user in.
+    async fn do_compare_password(&self, request: &LdapCompareRequest) -> LdapResult<Vec<LdapOp>> {
+        let user_id = match get_user_id_from_distinguished_name(
+            &request.dn.to_ascii_lowercase(),
+            &self.ldap_info.base_dn,
+            &self.ldap_info.base_dn_str,
+        ) {
+            Ok(user_id) => user_id,
+            // Only the users have a password.
+            Err(_) => {
+                return Ok(vec![make_compare_result(
+                    LdapResultCode::NoSuchAttribute,
+                    request,
+                )])
+            }
+        };
+        let credentials = self.user_info.as_ref().ok_or_else(|| LdapError {
+            code: LdapResultCode::InsufficentAccessRights,
+            message: "No user currently bound".to_string(),
+        })?;
+        // Reading the user isn't enough, that would make every readonly account a password
+        // oracle.
+        if !credentials.is_admin() && credentials.user != user_id {
+            return Err(LdapError {
+                code: LdapResultCode::InsufficentAccessRights,
+                message: format!(
+                    r#"User `{}` cannot compare the password of user `{}`"#,
+                    &credentials.user, &user_id
+                ),
+            });
+        }
+        let password = match std::str::from_utf8(&request.val) {
+            Ok(password) => password.to_string(),
+            Err(_) => {
+                return Ok(vec![make_compare_result(
+                    LdapResultCode::CompareFalse,
+                    request,
+                )])
+            }
+        };
+        let result = self
+            .get_login_handler()
+            .compare_password(BindRequest {
+                name: user_id,
+                password,
+                source: self.client_address,
+                context: None,
+            })
+            .await;
+        let code = match result {
+            Ok(true) => LdapResultCode::CompareTrue,
+            Err(DomainError::Maintenance(message)) => {
+                return Err(LdapError {
+                    code: LdapResultCode::Unavailable,
+                    message: message.unwrap_or_else(|| "Under maintenance".to_string()),
+                })
+            }
+            // Throttled or locked, like a bind.
+            Ok(false) | Err(_) => LdapResultCode::CompareFalse,
+        };
+        Ok(vec![make_compare_result(code, request)])
+    }
+
     pub async fn do_compare(&mut self, request: LdapCompareRequest) -> LdapResult<Vec<LdapOp>> {
+        if request.atype.eq_ignore_ascii_case("userpassword") {
+            return self.do_compare_password(&request).await;
+        }
         let req = make_search_request::<String>(
             &self.ldap_info.base_dn_str,
             LdapFilter::Equality("dn".to_string(), request.dn.to_string()),
@@ -775,20 +1248,21 @@ impl<Backend: BackendHandler + LoginHandler + OpaqueHandler> LdapHandler<Backend
 
         match entries.first() {
             Some(LdapOp::SearchResultEntry(entry)) => {
-                let available = entry
+                let values = entry
                     .attributes
                     .iter()
-                    .any(|attr| attr.atype == request.atype && attr.vals.contains(&request.val));
-                Ok(vec![LdapOp::CompareResult(LdapResultOp {
-                    code: if available {
-                        LdapResultCode::CompareTrue
-                    } else {
-                        LdapResultCode::CompareFalse
-                    },
-                    matcheddn: request.dn,
-                    message: "".to_string(),
-                    referral: vec![],
-                })])
+                    .filter(|attr| attr.atype == request.atype)
+                    .flat_map(|attr| attr.vals.iter())
+                    .collect::<Vec<_>>();
+                let code = if values.is_empty() {
+                    // Unknown attribute, or no value for this entry.
+                    LdapResultCode::NoSuchAttribute
+                } else if values.contains(&&request.val) {
+                    LdapResultCode::CompareTrue
+                } else {
+                    LdapResultCode::CompareFalse
+                };
+                Ok(vec![make_compare_result(code, &request)])
             }
             Some(LdapOp::SearchResultDone(_)) => Ok(vec![LdapOp::CompareResult(LdapResultOp {
                 code: LdapResultCode::NoSuchObject,
@@ -807,6 +1281,32 @@ impl<Backend: BackendHandler + LoginHandler + OpaqueHandler> LdapHandler<Backend
         }
     }
 
+    /// Like [`Self::handle_ldap_message`], with the controls of the request. Also returns the
+    /// controls to send with the last response.
+    pub async fn handle_ldap_message_with_controls(
+        &mut self,
+        ldap_op: LdapOp,
+        controls: &[LdapControl],
+    ) -> Option<(Vec<LdapOp>, Vec<LdapControl>)> {
+        if let LdapOp::SearchRequest(request) = &ldap_op {
+            if let Some((size, cookie)) =
+                get_paged_results_control(controls).filter(|_| !is_root_dse_request(request))
+            {
+                return Some(match self.do_paged_search(request, size, cookie).await {
+                    Ok((results, cookie)) => (
+                        results,
+                        // The total number of entries is unknown.
+                        vec![LdapControl::SimplePagedResults { size: 0, cookie }],
+                    ),
+                    Err(e) => (vec![make_search_error(e.code, e.message)], Vec::new()),
+                });
+            }
+        }
+        self.handle_ldap_message(ldap_op)
+            .await
+            .map(|results| (results, Vec::new()))
+    }
+
     pub async fn handle_ldap_message(&mut self, ldap_op: LdapOp) -> Option<Vec<LdapOp>> {
         Some(match ldap_op {
             LdapOp::BindRequest(request) => {
@@ -885,6 +1385,8 @@ mod tests {
             .with(eq(BindRequest {
                 name: UserId::new("test"),
                 password: "pass".to_string(),
+                source: None,
+                context: None,
             }))
             .return_once(|_| Ok(()));
         let group = group.to_string();
@@ -939,6 +1441,8 @@ mod tests {
             .with(eq(crate::domain::handler::BindRequest {
                 name: UserId::new("bob"),
                 password: "pass".to_string(),
+                source: None,
+                context: None,
             }))
             .times(1)
             .return_once(|_| Ok(()));
@@ -965,6 +1469,145 @@ mod tests {
         );
     }
 
+    fn make_start_tls_request() -> LdapOp {
+        LdapOp::ExtendedRequest(LdapExtendedRequest {
+            name: START_TLS_OID.to_string(),
+            value: None,
+        })
+    }
+
+    #[tokio::test]
+    async fn test_bind_requires_tls() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_bind().times(1).return_once(|_| Ok(()));
+        mock.expect_get_user_groups()
+            .with(eq(UserId::new("bob")))
+            .return_once(|_| Ok(HashSet::new()));
+        let mut ldap_handler = LdapHandler::new(
+            AccessControlledBackendHandler::new(mock),
+            "dc=example,dc=com".to_string(),
+            vec![],
+            vec![],
+            false,
+        )
+        .with_start_tls()
+        .with_tls_required_for_bind(true);
+        let request = LdapBindRequest {
+            dn: "uid=bob,ou=people,dc=example,dc=com".to_string(),
+            cred: LdapBindCred::Simple("pass".to_string()),
+        };
+        // Refused before the password is checked.
+        assert_eq!(
+            ldap_handler.do_bind(&request).await.0,
+            LdapResultCode::ConfidentialityRequired
+        );
+
+        assert_eq!(
+            ldap_handler
+                .handle_ldap_message(make_start_tls_request())
+                .await,
+            Some(vec![LdapOp::ExtendedResponse(LdapExtendedResponse {
+                res: LdapResultOp {
+                    code: LdapResultCode::Success,
+                    matcheddn: "".to_string(),
+                    message: "".to_string(),
+                    referral: vec![],
+                },
+                name: Some(START_TLS_OID.to_string()),
+                value: None,
+            })])
+        );
+        assert!(ldap_handler.take_start_tls_request());
+        assert!(!ldap_handler.take_start_tls_request());
+        ldap_handler.start_tls_established();
+        assert_eq!(
+            ldap_handler.do_bind(&request).await.0,
+            LdapResultCode::Success
+        );
+        // Only once per connection.
+        assert_eq!(
+            ldap_handler
+                .handle_ldap_message(make_start_tls_request())
+                .await,
+            Some(vec![make_extended_response(
+                LdapResultCode::OperationsError,
+                "The connection is already encrypted".to_string(),
+            )])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_start_tls_resets_the_session() {
+        let mut ldap_handler = setup_bound_admin_handler(MockTestBackendHandler::new()).await;
+        ldap_handler.transport_secure = false;
+        ldap_handler.start_tls_available = true;
+        assert!(ldap_handler.user_info.is_some());
+        ldap_handler
+            .handle_ldap_message(make_start_tls_request())
+            .await
+            .unwrap();
+        assert!(ldap_handler.take_start_tls_request());
+        // The client binds again over TLS.
+        assert!(ldap_handler.user_info.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_start_tls_not_configured() {
+        let mut ldap_handler = LdapHandler::new(
+            AccessControlledBackendHandler::new(MockTestBackendHandler::new()),
+            "dc=example,dc=com".to_string(),
+            vec![],
+            vec![],
+            false,
+        );
+        assert_eq!(
+            ldap_handler
+                .handle_ldap_message(make_start_tls_request())
+                .await,
+            Some(vec![make_extended_response(
+                LdapResultCode::Unavailable,
+                "StartTLS is not configured".to_string(),
+            )])
+        );
+        assert!(!ldap_handler.take_start_tls_request());
+    }
+
+    #[tokio::test]
+    async fn test_bind_rate_limited() {
+        use crate::infra::{configuration::RateLimitOptionsBuilder, rate_limiter::RateLimiter};
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_bind()
+            .times(2)
+            .returning(|_| Err(DomainError::AuthenticationError("wrong".to_string())));
+        let rate_limiter = RateLimiter::new(
+            &RateLimitOptionsBuilder::default()
+                .rate(0.5)
+                .burst(2)
+                .build()
+                .unwrap(),
+        );
+        let mut ldap_handler = LdapHandler::new_for_tests(mock, "dc=example,dc=com")
+            .with_client_address(Some("192.0.2.1".parse().unwrap()))
+            .with_rate_limiter(Arc::new(rate_limiter));
+        let request = LdapBindRequest {
+            dn: "uid=bob,ou=people,dc=example,dc=com".to_string(),
+            cred: LdapBindCred::Simple("wrong".to_string()),
+        };
+        for _ in 0..2 {
+            assert_eq!(
+                ldap_handler.do_bind(&request).await,
+                (LdapResultCode::InvalidCredentials, "".to_string())
+            );
+        }
+        assert_eq!(
+            ldap_handler.do_bind(&request).await,
+            (
+                LdapResultCode::InvalidCredentials,
+                "Too many attempts, retry in 2 seconds".to_string()
+            )
+        );
+    }
+
     #[tokio::test]
     async fn test_admin_bind() {
         let mut mock = MockTestBackendHandler::new();
@@ -972,6 +1615,8 @@ mod tests {
             .with(eq(crate::domain::handler::BindRequest {
                 name: UserId::new("test"),
                 password: "pass".to_string(),
+                source: None,
+                context: None,
             }))
             .times(1)
             .return_once(|_| Ok(()));
@@ -1037,6 +1682,106 @@ mod tests {
         );
     }
 
+    #[tokio::test]
+    async fn test_paged_search() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_list_users_page()
+            .with(
+                eq(Some(true.into())),
+                eq(false),
+                eq(10),
+                mockall::predicate::always(),
+            )
+            .times(3)
+            .returning(|_, _, first, after: Option<UserId>| {
+                let users = (0..30)
+                    .map(|i| UserId::new(&format!("user{:02}", i)))
+                    .filter(|user_id| after.as_ref().map_or(true, |after| user_id > after))
+                    .collect::<Vec<_>>();
+                Ok(UserPage {
+                    has_next_page: users.len() as u64 > first,
+                    users: users
+                        .into_iter()
+                        .take(first as usize)
+                        .map(|user_id| UserAndGroups {
+                            user: User {
+                                user_id,
+                                ..Default::default()
+                            },
+                            groups: None,
+                        })
+                        .collect(),
+                })
+            });
+        let mut ldap_handler = setup_bound_readonly_handler(mock).await;
+        let request = LdapOp::SearchRequest(make_user_search_request(
+            LdapFilter::And(vec![]),
+            vec!["uid"],
+        ));
+        let mut cookie = Vec::new();
+        let mut pages = Vec::new();
+        loop {
+            let (results, controls) = ldap_handler
+                .handle_ldap_message_with_controls(
+                    request.clone(),
+                    &[LdapControl::SimplePagedResults { size: 10, cookie }],
+                )
+                .await
+                .unwrap();
+            assert_eq!(results.last(), Some(&make_search_success()));
+            pages.push(
+                results
+                    .into_iter()
+                    .filter_map(|op| match op {
+                        LdapOp::SearchResultEntry(entry) => Some(entry.dn),
+                        _ => None,
+                    })
+                    .collect::<Vec<_>>(),
+            );
+            cookie = match controls.as_slice() {
+                [LdapControl::SimplePagedResults { size, cookie }] => {
+                    assert_eq!(*size, 0);
+                    cookie.clone()
+                }
+                c => panic!("Unexpected controls: {:?}", c),
+            };
+            if cookie.is_empty() {
+                break;
+            }
+        }
+        let expected = (0..30)
+            .map(|i| format!("uid=user{:02},ou=people,dc=example,dc=com", i))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            pages,
+            expected.chunks(10).map(<[_]>::to_vec).collect::<Vec<_>>()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_paged_search_unknown_cookie() {
+        let mut ldap_handler = setup_bound_readonly_handler(MockTestBackendHandler::new()).await;
+        let request = make_user_search_request(LdapFilter::And(vec![]), vec!["uid"]);
+        assert_eq!(
+            ldap_handler
+                .handle_ldap_message_with_controls(
+                    LdapOp::SearchRequest(request),
+                    &[LdapControl::SimplePagedResults {
+                        size: 10,
+                        cookie: b"forged".to_vec(),
+                    }],
+                )
+                .await,
+            Some((
+                vec![make_search_error(
+                    LdapResultCode::UnwillingToPerform,
+                    "Unknown paged results cookie".to_string()
+                )],
+                vec![]
+            ))
+        );
+    }
+
     #[tokio::test]
     async fn test_search_readonly_user() {
         let mut mock = MockTestBackendHandler::new();
@@ -1257,6 +2002,7 @@ mod tests {
                             .with_ymd_and_hms(2014, 7, 8, 9, 10, 11)
                             .unwrap()
                             .naive_utc(),
+                        is_enabled: true,
                     },
                     groups: None,
                 },
@@ -1928,6 +2674,56 @@ mod tests {
         );
     }
 
+    #[tokio::test]
+    async fn test_search_account_lock() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_list_users()
+            .with(
+                eq(Some(UserRequestFilter::And(vec![
+                    UserRequestFilter::Equality(UserColumn::IsEnabled, "false".to_string()),
+                ]))),
+                eq(false),
+            )
+            .times(1)
+            .return_once(|_, _| {
+                Ok(vec![UserAndGroups {
+                    user: User {
+                        user_id: UserId::new("bob_1"),
+                        is_enabled: false,
+                        ..Default::default()
+                    },
+                    groups: None,
+                }])
+            });
+        let mut ldap_handler = setup_bound_admin_handler(mock).await;
+        let request = make_user_search_request(
+            LdapFilter::And(vec![LdapFilter::Equality(
+                "nsAccountLock".to_string(),
+                "TRUE".to_string(),
+            )]),
+            vec!["nsAccountLock", "accountDisabled"],
+        );
+        assert_eq!(
+            ldap_handler.do_search_or_dse(&request).await,
+            Ok(vec![
+                LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                    dn: "uid=bob_1,ou=people,dc=example,dc=com".to_string(),
+                    attributes: vec![
+                        LdapPartialAttribute {
+                            atype: "nsAccountLock".to_string(),
+                            vals: vec![b"TRUE".to_vec()]
+                        },
+                        LdapPartialAttribute {
+                            atype: "accountDisabled".to_string(),
+                            vals: vec![b"TRUE".to_vec()]
+                        },
+                    ]
+                }),
+                make_search_success()
+            ])
+        );
+    }
+
     #[tokio::test]
     async fn test_search_both() {
         let mut mock = MockTestBackendHandler::new();
@@ -2236,6 +3032,7 @@ mod tests {
         let request = registration::ClientRegistrationStartRequest {
             username: "bob".into(),
             registration_start_request: registration_start_request.message,
+            context: None,
         };
         let start_response = opaque::server::registration::start_registration(
             &opaque::server::ServerSetup::new(&mut rng),
@@ -2243,15 +3040,17 @@ mod tests {
             &request.username,
         )
         .unwrap();
-        mock.expect_registration_start().times(1).return_once(|_| {
-            Ok(registration::ServerRegistrationStartResponse {
-                server_data: "".to_string(),
-                registration_response: start_response.message,
-            })
-        });
+        mock.expect_registration_start()
+            .times(1)
+            .return_once(|_, _| {
+                Ok(registration::ServerRegistrationStartResponse {
+                    server_data: "".to_string(),
+                    registration_response: start_response.message,
+                })
+            });
         mock.expect_registration_finish()
             .times(1)
-            .return_once(|_| Ok(()));
+            .return_once(|_, _, _| Ok(()));
         let mut ldap_handler = setup_bound_admin_handler(mock).await;
         let request = LdapOp::ExtendedRequest(
             LdapPasswordModifyRequest {
@@ -2284,6 +3083,7 @@ mod tests {
         let request = registration::ClientRegistrationStartRequest {
             username: "bob".into(),
             registration_start_request: registration_start_request.message,
+            context: None,
         };
         let start_response = opaque::server::registration::start_registration(
             &opaque::server::ServerSetup::new(&mut rng),
@@ -2291,15 +3091,17 @@ mod tests {
             &request.username,
         )
         .unwrap();
-        mock.expect_registration_start().times(1).return_once(|_| {
-            Ok(registration::ServerRegistrationStartResponse {
-                server_data: "".to_string(),
-                registration_response: start_response.message,
-            })
-        });
+        mock.expect_registration_start()
+            .times(1)
+            .return_once(|_, _| {
+                Ok(registration::ServerRegistrationStartResponse {
+                    server_data: "".to_string(),
+                    registration_response: start_response.message,
+                })
+            });
         mock.expect_registration_finish()
             .times(1)
-            .return_once(|_| Ok(()));
+            .return_once(|_, _, _| Ok(()));
         let mut ldap_handler = setup_bound_admin_handler(mock).await;
         let request = LdapOp::ModifyRequest(LdapModifyRequest {
             dn: "uid=bob,ou=people,dc=example,dc=com".to_string(),
@@ -2334,6 +3136,7 @@ mod tests {
         let request = registration::ClientRegistrationStartRequest {
             username: "bob".into(),
             registration_start_request: registration_start_request.message,
+            context: None,
         };
         let start_response = opaque::server::registration::start_registration(
             &opaque::server::ServerSetup::new(&mut rng),
@@ -2341,15 +3144,17 @@ mod tests {
             &request.username,
         )
         .unwrap();
-        mock.expect_registration_start().times(1).return_once(|_| {
-            Ok(registration::ServerRegistrationStartResponse {
-                server_data: "".to_string(),
-                registration_response: start_response.message,
-            })
-        });
+        mock.expect_registration_start()
+            .times(1)
+            .return_once(|_, _| {
+                Ok(registration::ServerRegistrationStartResponse {
+                    server_data: "".to_string(),
+                    registration_response: start_response.message,
+                })
+            });
         mock.expect_registration_finish()
             .times(1)
-            .return_once(|_| Ok(()));
+            .return_once(|_, _, _| Ok(()));
         let mut ldap_handler = setup_bound_password_manager_handler(mock).await;
         let request = LdapOp::ExtendedRequest(
             LdapPasswordModifyRequest {
@@ -2492,7 +3297,7 @@ mod tests {
         assert_eq!(
             ldap_handler.do_search_or_dse(&request).await,
             Ok(vec![
-                root_dse_response("dc=example,dc=com"),
+                root_dse_response("dc=example,dc=com", false),
                 make_search_success()
             ])
         );
@@ -2770,6 +3575,125 @@ mod tests {
         );
     }
 
+    #[tokio::test]
+    async fn test_compare_unknown_attribute() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_list_users().returning(|_, _| {
+            Ok(vec![UserAndGroups {
+                user: User {
+                    user_id: UserId::new("bob"),
+                    email: "bob@bobmail.bob".into(),
+                    ..Default::default()
+                },
+                groups: None,
+            }])
+        });
+        mock.expect_list_groups().returning(|_| Ok(vec![]));
+        let mut ldap_handler = setup_bound_admin_handler(mock).await;
+        let request = LdapCompareRequest {
+            dn: "uid=bob,ou=people,dc=example,dc=com".to_string(),
+            atype: "nonExistentAttribute".to_owned(),
+            val: b"bob".to_vec(),
+        };
+        assert_eq!(
+            ldap_handler.do_compare(request.clone()).await,
+            Ok(vec![make_compare_result(
+                LdapResultCode::NoSuchAttribute,
+                &request
+            )])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_compare_user_password() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_compare_password()
+            .withf(|request| request.name == UserId::new("bob"))
+            .times(2)
+            .returning(|request| Ok(request.password == "bob_password"));
+        let mut ldap_handler = setup_bound_admin_handler(mock).await;
+        let request = |password: &str| LdapCompareRequest {
+            dn: "uid=bob,ou=people,dc=example,dc=com".to_string(),
+            atype: "userPassword".to_owned(),
+            val: password.as_bytes().to_vec(),
+        };
+        assert_eq!(
+            ldap_handler.do_compare(request("bob_password")).await,
+            Ok(vec![make_compare_result(
+                LdapResultCode::CompareTrue,
+                &request("bob_password")
+            )])
+        );
+        assert_eq!(
+            ldap_handler.do_compare(request("wrong_password")).await,
+            Ok(vec![make_compare_result(
+                LdapResultCode::CompareFalse,
+                &request("wrong_password")
+            )])
+        );
+        // The groups don't have a password.
+        let group_request = LdapCompareRequest {
+            dn: "cn=group,ou=groups,dc=example,dc=com".to_string(),
+            atype: "userPassword".to_owned(),
+            val: b"bob_password".to_vec(),
+        };
+        assert_eq!(
+            ldap_handler.do_compare(group_request.clone()).await,
+            Ok(vec![make_compare_result(
+                LdapResultCode::NoSuchAttribute,
+                &group_request
+            )])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_compare_user_password_of_another_user() {
+        let request = LdapCompareRequest {
+            dn: "uid=bob,ou=people,dc=example,dc=com".to_string(),
+            atype: "userPassword".to_owned(),
+            val: b"bob_password".to_vec(),
+        };
+        for group in ["", "lldap_strict_readonly", "lldap_password_manager"] {
+            let mut ldap_handler =
+                setup_bound_handler_with_group(MockTestBackendHandler::new(), group).await;
+            assert_eq!(
+                ldap_handler
+                    .do_compare(request.clone())
+                    .await
+                    .unwrap_err()
+                    .code,
+                LdapResultCode::InsufficentAccessRights
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_compare_own_user_password() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_compare_password()
+            .with(eq(BindRequest {
+                name: UserId::new("test"),
+                password: "pass".to_string(),
+                source: None,
+                context: None,
+            }))
+            .times(1)
+            .return_once(|_| Ok(true));
+        let mut ldap_handler = setup_bound_handler_with_group(mock, "").await;
+        let request = LdapCompareRequest {
+            dn: "uid=test,ou=people,dc=example,dc=com".to_string(),
+            atype: "userPassword".to_owned(),
+            val: b"pass".to_vec(),
+        };
+        assert_eq!(
+            ldap_handler.do_compare(request.clone()).await,
+            Ok(vec![make_compare_result(
+                LdapResultCode::CompareTrue,
+                &request
+            )])
+        );
+    }
+
     #[tokio::test]
     async fn test_user_ou_search() {
         let mut ldap_handler = setup_bound_readonly_handler(MockTestBackendHandler::new()).await;
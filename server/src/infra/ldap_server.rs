@@ -1,5 +1,6 @@
 use crate::{
     domain::{
+        correlation::{correlation_id, new_correlation_id, with_correlation_id},
         handler::{BackendHandler, LoginHandler},
         opaque_handler::OpaqueHandler,
         types::AttributeName,
@@ -8,19 +9,21 @@ use crate::{
         access_control::AccessControlledBackendHandler,
         configuration::{Configuration, LdapsOptions},
         ldap_handler::LdapHandler,
+        rate_limiter::RateLimiter,
     },
 };
 use actix_rt::net::TcpStream;
 use actix_server::ServerBuilder;
 use actix_service::{fn_service, ServiceFactoryExt};
-use anyhow::{anyhow, Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use ldap3_proto::{control::LdapControl, proto::LdapMsg, proto::LdapOp, LdapCodec};
 use rustls::PrivateKey;
+use std::{net::IpAddr, sync::Arc};
 use tokio_rustls::TlsAcceptor as RustlsTlsAcceptor;
 use tokio_util::codec::{FramedRead, FramedWrite};
 use tracing::{debug, error, info, instrument};
 
-#[instrument(skip_all, level = "info", name = "LDAP request")]
+#[instrument(skip_all, level = "info", name = "LDAP request", fields(correlation_id = %correlation_id()))]
 async fn handle_ldap_message<Backend, Writer>(
     msg: Result<LdapMsg, std::io::Error>,
     resp: &mut Writer,
@@ -34,22 +37,27 @@ where
     use futures_util::SinkExt;
     let msg = msg.context("while receiving LDAP op")?;
     debug!(?msg);
-    match session.handle_ldap_message(msg.op).await {
+    match session
+        .handle_ldap_message_with_controls(msg.op, &msg.ctrl)
+        .await
+    {
         None => return Ok(false),
-        Some(result) => {
+        Some((result, response_controls)) => {
             if result.is_empty() {
                 debug!("No response");
             }
             let results: i64 = result.len().try_into().unwrap();
             for response in result.into_iter() {
                 debug!(?response);
-                let controls = if matches!(response, LdapOp::SearchResultDone(_)) {
+                let controls = if !matches!(response, LdapOp::SearchResultDone(_)) {
+                    vec![]
+                } else if !response_controls.is_empty() {
+                    response_controls.clone()
+                } else {
                     vec![LdapControl::SimplePagedResults {
                         size: results - 1, // Avoid counting SearchResultDone as a result
                         cookie: vec![],
                     }]
-                } else {
-                    vec![]
                 };
                 resp.send(LdapMsg {
                     msgid: msg.msgid,
@@ -68,14 +76,24 @@ where
     Ok(true)
 }
 
-#[instrument(skip_all, level = "info", name = "LDAP session")]
-async fn handle_ldap_stream<Stream, Backend>(
+/// How the connections of a port are served.
+#[derive(Clone)]
+struct SessionOptions {
+    /// Whether the connection is already encrypted: LDAPS.
+    secure: bool,
+    /// To upgrade a plain connection with StartTLS, when there is a certificate.
+    start_tls_acceptor: Option<RustlsTlsAcceptor>,
+    require_tls_for_bind: bool,
+    /// Limits the binds from the address of the client.
+    rate_limiter: Option<Arc<RateLimiter>>,
+}
+
+/// Answers the requests until the client leaves, or until StartTLS is accepted: then the stream
+/// is given back for the TLS handshake.
+async fn serve_ldap_requests<Stream, Backend>(
     stream: Stream,
-    backend_handler: Backend,
-    ldap_base_dn: String,
-    ignored_user_attributes: Vec<AttributeName>,
-    ignored_group_attributes: Vec<AttributeName>,
-) -> Result<Stream>
+    session: &mut LdapHandler<Backend>,
+) -> Result<Option<Stream>>
 where
     Backend: BackendHandler + LoginHandler + OpaqueHandler + 'static,
     Stream: tokio::io::AsyncRead + tokio::io::AsyncWrite + std::marker::Unpin,
@@ -86,22 +104,70 @@ where
     let mut requests = FramedRead::new(r, LdapCodec::default());
     let mut resp = FramedWrite::new(w, LdapCodec::default());
 
+    while let Some(msg) = requests.next().await {
+        // Create the request span inside the scope, so that it gets the correlation ID too.
+        if !with_correlation_id(new_correlation_id(), async {
+            handle_ldap_message(msg, &mut resp, session).await
+        })
+        .await
+        .context("while handling incoming messages")?
+        {
+            break;
+        }
+        if session.take_start_tls_request() {
+            // The client has to wait for the response before the handshake.
+            if !requests.read_buffer().is_empty() {
+                bail!("Received more requests after StartTLS");
+            }
+            return Ok(Some(requests.into_inner().unsplit(resp.into_inner())));
+        }
+    }
+    Ok(None)
+}
+
+#[instrument(skip_all, level = "info", name = "LDAP session")]
+async fn handle_ldap_stream<Stream, Backend>(
+    stream: Stream,
+    backend_handler: Backend,
+    ldap_base_dn: String,
+    ignored_user_attributes: Vec<AttributeName>,
+    ignored_group_attributes: Vec<AttributeName>,
+    options: SessionOptions,
+    client_address: Option<IpAddr>,
+) -> Result<()>
+where
+    Backend: BackendHandler + LoginHandler + OpaqueHandler + 'static,
+    Stream: tokio::io::AsyncRead + tokio::io::AsyncWrite + std::marker::Unpin,
+{
     let mut session = LdapHandler::new(
         AccessControlledBackendHandler::new(backend_handler),
         ldap_base_dn,
         ignored_user_attributes,
         ignored_group_attributes,
-    );
+        options.secure,
+    )
+    .with_tls_required_for_bind(options.require_tls_for_bind)
+    .with_client_address(client_address);
+    let start_tls_acceptor = options.start_tls_acceptor.filter(|_| !options.secure);
+    if start_tls_acceptor.is_some() {
+        session = session.with_start_tls();
+    }
+    if let Some(rate_limiter) = options.rate_limiter {
+        session = session.with_rate_limiter(rate_limiter);
+    }
 
-    while let Some(msg) = requests.next().await {
-        if !handle_ldap_message(msg, &mut resp, &mut session)
+    if let Some(stream) = serve_ldap_requests(stream, &mut session).await? {
+        let tls_acceptor =
+            start_tls_acceptor.expect("StartTLS is only accepted with a TLS acceptor");
+        let tls_stream = tls_acceptor
+            .accept(stream)
             .await
-            .context("while handling incoming messages")?
-        {
-            break;
-        }
+            .context("while negotiating StartTLS")?;
+        debug!("Upgraded the connection with StartTLS");
+        session.start_tls_established();
+        serve_ldap_requests(tls_stream, &mut session).await?;
     }
-    Ok(requests.into_inner().unsplit(resp.into_inner()))
+    Ok(())
 }
 
 fn read_private_key(key_file: &str) -> Result<PrivateKey> {
@@ -163,16 +229,32 @@ fn get_tls_acceptor(ldaps_options: &LdapsOptions) -> Result<RustlsTlsAcceptor> {
 pub fn build_ldap_server<Backend>(
     config: &Configuration,
     backend_handler: Backend,
+    rate_limiter: Option<Arc<RateLimiter>>,
     server_builder: ServerBuilder,
 ) -> Result<ServerBuilder>
 where
     Backend: BackendHandler + LoginHandler + OpaqueHandler + Clone + 'static,
 {
+    let tls_acceptor = if config.ldaps_options.enabled {
+        Some(
+            get_tls_acceptor(&config.ldaps_options)
+                .context("while setting up the SSL certificate")?,
+        )
+    } else {
+        None
+    };
     let context = (
         backend_handler,
         config.ldap_base_dn.clone(),
         config.ignored_user_attributes.clone(),
         config.ignored_group_attributes.clone(),
+        // The plain port offers StartTLS with the LDAPS certificate.
+        SessionOptions {
+            secure: false,
+            start_tls_acceptor: tls_acceptor.clone(),
+            require_tls_for_bind: config.require_tls_for_bind,
+            rate_limiter,
+        },
     );
 
     let context_for_tls = context.clone();
@@ -182,13 +264,17 @@ where
         fn_service(move |stream: TcpStream| {
             let context = context.clone();
             async move {
-                let (handler, base_dn, ignored_user_attributes, ignored_group_attributes) = context;
+                let (handler, base_dn, ignored_user_attributes, ignored_group_attributes, options) =
+                    context;
+                let client_address = stream.peer_addr().ok().map(|address| address.ip());
                 handle_ldap_stream(
                     stream,
                     handler,
                     base_dn,
                     ignored_user_attributes,
                     ignored_group_attributes,
+                    options,
+                    client_address,
                 )
                 .await
             }
@@ -200,21 +286,24 @@ where
     let server_builder = server_builder
         .bind("ldap", (config.ldap_host.clone(), config.ldap_port), binder)
         .with_context(|| format!("while binding to the port {}", config.ldap_port));
-    if config.ldaps_options.enabled {
-        let tls_context = (
-            context_for_tls,
-            get_tls_acceptor(&config.ldaps_options)
-                .context("while setting up the SSL certificate")?,
-        );
+    if let Some(tls_acceptor) = tls_acceptor {
+        let tls_context = (context_for_tls, tls_acceptor);
         let tls_binder = move || {
             let tls_context = tls_context.clone();
             fn_service(move |stream: TcpStream| {
                 let tls_context = tls_context.clone();
                 async move {
                     let (
-                        (handler, base_dn, ignored_user_attributes, ignored_group_attributes),
+                        (
+                            handler,
+                            base_dn,
+                            ignored_user_attributes,
+                            ignored_group_attributes,
+                            options,
+                        ),
                         tls_acceptor,
                     ) = tls_context;
+                    let client_address = stream.peer_addr().ok().map(|address| address.ip());
                     let tls_stream = tls_acceptor.accept(stream).await?;
                     handle_ldap_stream(
                         tls_stream,
@@ -222,6 +311,12 @@ where
                         base_dn,
                         ignored_user_attributes,
                         ignored_group_attributes,
+                        SessionOptions {
+                            secure: true,
+                            start_tls_acceptor: None,
+                            ..options
+                        },
+                        client_address,
                     )
                     .await
                 }
@@ -245,3 +340,160 @@ where
         server_builder
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        domain::{handler::BindRequest, types::UserId},
+        infra::{
+            configuration::LdapsOptionsBuilder, ldap_handler::START_TLS_OID,
+            test_utils::MockTestBackendHandler,
+        },
+    };
+    use futures_util::{SinkExt, StreamExt};
+    use ldap3_proto::proto::{LdapBindCred, LdapBindRequest, LdapExtendedRequest, LdapResultCode};
+    use mockall::predicate::eq;
+    use std::{collections::HashSet, sync::Arc};
+    use tokio_util::codec::Framed;
+
+    // A certificate for "localhost", signed by the test CA in ca.pem.
+    fn test_certificate_file(name: &str) -> String {
+        format!("{}/tests/certs/{}", env!("CARGO_MANIFEST_DIR"), name)
+    }
+
+    fn get_tls_connector() -> tokio_rustls::TlsConnector {
+        use std::{fs::File, io::BufReader};
+        let mut roots = rustls::RootCertStore::empty();
+        for certificate in rustls_pemfile::certs(&mut BufReader::new(
+            File::open(test_certificate_file("ca.pem")).unwrap(),
+        ))
+        .unwrap()
+        {
+            roots.add(&rustls::Certificate(certificate)).unwrap();
+        }
+        Arc::new(
+            rustls::ClientConfig::builder()
+                .with_safe_defaults()
+                .with_root_certificates(roots)
+                .with_no_client_auth(),
+        )
+        .into()
+    }
+
+    async fn send_request<Stream>(
+        connection: &mut Framed<Stream, LdapCodec>,
+        msgid: i32,
+        op: LdapOp,
+    ) -> LdapOp
+    where
+        Stream: tokio::io::AsyncRead + tokio::io::AsyncWrite + std::marker::Unpin,
+    {
+        connection
+            .send(LdapMsg {
+                msgid,
+                op,
+                ctrl: vec![],
+            })
+            .await
+            .unwrap();
+        let response = connection.next().await.unwrap().unwrap();
+        assert_eq!(response.msgid, msgid);
+        response.op
+    }
+
+    fn bind_request() -> LdapOp {
+        LdapOp::BindRequest(LdapBindRequest {
+            dn: "uid=bob,ou=people,dc=example,dc=com".to_string(),
+            cred: LdapBindCred::Simple("pass".to_string()),
+        })
+    }
+
+    fn result_code(op: LdapOp) -> LdapResultCode {
+        match op {
+            LdapOp::BindResponse(response) => response.res.code,
+            LdapOp::ExtendedResponse(response) => response.res.code,
+            op => panic!("Unexpected response: {:?}", op),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_start_tls_then_bind() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_bind()
+            .with(eq(BindRequest {
+                name: UserId::new("bob"),
+                password: "pass".to_string(),
+                source: None,
+                context: None,
+            }))
+            .times(1)
+            .return_once(|_| Ok(()));
+        mock.expect_get_user_groups()
+            .with(eq(UserId::new("bob")))
+            .return_once(|_| Ok(HashSet::new()));
+        let tls_acceptor = get_tls_acceptor(
+            &LdapsOptionsBuilder::default()
+                .enabled(true)
+                .cert_file(test_certificate_file("cert.pem"))
+                .key_file(test_certificate_file("key.pem"))
+                .build()
+                .unwrap(),
+        )
+        .unwrap();
+        let (client_stream, server_stream) = tokio::io::duplex(64 * 1024);
+        let server = handle_ldap_stream(
+            server_stream,
+            mock,
+            "dc=example,dc=com".to_string(),
+            vec![],
+            vec![],
+            SessionOptions {
+                secure: false,
+                start_tls_acceptor: Some(tls_acceptor),
+                require_tls_for_bind: true,
+                rate_limiter: None,
+            },
+            None,
+        );
+        let client = async move {
+            let mut connection = Framed::new(client_stream, LdapCodec::default());
+            assert_eq!(
+                result_code(send_request(&mut connection, 1, bind_request()).await),
+                LdapResultCode::ConfidentialityRequired
+            );
+            let start_tls = send_request(
+                &mut connection,
+                2,
+                LdapOp::ExtendedRequest(LdapExtendedRequest {
+                    name: START_TLS_OID.to_string(),
+                    value: None,
+                }),
+            )
+            .await;
+            assert_eq!(result_code(start_tls), LdapResultCode::Success);
+            let tls_stream = get_tls_connector()
+                .connect(
+                    rustls::ServerName::try_from("localhost").unwrap(),
+                    connection.into_inner(),
+                )
+                .await
+                .unwrap();
+            let mut connection = Framed::new(tls_stream, LdapCodec::default());
+            assert_eq!(
+                result_code(send_request(&mut connection, 3, bind_request()).await),
+                LdapResultCode::Success
+            );
+            connection
+                .send(LdapMsg {
+                    msgid: 4,
+                    op: LdapOp::UnbindRequest,
+                    ctrl: vec![],
+                })
+                .await
+                .unwrap();
+        };
+        let (server_result, ()) = tokio::join!(server, client);
+        server_result.unwrap();
+    }
+}
@@ -1,11 +1,16 @@
-use crate::infra::configuration::Configuration;
+use crate::{
+    domain::correlation::correlation_id,
+    infra::configuration::{Configuration, OpenTelemetryOptions},
+};
 use actix_web::{
     dev::{ServiceRequest, ServiceResponse},
     Error,
 };
 use tracing::{debug, error, Span};
 use tracing_actix_web::RootSpanBuilder;
-use tracing_subscriber::{filter::EnvFilter, layer::SubscriberExt, util::SubscriberInitExt};
+use tracing_subscriber::{
+    filter::EnvFilter, layer::SubscriberExt, util::SubscriberInitExt, Layer, Registry,
+};
 
 /// We will define a custom root span builder to capture additional fields, specific
 /// to our application, on top of the ones provided by `DefaultRootSpanBuilder` out of the box.
@@ -16,7 +21,8 @@ impl RootSpanBuilder for CustomRootSpanBuilder {
         tracing::debug_span!(
             "HTTP request",
             method = request.method().to_string(),
-            uri = request.uri().to_string()
+            uri = request.uri().to_string(),
+            correlation_id = %correlation_id(),
         )
     }
 
@@ -43,12 +49,69 @@ pub fn init(config: &Configuration) -> anyhow::Result<()> {
         })
     });
     tracing_subscriber::registry()
+        .with(opentelemetry_layer(&config.opentelemetry_options)?)
         .with(env_filter)
         .with(tracing_forest::ForestLayer::default())
         .init();
     Ok(())
 }
 
+type BoxedLayer = Box<dyn Layer<Registry> + Send + Sync>;
+
+#[cfg(feature = "opentelemetry")]
+fn opentelemetry_layer(options: &OpenTelemetryOptions) -> anyhow::Result<Option<BoxedLayer>> {
+    use anyhow::Context;
+    use opentelemetry_otlp::WithExportConfig;
+    use opentelemetry_sdk::{
+        trace::{config, Sampler},
+        Resource,
+    };
+    let endpoint = match &options.endpoint {
+        Some(endpoint) => endpoint,
+        None => return Ok(None),
+    };
+    let tracer = opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(
+            opentelemetry_otlp::new_exporter()
+                .tonic()
+                .with_endpoint(endpoint),
+        )
+        .with_trace_config(
+            config()
+                .with_sampler(Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(
+                    options.sampling_ratio,
+                ))))
+                .with_resource(Resource::new([opentelemetry::KeyValue::new(
+                    "service.name",
+                    "lldap",
+                )])),
+        )
+        // The server runs on a single-threaded runtime: export from a separate thread.
+        .install_batch(opentelemetry_sdk::runtime::TokioCurrentThread)
+        .context("while setting up the OpenTelemetry exporter")?;
+    Ok(Some(Box::new(
+        tracing_opentelemetry::layer().with_tracer(tracer),
+    )))
+}
+
+#[cfg(not(feature = "opentelemetry"))]
+fn opentelemetry_layer(options: &OpenTelemetryOptions) -> anyhow::Result<Option<BoxedLayer>> {
+    if options.endpoint.is_some() {
+        anyhow::bail!(
+            "An OpenTelemetry endpoint was configured, but LLDAP was built without the \
+             \"opentelemetry\" feature"
+        );
+    }
+    Ok(None)
+}
+
+/// Exports the spans that are still buffered, before exiting.
+pub fn shutdown() {
+    #[cfg(feature = "opentelemetry")]
+    opentelemetry::global::shutdown_tracer_provider();
+}
+
 #[cfg(test)]
 pub fn init_for_tests() {
     if let Err(e) = tracing_subscriber::FmtSubscriber::builder()
@@ -59,3 +122,64 @@ pub fn init_for_tests() {
         log::warn!("Could not set up test logging: {:#}", e);
     }
 }
+
+#[cfg(all(test, feature = "opentelemetry"))]
+mod tests {
+    use super::*;
+    use crate::domain::{
+        handler::{BindRequest, LoginHandler},
+        sql_backend_handler::{tests::*, SqlBackendHandler},
+        types::UserId,
+    };
+    use futures::future::BoxFuture;
+    use opentelemetry::trace::TracerProvider as _;
+    use opentelemetry_sdk::{
+        export::trace::{ExportResult, SpanData, SpanExporter},
+        trace::TracerProvider,
+    };
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Clone, Debug, Default)]
+    struct RecordingExporter {
+        spans: Arc<Mutex<Vec<SpanData>>>,
+    }
+
+    impl SpanExporter for RecordingExporter {
+        fn export(&mut self, batch: Vec<SpanData>) -> BoxFuture<'static, ExportResult> {
+            self.spans.lock().unwrap().extend(batch);
+            Box::pin(std::future::ready(Ok(())))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_bind_span_is_exported() {
+        let exporter = RecordingExporter::default();
+        let provider = TracerProvider::builder()
+            .with_simple_exporter(exporter.clone())
+            .build();
+        let _guard = tracing::subscriber::set_default(
+            tracing_subscriber::registry()
+                .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("lldap"))),
+        );
+        let handler = SqlBackendHandler::new(get_default_config(), get_initialized_db().await);
+        insert_user(&handler, "bob", "bob00").await;
+        handler
+            .bind(BindRequest {
+                name: UserId::new("bob"),
+                password: "bob00".to_owned(),
+                source: None,
+                context: None,
+            })
+            .await
+            .unwrap();
+        provider.force_flush();
+        let names = exporter
+            .spans
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|s| s.name.to_string())
+            .collect::<Vec<_>>();
+        assert!(names.iter().any(|n| n == "bind"), "{:?}", names);
+    }
+}
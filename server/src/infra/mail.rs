@@ -1,114 +1,343 @@
-use crate::infra::{cli::SmtpEncryption, configuration::MailOptions};
+use crate::{
+    domain::{events::WelcomeNotifier, types::UserId},
+    infra::{cli::SmtpEncryption, configuration::MailOptions},
+};
 use anyhow::{anyhow, Ok, Result};
+use async_trait::async_trait;
 use lettre::{
     message::Mailbox, transport::smtp::authentication::Credentials, AsyncSmtpTransport,
     AsyncTransport, Message, Tokio1Executor,
 };
-use tracing::debug;
-
-async fn send_email(
-    to: Mailbox,
-    subject: &str,
-    body: String,
-    options: &MailOptions,
-    server_url: &url::Url,
-) -> Result<()> {
-    let from = options
-        .from
-        .clone()
-        .unwrap_or_else(|| "LLDAP <nobody@lldap>".parse().unwrap());
-    let reply_to = options.reply_to.clone().unwrap_or_else(|| from.clone());
-    debug!(
-        "Sending email to '{}' as '{}' via '{}'@'{}':'{}'",
-        &to, &from, &options.user, &options.server, options.port
-    );
-    let email = Message::builder()
-        .message_id(Some(format!(
-            "<{}@{}>",
-            uuid::Uuid::new_v1(
-                uuid::Timestamp::now(uuid::NoContext),
-                "lldap!".as_bytes().try_into().unwrap()
-            ),
-            server_url.domain().unwrap_or_default()
-        )))
-        .from(from)
-        .reply_to(reply_to)
-        .to(to)
-        .subject(subject)
-        .singlepart(
-            lettre::message::SinglePart::builder()
-                .header(lettre::message::header::ContentType::TEXT_PLAIN)
-                .body(body),
-        )?;
-    let mut mailer = match options.smtp_encryption {
-        SmtpEncryption::None => {
-            AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&options.server)
-        }
-        SmtpEncryption::Tls => AsyncSmtpTransport::<Tokio1Executor>::relay(&options.server)?,
-        SmtpEncryption::StartTls => {
-            AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&options.server)?
+use std::sync::Arc;
+use tracing::{debug, info, warn};
+
+const DEFAULT_PASSWORD_RESET_TEMPLATE: &str = "Hello {username},
+This email has been sent to you in order to validate your identity.
+If you did not initiate the process your credentials might have been
+compromised. You should reset your password and contact an administrator.
+
+To reset your password please visit the following URL: {link}
+
+Please contact an administrator if you did not initiate the process.";
+
+const DEFAULT_WELCOME_TEMPLATE: &str = "Hello {username},
+An account was created for you.
+
+You can log in at the following URL: {link}";
+
+/// A plain text email, before it's formatted for the transport.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Email {
+    pub to: Mailbox,
+    pub subject: String,
+    pub body: String,
+}
+
+/// Delivers the emails.
+#[async_trait]
+pub trait EmailSender: Send + Sync {
+    async fn send(&self, email: Email) -> Result<()>;
+}
+
+/// Sends the emails through the SMTP server of the configuration.
+pub struct SmtpEmailSender {
+    options: MailOptions,
+    server_url: url::Url,
+}
+
+impl SmtpEmailSender {
+    pub fn new(options: MailOptions, server_url: url::Url) -> Self {
+        Self {
+            options,
+            server_url,
         }
-    };
-    if options.user.as_str() != "" {
-        let creds = Credentials::new(
-            options.user.clone(),
-            options.password.unsecure().to_string(),
-        );
-        mailer = mailer.credentials(creds)
     }
+}
 
-    if let Err(e) = mailer.port(options.port).build().send(email).await {
-        if e.to_string().contains("CorruptMessage") {
-            Err(anyhow!("CorruptMessage returned by lettre, this usually means the SMTP encryption setting is wrong.").context(e))
+#[async_trait]
+impl EmailSender for SmtpEmailSender {
+    async fn send(&self, email: Email) -> Result<()> {
+        let options = &self.options;
+        let from = options
+            .from
+            .clone()
+            .unwrap_or_else(|| "LLDAP <nobody@lldap>".parse().unwrap());
+        let reply_to = options.reply_to.clone().unwrap_or_else(|| from.clone());
+        debug!(
+            "Sending email to '{}' as '{}' via '{}'@'{}':'{}'",
+            &email.to, &from, &options.user, &options.server, options.port
+        );
+        let message = Message::builder()
+            .message_id(Some(format!(
+                "<{}@{}>",
+                uuid::Uuid::new_v1(
+                    uuid::Timestamp::now(uuid::NoContext),
+                    "lldap!".as_bytes().try_into().unwrap()
+                ),
+                self.server_url.domain().unwrap_or_default()
+            )))
+            .from(from)
+            .reply_to(reply_to)
+            .to(email.to)
+            .subject(email.subject)
+            .singlepart(
+                lettre::message::SinglePart::builder()
+                    .header(lettre::message::header::ContentType::TEXT_PLAIN)
+                    .body(email.body),
+            )?;
+        let mut mailer = match options.smtp_encryption {
+            SmtpEncryption::None => {
+                AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&options.server)
+            }
+            SmtpEncryption::Tls => AsyncSmtpTransport::<Tokio1Executor>::relay(&options.server)?,
+            SmtpEncryption::StartTls => {
+                AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&options.server)?
+            }
+        };
+        if options.user.as_str() != "" {
+            let creds = Credentials::new(
+                options.user.clone(),
+                options.password.unsecure().to_string(),
+            );
+            mailer = mailer.credentials(creds)
+        }
+
+        if let Err(e) = mailer.port(options.port).build().send(message).await {
+            if e.to_string().contains("CorruptMessage") {
+                Err(anyhow!("CorruptMessage returned by lettre, this usually means the SMTP encryption setting is wrong.").context(e))
+            } else {
+                Err(e.into())
+            }
         } else {
-            Err(e.into())
+            Ok(())
         }
-    } else {
-        Ok(())
     }
 }
 
-pub async fn send_password_reset_email(
-    username: &str,
-    to: &str,
-    token: &str,
-    server_url: &url::Url,
-    options: &MailOptions,
-) -> Result<()> {
-    let to = to.parse()?;
-    let mut reset_url = server_url.clone();
-    reset_url
-        .path_segments_mut()
-        .unwrap()
-        .extend(["reset-password", "step2", token]);
-    let body = format!(
-        "Hello {},
-This email has been sent to you in order to validate your identity.
-If you did not initiate the process your credentials might have been
-compromised. You should reset your password and contact an administrator.
+/// Fills the `{username}` and `{link}` placeholders of a template.
+fn render_template(template: &str, username: &str, link: &url::Url) -> String {
+    template
+        .replace("{username}", username)
+        .replace("{link}", link.as_str())
+}
 
-To reset your password please visit the following URL: {}
+/// Writes the transactional emails from the templates of the configuration.
+#[derive(Clone)]
+pub struct Mailer {
+    sender: Arc<dyn EmailSender>,
+    options: MailOptions,
+    server_url: url::Url,
+}
 
-Please contact an administrator if you did not initiate the process.",
-        username, reset_url
-    );
-    send_email(
-        to,
-        "[LLDAP] Password reset requested",
-        body,
-        options,
-        server_url,
-    )
-    .await
+impl Mailer {
+    pub fn new(options: MailOptions, server_url: url::Url) -> Self {
+        Self {
+            sender: Arc::new(SmtpEmailSender::new(options.clone(), server_url.clone())),
+            options,
+            server_url,
+        }
+    }
+
+    pub fn with_sender(self, sender: Arc<dyn EmailSender>) -> Self {
+        Self { sender, ..self }
+    }
+
+    fn url_with_path(&self, segments: &[&str]) -> url::Url {
+        let mut url = self.server_url.clone();
+        url.path_segments_mut()
+            .unwrap()
+            .pop_if_empty()
+            .extend(segments);
+        url
+    }
+
+    /// Sends the email in the background: the errors are logged, and never fail the operation
+    /// that triggered it.
+    fn send_in_background(&self, to: &str, subject: &str, body: String) {
+        let to = match to.parse() {
+            std::result::Result::Ok(to) => to,
+            Err(e) => {
+                warn!("Invalid email address '{}': {}", to, e);
+                return;
+            }
+        };
+        let email = Email {
+            to,
+            subject: subject.to_owned(),
+            body,
+        };
+        let sender = self.sender.clone();
+        tokio::spawn(async move {
+            let to = email.to.clone();
+            if let Err(e) = sender.send(email).await {
+                warn!("Error sending email to '{}': {:#}", to, e);
+            }
+        });
+    }
+
+    pub fn send_password_reset_email(&self, username: &str, to: &str, token: &str) {
+        let link = self.url_with_path(&["reset-password", "step2", token]);
+        let body = render_template(
+            self.options
+                .password_reset_template
+                .as_deref()
+                .unwrap_or(DEFAULT_PASSWORD_RESET_TEMPLATE),
+            username,
+            &link,
+        );
+        self.send_in_background(to, "[LLDAP] Password reset requested", body);
+    }
+
+    pub fn send_welcome_email(&self, username: &str, to: &str) {
+        let body = render_template(
+            self.options
+                .welcome_template
+                .as_deref()
+                .unwrap_or(DEFAULT_WELCOME_TEMPLATE),
+            username,
+            &self.server_url,
+        );
+        self.send_in_background(to, "[LLDAP] Welcome", body);
+    }
+
+    pub fn send_login_link_email(&self, username: &str, to: &str, token: &str) {
+        let login_url = self.url_with_path(&["auth", "login_link", "step2", token]);
+        let body = format!(
+            "Hello {},
+A link to log in without your password was requested for your account.
+
+To log in please visit the following URL: {}
+The link works only once, and expires shortly.
+
+If you did not request it, you can ignore this email.",
+            username, login_url
+        );
+        self.send_in_background(to, "[LLDAP] Login link", body);
+    }
+}
+
+impl WelcomeNotifier for Mailer {
+    fn welcome(&self, user_id: &UserId, email: &str, display_name: Option<&str>) {
+        info!(r#"Sending a welcome email to "{}""#, user_id);
+        self.send_welcome_email(display_name.unwrap_or_else(|| user_id.as_str()), email);
+    }
 }
 
 pub async fn send_test_email(to: Mailbox, options: &MailOptions) -> Result<()> {
-    send_email(
-        to,
-        "LLDAP test email",
-        "The test is successful! You can send emails from LLDAP".to_string(),
-        options,
-        &url::Url::parse("http://localhost").unwrap(),
+    SmtpEmailSender::new(
+        options.clone(),
+        url::Url::parse("http://localhost").unwrap(),
     )
+    .send(Email {
+        to,
+        subject: "LLDAP test email".to_owned(),
+        body: "The test is successful! You can send emails from LLDAP".to_string(),
+    })
     .await
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::infra::configuration::MailOptionsBuilder;
+    use pretty_assertions::assert_eq;
+    use tokio::sync::mpsc;
+
+    struct ChannelEmailSender(mpsc::UnboundedSender<Email>);
+
+    #[async_trait]
+    impl EmailSender for ChannelEmailSender {
+        async fn send(&self, email: Email) -> Result<()> {
+            self.0.send(email)?;
+            Ok(())
+        }
+    }
+
+    struct FailingEmailSender;
+
+    #[async_trait]
+    impl EmailSender for FailingEmailSender {
+        async fn send(&self, _: Email) -> Result<()> {
+            Err(anyhow!("Connection refused"))
+        }
+    }
+
+    fn get_mailer(options: MailOptions) -> (Mailer, mpsc::UnboundedReceiver<Email>) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let mailer = Mailer::new(
+            options,
+            url::Url::parse("https://ldap.example.com").unwrap(),
+        )
+        .with_sender(Arc::new(ChannelEmailSender(tx)));
+        (mailer, rx)
+    }
+
+    #[tokio::test]
+    async fn test_password_reset_email_contains_the_link() {
+        let (mailer, mut emails) = get_mailer(MailOptions::default());
+        mailer.send_password_reset_email("Bob", "bob@example.com", "abc123");
+        let email = emails.recv().await.unwrap();
+        assert_eq!(email.to, "bob@example.com".parse().unwrap());
+        assert_eq!(email.subject, "[LLDAP] Password reset requested");
+        assert!(email.body.starts_with("Hello Bob,"));
+        assert!(email
+            .body
+            .contains("https://ldap.example.com/reset-password/step2/abc123"));
+    }
+
+    #[tokio::test]
+    async fn test_custom_templates() {
+        let (mailer, mut emails) = get_mailer(
+            MailOptionsBuilder::default()
+                .password_reset_template(Some("Reset {username}: {link}".to_owned()))
+                .welcome_template(Some("Welcome {username}, see {link}".to_owned()))
+                .build()
+                .unwrap(),
+        );
+        mailer.send_password_reset_email("bob", "bob@example.com", "abc123");
+        assert_eq!(
+            emails.recv().await.unwrap().body,
+            "Reset bob: https://ldap.example.com/reset-password/step2/abc123"
+        );
+        mailer.welcome(&UserId::new("bob"), "bob@example.com", Some("Bob"));
+        let email = emails.recv().await.unwrap();
+        assert_eq!(email.subject, "[LLDAP] Welcome");
+        assert_eq!(email.body, "Welcome Bob, see https://ldap.example.com/");
+    }
+
+    #[tokio::test]
+    async fn test_welcome_email_on_user_creation() {
+        use crate::domain::sql_backend_handler::{tests::*, SqlBackendHandler};
+        let (mailer, mut emails) = get_mailer(MailOptions::default());
+        let handler = SqlBackendHandler::new(get_default_config(), get_initialized_db().await)
+            .with_welcome_notifier(Arc::new(mailer));
+        insert_user_no_password(&handler, "bob").await;
+        let email = emails.recv().await.unwrap();
+        assert_eq!(email.to, "bob@bob.bob".parse().unwrap());
+        assert_eq!(email.subject, "[LLDAP] Welcome");
+        assert!(email.body.starts_with("Hello display bob,"));
+    }
+
+    #[tokio::test]
+    async fn test_sending_errors_are_not_returned() {
+        let mailer = Mailer::new(
+            MailOptions::default(),
+            url::Url::parse("https://ldap.example.com").unwrap(),
+        )
+        .with_sender(Arc::new(FailingEmailSender));
+        mailer.send_password_reset_email("bob", "bob@example.com", "abc123");
+        mailer.send_welcome_email("bob", "not an email");
+        mailer.send_login_link_email("bob", "bob@example.com", "abc123");
+    }
+
+    #[tokio::test]
+    async fn test_login_link_email_contains_the_link() {
+        let (mailer, mut emails) = get_mailer(MailOptions::default());
+        mailer.send_login_link_email("Bob", "bob@example.com", "abc123");
+        let email = emails.recv().await.unwrap();
+        assert_eq!(email.subject, "[LLDAP] Login link");
+        assert!(email
+            .body
+            .contains("https://ldap.example.com/auth/login_link/step2/abc123"));
+    }
+}
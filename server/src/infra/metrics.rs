@@ -0,0 +1,209 @@
+use actix_http::HttpServiceBuilder;
+use actix_server::ServerBuilder;
+use actix_service::map_config;
+use actix_web::{dev::AppConfig, web, App, HttpResponse};
+use anyhow::{Context, Result};
+use once_cell::sync::{Lazy, OnceCell};
+use prometheus::{
+    core::Collector, Encoder, Histogram, HistogramOpts, IntCounter, IntCounterVec, IntGauge, Opts,
+    Registry, TextEncoder,
+};
+use sea_orm::{DatabaseBackend, DatabaseConnection};
+use std::time::Duration;
+use tracing::info;
+
+struct Metrics {
+    registry: Registry,
+    bind_total: IntCounterVec,
+    opaque_login_start_total: IntCounter,
+    opaque_login_finish_total: IntCounterVec,
+    sql_query_duration_seconds: Histogram,
+    sql_pool_connections: IntGauge,
+    sql_pool_idle_connections: IntGauge,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let result_counter = |name: &str, help: &str| {
+            IntCounterVec::new(Opts::new(name, help), &["result"]).expect("valid metric")
+        };
+        let metrics = Metrics {
+            registry: Registry::new(),
+            bind_total: result_counter("lldap_bind_total", "LDAP binds, by result."),
+            opaque_login_start_total: IntCounter::new(
+                "lldap_opaque_login_start_total",
+                "Web logins started.",
+            )
+            .expect("valid metric"),
+            opaque_login_finish_total: result_counter(
+                "lldap_opaque_login_finish_total",
+                "Web logins finished, by result.",
+            ),
+            sql_query_duration_seconds: Histogram::with_opts(HistogramOpts::new(
+                "lldap_sql_query_duration_seconds",
+                "Duration of the SQL queries.",
+            ))
+            .expect("valid metric"),
+            sql_pool_connections: IntGauge::new(
+                "lldap_sql_pool_connections",
+                "Open connections of the SQL pool.",
+            )
+            .expect("valid metric"),
+            sql_pool_idle_connections: IntGauge::new(
+                "lldap_sql_pool_idle_connections",
+                "Idle connections of the SQL pool.",
+            )
+            .expect("valid metric"),
+        };
+        let collectors: [Box<dyn Collector>; 6] = [
+            Box::new(metrics.bind_total.clone()),
+            Box::new(metrics.opaque_login_start_total.clone()),
+            Box::new(metrics.opaque_login_finish_total.clone()),
+            Box::new(metrics.sql_query_duration_seconds.clone()),
+            Box::new(metrics.sql_pool_connections.clone()),
+            Box::new(metrics.sql_pool_idle_connections.clone()),
+        ];
+        for collector in collectors {
+            metrics
+                .registry
+                .register(collector)
+                .expect("the metric names are unique");
+        }
+        metrics
+    }
+}
+
+static METRICS: Lazy<Metrics> = Lazy::new(Metrics::new);
+
+/// The pool whose size is reported.
+static SQL_POOL: OnceCell<DatabaseConnection> = OnceCell::new();
+
+fn result_label(success: bool) -> &'static str {
+    if success {
+        "success"
+    } else {
+        "failure"
+    }
+}
+
+pub fn record_bind(success: bool) {
+    METRICS
+        .bind_total
+        .with_label_values(&[result_label(success)])
+        .inc();
+}
+
+pub fn record_opaque_login_start() {
+    METRICS.opaque_login_start_total.inc();
+}
+
+pub fn record_opaque_login_finish(success: bool) {
+    METRICS
+        .opaque_login_finish_total
+        .with_label_values(&[result_label(success)])
+        .inc();
+}
+
+fn observe_sql_query(elapsed: Duration) {
+    METRICS
+        .sql_query_duration_seconds
+        .observe(elapsed.as_secs_f64());
+}
+
+/// Measures the duration of the queries run through the pool, and reports its size.
+pub fn instrument_sql_pool(sql_pool: &mut DatabaseConnection) {
+    sql_pool.set_metric_callback(|info| observe_sql_query(info.elapsed));
+    // Only the first pool is reported, there is only one outside of the tests.
+    let _ = SQL_POOL.set(sql_pool.clone());
+}
+
+fn update_sql_pool_size(sql_pool: &DatabaseConnection) {
+    let (size, idle) = match sql_pool.get_database_backend() {
+        DatabaseBackend::Sqlite => {
+            let pool = sql_pool.get_sqlite_connection_pool();
+            (pool.size(), pool.num_idle())
+        }
+        DatabaseBackend::Postgres => {
+            let pool = sql_pool.get_postgres_connection_pool();
+            (pool.size(), pool.num_idle())
+        }
+        DatabaseBackend::MySql => {
+            let pool = sql_pool.get_mysql_connection_pool();
+            (pool.size(), pool.num_idle())
+        }
+    };
+    METRICS.sql_pool_connections.set(size as i64);
+    METRICS.sql_pool_idle_connections.set(idle as i64);
+}
+
+/// The metrics, in the Prometheus text format.
+pub fn render() -> String {
+    if let Some(sql_pool) = SQL_POOL.get() {
+        update_sql_pool_size(sql_pool);
+    }
+    let mut buffer = Vec::new();
+    TextEncoder::new()
+        .encode(&METRICS.registry.gather(), &mut buffer)
+        .expect("the metrics can be encoded");
+    String::from_utf8(buffer).expect("the metrics are valid UTF-8")
+}
+
+async fn metrics_handler() -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type(prometheus::TEXT_FORMAT)
+        .body(render())
+}
+
+pub fn configure_endpoint(cfg: &mut web::ServiceConfig) {
+    cfg.route("/metrics", web::get().to(metrics_handler));
+}
+
+/// Serves the metrics on their own address, e.g. to keep them on an internal network.
+pub fn build_metrics_server(
+    bind_address: &str,
+    server_builder: ServerBuilder,
+) -> Result<ServerBuilder> {
+    info!("Starting the metrics server on {}", bind_address);
+    server_builder
+        .bind("metrics", bind_address, || {
+            HttpServiceBuilder::default()
+                .finish(map_config(App::new().configure(configure_endpoint), |_| {
+                    AppConfig::default()
+                }))
+                .tcp()
+        })
+        .with_context(|| format!("While bringing up the metrics server on {}", bind_address))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bind_successes() -> u64 {
+        METRICS.bind_total.with_label_values(&["success"]).get()
+    }
+
+    #[tokio::test]
+    async fn test_bind_counter() {
+        use crate::domain::{
+            handler::{BindRequest, LoginHandler},
+            sql_backend_handler::{tests::*, SqlBackendHandler},
+            types::UserId,
+        };
+        let handler = SqlBackendHandler::new(get_default_config(), get_initialized_db().await);
+        insert_user(&handler, "bob", "bob00").await;
+        let before = bind_successes();
+        handler
+            .bind(BindRequest {
+                name: UserId::new("bob"),
+                password: "bob00".to_owned(),
+                context: None,
+                source: None,
+            })
+            .await
+            .unwrap();
+        // The other tests may bind concurrently.
+        assert!(bind_successes() > before);
+        assert!(render().contains(r#"lldap_bind_total{result="success"}"#));
+    }
+}
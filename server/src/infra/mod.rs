@@ -1,19 +1,27 @@
 pub mod access_control;
+pub mod auth_monitor;
 pub mod auth_service;
 pub mod cli;
 pub mod configuration;
+pub mod database_encryption;
 pub mod database_string;
 pub mod db_cleaner;
 pub mod graphql;
 pub mod healthcheck;
+pub mod inactive_user_monitor;
 pub mod jwt_sql_tables;
 pub mod ldap_handler;
 pub mod ldap_server;
 pub mod logging;
 pub mod mail;
+pub mod metrics;
+pub mod rate_limiter;
 pub mod sql_backend_handler;
 pub mod tcp_backend_handler;
 pub mod tcp_server;
+pub mod user_import;
+pub mod webhook_event_publisher;
+pub mod webhook_notifier;
 
 #[cfg(test)]
 pub mod test_utils;
@@ -0,0 +1,263 @@
+use crate::{
+    domain::error::{DomainError, Result},
+    infra::configuration::RateLimitOptions,
+};
+use actix::prelude::{Actor, AsyncContext, Context};
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    hash::{Hash, Hasher},
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tracing::{debug, info};
+
+/// Enough to keep the contention low with many client addresses.
+const SHARD_COUNT: usize = 16;
+
+#[derive(Clone, Copy, Debug)]
+struct TokenBucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct BucketLimits {
+    /// Tokens added per second. 0 disables the limit.
+    rate: f64,
+    burst: f64,
+}
+
+impl BucketLimits {
+    fn refill(&self, bucket: &mut TokenBucket, now: Instant) {
+        let elapsed = now.saturating_duration_since(bucket.last_refill);
+        bucket.tokens = (bucket.tokens + elapsed.as_secs_f64() * self.rate).min(self.burst);
+        bucket.last_refill = now;
+    }
+}
+
+type Shard = Mutex<HashMap<(IpAddr, bool), TokenBucket>>;
+
+/// Token buckets for each client address, to limit the authentication attempts. The
+/// authenticated clients have their own buckets, with separate limits.
+pub struct RateLimiter {
+    shards: Vec<Shard>,
+    unauthenticated: BucketLimits,
+    authenticated: BucketLimits,
+}
+
+impl RateLimiter {
+    pub fn new(options: &RateLimitOptions) -> Self {
+        Self {
+            shards: (0..SHARD_COUNT).map(|_| Shard::default()).collect(),
+            unauthenticated: BucketLimits {
+                rate: options.rate,
+                burst: options.burst as f64,
+            },
+            authenticated: BucketLimits {
+                rate: options.authenticated_rate,
+                burst: options.authenticated_burst as f64,
+            },
+        }
+    }
+
+    fn limits(&self, authenticated: bool) -> &BucketLimits {
+        if authenticated {
+            &self.authenticated
+        } else {
+            &self.unauthenticated
+        }
+    }
+
+    fn shard(&self, source: &IpAddr) -> &Shard {
+        let mut hasher = DefaultHasher::new();
+        source.hash(&mut hasher);
+        &self.shards[hasher.finish() as usize % SHARD_COUNT]
+    }
+
+    /// Takes a token from the bucket of the address, or returns `RateLimited` with the time until
+    /// the next one if it's empty.
+    pub fn check(&self, source: IpAddr, authenticated: bool) -> Result<()> {
+        self.check_at(source, authenticated, Instant::now())
+    }
+
+    fn check_at(&self, source: IpAddr, authenticated: bool, now: Instant) -> Result<()> {
+        let limits = self.limits(authenticated);
+        if limits.rate <= 0.0 {
+            return Ok(());
+        }
+        let mut buckets = self.shard(&source).lock().unwrap();
+        let bucket = buckets
+            .entry((source, authenticated))
+            .or_insert(TokenBucket {
+                tokens: limits.burst,
+                last_refill: now,
+            });
+        limits.refill(bucket, now);
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return Ok(());
+        }
+        debug!("Rate limiting {}", source);
+        Err(DomainError::RateLimited(
+            format!("Too many requests from {}", source),
+            // Rounded up, for the Retry-After header.
+            Some(Duration::from_secs(
+                ((1.0 - bucket.tokens) / limits.rate).ceil() as u64,
+            )),
+        ))
+    }
+
+    /// Forgets the buckets that are full again: they are the same as new ones.
+    pub fn evict_idle_buckets(&self) -> usize {
+        self.evict_idle_buckets_at(Instant::now())
+    }
+
+    fn evict_idle_buckets_at(&self, now: Instant) -> usize {
+        let mut evicted = 0;
+        for shard in &self.shards {
+            let mut buckets = shard.lock().unwrap();
+            let before = buckets.len();
+            buckets.retain(|(_, authenticated), bucket| {
+                let limits = self.limits(*authenticated);
+                limits.refill(bucket, now);
+                bucket.tokens < limits.burst
+            });
+            evicted += before - buckets.len();
+        }
+        evicted
+    }
+
+    #[cfg(test)]
+    fn bucket_count(&self) -> usize {
+        self.shards.iter().map(|s| s.lock().unwrap().len()).sum()
+    }
+}
+
+/// Periodically evicts the idle buckets, to bound the memory used by the limiter.
+pub struct RateLimiterEvictor {
+    rate_limiter: Arc<RateLimiter>,
+    interval: Duration,
+}
+
+impl RateLimiterEvictor {
+    pub fn new(rate_limiter: Arc<RateLimiter>, interval: Duration) -> Self {
+        Self {
+            rate_limiter,
+            interval,
+        }
+    }
+}
+
+impl Actor for RateLimiterEvictor {
+    type Context = Context<Self>;
+
+    fn started(&mut self, context: &mut Context<Self>) {
+        info!("Rate limiter eviction started");
+        context.run_interval(self.interval, |this, _| {
+            let evicted = this.rate_limiter.evict_idle_buckets();
+            debug!("Evicted {} idle rate limiting buckets", evicted);
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::infra::configuration::RateLimitOptionsBuilder;
+    use pretty_assertions::assert_eq;
+
+    fn get_rate_limiter() -> RateLimiter {
+        RateLimiter::new(
+            &RateLimitOptionsBuilder::default()
+                .rate(1.0)
+                .burst(3)
+                .authenticated_rate(10.0)
+                .authenticated_burst(5)
+                .build()
+                .unwrap(),
+        )
+    }
+
+    fn ip(address: &str) -> IpAddr {
+        address.parse().unwrap()
+    }
+
+    #[test]
+    fn test_empty_bucket_recovers_after_refill() {
+        let rate_limiter = get_rate_limiter();
+        let source = ip("192.0.2.1");
+        let start = Instant::now();
+        for _ in 0..3 {
+            rate_limiter.check_at(source, false, start).unwrap();
+        }
+        match rate_limiter.check_at(source, false, start).unwrap_err() {
+            DomainError::RateLimited(_, Some(retry_after)) => {
+                assert_eq!(retry_after, Duration::from_secs(1))
+            }
+            e => panic!("Unexpected error: {:?}", e),
+        }
+        // Other addresses have their own bucket.
+        rate_limiter
+            .check_at(ip("192.0.2.2"), false, start)
+            .unwrap();
+        let later = start + Duration::from_millis(500);
+        rate_limiter.check_at(source, false, later).unwrap_err();
+        let later = start + Duration::from_secs(1);
+        rate_limiter.check_at(source, false, later).unwrap();
+        rate_limiter.check_at(source, false, later).unwrap_err();
+    }
+
+    #[test]
+    fn test_authenticated_limits() {
+        let rate_limiter = get_rate_limiter();
+        let source = ip("2001:db8::1");
+        let start = Instant::now();
+        for _ in 0..3 {
+            rate_limiter.check_at(source, false, start).unwrap();
+        }
+        rate_limiter.check_at(source, false, start).unwrap_err();
+        for _ in 0..5 {
+            rate_limiter.check_at(source, true, start).unwrap();
+        }
+        rate_limiter.check_at(source, true, start).unwrap_err();
+        rate_limiter
+            .check_at(source, true, start + Duration::from_millis(100))
+            .unwrap();
+    }
+
+    #[test]
+    fn test_disabled_limit() {
+        let rate_limiter = RateLimiter::new(&RateLimitOptions::default());
+        for _ in 0..100 {
+            rate_limiter.check(ip("192.0.2.1"), false).unwrap();
+        }
+        assert_eq!(rate_limiter.bucket_count(), 0);
+    }
+
+    #[test]
+    fn test_evict_idle_buckets() {
+        let rate_limiter = get_rate_limiter();
+        let start = Instant::now();
+        rate_limiter
+            .check_at(ip("192.0.2.1"), false, start)
+            .unwrap();
+        for _ in 0..3 {
+            rate_limiter
+                .check_at(ip("192.0.2.2"), false, start)
+                .unwrap();
+        }
+        assert_eq!(rate_limiter.evict_idle_buckets_at(start), 0);
+        // The first bucket is full again, the second one is still missing two tokens.
+        assert_eq!(
+            rate_limiter.evict_idle_buckets_at(start + Duration::from_secs(1)),
+            1
+        );
+        assert_eq!(rate_limiter.bucket_count(), 1);
+        assert_eq!(
+            rate_limiter.evict_idle_buckets_at(start + Duration::from_secs(3)),
+            1
+        );
+        assert_eq!(rate_limiter.bucket_count(), 0);
+    }
+}
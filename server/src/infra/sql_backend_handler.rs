@@ -1,8 +1,9 @@
 use super::tcp_backend_handler::TcpBackendHandler;
 use crate::domain::{
     error::*,
-    model::{self, JwtRefreshStorageColumn, JwtStorageColumn, PasswordResetTokensColumn},
+    model::{self, JwtRefreshStorageColumn, JwtStorageColumn, UserColumn, UserSessionsColumn},
     sql_backend_handler::SqlBackendHandler,
+    sql_password_reset::hash_reset_token,
     types::UserId,
 };
 use async_trait::async_trait;
@@ -11,7 +12,10 @@ use sea_orm::{
     sea_query::{Cond, Expr},
     ActiveModelTrait, ColumnTrait, EntityTrait, IntoActiveModel, QueryFilter, QuerySelect,
 };
-use std::collections::HashSet;
+use std::{
+    collections::HashSet,
+    sync::{Arc, RwLock},
+};
 use tracing::{debug, instrument};
 
 fn gen_random_string(len: usize) -> String {
@@ -27,8 +31,8 @@ fn gen_random_string(len: usize) -> String {
 #[async_trait]
 impl TcpBackendHandler for SqlBackendHandler {
     #[instrument(skip_all, level = "debug")]
-    async fn get_jwt_blacklist(&self) -> anyhow::Result<HashSet<u64>> {
-        Ok(model::JwtStorage::find()
+    async fn load_jwt_blacklist(&self) -> anyhow::Result<Arc<RwLock<HashSet<u64>>>> {
+        let jwt_blacklist = model::JwtStorage::find()
             .select_only()
             .column(JwtStorageColumn::JwtHash)
             .filter(JwtStorageColumn::Blacklisted.eq(true))
@@ -37,11 +41,18 @@ impl TcpBackendHandler for SqlBackendHandler {
             .await?
             .into_iter()
             .map(|m| m.0 as u64)
-            .collect::<HashSet<u64>>())
+            .collect::<HashSet<u64>>();
+        *self.jwt_blacklist.write().unwrap() = jwt_blacklist;
+        Ok(self.jwt_blacklist.clone())
     }
 
     #[instrument(skip_all, level = "debug")]
-    async fn create_refresh_token(&self, user: &UserId) -> Result<(String, chrono::Duration)> {
+    async fn create_refresh_token(
+        &self,
+        user: &UserId,
+        device: Option<&str>,
+        source: Option<&str>,
+    ) -> Result<(String, chrono::Duration)> {
         debug!(?user);
         // TODO: Initialize the rng only once. Maybe Arc<Cell>?
         let refresh_token = gen_random_string(100);
@@ -53,13 +64,30 @@ impl TcpBackendHandler for SqlBackendHandler {
             s.finish()
         };
         let duration = chrono::Duration::days(30);
+        let now = chrono::Utc::now().naive_utc();
         let new_token = model::jwt_refresh_storage::Model {
             refresh_token_hash: refresh_token_hash as i64,
             user_id: user.clone(),
-            expiry_date: chrono::Utc::now().naive_utc() + duration,
+            expiry_date: now + duration,
         }
         .into_active_model();
         new_token.insert(&self.sql_pool).await?;
+        if self.config.track_sessions {
+            // Truncated to fit in the column, user agents can be long.
+            let truncate = |s: &str| s.chars().take(255).collect::<String>();
+            model::user_sessions::Model {
+                session_id: refresh_token_hash as i64,
+                user_id: user.clone(),
+                creation_date: now,
+                last_seen: now,
+                expiry_date: now + duration,
+                device: device.map(truncate),
+                source: source.map(truncate),
+            }
+            .into_active_model()
+            .insert(&self.sql_pool)
+            .await?;
+        }
         Ok((refresh_token, duration))
     }
 
@@ -85,13 +113,22 @@ impl TcpBackendHandler for SqlBackendHandler {
     #[instrument(skip_all, level = "debug")]
     async fn check_token(&self, refresh_token_hash: u64, user: &UserId) -> Result<bool> {
         debug!(?user);
-        Ok(
-            model::JwtRefreshStorage::find_by_id(refresh_token_hash as i64)
-                .filter(JwtRefreshStorageColumn::UserId.eq(user))
-                .one(&self.sql_pool)
-                .await?
-                .is_some(),
-        )
+        let found = model::JwtRefreshStorage::find_by_id(refresh_token_hash as i64)
+            .filter(JwtRefreshStorageColumn::UserId.eq(user))
+            .one(&self.sql_pool)
+            .await?
+            .is_some();
+        if found && self.config.track_sessions {
+            model::UserSessions::update_many()
+                .col_expr(
+                    UserSessionsColumn::LastSeen,
+                    Expr::value(chrono::Utc::now().naive_utc()),
+                )
+                .filter(UserSessionsColumn::SessionId.eq(refresh_token_hash as i64))
+                .exec(&self.sql_pool)
+                .await?;
+        }
+        Ok(found)
     }
 
     #[instrument(skip_all, level = "debug")]
@@ -116,6 +153,10 @@ impl TcpBackendHandler for SqlBackendHandler {
             .filter(JwtStorageColumn::UserId.eq(user))
             .exec(&self.sql_pool)
             .await?;
+        self.jwt_blacklist
+            .write()
+            .unwrap()
+            .extend(valid_tokens.iter().copied());
         Ok(valid_tokens)
     }
 
@@ -124,6 +165,9 @@ impl TcpBackendHandler for SqlBackendHandler {
         model::JwtRefreshStorage::delete_by_id(refresh_token_hash as i64)
             .exec(&self.sql_pool)
             .await?;
+        model::UserSessions::delete_by_id(refresh_token_hash as i64)
+            .exec(&self.sql_pool)
+            .await?;
         Ok(())
     }
 
@@ -131,6 +175,7 @@ impl TcpBackendHandler for SqlBackendHandler {
     async fn start_password_reset(&self, user: &UserId) -> Result<Option<String>> {
         debug!(?user);
         if model::User::find_by_id(user.clone())
+            .filter(UserColumn::DeletedAt.is_null())
             .one(&self.sql_pool)
             .await?
             .is_none()
@@ -139,24 +184,13 @@ impl TcpBackendHandler for SqlBackendHandler {
             return Ok(None);
         }
 
-        let token = gen_random_string(100);
-        let duration = chrono::Duration::minutes(10);
-
-        let new_token = model::password_reset_tokens::Model {
-            token: token.clone(),
-            user_id: user.clone(),
-            expiry_date: chrono::Utc::now().naive_utc() + duration,
-        }
-        .into_active_model();
-        new_token.insert(&self.sql_pool).await?;
-        Ok(Some(token))
+        Ok(Some(self.create_password_reset_token(user).await?))
     }
 
     #[instrument(skip_all, level = "debug", ret)]
     async fn get_user_id_for_password_reset_token(&self, token: &str) -> Result<UserId> {
-        Ok(model::PasswordResetTokens::find_by_id(token.to_owned())
-            .filter(PasswordResetTokensColumn::ExpiryDate.gt(chrono::Utc::now().naive_utc()))
-            .one(&self.sql_pool)
+        Ok(self
+            .find_password_reset_token(token)
             .await?
             .ok_or_else(|| DomainError::EntityNotFound("Invalid reset token".to_owned()))?
             .user_id)
@@ -164,15 +198,19 @@ impl TcpBackendHandler for SqlBackendHandler {
 
     #[instrument(skip_all, level = "debug")]
     async fn delete_password_reset_token(&self, token: &str) -> Result<()> {
-        let result = model::PasswordResetTokens::delete_by_id(token.to_owned())
+        let result = model::PasswordResetTokens::delete_by_id(hash_reset_token(token))
             .exec(&self.sql_pool)
             .await?;
         if result.rows_affected == 0 {
-            return Err(DomainError::EntityNotFound(format!(
-                "No such password reset token: '{}'",
-                token
-            )));
+            return Err(DomainError::EntityNotFound(
+                "No such password reset token".to_owned(),
+            ));
         }
         Ok(())
     }
+
+    #[instrument(skip_all, level = "debug", err, fields(user = %user))]
+    async fn issue_login_link(&self, user: &UserId) -> Result<String> {
+        self.create_login_link(user).await
+    }
 }
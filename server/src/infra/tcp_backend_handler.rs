@@ -1,13 +1,24 @@
 use async_trait::async_trait;
 use chrono::NaiveDateTime;
-use std::collections::HashSet;
+use std::{
+    collections::HashSet,
+    sync::{Arc, RwLock},
+};
 
 use crate::domain::{error::Result, types::UserId};
 
 #[async_trait]
 pub trait TcpBackendHandler: Sync {
-    async fn get_jwt_blacklist(&self) -> anyhow::Result<HashSet<u64>>;
-    async fn create_refresh_token(&self, user: &UserId) -> Result<(String, chrono::Duration)>;
+    /// Loads the revoked JWTs from the database. The handler keeps the returned set up to date
+    /// when it revokes tokens.
+    async fn load_jwt_blacklist(&self) -> anyhow::Result<Arc<RwLock<HashSet<u64>>>>;
+    /// The device and source are recorded with the session, if sessions are tracked.
+    async fn create_refresh_token(
+        &self,
+        user: &UserId,
+        device: Option<&str>,
+        source: Option<&str>,
+    ) -> Result<(String, chrono::Duration)>;
     async fn register_jwt(
         &self,
         user: &UserId,
@@ -26,4 +37,7 @@ pub trait TcpBackendHandler: Sync {
     async fn get_user_id_for_password_reset_token(&self, token: &str) -> Result<UserId>;
 
     async fn delete_password_reset_token(&self, token: &str) -> Result<()>;
+
+    /// Creates a single-use link to log in without a password, to send to the user by email.
+    async fn issue_login_link(&self, user: &UserId) -> Result<String>;
 }
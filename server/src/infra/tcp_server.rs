@@ -1,14 +1,17 @@
 use crate::{
     domain::{
+        correlation::{correlation_id_or_new, sync_with_correlation_id, with_correlation_id},
         error::DomainError,
-        handler::{BackendHandler, LoginHandler},
+        handler::{BackendHandler, HealthStatus, LoginHandler},
         opaque_handler::OpaqueHandler,
     },
     infra::{
         access_control::{AccessControlledBackendHandler, ReadonlyBackendHandler},
         auth_service,
-        configuration::{Configuration, MailOptions},
+        configuration::Configuration,
         logging::CustomRootSpanBuilder,
+        mail::Mailer,
+        rate_limiter::RateLimiter,
         tcp_backend_handler::*,
     },
 };
@@ -16,14 +19,23 @@ use actix_files::Files;
 use actix_http::{header, HttpServiceBuilder};
 use actix_server::ServerBuilder;
 use actix_service::map_config;
-use actix_web::{dev::AppConfig, guard, web, App, HttpResponse, Responder};
+use actix_web::{
+    dev::{AppConfig, Service},
+    guard, web, App, HttpRequest, HttpResponse, Responder,
+};
 use anyhow::{Context, Result};
 use hmac::Hmac;
+use lldap_auth::{password_policy::PasswordPolicy, server_setup::PublicServerSetup};
 use sha2::Sha512;
 use std::collections::HashSet;
-use std::sync::RwLock;
+use std::net::IpAddr;
+use std::sync::{Arc, RwLock};
 use tracing::info;
 
+/// Header used to pass the correlation ID of a request, to follow it across the logs. It's
+/// generated when absent, and always sent back.
+pub const CORRELATION_ID_HEADER: &str = "x-correlation-id";
+
 async fn index<Backend>(data: web::Data<AppState<Backend>>) -> actix_web::Result<impl Responder> {
     let mut file = std::fs::read_to_string(r"./app/index.html")?;
 
@@ -58,16 +70,45 @@ pub type TcpResult<T> = std::result::Result<T, TcpError>;
 pub(crate) fn error_to_http_response(error: TcpError) -> HttpResponse {
     match error {
         TcpError::DomainError(ref de) => match de {
-            DomainError::AuthenticationError(_) | DomainError::AuthenticationProtocolError(_) => {
-                HttpResponse::Unauthorized()
-            }
+            DomainError::AuthenticationError(_)
+            | DomainError::AuthenticationProtocolError(_)
+            | DomainError::PasswordExpired(_)
+            | DomainError::AccountLocked(_)
+            | DomainError::AccountDisabled(_)
+            | DomainError::TotpRequired(_) => HttpResponse::Unauthorized(),
             DomainError::DatabaseError(_)
             | DomainError::DatabaseTransactionError(_)
             | DomainError::InternalError(_)
+            | DomainError::TamperedPasswordFile(_)
             | DomainError::UnknownCryptoError(_) => HttpResponse::InternalServerError(),
             DomainError::Base64DecodeError(_)
             | DomainError::BinarySerializationError(_)
-            | DomainError::EntityNotFound(_) => HttpResponse::BadRequest(),
+            | DomainError::EntityNotFound(_)
+            | DomainError::InvalidServerData(_)
+            | DomainError::PasswordPolicyViolation(_)
+            | DomainError::PasswordReused(_)
+            | DomainError::InvalidTotpSecret(_)
+            | DomainError::WeakPassword(_)
+            | DomainError::ConfusableUsername(_)
+            | DomainError::InvalidUserId(_)
+            | DomainError::InsecureTransport(_)
+            | DomainError::GroupCycle(_)
+            | DomainError::AttributeTypeMismatch(_) => HttpResponse::BadRequest(),
+            DomainError::RateLimited(_, retry_after) => {
+                let mut response = HttpResponse::TooManyRequests();
+                if let Some(retry_after) = retry_after {
+                    response
+                        .insert_header((header::RETRY_AFTER, retry_after.as_secs().to_string()));
+                }
+                response
+            }
+            DomainError::TooManyOutstandingRegistrations(_) => HttpResponse::TooManyRequests(),
+            DomainError::CredentialsChanged(_) | DomainError::PasswordAlreadySet(_) => {
+                HttpResponse::Conflict()
+            }
+            DomainError::Maintenance(_) | DomainError::DatabaseTimeout(_) => {
+                HttpResponse::ServiceUnavailable()
+            }
         },
         TcpError::BadRequest(_) => HttpResponse::BadRequest(),
         TcpError::NotFoundError(_) => HttpResponse::NotFound(),
@@ -91,6 +132,39 @@ async fn main_js_handler<Backend>(
         .insert_header((header::CONTENT_TYPE, "text/javascript")))
 }
 
+async fn health_report_handler<Backend>(data: web::Data<AppState<Backend>>) -> HttpResponse
+where
+    Backend: BackendHandler + 'static,
+{
+    let report = data
+        .backend_handler
+        .unsafe_get_handler()
+        .full_health_report()
+        .await;
+    match report.status {
+        HealthStatus::Failing => HttpResponse::ServiceUnavailable(),
+        HealthStatus::Ok | HealthStatus::Degraded => HttpResponse::Ok(),
+    }
+    .json(report)
+}
+
+/// For the readiness probes: 503 with the failing components when LLDAP can't serve requests.
+async fn readiness_handler<Backend>(data: web::Data<AppState<Backend>>) -> HttpResponse
+where
+    Backend: BackendHandler + 'static,
+{
+    let report = data
+        .backend_handler
+        .unsafe_get_handler()
+        .readiness_report()
+        .await;
+    match report.status {
+        HealthStatus::Failing => HttpResponse::ServiceUnavailable(),
+        HealthStatus::Ok | HealthStatus::Degraded => HttpResponse::Ok(),
+    }
+    .json(report)
+}
+
 async fn wasm_handler() -> actix_web::Result<impl Responder> {
     Ok(actix_files::NamedFile::open_async("./app/pkg/lldap_app_bg.wasm").await?)
 }
@@ -108,29 +182,63 @@ async fn wasm_handler_compressed() -> actix_web::Result<impl Responder> {
 fn http_config<Backend>(
     cfg: &mut web::ServiceConfig,
     backend_handler: Backend,
-    jwt_secret: secstr::SecUtf8,
-    jwt_blacklist: HashSet<u64>,
-    server_url: url::Url,
-    mail_options: MailOptions,
+    jwt_blacklist: Arc<RwLock<HashSet<u64>>>,
+    rate_limiter: Option<Arc<RateLimiter>>,
+    config: &Configuration,
 ) where
     Backend: TcpBackendHandler + BackendHandler + LoginHandler + OpaqueHandler + Clone + 'static,
 {
-    let enable_password_reset = mail_options.enable_password_reset;
+    let enable_password_reset = config.smtp_options.enable_password_reset;
+    let enable_login_links = config.enable_login_links;
+    let enable_self_service_deletion = config.enable_self_service_deletion;
+    let serve_metrics =
+        config.metrics_options.enable_metrics && config.metrics_options.bind_address.is_none();
+    let enable_health_report = config.enable_health_report;
     cfg.app_data(web::Data::new(AppState::<Backend> {
         backend_handler: AccessControlledBackendHandler::new(backend_handler),
-        jwt_key: hmac::Mac::new_from_slice(jwt_secret.unsecure().as_bytes()).unwrap(),
-        jwt_blacklist: RwLock::new(jwt_blacklist),
-        server_url,
-        mail_options,
+        jwt_key: hmac::Mac::new_from_slice(config.jwt_secret.unsecure().as_bytes()).unwrap(),
+        jwt_blacklist,
+        server_url: config.http_url.clone(),
+        mailer: Mailer::new(config.smtp_options.clone(), config.http_url.clone()),
+        rate_limiter,
+        mask_registration_user_existence: config.mask_registration_user_existence,
+        allow_email_login: config.allow_email_login,
+        trusted_proxies: config.trusted_proxies.clone(),
+        public_server_setup: config.public_server_setup(),
+        password_policy: config.password_policy(),
+        step_up_auth_max_age: config
+            .step_up_auth_max_age_seconds
+            .map(|s| chrono::Duration::seconds(s as i64)),
     }))
     .route(
         "/health",
         web::get().to(|| async { HttpResponse::Ok().finish() }),
     )
-    .service(
-        web::scope("/auth")
-            .configure(|cfg| auth_service::configure_server::<Backend>(cfg, enable_password_reset)),
+    // Liveness and readiness probes.
+    .route(
+        "/healthz",
+        web::get().to(|| async { HttpResponse::Ok().finish() }),
     )
+    .route("/readyz", web::get().to(readiness_handler::<Backend>))
+    .configure(|cfg| {
+        if serve_metrics {
+            super::metrics::configure_endpoint(cfg)
+        }
+        if enable_health_report {
+            cfg.route(
+                "/health/report",
+                web::get().to(health_report_handler::<Backend>),
+            );
+        }
+    })
+    .service(web::scope("/auth").configure(|cfg| {
+        auth_service::configure_server::<Backend>(
+            cfg,
+            enable_password_reset,
+            enable_login_links,
+            enable_self_service_deletion,
+        )
+    }))
     // API endpoint.
     .service(
         web::scope("/api")
@@ -155,11 +263,34 @@ fn http_config<Backend>(
 pub(crate) struct AppState<Backend> {
     pub backend_handler: AccessControlledBackendHandler<Backend>,
     pub jwt_key: Hmac<Sha512>,
-    pub jwt_blacklist: RwLock<HashSet<u64>>,
+    /// Shared with the backend handler, which adds the JWTs it revokes.
+    pub jwt_blacklist: Arc<RwLock<HashSet<u64>>>,
     pub server_url: url::Url,
-    pub mail_options: MailOptions,
+    pub mailer: Mailer,
+    /// Limits the login and password change attempts from a single address.
+    pub rate_limiter: Option<Arc<RateLimiter>>,
+    /// Answer password registrations the same way whether or not the user exists.
+    pub mask_registration_user_existence: bool,
+    /// Whether the users can be identified by their email for password resets.
+    pub allow_email_login: bool,
+    /// Reverse proxies whose forwarding headers are believed.
+    pub trusted_proxies: Vec<IpAddr>,
+    pub public_server_setup: PublicServerSetup,
+    /// Sent to the clients, which check the new passwords before registering them.
+    pub password_policy: PasswordPolicy,
+    /// If set, password changes require a login more recent than this.
+    pub step_up_auth_max_age: Option<chrono::Duration>,
 }
 
+impl<Backend> AppState<Backend> {
+    /// Whether the request comes straight from a trusted reverse proxy, so that its forwarding
+    /// headers can be believed.
+    pub fn is_from_trusted_proxy(&self, request: &HttpRequest) -> bool {
+        request
+            .peer_addr()
+            .is_some_and(|peer| self.trusted_proxies.contains(&peer.ip()))
+    }
+}
 impl<Backend: BackendHandler> AppState<Backend> {
     pub fn get_readonly_handler(&self) -> &impl ReadonlyBackendHandler {
         self.backend_handler.unsafe_get_handler()
@@ -184,18 +315,17 @@ impl<Backend: LoginHandler> AppState<Backend> {
 pub async fn build_tcp_server<Backend>(
     config: &Configuration,
     backend_handler: Backend,
+    rate_limiter: Option<Arc<RateLimiter>>,
     server_builder: ServerBuilder,
 ) -> Result<ServerBuilder>
 where
     Backend: TcpBackendHandler + BackendHandler + LoginHandler + OpaqueHandler + Clone + 'static,
 {
-    let jwt_secret = config.jwt_secret.clone();
     let jwt_blacklist = backend_handler
-        .get_jwt_blacklist()
+        .load_jwt_blacklist()
         .await
         .context("while getting the jwt blacklist")?;
-    let server_url = config.http_url.clone();
-    let mail_options = config.smtp_options.clone();
+    let http_config_options = config.clone();
     let verbose = config.verbose;
     info!("Starting the API/web server on port {}", config.http_port);
     server_builder
@@ -204,10 +334,9 @@ where
             (config.http_host.clone(), config.http_port),
             move || {
                 let backend_handler = backend_handler.clone();
-                let jwt_secret = jwt_secret.clone();
                 let jwt_blacklist = jwt_blacklist.clone();
-                let server_url = server_url.clone();
-                let mail_options = mail_options.clone();
+                let rate_limiter = rate_limiter.clone();
+                let config = http_config_options.clone();
                 HttpServiceBuilder::default()
                     .finish(map_config(
                         App::new()
@@ -215,14 +344,32 @@ where
                                 verbose,
                                 tracing_actix_web::TracingLogger::<CustomRootSpanBuilder>::new(),
                             ))
+                            .wrap_fn(|req, srv| {
+                                let id = correlation_id_or_new(
+                                    req.headers()
+                                        .get(CORRELATION_ID_HEADER)
+                                        .and_then(|h| h.to_str().ok()),
+                                );
+                                let response =
+                                    sync_with_correlation_id(id.clone(), || srv.call(req));
+                                with_correlation_id(id.clone(), async move {
+                                    let mut response = response.await?;
+                                    if let Ok(value) = header::HeaderValue::from_str(&id) {
+                                        response.headers_mut().insert(
+                                            header::HeaderName::from_static(CORRELATION_ID_HEADER),
+                                            value,
+                                        );
+                                    }
+                                    Ok(response)
+                                })
+                            })
                             .configure(move |cfg| {
                                 http_config(
                                     cfg,
                                     backend_handler,
-                                    jwt_secret,
                                     jwt_blacklist,
-                                    server_url,
-                                    mail_options,
+                                    rate_limiter,
+                                    &config,
                                 )
                             }),
                         |_| AppConfig::default(),
@@ -237,3 +384,483 @@ where
             )
         })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::{
+        error::Result as DomainResult,
+        handler::BindRequest,
+        sql_backend_handler::{tests::*, SqlBackendHandler},
+        types::UserId,
+    };
+    use actix_web::{http::StatusCode, test};
+    use lldap_auth::{login, opaque, password_reset, registration};
+
+    async fn init_app(
+        handler: &SqlBackendHandler,
+        config: &Configuration,
+    ) -> impl Service<
+        actix_http::Request,
+        Response = actix_web::dev::ServiceResponse,
+        Error = actix_web::Error,
+    > {
+        let handler = handler.clone();
+        let config = config.clone();
+        test::init_service(App::new().configure(move |cfg| {
+            let jwt_blacklist = handler.jwt_blacklist.clone();
+            http_config(cfg, handler, jwt_blacklist, None, &config)
+        }))
+        .await
+    }
+
+    async fn simple_login<S>(app: &S, user: &str, password: &str) -> String
+    where
+        S: Service<
+            actix_http::Request,
+            Response = actix_web::dev::ServiceResponse,
+            Error = actix_web::Error,
+        >,
+    {
+        let response = test::call_service(
+            app,
+            test::TestRequest::post()
+                .uri("/auth/simple/login")
+                .set_json(login::ClientSimpleLoginRequest {
+                    username: user.into(),
+                    password: password.to_owned(),
+                })
+                .to_request(),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let login: login::ServerLoginResponse = test::read_body_json(response).await;
+        login.token
+    }
+
+    async fn change_password<S>(app: &S, token: &str, user: &str, password: &str) -> StatusCode
+    where
+        S: Service<
+            actix_http::Request,
+            Response = actix_web::dev::ServiceResponse,
+            Error = actix_web::Error,
+        >,
+    {
+        change_password_with(app, token, user, password, |request| request).await
+    }
+
+    /// `customize` is applied to the registration finish request.
+    async fn change_password_with<S>(
+        app: &S,
+        token: &str,
+        user: &str,
+        password: &str,
+        customize: impl FnOnce(test::TestRequest) -> test::TestRequest,
+    ) -> StatusCode
+    where
+        S: Service<
+            actix_http::Request,
+            Response = actix_web::dev::ServiceResponse,
+            Error = actix_web::Error,
+        >,
+    {
+        let mut rng = rand::rngs::OsRng;
+        let registration_start =
+            opaque::client::registration::start_registration(password.as_bytes(), &mut rng)
+                .unwrap();
+        let response = test::call_service(
+            app,
+            test::TestRequest::post()
+                .uri("/auth/opaque/register/start")
+                .insert_header((header::AUTHORIZATION, format!("Bearer {token}")))
+                .set_json(registration::ClientRegistrationStartRequest {
+                    username: user.into(),
+                    registration_start_request: registration_start.message,
+                    context: None,
+                })
+                .to_request(),
+        )
+        .await;
+        if response.status() != StatusCode::OK {
+            return response.status();
+        }
+        let start_response: registration::ServerRegistrationStartResponse =
+            test::read_body_json(response).await;
+        let registration_finish = opaque::client::registration::finish_registration(
+            registration_start.state,
+            start_response.registration_response,
+            &mut rng,
+        )
+        .unwrap();
+        test::call_service(
+            app,
+            customize(
+                test::TestRequest::post()
+                    .uri("/auth/opaque/register/finish")
+                    .insert_header((header::AUTHORIZATION, format!("Bearer {token}")))
+                    .set_json(registration::ClientRegistrationFinishRequest {
+                        server_data: start_response.server_data,
+                        registration_upload: registration_finish.message,
+                    }),
+            )
+            .to_request(),
+        )
+        .await
+        .status()
+    }
+
+    async fn bind(handler: &SqlBackendHandler, user: &str, password: &str) -> DomainResult<()> {
+        handler
+            .bind(BindRequest {
+                name: UserId::new(user),
+                password: password.to_string(),
+                source: None,
+                context: None,
+            })
+            .await
+    }
+
+    #[actix_web::test]
+    async fn test_password_reset_with_step_up_auth() {
+        let mut config = get_default_config();
+        config.smtp_options.enable_password_reset = true;
+        config.step_up_auth_max_age_seconds = Some(300);
+        let handler = SqlBackendHandler::new(config.clone(), get_initialized_db().await);
+        insert_user(&handler, "bob", "bob00000").await;
+        let reset_token = handler
+            .start_password_reset(&UserId::new("bob"))
+            .await
+            .unwrap()
+            .unwrap();
+        let app = init_app(&handler, &config).await;
+        let response = test::call_service(
+            &app,
+            test::TestRequest::get()
+                .uri(&format!("/auth/reset/step2/{reset_token}"))
+                .to_request(),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let reset: password_reset::ServerPasswordResetResponse =
+            test::read_body_json(response).await;
+        assert_eq!(
+            change_password(&app, &reset.token, "bob", "new_password").await,
+            StatusCode::OK
+        );
+        bind(&handler, "bob", "new_password").await.unwrap();
+    }
+
+    #[actix_web::test]
+    async fn test_admin_password_reset_must_be_changed() {
+        let mut config = get_default_config();
+        config.smtp_options.enable_password_reset = true;
+        config.force_password_change_after_admin_reset = true;
+        let handler = SqlBackendHandler::new(config.clone(), get_initialized_db().await);
+        insert_user(&handler, "admin", "admin-pass").await;
+        let admin_group = insert_group(&handler, "lldap_admin").await;
+        insert_membership(&handler, admin_group, "admin").await;
+        insert_user(&handler, "bob", "bob00000").await;
+        let app = init_app(&handler, &config).await;
+        let admin_token = simple_login(&app, "admin", "admin-pass").await;
+        assert_eq!(
+            change_password(&app, &admin_token, "bob", "admin-set").await,
+            StatusCode::OK
+        );
+        assert!(matches!(
+            bind(&handler, "bob", "admin-set").await.unwrap_err(),
+            DomainError::PasswordExpired(_)
+        ));
+        // Bob sets his own password, which clears the flag.
+        let reset_token = handler
+            .start_password_reset(&UserId::new("bob"))
+            .await
+            .unwrap()
+            .unwrap();
+        let response = test::call_service(
+            &app,
+            test::TestRequest::get()
+                .uri(&format!("/auth/reset/step2/{reset_token}"))
+                .to_request(),
+        )
+        .await;
+        let reset: password_reset::ServerPasswordResetResponse =
+            test::read_body_json(response).await;
+        assert_eq!(
+            change_password(&app, &reset.token, "bob", "bob-chosen").await,
+            StatusCode::OK
+        );
+        bind(&handler, "bob", "bob-chosen").await.unwrap();
+        // The admin changing their own password doesn't have to change it again.
+        assert_eq!(
+            change_password(&app, &admin_token, "admin", "admin-pass2").await,
+            StatusCode::OK
+        );
+        bind(&handler, "admin", "admin-pass2").await.unwrap();
+    }
+
+    #[actix_web::test]
+    async fn test_reset_token_allows_password_overwrite() {
+        let mut config = get_default_config();
+        config.smtp_options.enable_password_reset = true;
+        config.reject_password_overwrite = true;
+        let handler = SqlBackendHandler::new(config.clone(), get_initialized_db().await);
+        insert_user(&handler, "bob", "bob00000").await;
+        let app = init_app(&handler, &config).await;
+        let token = simple_login(&app, "bob", "bob00000").await;
+        assert_eq!(
+            change_password(&app, &token, "bob", "bob11111").await,
+            StatusCode::CONFLICT
+        );
+        let reset_token = handler
+            .start_password_reset(&UserId::new("bob"))
+            .await
+            .unwrap()
+            .unwrap();
+        let response = test::call_service(
+            &app,
+            test::TestRequest::get()
+                .uri(&format!("/auth/reset/step2/{reset_token}"))
+                .to_request(),
+        )
+        .await;
+        let reset: password_reset::ServerPasswordResetResponse =
+            test::read_body_json(response).await;
+        assert_eq!(
+            change_password(&app, &reset.token, "bob", "bob11111").await,
+            StatusCode::OK
+        );
+        // The reset only allowed one overwrite.
+        let token = simple_login(&app, "bob", "bob11111").await;
+        assert_eq!(
+            change_password(&app, &token, "bob", "bob22222").await,
+            StatusCode::CONFLICT
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_login_finish_retried_with_totp_code() {
+        use crate::domain::{handler::BackendHandler, totp};
+        /// "12345678901234567890" in base32.
+        const TOTP_SECRET: &str = "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ";
+        let config = get_default_config();
+        let handler = SqlBackendHandler::new(config.clone(), get_initialized_db().await);
+        insert_user(&handler, "bob", "bob00000").await;
+        handler
+            .set_totp_secret(&UserId::new("bob"), Some(TOTP_SECRET.to_owned()))
+            .await
+            .unwrap();
+        let app = init_app(&handler, &config).await;
+        let mut rng = rand::rngs::OsRng;
+        let login_start = opaque::client::login::start_login("bob00000", &mut rng).unwrap();
+        let response = test::call_service(
+            &app,
+            test::TestRequest::post()
+                .uri("/auth/opaque/login/start")
+                .set_json(login::ClientLoginStartRequest {
+                    username: "bob".into(),
+                    login_start_request: login_start.message,
+                    challenge: None,
+                    context: None,
+                })
+                .to_request(),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let start_response: login::ServerLoginStartResponse = test::read_body_json(response).await;
+        let login_finish = opaque::client::login::finish_login(
+            login_start.state,
+            start_response.credential_response,
+        )
+        .unwrap();
+        let finish_request = login::ClientLoginFinishRequest {
+            server_data: start_response.server_data,
+            credential_finalization: login_finish.message,
+            consent: None,
+            device_fingerprint: None,
+            username: Some("bob".into()),
+            totp_code: None,
+        };
+        let response = test::call_service(
+            &app,
+            test::TestRequest::post()
+                .uri("/auth/opaque/login/finish")
+                .set_json(finish_request.clone())
+                .to_request(),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let totp_response: login::ServerLoginFinishTotpResponse =
+            test::read_body_json(response).await;
+        assert!(totp_response.requires_totp);
+        // The client sends the same finalization again, with the code.
+        let totp_code = totp::code_at(
+            &totp::decode_secret(TOTP_SECRET).unwrap(),
+            chrono::Utc::now().timestamp(),
+            config.totp_period_seconds,
+        );
+        let response = test::call_service(
+            &app,
+            test::TestRequest::post()
+                .uri("/auth/opaque/login/finish")
+                .set_json(login::ClientLoginFinishRequest {
+                    totp_code: Some(totp_code),
+                    ..finish_request
+                })
+                .to_request(),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let login: login::ServerLoginResponse = test::read_body_json(response).await;
+        assert!(!login.token.is_empty());
+    }
+
+    #[actix_web::test]
+    async fn test_forwarded_proto_only_from_trusted_proxies() {
+        let proxy: std::net::SocketAddr = "10.0.0.1:4321".parse().unwrap();
+        let mut config = get_default_config();
+        config.require_secure_transport_for_password_ops = true;
+        config.trusted_proxies = vec![proxy.ip()];
+        let handler = SqlBackendHandler::new(config.clone(), get_initialized_db().await);
+        insert_user(&handler, "bob", "bob00000").await;
+        let app = init_app(&handler, &config).await;
+        let token = simple_login(&app, "bob", "bob00000").await;
+        assert_eq!(
+            change_password(&app, &token, "bob", "new_password").await,
+            StatusCode::BAD_REQUEST
+        );
+        // A client can't claim to be behind TLS.
+        assert_eq!(
+            change_password_with(&app, &token, "bob", "new_password", |request| {
+                request
+                    .peer_addr("192.168.1.1:1234".parse().unwrap())
+                    .insert_header(("x-forwarded-proto", "https"))
+            })
+            .await,
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(
+            change_password_with(&app, &token, "bob", "new_password", |request| {
+                request
+                    .peer_addr(proxy)
+                    .insert_header(("x-forwarded-proto", "https"))
+            })
+            .await,
+            StatusCode::OK
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_forwarded_for_only_from_trusted_proxies() {
+        let mut config = get_default_config();
+        config.throttle_options.max_registrations = 1;
+        let handler = SqlBackendHandler::new(config.clone(), get_initialized_db().await);
+        insert_user(&handler, "bob", "bob00000").await;
+        let app = init_app(&handler, &config).await;
+        let token = simple_login(&app, "bob", "bob00000").await;
+        let mut statuses = Vec::new();
+        // The client isn't a trusted proxy: changing the header doesn't get around the limit.
+        for forwarded_for in ["1.1.1.1", "2.2.2.2"] {
+            let mut rng = rand::rngs::OsRng;
+            let registration_start =
+                opaque::client::registration::start_registration("password".as_bytes(), &mut rng)
+                    .unwrap();
+            let response = test::call_service(
+                &app,
+                test::TestRequest::post()
+                    .uri("/auth/opaque/register/start")
+                    .peer_addr("192.168.1.1:1234".parse().unwrap())
+                    .insert_header(("x-forwarded-for", forwarded_for))
+                    .insert_header((header::AUTHORIZATION, format!("Bearer {token}")))
+                    .set_json(registration::ClientRegistrationStartRequest {
+                        username: "bob".into(),
+                        registration_start_request: registration_start.message,
+                        context: None,
+                    })
+                    .to_request(),
+            )
+            .await;
+            statuses.push(response.status());
+        }
+        assert_eq!(statuses, [StatusCode::OK, StatusCode::TOO_MANY_REQUESTS]);
+    }
+
+    #[actix_web::test]
+    async fn test_login_link_request_does_not_reveal_users() {
+        let mut config = get_default_config();
+        config.enable_login_links = true;
+        let handler = SqlBackendHandler::new(config.clone(), get_initialized_db().await);
+        insert_user(&handler, "bob", "bob00000").await;
+        let app = init_app(&handler, &config).await;
+        let mut answers = Vec::new();
+        // The SMTP server of the default configuration is unreachable.
+        for user in ["bob", "bob@bob.bob", "eve"] {
+            let response = test::call_service(
+                &app,
+                test::TestRequest::get()
+                    .uri(&format!("/auth/login_link/step1/{user}"))
+                    .to_request(),
+            )
+            .await;
+            answers.push((response.status(), test::read_body(response).await));
+        }
+        assert_eq!(answers[0].0, StatusCode::OK);
+        assert!(answers.iter().all(|answer| answer == &answers[0]));
+    }
+
+    #[actix_web::test]
+    async fn test_login_link_is_not_a_recent_auth() {
+        let mut config = get_default_config();
+        config.enable_login_links = true;
+        config.step_up_auth_max_age_seconds = Some(300);
+        let handler = SqlBackendHandler::new(config.clone(), get_initialized_db().await);
+        insert_user(&handler, "bob", "bob00000").await;
+        let link_token = handler.issue_login_link(&UserId::new("bob")).await.unwrap();
+        let app = init_app(&handler, &config).await;
+        let response = test::call_service(
+            &app,
+            test::TestRequest::get()
+                .uri(&format!("/auth/login_link/step2/{link_token}"))
+                .to_request(),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::FOUND);
+        let login: login::ServerLoginResponse = test::read_body_json(response).await;
+        assert_eq!(
+            change_password(&app, &login.token, "bob", "new_password").await,
+            StatusCode::UNAUTHORIZED
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_password_policy_is_public() {
+        let mut config = get_default_config();
+        config.password_policy_options.min_length = 12;
+        let handler = SqlBackendHandler::new(config.clone(), get_initialized_db().await);
+        let app = init_app(&handler, &config).await;
+        let response = test::call_service(
+            &app,
+            test::TestRequest::get()
+                .uri("/auth/password_policy")
+                .to_request(),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let policy: PasswordPolicy = test::read_body_json(response).await;
+        assert_eq!(policy, config.password_policy());
+    }
+
+    #[actix_web::test]
+    async fn test_readyz() {
+        let config = get_default_config();
+        let handler = SqlBackendHandler::new(config.clone(), get_initialized_db().await);
+        let app = init_app(&handler, &config).await;
+        let response =
+            test::call_service(&app, test::TestRequest::get().uri("/readyz").to_request()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body: serde_json::Value = test::read_body_json(response).await;
+        assert_eq!(body["status"], "ok");
+        let response =
+            test::call_service(&app, test::TestRequest::get().uri("/healthz").to_request()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+}
@@ -1,6 +1,7 @@
 use crate::domain::{error::Result, handler::*, opaque_handler::*, types::*};
 
 use async_trait::async_trait;
+use secstr::SecUtf8;
 use std::collections::HashSet;
 
 mockall::mock! {
@@ -11,10 +12,14 @@ mockall::mock! {
     #[async_trait]
     impl LoginHandler for TestBackendHandler {
         async fn bind(&self, request: BindRequest) -> Result<()>;
+        async fn health_bind(&self, request: BindRequest) -> Result<()>;
+        async fn compare_password(&self, request: BindRequest) -> Result<bool>;
+        async fn bind_with_token(&self, token: &str) -> Result<UserId>;
     }
     #[async_trait]
     impl GroupListerBackendHandler for TestBackendHandler {
         async fn list_groups(&self, filters: Option<GroupRequestFilter>) -> Result<Vec<Group>>;
+        async fn list_groups_page(&self, filters: Option<GroupRequestFilter>, first: u64, after: Option<GroupId>) -> Result<GroupPage>;
     }
     #[async_trait]
     impl GroupBackendHandler for TestBackendHandler {
@@ -26,6 +31,7 @@ mockall::mock! {
     #[async_trait]
     impl UserListerBackendHandler for TestBackendHandler {
         async fn list_users(&self, filters: Option<UserRequestFilter>, get_groups: bool) -> Result<Vec<UserAndGroups>>;
+        async fn list_users_page(&self, filters: Option<UserRequestFilter>, get_groups: bool, first: u64, after: Option<UserId>) -> Result<UserPage>;
     }
     #[async_trait]
     impl UserBackendHandler for TestBackendHandler {
@@ -34,6 +40,8 @@ mockall::mock! {
         async fn update_user(&self, request: UpdateUserRequest) -> Result<()>;
         async fn delete_user(&self, user_id: &UserId) -> Result<()>;
         async fn get_user_groups(&self, user_id: &UserId) -> Result<HashSet<GroupDetails>>;
+        async fn get_password_changed_at(&self, user_id: &UserId) -> Result<Option<chrono::NaiveDateTime>>;
+        async fn get_last_login_date(&self, user_id: &UserId) -> Result<Option<chrono::NaiveDateTime>>;
         async fn add_user_to_group(&self, user_id: &UserId, group_id: GroupId) -> Result<()>;
         async fn remove_user_from_group(&self, user_id: &UserId, group_id: GroupId) -> Result<()>;
     }
@@ -49,21 +57,73 @@ mockall::mock! {
         async fn delete_group_attribute(&self, name: &AttributeName) -> Result<()>;
     }
     #[async_trait]
-    impl BackendHandler for TestBackendHandler {}
+    impl BackendHandler for TestBackendHandler {
+        async fn failed_login_report(&self, user_id: &UserId, window: chrono::Duration) -> Result<FailedLoginReport>;
+        async fn set_users_enabled(&self, user_ids: &[UserId], enabled: bool) -> Result<u64>;
+        async fn set_user_enabled(&self, user_id: &UserId, enabled: bool) -> Result<()>;
+        async fn current_auth_success_ratio(&self, window: chrono::Duration) -> Result<AuthSuccessRatio>;
+        async fn set_passwords_batch(&self, passwords: Vec<(UserId, SecUtf8)>, must_change_password: bool) -> Vec<(UserId, Result<()>)>;
+        async fn find_duplicate_emails(&self) -> Result<Vec<DuplicateEmail>>;
+        async fn interop_self_test(&self, login_start_request: &[u8]) -> Result<bool>;
+        async fn issue_temporary_password(&self, user_id: &UserId) -> Result<SecUtf8>;
+        async fn migrate_legacy_to_opaque(&self, user_id: &UserId, clear_password: &SecUtf8) -> Result<()>;
+        async fn audit_password_store(&self) -> Result<Vec<PasswordFileAudit>>;
+        async fn validate_imported_files(
+            &self,
+            canary_credentials: Vec<(UserId, SecUtf8)>,
+        ) -> Result<Vec<(UserId, LoginFailureReason)>>;
+        async fn set_maintenance_mode(&self, mode: MaintenanceMode);
+        async fn get_maintenance_mode(&self) -> MaintenanceMode;
+        async fn list_inactive_users(&self, since: chrono::NaiveDateTime) -> Result<Vec<InactiveUser>>;
+        async fn disable_inactive_users(&self, since: chrono::NaiveDateTime) -> Result<Vec<UserId>>;
+        async fn revoke_user_tokens(&self, user_id: &UserId) -> Result<()>;
+        async fn list_user_sessions(&self, user_id: &UserId) -> Result<Vec<UserSession>>;
+        async fn revoke_session(&self, session_id: i64) -> Result<()>;
+        async fn set_totp_secret(&self, user_id: &UserId, secret: Option<String>) -> Result<()>;
+        async fn transfer_credential(&self, from: &UserId, to: &UserId) -> Result<()>;
+        async fn invalidate_all_passwords(&self) -> Result<u64>;
+        async fn allow_password_overwrite(&self, user_id: &UserId) -> Result<()>;
+        async fn create_users_bulk(&self, requests: Vec<CreateUserRequest>) -> Result<()>;
+        async fn query_audit_log(
+            &self,
+            since: chrono::NaiveDateTime,
+            until: chrono::NaiveDateTime,
+        ) -> Result<Vec<AuditLogEntry>>;
+        async fn proposed_policy_compliance_report(&self) -> Result<PolicyComplianceReport>;
+        async fn add_group_to_group(&self, group_id: GroupId, parent_group_id: GroupId) -> Result<()>;
+        async fn remove_group_from_group(&self, group_id: GroupId, parent_group_id: GroupId) -> Result<()>;
+        async fn get_effective_groups(&self, user_id: &UserId) -> Result<HashSet<GroupDetails>>;
+        async fn delete_own_account(&self, request: BindRequest) -> Result<()>;
+        async fn restore_user(&self, user_id: &UserId) -> Result<()>;
+        async fn set_user_attribute(&self, user_id: &UserId, name: AttributeName, values: Vec<String>) -> Result<()>;
+        async fn full_health_report(&self) -> HealthReport;
+        async fn readiness_report(&self) -> HealthReport;
+        async fn generate_reset_token(&self, user_id: &UserId) -> Result<SecUtf8>;
+        async fn reset_password_with_token(&self, token: &str, new_password: SecUtf8) -> Result<()>;
+    }
     #[async_trait]
     impl OpaqueHandler for TestBackendHandler {
+        async fn login_challenge(&self) -> Result<login::ServerLoginChallengeResponse>;
         async fn login_start(
             &self,
-            request: login::ClientLoginStartRequest
+            request: login::ClientLoginStartRequest,
+            source: Option<std::net::IpAddr>
         ) -> Result<login::ServerLoginStartResponse>;
-        async fn login_finish(&self, request: login::ClientLoginFinishRequest) -> Result<UserId>;
+        async fn login_finish(
+            &self,
+            request: login::ClientLoginFinishRequest,
+            source: Option<std::net::IpAddr>
+        ) -> Result<SuccessfulLogin>;
         async fn registration_start(
             &self,
-            request: registration::ClientRegistrationStartRequest
+            request: registration::ClientRegistrationStartRequest,
+            source: Option<std::net::IpAddr>
         ) -> Result<registration::ServerRegistrationStartResponse>;
         async fn registration_finish(
             &self,
-            request: registration::ClientRegistrationFinishRequest
+            request: registration::ClientRegistrationFinishRequest,
+            actor: &UserId,
+            transport_secure: bool
         ) -> Result<()>;
     }
 }
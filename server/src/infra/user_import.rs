@@ -0,0 +1,85 @@
+use crate::domain::{handler::CreateUserRequest, types::UserId};
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+
+/// A line of the CSV file. The display name and the password hash can be empty, and the
+/// `password_hash` column left out.
+#[derive(Debug, Deserialize)]
+struct ImportedUser {
+    uid: String,
+    email: String,
+    #[serde(default)]
+    display_name: Option<String>,
+    /// The bcrypt hash of the password, e.g. "$2b$12$...".
+    #[serde(default)]
+    password_hash: Option<String>,
+}
+
+/// Reads the users from a CSV file with a header line: `uid,email,display_name,password_hash`.
+pub fn parse_users_csv(reader: impl std::io::Read) -> Result<Vec<CreateUserRequest>> {
+    csv::ReaderBuilder::new()
+        .flexible(true)
+        .trim(csv::Trim::All)
+        .from_reader(reader)
+        .deserialize::<ImportedUser>()
+        .map(|user| {
+            let user = user.context("while reading the users CSV file")?;
+            if user.uid.is_empty() {
+                bail!("Missing uid for the user with the email {}", user.email);
+            }
+            Ok(CreateUserRequest {
+                user_id: UserId::new(&user.uid),
+                email: user.email.into(),
+                display_name: user.display_name,
+                legacy_password_hash: user.password_hash,
+                ..Default::default()
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_parse_users_csv() {
+        let csv = "uid,email,display_name,password_hash
+bob,bob@example.com,\"Bob, the builder\",$2b$04$K7pW2vI5j2ZQ1Zk3lJ8qTe1Tz2JbVQ5r3i8oP9xWm0yVQw2nB6m5e
+alice,alice@example.com,,
+";
+        let users = parse_users_csv(csv.as_bytes()).unwrap();
+        assert_eq!(
+            users,
+            vec![
+                CreateUserRequest {
+                    user_id: UserId::new("bob"),
+                    email: "bob@example.com".into(),
+                    display_name: Some("Bob, the builder".to_owned()),
+                    legacy_password_hash: Some(
+                        "$2b$04$K7pW2vI5j2ZQ1Zk3lJ8qTe1Tz2JbVQ5r3i8oP9xWm0yVQw2nB6m5e".to_owned()
+                    ),
+                    ..Default::default()
+                },
+                CreateUserRequest {
+                    user_id: UserId::new("alice"),
+                    email: "alice@example.com".into(),
+                    ..Default::default()
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_users_csv_without_password_column() {
+        let users = parse_users_csv("uid,email\nbob,bob@example.com\n".as_bytes()).unwrap();
+        assert_eq!(users.len(), 1);
+        assert_eq!(users[0].legacy_password_hash, None);
+    }
+
+    #[test]
+    fn test_parse_users_csv_missing_uid() {
+        parse_users_csv("uid,email\n,bob@example.com\n".as_bytes()).unwrap_err();
+    }
+}
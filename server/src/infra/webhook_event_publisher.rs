@@ -0,0 +1,44 @@
+use crate::domain::{
+    error::Result,
+    events::{EventPublisher, LoginEvent},
+};
+use async_trait::async_trait;
+use std::time::Duration;
+use tracing::warn;
+
+/// POSTs each login event as JSON to a URL, e.g. the HTTP bridge of a message queue.
+pub struct WebhookEventPublisher {
+    client: reqwest::Client,
+    url: url::Url,
+}
+
+impl WebhookEventPublisher {
+    pub fn new(url: url::Url) -> Self {
+        Self {
+            client: reqwest::Client::builder()
+                .timeout(Duration::from_secs(10))
+                .build()
+                .expect("the HTTP client has a valid configuration"),
+            url,
+        }
+    }
+}
+
+#[async_trait]
+impl EventPublisher for WebhookEventPublisher {
+    async fn publish(&self, event: &LoginEvent) -> Result<()> {
+        let request = self
+            .client
+            .post(self.url.clone())
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(serde_json::to_string(event).expect("login events serialize to JSON"));
+        // The login doesn't wait for the delivery.
+        tokio::spawn(async move {
+            match request.send().await.and_then(|r| r.error_for_status()) {
+                Ok(_) => {}
+                Err(e) => warn!("Could not publish a login event: {}", e),
+            }
+        });
+        Ok(())
+    }
+}
@@ -0,0 +1,161 @@
+use crate::domain::events::{LifecycleEvent, LifecycleNotifier};
+use hmac::Mac;
+use secstr::SecUtf8;
+use std::time::Duration;
+use tracing::{debug, warn};
+
+/// HMAC-SHA256 of the body with the shared secret, as "sha256=<hex>".
+pub const SIGNATURE_HEADER: &str = "x-lldap-signature";
+
+const INITIAL_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// POSTs each lifecycle event as JSON to a URL, retrying with an exponential backoff.
+pub struct WebhookNotifier {
+    client: reqwest::Client,
+    url: url::Url,
+    secret: Option<SecUtf8>,
+    max_attempts: u32,
+}
+
+impl WebhookNotifier {
+    pub fn new(url: url::Url, secret: Option<SecUtf8>, max_attempts: u32) -> Self {
+        Self {
+            client: reqwest::Client::builder()
+                .timeout(Duration::from_secs(10))
+                .build()
+                .expect("the HTTP client has a valid configuration"),
+            url,
+            secret,
+            max_attempts: max_attempts.max(1),
+        }
+    }
+}
+
+fn sign(secret: &SecUtf8, body: &str) -> String {
+    let mut mac = hmac::Hmac::<sha2::Sha256>::new_from_slice(secret.unsecure().as_bytes())
+        .expect("HMAC accepts keys of any length");
+    mac.update(body.as_bytes());
+    let signature = mac
+        .finalize()
+        .into_bytes()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<String>();
+    format!("sha256={}", signature)
+}
+
+impl LifecycleNotifier for WebhookNotifier {
+    fn notify(&self, event: LifecycleEvent) {
+        let body = serde_json::to_string(&event).expect("lifecycle events serialize to JSON");
+        let mut request = self
+            .client
+            .post(self.url.clone())
+            .header(reqwest::header::CONTENT_TYPE, "application/json");
+        if let Some(secret) = &self.secret {
+            request = request.header(SIGNATURE_HEADER, sign(secret, &body));
+        }
+        let request = request.body(body);
+        let max_attempts = self.max_attempts;
+        tokio::spawn(async move {
+            let mut delay = INITIAL_RETRY_DELAY;
+            for attempt in 1..=max_attempts {
+                let result = request
+                    .try_clone()
+                    .expect("the body is not a stream")
+                    .send()
+                    .await
+                    .and_then(|r| r.error_for_status());
+                match result {
+                    Ok(_) => return,
+                    Err(e) if attempt < max_attempts => {
+                        debug!(
+                            "Could not deliver the {:?} event of \"{}\", retrying in {:?}: {}",
+                            event.event_type, event.user_id, delay, e
+                        );
+                        tokio::time::sleep(delay).await;
+                        delay *= 2;
+                    }
+                    Err(e) => warn!(
+                        "Could not deliver the {:?} event of \"{}\" after {} attempts: {}",
+                        event.event_type, event.user_id, max_attempts, e
+                    ),
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::{
+        sql_backend_handler::{tests::*, SqlBackendHandler},
+        types::UserId,
+    };
+    use std::sync::Arc;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    /// Accepts a single request, answers 200 and returns its headers and body.
+    async fn receive_request(listener: TcpListener) -> (String, String) {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut request = Vec::new();
+        let mut buffer = [0u8; 4096];
+        loop {
+            let read = stream.read(&mut buffer).await.unwrap();
+            request.extend_from_slice(&buffer[..read]);
+            let text = String::from_utf8_lossy(&request).to_string();
+            if let Some((headers, body)) = text.split_once("\r\n\r\n") {
+                let content_length = headers
+                    .lines()
+                    .find_map(|l| {
+                        l.to_lowercase()
+                            .strip_prefix("content-length:")
+                            .map(|v| v.trim().parse::<usize>().unwrap())
+                    })
+                    .unwrap_or_default();
+                if body.len() >= content_length {
+                    stream
+                        .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n")
+                        .await
+                        .unwrap();
+                    return (headers.to_lowercase(), body.to_owned());
+                }
+            }
+            assert!(read > 0, "The connection was closed");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_create_user_webhook() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = url::Url::parse(&format!("http://{}/", listener.local_addr().unwrap())).unwrap();
+        let secret = SecUtf8::from("shared secret");
+        let handler = SqlBackendHandler::new(get_default_config(), get_initialized_db().await)
+            .with_lifecycle_notifier(Arc::new(WebhookNotifier::new(url, Some(secret.clone()), 1)));
+        insert_user_no_password(&handler, "bob").await;
+        let (headers, body) =
+            tokio::time::timeout(Duration::from_secs(10), receive_request(listener))
+                .await
+                .unwrap();
+        let event: LifecycleEvent = serde_json::from_str(&body).unwrap();
+        assert_eq!(
+            event.event_type,
+            crate::domain::events::LifecycleEventType::UserCreated
+        );
+        assert_eq!(event.user_id, UserId::new("bob"));
+        assert_eq!(event.group_id, None);
+        assert!(headers.contains(&format!("{}: {}", SIGNATURE_HEADER, sign(&secret, &body))));
+    }
+
+    #[test]
+    fn test_signature() {
+        // From the HMAC-SHA256 test vectors (RFC 4231, test case 2).
+        assert_eq!(
+            sign(&SecUtf8::from("Jefe"), "what do ya want for nothing?"),
+            "sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+}
@@ -3,24 +3,36 @@
 // TODO: Remove next line once ubuntu upgrades rustc to >=1.67.1
 #![allow(clippy::uninlined_format_args)]
 
-use std::time::Duration;
+use std::{sync::Arc, time::Duration};
 
 use crate::{
     domain::{
         handler::{
-            CreateGroupRequest, CreateUserRequest, GroupBackendHandler, GroupListerBackendHandler,
-            GroupRequestFilter, UserBackendHandler, UserListerBackendHandler, UserRequestFilter,
+            BackendHandler, CreateGroupRequest, CreateUserRequest, GroupBackendHandler,
+            GroupListerBackendHandler, GroupRequestFilter, UserBackendHandler,
+            UserListerBackendHandler, UserRequestFilter,
         },
         sql_backend_handler::SqlBackendHandler,
-        sql_opaque_handler::register_password,
-        sql_tables::{get_private_key_info, set_private_key_info},
+        sql_opaque_handler::{register_password, PasswordActor},
+        sql_tables::{
+            get_private_key_info, get_server_setup_fingerprint, set_private_key_info,
+            set_server_setup_fingerprint,
+        },
     },
     infra::{
+        auth_monitor::AuthMonitor,
         cli::*,
-        configuration::{compare_private_key_hashes, Configuration},
-        database_string::DatabaseUrl,
+        configuration::{
+            compare_cluster_setup_fingerprints, compare_private_key_hashes, Configuration,
+            MetricsOptions,
+        },
         db_cleaner::Scheduler,
-        healthcheck, mail,
+        healthcheck,
+        inactive_user_monitor::InactiveUserMonitor,
+        mail,
+        rate_limiter::{RateLimiter, RateLimiterEvictor},
+        webhook_event_publisher::WebhookEventPublisher,
+        webhook_notifier::WebhookNotifier,
     },
 };
 use actix::Actor;
@@ -48,7 +60,12 @@ async fn create_admin_user(handler: &SqlBackendHandler, config: &Configuration)
             ..Default::default()
         })
         .and_then(|_| {
-            register_password(handler, config.ldap_user_dn.clone(), &config.ldap_user_pass)
+            register_password(
+                handler,
+                config.ldap_user_dn.clone(),
+                &config.ldap_user_pass,
+                PasswordActor::Admin,
+            )
         })
         .await
         .context("Error creating admin user")?;
@@ -80,29 +97,85 @@ async fn ensure_group_exists(handler: &SqlBackendHandler, group_name: &str) -> R
     Ok(())
 }
 
-async fn setup_sql_tables(database_url: &DatabaseUrl) -> Result<DatabaseConnection> {
-    let sql_pool = {
-        let mut sql_opt = sea_orm::ConnectOptions::new(database_url.to_string());
-        sql_opt
-            .max_connections(5)
-            .sqlx_logging(true)
-            .sqlx_logging_level(log::LevelFilter::Debug);
-        Database::connect(sql_opt).await?
+/// Emails identify users for password resets: warns about the ones shared by several users, or
+/// refuses to start if they have to be unique. Returns whether the users can still be
+/// identified by their email.
+async fn check_unique_emails(
+    handler: &impl BackendHandler,
+    require_unique_emails: bool,
+    allow_email_login: bool,
+) -> Result<bool> {
+    use itertools::Itertools;
+    let duplicates = handler
+        .find_duplicate_emails()
+        .await
+        .context("while looking for duplicate emails")?;
+    if duplicates.is_empty() {
+        return Ok(allow_email_login);
+    }
+    let description = duplicates
+        .iter()
+        .map(|d| format!("{} ({})", d.email, d.user_ids.iter().join(", ")))
+        .join("; ");
+    if require_unique_emails {
+        bail!(
+            "Some emails are shared by several users: {}. Change them, or set require_unique_emails to false",
+            description
+        );
+    }
+    if allow_email_login {
+        // Whatever require_unique_emails says: a shared email could log in the wrong user.
+        warn!(
+            "Some emails are shared by several users, the users can only be identified by their user ID until they are changed: {}",
+            description
+        );
+    } else {
+        warn!("Some emails are shared by several users: {}", description);
+    }
+    Ok(false)
+}
+
+async fn setup_sql_tables(config: &Configuration) -> Result<DatabaseConnection> {
+    let database_url = config
+        .database_url
+        .with_tls_options(&config.database_tls_options)?;
+    let mut sql_opt = config.db_connect_options(database_url.to_string());
+    sql_opt
+        .sqlx_logging(true)
+        .sqlx_logging_level(log::LevelFilter::Debug);
+    let mut sql_pool = match &config.database_encryption_key {
+        Some(key) => {
+            infra::database_encryption::connect_encrypted(
+                &database_url,
+                key,
+                sql_opt.sqlx_pool_options(),
+            )
+            .await?
+        }
+        None => Database::connect(sql_opt).await?,
     };
+    if config.metrics_options.enable_metrics {
+        infra::metrics::instrument_sql_pool(&mut sql_pool);
+    }
     domain::sql_tables::init_table(&sql_pool)
         .await
         .context("while creating base tables")?;
     infra::jwt_sql_tables::init_table(&sql_pool)
         .await
         .context("while creating jwt tables")?;
+    if config.db_warm_up_pool {
+        domain::sql_tables::warm_up_pool(&sql_pool, config.db_min_connections)
+            .await
+            .context("while opening the database connections")?;
+    }
     Ok(sql_pool)
 }
 
 #[instrument(skip_all)]
-async fn set_up_server(config: Configuration) -> Result<ServerBuilder> {
+async fn set_up_server(mut config: Configuration) -> Result<ServerBuilder> {
     info!("Starting LLDAP version {}", env!("CARGO_PKG_VERSION"));
 
-    let sql_pool = setup_sql_tables(&config.database_url).await?;
+    let sql_pool = setup_sql_tables(&config).await?;
     let private_key_info = config.get_private_key_info();
     let force_update_private_key = config.force_update_private_key;
     match (
@@ -117,16 +190,51 @@ async fn set_up_server(config: Configuration) -> Result<ServerBuilder> {
         }
         (Ok(true), _) | (Err(_), true) => {
             set_private_key_info(&sql_pool, private_key_info).await?;
+            set_server_setup_fingerprint(&sql_pool, config.cluster_setup_fingerprint()).await?;
         }
         (Ok(false), false) => {}
         (Err(e), false) => {
             return Err(anyhow!("The private key encoding the passwords has changed since last successful startup. Changing the private key will invalidate all existing passwords. If you want to proceed, restart the server with the CLI arg --force-update-private-key=true or the env variable LLDAP_FORCE_UPDATE_PRIVATE_KEY=true. You probably also want --force-ldap-user-pass-reset / LLDAP_FORCE_LDAP_USER_PASS_RESET=true to reset the admin password to the value in the configuration.").context(e));
         }
     }
+    if compare_cluster_setup_fingerprints(
+        get_server_setup_fingerprint(&sql_pool).await?.as_ref(),
+        &config.cluster_setup_fingerprint(),
+    )? {
+        set_server_setup_fingerprint(&sql_pool, config.cluster_setup_fingerprint()).await?;
+    }
     let backend_handler = SqlBackendHandler::new(config.clone(), sql_pool.clone());
+    let backend_handler = match &config.login_event_webhook_url {
+        Some(url) => {
+            backend_handler.with_event_publisher(Arc::new(WebhookEventPublisher::new(url.clone())))
+        }
+        None => backend_handler,
+    };
+    let backend_handler = match &config.lifecycle_webhook_options.url {
+        Some(url) => backend_handler.with_lifecycle_notifier(Arc::new(WebhookNotifier::new(
+            url.clone(),
+            config.lifecycle_webhook_options.secret.clone(),
+            config.lifecycle_webhook_options.max_attempts,
+        ))),
+        None => backend_handler,
+    };
+    let backend_handler = if config.smtp_options.enable_welcome_email {
+        backend_handler.with_welcome_notifier(Arc::new(mail::Mailer::new(
+            config.smtp_options.clone(),
+            config.http_url.clone(),
+        )))
+    } else {
+        backend_handler
+    };
     ensure_group_exists(&backend_handler, "lldap_admin").await?;
     ensure_group_exists(&backend_handler, "lldap_password_manager").await?;
     ensure_group_exists(&backend_handler, "lldap_strict_readonly").await?;
+    config.allow_email_login = check_unique_emails(
+        &backend_handler,
+        config.require_unique_emails,
+        config.allow_email_login,
+    )
+    .await?;
     let admin_present = if let Ok(admins) = backend_handler
         .list_users(
             Some(UserRequestFilter::MemberOf("lldap_admin".into())),
@@ -146,10 +254,18 @@ async fn set_up_server(config: Configuration) -> Result<ServerBuilder> {
             .context("while creating the admin user")?;
     } else if config.force_ldap_user_pass_reset {
         warn!("Forcing admin password reset to the config-provided password");
+        backend_handler
+            .allow_password_overwrite(&config.ldap_user_dn)
+            .await
+            .context(format!(
+                "while resetting admin password for {}",
+                &config.ldap_user_dn
+            ))?;
         register_password(
             &backend_handler,
             config.ldap_user_dn.clone(),
             &config.ldap_user_pass,
+            PasswordActor::Admin,
         )
         .await
         .context(format!(
@@ -160,18 +276,65 @@ async fn set_up_server(config: Configuration) -> Result<ServerBuilder> {
     if config.force_update_private_key || config.force_ldap_user_pass_reset {
         bail!("Restart the server without --force-update-private-key or --force-ldap-user-pass-reset to continue.");
     }
+    let rate_limit_options = &config.rate_limit_options;
+    let rate_limiter =
+        if rate_limit_options.rate > 0.0 || rate_limit_options.authenticated_rate > 0.0 {
+            let rate_limiter = Arc::new(RateLimiter::new(rate_limit_options));
+            RateLimiterEvictor::new(
+                rate_limiter.clone(),
+                Duration::from_secs(rate_limit_options.eviction_interval_seconds),
+            )
+            .start();
+            Some(rate_limiter)
+        } else {
+            None
+        };
     let server_builder = infra::ldap_server::build_ldap_server(
         &config,
         backend_handler.clone(),
+        rate_limiter.clone(),
         actix_server::Server::build(),
     )
     .context("while binding the LDAP server")?;
+    if config.require_tls_for_bind && !config.ldaps_options.enabled {
+        warn!("require_tls_for_bind is set without ldaps_options: all the LDAP binds are refused");
+    }
+    if config.auth_alert_options.failure_ratio_threshold > 0.0 {
+        if config.enable_auth_events {
+            AuthMonitor::new(config.auth_alert_options.clone(), backend_handler.clone()).start();
+        } else {
+            warn!("auth_alert_options.failure_ratio_threshold is set, but the alerts need enable_auth_events");
+        }
+    }
+    if config.disable_inactive_users_after_days > 0 {
+        if config.track_login_statistics {
+            InactiveUserMonitor::new(
+                config.disable_inactive_users_after_days,
+                backend_handler.clone(),
+            )
+            .start();
+        } else {
+            warn!("disable_inactive_users_after_days is set, but it needs track_login_statistics");
+        }
+    }
     let server_builder =
-        infra::tcp_server::build_tcp_server(&config, backend_handler, server_builder)
+        infra::tcp_server::build_tcp_server(&config, backend_handler, rate_limiter, server_builder)
             .await
             .context("while binding the TCP server")?;
+    let server_builder = match &config.metrics_options {
+        MetricsOptions {
+            enable_metrics: true,
+            bind_address: Some(bind_address),
+        } => infra::metrics::build_metrics_server(bind_address, server_builder)
+            .context("while binding the metrics server")?,
+        _ => server_builder,
+    };
     // Run every hour.
-    let scheduler = Scheduler::new("0 0 * * * * *", sql_pool);
+    let scheduler = Scheduler::new(
+        "0 0 * * * * *",
+        sql_pool,
+        config.deleted_user_retention_days,
+    );
     scheduler.start();
     Ok(server_builder)
 }
@@ -185,7 +348,9 @@ async fn run_server_command(opts: RunOpts) -> Result<()> {
     let server = set_up_server(config).await?.workers(1);
 
     dbg!("here");
-    server.run().await.context("while starting the server")
+    let result = server.run().await.context("while starting the server");
+    infra::logging::shutdown();
+    result
 }
 
 async fn send_test_email_command(opts: TestEmailOpts) -> Result<()> {
@@ -233,11 +398,60 @@ async fn create_schema_command(opts: RunOpts) -> Result<()> {
     debug!("CLI: {:#?}", &opts);
     let config = infra::configuration::init(opts)?;
     infra::logging::init(&config)?;
-    setup_sql_tables(&config.database_url).await?;
+    setup_sql_tables(&config).await?;
     info!("Schema created successfully.");
     Ok(())
 }
 
+async fn rotate_keys_command(opts: RotateKeysOpts) -> Result<()> {
+    debug!("CLI: {:#?}", &opts);
+    let new_key_seed = opts.new_key_seed.clone();
+    let mut config = infra::configuration::init(opts)?;
+    infra::logging::init(&config)?;
+    let sql_pool = setup_sql_tables(&config).await?;
+    // Don't invalidate all the passwords because of a misplaced key file.
+    compare_private_key_hashes(
+        get_private_key_info(&sql_pool).await?.as_ref(),
+        &config.get_private_key_info(),
+    )
+    .context("while checking the current server key")?;
+    config
+        .rotate_server_setup(new_key_seed.as_deref())
+        .context("while replacing the server key")?;
+    let invalidated = SqlBackendHandler::new(config.clone(), sql_pool.clone())
+        .invalidate_all_passwords()
+        .await
+        .context("while invalidating the passwords")?;
+    set_private_key_info(&sql_pool, config.get_private_key_info()).await?;
+    set_server_setup_fingerprint(&sql_pool, config.cluster_setup_fingerprint()).await?;
+    info!(
+        "Replaced the server key and invalidated the passwords of {} users. Restart the server within {} seconds for the logins in flight to get a meaningful error, and set the admin password again with --force-ldap-user-pass-reset.",
+        invalidated, config.key_rotation_grace_seconds
+    );
+    if new_key_seed.is_some() {
+        warn!("Put the new key seed in the configuration before restarting the server.");
+    }
+    Ok(())
+}
+
+async fn import_users_command(opts: ImportUsersOpts) -> Result<()> {
+    debug!("CLI: {:#?}", &opts);
+    let csv_file = opts.csv_file.clone();
+    let config = infra::configuration::init(opts)?;
+    infra::logging::init(&config)?;
+    let users = infra::user_import::parse_users_csv(
+        std::fs::File::open(&csv_file).context(format!("while opening {}", csv_file))?,
+    )?;
+    let sql_pool = setup_sql_tables(&config).await?;
+    let count = users.len();
+    SqlBackendHandler::new(config, sql_pool)
+        .create_users_bulk(users)
+        .await
+        .context("while importing the users, none were created")?;
+    info!("Imported {} users from {}.", count, csv_file);
+    Ok(())
+}
+
 #[actix::main]
 async fn main() -> Result<()> {
     let cli_opts = infra::cli::init();
@@ -247,5 +461,56 @@ async fn main() -> Result<()> {
         Command::HealthCheck(opts) => run_healthcheck(opts).await,
         Command::SendTestEmail(opts) => send_test_email_command(opts).await,
         Command::CreateSchema(opts) => create_schema_command(opts).await,
+        Command::RotateKeys(opts) => rotate_keys_command(opts).await,
+        Command::ImportUsers(opts) => import_users_command(opts).await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{domain::handler::DuplicateEmail, infra::test_utils::MockTestBackendHandler};
+
+    fn handler_with_duplicates(duplicates: Vec<DuplicateEmail>) -> MockTestBackendHandler {
+        let mut handler = MockTestBackendHandler::new();
+        handler
+            .expect_find_duplicate_emails()
+            .return_once(|| Ok(duplicates));
+        handler
+    }
+
+    #[tokio::test]
+    async fn test_check_unique_emails() {
+        let duplicates = vec![DuplicateEmail {
+            email: "bob@example.com".to_owned(),
+            user_ids: vec![
+                crate::domain::types::UserId::new("bob"),
+                crate::domain::types::UserId::new("bobby"),
+            ],
+        }];
+        assert!(
+            !check_unique_emails(&handler_with_duplicates(duplicates.clone()), false, false)
+                .await
+                .unwrap()
+        );
+        // The emails can't identify the users anymore, even if they don't have to be unique.
+        assert!(
+            !check_unique_emails(&handler_with_duplicates(duplicates.clone()), false, true)
+                .await
+                .unwrap()
+        );
+        let err = check_unique_emails(&handler_with_duplicates(duplicates), true, true)
+            .await
+            .unwrap_err();
+        assert!(
+            err.to_string().contains("bob@example.com (bob, bobby)"),
+            "{}",
+            err
+        );
+        assert!(
+            check_unique_emails(&handler_with_duplicates(vec![]), true, true)
+                .await
+                .unwrap()
+        );
     }
 }
@@ -123,6 +123,7 @@ fn main() -> Result<()> {
     let start_request = registration::ClientRegistrationStartRequest {
         username: opts.username.clone().into(),
         registration_start_request: registration_start_request.message,
+        context: None,
     };
     let res = register_start(&opts.base_url, &token, start_request)?;
 